@@ -0,0 +1,313 @@
+//! Asynchronous Memcached Client (tokio)
+//!
+//! Available with the `tokio` cargo feature. `AsyncClient`
+//! multiplexes any number of concurrent requests over one
+//! connection. Each request is tagged with a unique `opaque`
+//! and a background task matches replies back to the waiting
+//! futures with `ResHeader::get_opaque`.
+//!
+//! Quiet opcodes are sent in their loud form, since every
+//! request waits on it's reply, and the entries of a `Stat`
+//! reply are collected until it's closing packet.
+//!
+//! `execute` is cancel safe as far as the connection goes.
+//! Encoded packets are handed whole to a writer task, so a
+//! dropped future never leaves half a packet on the wire, and
+//! it's reply is discarded when it arrives. A request which was
+//! already handed over is still sent, so dropping the future of
+//! a mutation does not undo it.
+
+use super::ParseResult;
+use super::opcode::OpCode;
+use super::status::StatusField;
+use super::response::OwnedResponse;
+use super::builder::RequestBuilder;
+use super::stream::StreamDecoder;
+#[cfg(feature = "compression")]
+use super::compression::{
+  Compression,
+  decompress_response
+};
+use super::client::{
+  ClientError,
+  Item,
+  check,
+  counter
+};
+use super::stats::Stats;
+use tokio::io::{
+  AsyncRead,
+  AsyncWrite,
+  AsyncReadExt,
+  AsyncWriteExt
+};
+use tokio::net::{
+  TcpStream,
+  ToSocketAddrs
+};
+use tokio::sync::mpsc::{
+  unbounded_channel,
+  UnboundedReceiver,
+  UnboundedSender
+};
+use tokio::sync::oneshot;
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{
+  AtomicU32,
+  Ordering
+};
+
+/// A request waiting on it's replies
+struct Waiter {
+  tx: oneshot::Sender<Vec<OwnedResponse>>,
+  stats: Vec<OwnedResponse>
+}
+
+/// Replies waiting to be delivered, by `opaque`
+type Pending = Arc<::std::sync::Mutex<Option<HashMap<u32, Waiter>>>>;
+
+/// Error returned once the connection has gone away
+fn closed() -> ClientError {
+  ClientError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"))
+}
+
+/// Background task reading replies off the connection
+async fn read_loop<R: AsyncRead + Unpin>(mut r: R, pending: Pending) {
+  let mut decoder = StreamDecoder::<OwnedResponse>::new();
+  let mut buf = vec![0u8; 4096];
+  'conn: loop {
+    let n = match r.read(&mut buf).await {
+      Ok(0) | Err(_) => break 'conn,
+      Ok(n) => n
+    };
+    decoder.push(&buf[..n]);
+    loop {
+      let resp = match decoder.next_packet() {
+        ParseResult::Ok(Option::Some(resp)) => resp,
+        ParseResult::Ok(Option::None) => break,
+        ParseResult::Err(_) => break 'conn
+      };
+      let opaque = resp.get_opaque();
+      let waiter = match *pending.lock().unwrap() {
+        //stat entries wait for the closing reply without a key
+        Option::Some(ref mut map) if resp.get_opcode() == OpCode::Stat && resp.has_key() => {
+          if let Some(w) = map.get_mut(&opaque) {
+            w.stats.push(resp);
+          }
+          continue;
+        },
+        Option::Some(ref mut map) => map.remove(&opaque),
+        Option::None => break 'conn
+      };
+      if let Some(mut w) = waiter {
+        w.stats.push(resp);
+        let _ = w.tx.send(w.stats);
+      }
+    }
+  }
+  //dropping the senders wakes every waiter with an error
+  pending.lock().unwrap().take();
+}
+
+/// Background task writing whole packets to the connection
+async fn write_loop<W: AsyncWrite + Unpin>(mut w: W, mut packets: UnboundedReceiver<Vec<u8>>, pending: Pending) {
+  while let Some(mut out) = packets.recv().await {
+    while let Ok(more) = packets.try_recv() {
+      out.extend_from_slice(&more);
+    }
+    let wrote = match w.write_all(&out).await {
+      Ok(()) => w.flush().await,
+      Err(e) => Err(e)
+    };
+    if wrote.is_err() {
+      break;
+    }
+  }
+  let _ = w.shutdown().await;
+  pending.lock().unwrap().take();
+}
+
+/// Forgets a request's reply when it's future is dropped
+struct Waiting<'a> {
+  pending: &'a Pending,
+  opaque: u32
+}
+impl<'a> Drop for Waiting<'a> {
+  fn drop(&mut self) {
+    if let Some(ref mut map) = *self.pending.lock().unwrap() {
+      map.remove(&self.opaque);
+    }
+  }
+}
+
+/// Asynchronous, multiplexing Memcached Client
+///
+/// Requests may be issued concurrently from many tasks, the
+/// client is `Send + Sync` and can be shared with an `Arc`.
+/// Must be created from within a tokio runtime.
+pub struct AsyncClient {
+  writer: UnboundedSender<Vec<u8>>,
+  pending: Pending,
+  opaque: AtomicU32,
+  #[cfg(feature = "compression")]
+  compression: Option<Compression>
+}
+impl AsyncClient {
+
+  /// Connect to a server over TCP
+  pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<AsyncClient, ClientError> {
+    let stream = TcpStream::connect(addr).await?;
+    stream.set_nodelay(true)?;
+    Ok(AsyncClient::new(stream))
+  }
+  /// Wrap a connected stream, spawning the tasks which write
+  /// requests and read replies.
+  pub fn new<S: AsyncRead + AsyncWrite + Send + 'static>(stream: S) -> AsyncClient {
+    let (r, w) = tokio::io::split(stream);
+    let pending: Pending = Arc::new(::std::sync::Mutex::new(Some(HashMap::new())));
+    let (writer, packets) = unbounded_channel();
+    tokio::spawn(read_loop(r, pending.clone()));
+    tokio::spawn(write_loop(w, packets, pending.clone()));
+    AsyncClient {
+      writer,
+      pending,
+      opaque: AtomicU32::new(0),
+      #[cfg(feature = "compression")]
+      compression: None
+    }
+  }
+  /// Compress large values sent by `execute`, see
+  /// `Client::set_compression`.
+  #[cfg(feature = "compression")]
+  #[inline]
+  pub fn set_compression(&mut self, c: Option<Compression>) {
+    self.compression = c;
+  }
+  /// Build a request (with a fresh `opaque`) in it's loud
+  /// form, send it, and wait for all of it's replies. The last
+  /// is never missing.
+  async fn send(&self, b: RequestBuilder<'_>) -> Result<Vec<OwnedResponse>, ClientError> {
+    let opaque = self.opaque.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+    let mut req = b.opaque(opaque).build()?;
+    req.set_opcode(req.get_opcode().loud_form());
+    #[cfg(feature = "compression")]
+    if let Some(ref c) = self.compression {
+      c.compress_request(&mut req);
+    }
+    let (tx, rx) = oneshot::channel();
+    match *self.pending.lock().unwrap() {
+      Option::Some(ref mut map) => map.insert(opaque, Waiter{ tx, stats: Vec::new() }),
+      Option::None => return Err(closed())
+    };
+    let _waiting = Waiting{ pending: &self.pending, opaque };
+    if self.writer.send(req.encode_self().get_vec()).is_err() {
+      return Err(closed());
+    }
+    rx.await.map_err(|_| closed())
+  }
+  /// Build a request (with a fresh `opaque`), send it, and
+  /// wait for it's reply. The reply is returned even if it
+  /// carries an error status. For `Stat` this is the closing
+  /// reply, `stat` returns the entries.
+  pub async fn execute(&self, b: RequestBuilder<'_>) -> Result<OwnedResponse, ClientError> {
+    #[cfg_attr(not(feature = "compression"), allow(unused_mut))]
+    let mut resp = self.send(b).await?.pop().ok_or_else(closed)?;
+    #[cfg(feature = "compression")]
+    decompress_response(&mut resp)?;
+    Ok(resp)
+  }
+  /// Read an item. A missing key is `Ok(None)`.
+  pub async fn get(&self, key: &[u8]) -> Result<Option<Item>, ClientError> {
+    let resp = self.execute(RequestBuilder::get(key)).await?;
+    match resp.check_status() {
+      Err(StatusField::KeyNotFound) => Ok(None),
+      Err(e) => Err(ClientError::Status(e)),
+      Ok(()) => Item::from_response(resp).map(Some)
+    }
+  }
+  /// Store an item. Returns the item's new CAS value.
+  pub async fn set(&self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> Result<u64, ClientError> {
+    let b = RequestBuilder::set(key, value).flags(flags).expire(expiration);
+    Ok(check(self.execute(b).await?)?.get_cas())
+  }
+  /// Store an item only if it does not exist. Returns the
+  /// item's new CAS value.
+  pub async fn add(&self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> Result<u64, ClientError> {
+    let b = RequestBuilder::add(key, value).flags(flags).expire(expiration);
+    Ok(check(self.execute(b).await?)?.get_cas())
+  }
+  /// Store an item only if it already exists. Returns the
+  /// item's new CAS value.
+  pub async fn replace(&self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> Result<u64, ClientError> {
+    let b = RequestBuilder::replace(key, value).flags(flags).expire(expiration);
+    Ok(check(self.execute(b).await?)?.get_cas())
+  }
+  pub async fn delete(&self, key: &[u8]) -> Result<(), ClientError> {
+    check(self.execute(RequestBuilder::delete(key)).await?).map(|_| ())
+  }
+  /// Increment a counter, returning it's new value. A missing
+  /// counter is created holding `initial`.
+  pub async fn incr(&self, key: &[u8], delta: u64, initial: u64, expiration: u32) -> Result<u64, ClientError> {
+    let b = RequestBuilder::incr(key, delta).initial(initial).expire(expiration);
+    counter(&check(self.execute(b).await?)?)
+  }
+  /// Decrement a counter, returning it's new value. A missing
+  /// counter is created holding `initial`.
+  pub async fn decr(&self, key: &[u8], delta: u64, initial: u64, expiration: u32) -> Result<u64, ClientError> {
+    let b = RequestBuilder::decr(key, delta).initial(initial).expire(expiration);
+    counter(&check(self.execute(b).await?)?)
+  }
+  pub async fn append(&self, key: &[u8], value: &[u8]) -> Result<(), ClientError> {
+    check(self.execute(RequestBuilder::append(key, value)).await?).map(|_| ())
+  }
+  pub async fn prepend(&self, key: &[u8], value: &[u8]) -> Result<(), ClientError> {
+    check(self.execute(RequestBuilder::prepend(key, value)).await?).map(|_| ())
+  }
+  /// Change the expiration of an item
+  pub async fn touch(&self, key: &[u8], expiration: u32) -> Result<(), ClientError> {
+    check(self.execute(RequestBuilder::touch(key, expiration)).await?).map(|_| ())
+  }
+  /// Get And Touch. A missing key is `Ok(None)`.
+  pub async fn gat(&self, key: &[u8], expiration: u32) -> Result<Option<Item>, ClientError> {
+    let resp = self.execute(RequestBuilder::gat(key, expiration)).await?;
+    match resp.check_status() {
+      Err(StatusField::KeyNotFound) => Ok(None),
+      Err(e) => Err(ClientError::Status(e)),
+      Ok(()) => Item::from_response(resp).map(Some)
+    }
+  }
+  /// Invalidate every item, optionally after a delay
+  pub async fn flush(&self, delay: Option<u32>) -> Result<(), ClientError> {
+    let b = match delay {
+      Option::Some(d) => RequestBuilder::flush().expire(d),
+      Option::None => RequestBuilder::flush()
+    };
+    check(self.execute(b).await?).map(|_| ())
+  }
+  /// The server's version string
+  pub async fn version(&self) -> Result<String, ClientError> {
+    let resp = check(self.execute(RequestBuilder::version()).await?)?;
+    Ok(String::from_utf8_lossy(&resp.body).into_owned())
+  }
+  pub async fn noop(&self) -> Result<(), ClientError> {
+    check(self.execute(RequestBuilder::noop()).await?).map(|_| ())
+  }
+  /// Read server statistics, optionally for a group such
+  /// as `"items"` or `"slabs"`. Pairs are `(name, value)`.
+  pub async fn stat(&self, group: Option<&str>) -> Result<Vec<(String, String)>, ClientError> {
+    let mut resps = self.send(RequestBuilder::stat(group.map(|g| g.as_bytes()))).await?;
+    if let Some(last) = resps.pop() {
+      check(last)?;
+    }
+    Ok(resps.iter().map(|r| (
+      String::from_utf8_lossy(&r.key).into_owned(),
+      String::from_utf8_lossy(&r.body).into_owned())).collect())
+  }
+  /// Read server statistics into a `Stats` map, see
+  /// `Client::stats`.
+  pub async fn stats(&self, group: Option<&str>) -> Result<Stats, ClientError> {
+    Ok(self.stat(group).await?.into_iter().collect())
+  }
+}
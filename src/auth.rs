@@ -0,0 +1,348 @@
+//! SASL Authentication
+//!
+//! Supports the `PLAIN` and `CRAM-MD5` mechanisms on both ends
+//! of the connection.
+//!
+//!* `Credentials::authenticate` runs an exchange from a
+//!  `Client`, answering `StatusField::AuthContinue` challenges
+//!  until the server accepts or rejects it.
+//!* `Authenticator` wraps a `server::Handler`, verifying
+//!  exchanges against a table of users and refusing every other
+//!  command (with `StatusField::AuthError`) until the connection
+//!  has authenticated.
+//!
+//! `PLAIN` sends the password in the clear, only use it over a
+//! trusted network.
+
+use super::opcode::OpCode;
+use super::status::StatusField;
+use super::builder::RequestBuilder;
+use super::client::{
+  Client,
+  ClientError
+};
+use super::server::{
+  Handler,
+  HandlerResult,
+  Session,
+  Sasl,
+  Value,
+  StoreRequest,
+  ArithRequest
+};
+use std::collections::HashMap;
+use std::io::{
+  Read,
+  Write
+};
+use std::sync::atomic::{
+  AtomicU64,
+  Ordering
+};
+use std::time::{
+  SystemTime,
+  UNIX_EPOCH
+};
+
+/// Challenges answered before an exchange is abandoned
+const MAX_STEPS: usize = 8;
+
+/// SASL mechanisms this crate implements
+#[derive(Copy,Clone,Debug,PartialEq,Eq,Hash)]
+pub enum Mechanism {
+  Plain,
+  CramMd5
+}
+impl Mechanism {
+  /// Name on the wire
+  #[inline]
+  pub fn name(&self) -> &'static str {
+    match *self {
+      Mechanism::Plain => "PLAIN",
+      Mechanism::CramMd5 => "CRAM-MD5"
+    }
+  }
+  #[inline]
+  pub fn from_name(name: &[u8]) -> Option<Mechanism> {
+    match name {
+      b"PLAIN" => Some(Mechanism::Plain),
+      b"CRAM-MD5" => Some(Mechanism::CramMd5),
+      _ => None
+    }
+  }
+}
+
+/// HMAC-MD5 (RFC 2104)
+pub fn hmac_md5(key: &[u8], data: &[u8]) -> [u8; 16] {
+  let mut block = [0u8; 64];
+  if key.len() > 64 {
+    block[..16].copy_from_slice(&md5::compute(key)[..]);
+  } else {
+    block[..key.len()].copy_from_slice(key);
+  }
+  let mut inner = md5::Context::new();
+  let mut outer = md5::Context::new();
+  let ipad: Vec<u8> = block.iter().map(|b| b ^ 0x36).collect();
+  let opad: Vec<u8> = block.iter().map(|b| b ^ 0x5C).collect();
+  inner.consume(&ipad);
+  inner.consume(data);
+  outer.consume(&opad);
+  outer.consume(&inner.compute()[..]);
+  outer.compute().0
+}
+
+#[inline]
+fn hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Compare secrets without an early exit, so the time taken
+/// does not tell how much of a guess was right. Only the length
+/// of `a` is revealed.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  if a.len() != b.len() {
+    return false;
+  }
+  a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// The `CRAM-MD5` answer to `challenge`, `user hexdigest`
+#[inline]
+fn cram_md5(user: &str, password: &str, challenge: &[u8]) -> Vec<u8> {
+  format!("{} {}", user, hex(&hmac_md5(password.as_bytes(), challenge))).into_bytes()
+}
+
+/// Mechanisms offered by a server, from `SASLlistmech`
+pub fn list_mechanisms<S: Read + Write>(c: &mut Client<S>) -> Result<Vec<String>, ClientError> {
+  let resp = c.execute(RequestBuilder::sasl_list())?;
+  resp.check_status()?;
+  Ok(String::from_utf8_lossy(&resp.body)
+    .split_whitespace()
+    .map(|s| s.to_string())
+    .collect())
+}
+
+/// A user name and password
+#[derive(Clone,PartialEq,Eq)]
+pub struct Credentials {
+  pub user: String,
+  pub password: String
+}
+impl Credentials {
+  #[inline]
+  pub fn new(user: &str, password: &str) -> Credentials {
+    Credentials {
+      user: user.to_string(),
+      password: password.to_string()
+    }
+  }
+  /// Data sent with `SASLAuth`
+  pub fn initial(&self, m: Mechanism) -> Vec<u8> {
+    match m {
+      //authorization id, user, password
+      Mechanism::Plain => format!("\0{}\0{}", self.user, self.password).into_bytes(),
+      Mechanism::CramMd5 => Vec::new()
+    }
+  }
+  /// Answer to a server challenge. `None` if the mechanism
+  /// does not take challenges.
+  pub fn respond(&self, m: Mechanism, challenge: &[u8]) -> Option<Vec<u8>> {
+    match m {
+      Mechanism::Plain => None,
+      Mechanism::CramMd5 => Some(cram_md5(&self.user, &self.password, challenge))
+    }
+  }
+  /// Authenticate a connection with `m`.
+  ///
+  /// A rejection is `ClientError::Status(StatusField::AuthError)`.
+  pub fn authenticate<S: Read + Write>(&self, c: &mut Client<S>, m: Mechanism) -> Result<(), ClientError> {
+    let name = m.name().as_bytes();
+    let mut resp = c.execute(RequestBuilder::sasl_auth(name, &self.initial(m)))?;
+    for _ in 0..MAX_STEPS {
+      match resp.get_status() {
+        StatusField::NoError => return Ok(()),
+        StatusField::AuthContinue => {
+          let answer = match self.respond(m, &resp.body) {
+            Option::Some(answer) => answer,
+            Option::None => return Err(ClientError::UnexpectedResponse(resp.get_opcode()))
+          };
+          resp = c.execute(RequestBuilder::sasl_step(name, &answer))?;
+        },
+        status => return Err(ClientError::Status(status))
+      };
+    }
+    Err(ClientError::Status(StatusField::AuthError))
+  }
+  /// Authenticate with the strongest mechanism the server
+  /// offers, returning the one used.
+  pub fn authenticate_best<S: Read + Write>(&self, c: &mut Client<S>) -> Result<Mechanism, ClientError> {
+    let offered = list_mechanisms(c)?;
+    let m = [Mechanism::CramMd5, Mechanism::Plain].iter()
+      .find(|m| offered.iter().any(|o| o == m.name()))
+      .cloned()
+      .ok_or(ClientError::Status(StatusField::AuthError))?;
+    self.authenticate(c, m)?;
+    Ok(m)
+  }
+}
+
+/// Server side SASL verification
+///
+/// Answers the SASL commands from a table of users, and only
+/// lets authenticated connections reach the wrapped handler.
+pub struct Authenticator<H: Handler> {
+  inner: H,
+  users: HashMap<String, String>,
+  nonce: AtomicU64
+}
+impl<H: Handler> Authenticator<H> {
+  #[inline]
+  pub fn new(inner: H) -> Authenticator<H> {
+    Authenticator {
+      inner,
+      users: HashMap::new(),
+      nonce: AtomicU64::new(0)
+    }
+  }
+  /// Add a user
+  #[inline]
+  pub fn with_user(mut self, user: &str, password: &str) -> Authenticator<H> {
+    self.users.insert(user.to_string(), password.to_string());
+    self
+  }
+  #[inline(always)]
+  pub fn get_ref(&self) -> &H {
+    &self.inner
+  }
+  /// A challenge which is not repeated
+  fn challenge(&self) -> Vec<u8> {
+    let n = self.nonce.fetch_add(1, Ordering::SeqCst);
+    let t = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    format!("<{}.{}@mbpr>", n, t).into_bytes()
+  }
+  fn accept(&self, session: &mut Session, user: &str) -> HandlerResult<Sasl> {
+    session.authenticated = true;
+    session.user = Some(user.to_string());
+    session.mechanism = None;
+    session.sasl_state.clear();
+    Ok(Sasl::Done(b"Authenticated".to_vec()))
+  }
+  fn reject(&self, session: &mut Session) -> HandlerResult<Sasl> {
+    session.authenticated = false;
+    session.user = None;
+    session.mechanism = None;
+    session.sasl_state.clear();
+    Err(StatusField::AuthError)
+  }
+}
+impl<H: Handler> Handler for Authenticator<H> {
+  fn get(&self, key: &[u8]) -> HandlerResult<Value> {
+    self.inner.get(key)
+  }
+  fn set(&self, req: StoreRequest) -> HandlerResult<u64> {
+    self.inner.set(req)
+  }
+  fn delete(&self, key: &[u8], cas: u64) -> HandlerResult<()> {
+    self.inner.delete(key, cas)
+  }
+  fn arith(&self, req: ArithRequest) -> HandlerResult<(u64, u64)> {
+    self.inner.arith(req)
+  }
+  fn touch(&self, key: &[u8], expiration: u32) -> HandlerResult<()> {
+    self.inner.touch(key, expiration)
+  }
+  fn gat(&self, key: &[u8], expiration: u32) -> HandlerResult<Value> {
+    self.inner.gat(key, expiration)
+  }
+  fn flush(&self, delay: Option<u32>) -> HandlerResult<()> {
+    self.inner.flush(delay)
+  }
+  fn stat(&self, group: Option<&[u8]>) -> HandlerResult<Vec<(String, String)>> {
+    self.inner.stat(group)
+  }
+  fn version(&self) -> String {
+    self.inner.version()
+  }
+  fn verbosity(&self, level: u32) -> HandlerResult<()> {
+    self.inner.verbosity(level)
+  }
+  fn sasl_mechs(&self) -> HandlerResult<Vec<u8>> {
+    Ok(b"CRAM-MD5 PLAIN".to_vec())
+  }
+  fn sasl_auth(&self, session: &mut Session, mechanism: &[u8], data: &[u8]) -> HandlerResult<Sasl> {
+    match Mechanism::from_name(mechanism) {
+      Option::Some(Mechanism::Plain) => {
+        let mut parts = data.split(|b| *b == 0);
+        let (_authzid, user, password) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+          (Some(a), Some(u), Some(p), None) => (a, String::from_utf8_lossy(u), p),
+          _ => return self.reject(session)
+        };
+        match self.users.get(user.as_ref()) {
+          Option::Some(expected) if constant_time_eq(expected.as_bytes(), password) => self.accept(session, &user),
+          _ => self.reject(session)
+        }
+      },
+      Option::Some(Mechanism::CramMd5) => {
+        let challenge = self.challenge();
+        session.authenticated = false;
+        session.mechanism = Some(Mechanism::CramMd5.name().to_string());
+        session.sasl_state = challenge.clone();
+        Ok(Sasl::Continue(challenge))
+      },
+      Option::None => self.reject(session)
+    }
+  }
+  fn sasl_step(&self, session: &mut Session, mechanism: &[u8], data: &[u8]) -> HandlerResult<Sasl> {
+    if Mechanism::from_name(mechanism) != Some(Mechanism::CramMd5)
+      || session.mechanism.as_ref().map(|m| m.as_bytes()) != Some(mechanism) {
+      return self.reject(session);
+    }
+    let answer = String::from_utf8_lossy(data).into_owned();
+    let user = match answer.rfind(' ') {
+      Option::Some(i) => &answer[..i],
+      Option::None => return self.reject(session)
+    };
+    let expected = match self.users.get(user) {
+      Option::Some(password) => cram_md5(user, password, &session.sasl_state),
+      Option::None => return self.reject(session)
+    };
+    if constant_time_eq(&expected, data) {
+      self.accept(session, user)
+    } else {
+      self.reject(session)
+    }
+  }
+  /// Only SASL commands, and `Quit`, are allowed before
+  /// authenticating
+  fn allow(&self, session: &Session, code: OpCode) -> bool {
+    let open = matches!(code,
+      OpCode::SASLlistmech |
+      OpCode::SASLAuth |
+      OpCode::SASLStep |
+      OpCode::Quit);
+    (session.authenticated || open) && self.inner.allow(session, code)
+  }
+}
+
+#[test]
+fn test_hmac_md5() {
+  //RFC 2104
+  assert_eq!(hex(&hmac_md5(&[0x0B; 16], b"Hi There")), "9294727a3638bb1c13f48ef8158bfc9d");
+  assert_eq!(hex(&hmac_md5(b"Jefe", b"what do ya want for nothing?")), "750c783e6ab0b503eaa86e310a5db738");
+  //RFC 2195
+  let answer = cram_md5("tim", "tanstaaftanstaaf", b"<1896.697170952@postoffice.reston.mci.net>");
+  assert_eq!(answer, b"tim b913a602c7eda7a495b4e6e7334d3890".to_vec());
+  assert_eq!(Mechanism::from_name(b"CRAM-MD5"), Some(Mechanism::CramMd5));
+  assert_eq!(Credentials::new("u", "p").initial(Mechanism::Plain), b"\0u\0p".to_vec());
+}
+
+#[test]
+fn test_constant_time_eq() {
+  assert!(constant_time_eq(b"", b""));
+  assert!(constant_time_eq(b"secret", b"secret"));
+  assert!(!constant_time_eq(b"secret", b"secreT"));
+  assert!(!constant_time_eq(b"secret", b"Secret"));
+  assert!(!constant_time_eq(b"secret", b"secre"));
+  assert!(!constant_time_eq(b"secret", b"secrets"));
+  assert!(!constant_time_eq(b"", b"x"));
+}
@@ -0,0 +1,124 @@
+//! Memcached protocol proxy
+//!
+//!```text
+//! mbpr-proxy [--mode text|binary] --upstream HOST:PORT... [--listen HOST:PORT]
+//! mbpr-proxy --mode binary --config FILE [--listen HOST:PORT]
+//!```
+//!
+//! In `text` mode (the default) accepts text protocol clients on
+//! `--listen` (default `127.0.0.1:11311`) and forwards their
+//! commands to the `--upstream` server over the binary protocol.
+//!
+//! In `binary` mode accepts binary protocol clients and routes
+//! each request by key to one of the `--upstream` servers (the
+//! flag may be repeated) with ketama consistent hashing. With
+//! `--config` (and the `json` feature) requests go through the
+//! route tree the file describes instead, see `mbpr::route`.
+
+extern crate mbpr;
+
+use mbpr::cluster::{
+  Node,
+  ServerRing
+};
+use mbpr::proxy::{
+  serve_binary,
+  serve_route,
+  serve_text
+};
+#[cfg(feature = "json")]
+use mbpr::route;
+use mbpr::route::Route;
+use std::env;
+#[cfg(feature = "json")]
+use std::fs;
+use std::net::{
+  SocketAddr,
+  TcpListener,
+  ToSocketAddrs
+};
+use std::process;
+use std::sync::Arc;
+
+const USAGE: &str = "usage: mbpr-proxy [--mode text|binary] --upstream HOST:PORT... [--listen HOST:PORT]
+       mbpr-proxy --mode binary --config FILE [--listen HOST:PORT]";
+
+fn fail(msg: &str) -> ! {
+  eprintln!("mbpr-proxy: {}\n{}", msg, USAGE);
+  process::exit(2);
+}
+
+fn resolve(addr: &str) -> SocketAddr {
+  match addr.to_socket_addrs().ok().and_then(|mut a| a.next()) {
+    Option::Some(a) => a,
+    Option::None => fail(&format!("cannot resolve {}", addr))
+  }
+}
+
+/// Split `HOST:PORT` into a ring node
+fn node(addr: &str) -> Node {
+  match addr.parse::<Node>() {
+    Ok(n) => n,
+    Err(e) => fail(&e)
+  }
+}
+
+/// Build the route tree in the config file at `path`
+#[cfg(feature = "json")]
+fn routes(path: &str) -> Arc<dyn Route> {
+  let config = match fs::read_to_string(path) {
+    Ok(c) => c,
+    Err(e) => fail(&format!("cannot read {}: {}", path, e))
+  };
+  match route::from_json(&config) {
+    Ok(r) => r,
+    Err(e) => fail(&format!("{}: {}", path, e))
+  }
+}
+#[cfg(not(feature = "json"))]
+fn routes(_: &str) -> Arc<dyn Route> {
+  fail("--config needs the json feature")
+}
+
+fn main() {
+  let mut listen = "127.0.0.1:11311".to_string();
+  let mut mode = "text".to_string();
+  let mut upstreams = Vec::new();
+  let mut config = None;
+  let mut args = env::args().skip(1);
+  while let Some(arg) = args.next() {
+    let value = |args: &mut dyn Iterator<Item = String>| match args.next() {
+      Option::Some(v) => v,
+      Option::None => fail(&format!("{} needs a value", arg))
+    };
+    match arg.as_str() {
+      "--listen" => listen = value(&mut args),
+      "--mode" => mode = value(&mut args),
+      "--upstream" => upstreams.push(value(&mut args)),
+      "--config" => config = Some(value(&mut args)),
+      "--help" | "-h" => {
+        println!("{}", USAGE);
+        return;
+      },
+      x => fail(&format!("unknown argument {}", x))
+    };
+  }
+  if upstreams.is_empty() && config.is_none() {
+    fail("--upstream is required");
+  }
+  let listener = match TcpListener::bind(resolve(&listen)) {
+    Ok(l) => l,
+    Err(e) => fail(&format!("cannot listen on {}: {}", listen, e))
+  };
+  let result = match mode.as_str() {
+    "text" if upstreams.len() == 1 => serve_text(listener, resolve(&upstreams[0])),
+    "text" => fail("text mode takes a single --upstream"),
+    "binary" if config.is_some() => serve_route(listener, routes(config.as_ref().unwrap())),
+    "binary" => serve_binary(listener, ServerRing::ketama(upstreams.iter().map(|u| node(u)).collect())),
+    x => fail(&format!("unknown mode {}", x))
+  };
+  if let Err(e) = result {
+    eprintln!("mbpr-proxy: {}", e);
+    process::exit(1);
+  }
+}
@@ -0,0 +1,489 @@
+use super::opcode::OpCode;
+use super::datatype::DataType;
+use super::request::{
+  Request,
+  OwnedRequest
+};
+use super::extras::{
+  ReqExtras,
+  SetExtras,
+  IncrExtras,
+  FlushExtras,
+  VerbosityExtras,
+  TouchExtras
+};
+use std::fmt;
+use std::error::Error;
+
+/// Longest key memcached will accept
+pub const MAX_KEY_LEN: usize = 250;
+
+/// Default limit on the size of a value (2MB)
+pub const MAX_BODY_LEN: usize = 2 * 1024 * 1024;
+
+/// Expirations larger then this (30 days) are unix timestamps
+pub const RELATIVE_EXPIRATION_LIMIT: u32 = 60 * 60 * 24 * 30;
+
+/// Reasons a `RequestBuilder` refused to build a packet
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum BuildError {
+  /// Key is longer then `MAX_KEY_LEN`. Holds the key's length.
+  KeyTooLong(usize),
+  /// Key contains whitespace or a control character. Holds
+  /// the offending byte and it's position in the key.
+  InvalidKeyByte(u8, usize),
+  /// The opcode needs a key but none was given
+  KeyRequired(OpCode),
+  /// The opcode does not take a key but one was given
+  KeyForbidden(OpCode),
+  /// The opcode does not take a value but one was given
+  BodyForbidden(OpCode),
+  /// The value is larger then the builder's limit. Holds
+  /// the value's length.
+  BodyTooLarge(usize),
+  /// The extras field does not fit the opcode's layout
+  BadExtras(OpCode)
+}
+impl fmt::Display for BuildError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      BuildError::KeyTooLong(len) => write!(f, "key is {} bytes, the limit is {}", len, MAX_KEY_LEN),
+      BuildError::InvalidKeyByte(b, pos) => write!(f, "key contains invalid byte 0x{:02X} at {}", b, pos),
+      BuildError::KeyRequired(code) => write!(f, "{:?} requires a key", code),
+      BuildError::KeyForbidden(code) => write!(f, "{:?} does not take a key", code),
+      BuildError::BodyForbidden(code) => write!(f, "{:?} does not take a value", code),
+      BuildError::BodyTooLarge(len) => write!(f, "value of {} bytes is too large", len),
+      BuildError::BadExtras(code) => write!(f, "extras do not match the layout of {:?}", code)
+    }
+  }
+}
+impl Error for BuildError { }
+
+/// What an opcode expects in a field
+#[derive(Copy,Clone,PartialEq,Eq)]
+enum Field {
+  Required,
+  Optional,
+  Forbidden
+}
+
+/// Key rules for each opcode
+fn key_rule(code: OpCode) -> Field {
+  match code {
+    OpCode::Quit |
+    OpCode::QuitQ |
+    OpCode::Flush |
+    OpCode::FlushQ |
+    OpCode::Nop |
+    OpCode::Version |
+    OpCode::Verbosity |
+    OpCode::SASLlistmech => Field::Forbidden,
+    OpCode::Stat => Field::Optional,
+    x => {
+      let byte: u8 = x.into();
+      if byte >= 0x30 {
+        Field::Optional
+      } else {
+        Field::Required
+      }
+    }
+  }
+}
+
+/// Value rules for each opcode
+fn body_rule(code: OpCode) -> Field {
+  match code {
+    OpCode::Set |
+    OpCode::SetQ |
+    OpCode::Add |
+    OpCode::AddQ |
+    OpCode::Replace |
+    OpCode::ReplaceQ |
+    OpCode::Append |
+    OpCode::AppendQ |
+    OpCode::Prepare |
+    OpCode::PrependQ |
+    OpCode::SASLAuth |
+    OpCode::SASLStep => Field::Optional,
+    x => {
+      let byte: u8 = x.into();
+      if byte >= 0x30 {
+        Field::Optional
+      } else {
+        Field::Forbidden
+      }
+    }
+  }
+}
+
+/// Checks a key against memcached's rules.
+///
+/// Keys must be no longer then `MAX_KEY_LEN` and may not
+/// contain whitespace or control characters.
+pub fn check_key(key: &[u8]) -> Result<(), BuildError> {
+  if key.len() > MAX_KEY_LEN {
+    return Err(BuildError::KeyTooLong(key.len()));
+  }
+  for (pos, b) in key.iter().enumerate() {
+    if *b <= 0x20 || *b == 0x7F {
+      return Err(BuildError::InvalidKeyByte(*b, pos));
+    }
+  }
+  Ok(())
+}
+
+/// Validating Request Builder
+///
+/// Unlike `Request::new` this checks the packet against the
+/// rules of the protocol before handing it out.
+///
+///* Keys are `<= 250` bytes with no whitespace or control characters
+///* Keys are present/absent as the opcode requires
+///* Values are only given to opcodes that store them, and are
+///  no larger then `MAX_BODY_LEN` (see `max_body`)
+///* The extras field matches the opcode's layout
+///
+/// Setting a field the opcode does not have (for example `flags`
+/// on a `Delete`) is reported as `BuildError::BadExtras` when the
+/// packet is built.
+#[derive(Clone,Debug)]
+pub struct RequestBuilder<'a> {
+  code: OpCode,
+  datatype: DataType,
+  vbucket: u16,
+  opaque: u32,
+  cas: u64,
+  extras: ReqExtras,
+  key: Option<&'a [u8]>,
+  body: Option<&'a [u8]>,
+  max_body: usize,
+  bad_extras: bool,
+  extra_buf: Vec<u8>
+}
+impl<'a> RequestBuilder<'a> {
+
+  /// Start a packet for any opcode. Fields are empty.
+  #[inline]
+  pub fn new(code: OpCode) -> RequestBuilder<'a> {
+    RequestBuilder {
+      code,
+      datatype: DataType::RAW,
+      vbucket: 0,
+      opaque: 0,
+      cas: 0,
+      extras: ReqExtras::None,
+      key: None,
+      body: None,
+      max_body: MAX_BODY_LEN,
+      bad_extras: false,
+      extra_buf: Vec::with_capacity(0)
+    }
+  }
+  #[inline]
+  fn with_key(code: OpCode, key: &'a [u8]) -> RequestBuilder<'a> {
+    RequestBuilder::new(code).key(key)
+  }
+  #[inline]
+  fn store(code: OpCode, key: &'a [u8], value: &'a [u8]) -> RequestBuilder<'a> {
+    RequestBuilder::with_key(code, key)
+      .value(value)
+      .extras(ReqExtras::Store(SetExtras::new(0, 0)))
+  }
+  #[inline]
+  fn arith(code: OpCode, key: &'a [u8], delta: u64) -> RequestBuilder<'a> {
+    RequestBuilder::with_key(code, key)
+      .extras(ReqExtras::Arith(IncrExtras::new(delta, 0, 0)))
+  }
+  #[inline]
+  pub fn get(key: &'a [u8]) -> RequestBuilder<'a> {
+    RequestBuilder::with_key(OpCode::Get, key)
+  }
+  #[inline]
+  pub fn getk(key: &'a [u8]) -> RequestBuilder<'a> {
+    RequestBuilder::with_key(OpCode::GetK, key)
+  }
+  #[inline]
+  pub fn set(key: &'a [u8], value: &'a [u8]) -> RequestBuilder<'a> {
+    RequestBuilder::store(OpCode::Set, key, value)
+  }
+  #[inline]
+  pub fn add(key: &'a [u8], value: &'a [u8]) -> RequestBuilder<'a> {
+    RequestBuilder::store(OpCode::Add, key, value)
+  }
+  #[inline]
+  pub fn replace(key: &'a [u8], value: &'a [u8]) -> RequestBuilder<'a> {
+    RequestBuilder::store(OpCode::Replace, key, value)
+  }
+  #[inline]
+  pub fn append(key: &'a [u8], value: &'a [u8]) -> RequestBuilder<'a> {
+    RequestBuilder::with_key(OpCode::Append, key).value(value)
+  }
+  #[inline]
+  pub fn prepend(key: &'a [u8], value: &'a [u8]) -> RequestBuilder<'a> {
+    RequestBuilder::with_key(OpCode::Prepare, key).value(value)
+  }
+  #[inline]
+  pub fn delete(key: &'a [u8]) -> RequestBuilder<'a> {
+    RequestBuilder::with_key(OpCode::Delete, key)
+  }
+  /// Increment a counter. By default a missing counter is
+  /// created with an initial value of `0`.
+  #[inline]
+  pub fn incr(key: &'a [u8], delta: u64) -> RequestBuilder<'a> {
+    RequestBuilder::arith(OpCode::Increment, key, delta)
+  }
+  /// Decrement a counter. By default a missing counter is
+  /// created with an initial value of `0`.
+  #[inline]
+  pub fn decr(key: &'a [u8], delta: u64) -> RequestBuilder<'a> {
+    RequestBuilder::arith(OpCode::Decrement, key, delta)
+  }
+  #[inline]
+  pub fn touch(key: &'a [u8], expiration: u32) -> RequestBuilder<'a> {
+    RequestBuilder::with_key(OpCode::Touch, key)
+      .extras(ReqExtras::Touch(TouchExtras::new(expiration)))
+  }
+  /// Get And Touch
+  #[inline]
+  pub fn gat(key: &'a [u8], expiration: u32) -> RequestBuilder<'a> {
+    RequestBuilder::with_key(OpCode::GAT, key)
+      .extras(ReqExtras::Touch(TouchExtras::new(expiration)))
+  }
+  /// Flush all items. Use `expire` to delay the flush.
+  #[inline]
+  pub fn flush() -> RequestBuilder<'a> {
+    RequestBuilder::new(OpCode::Flush)
+      .extras(ReqExtras::Flush(FlushExtras::new(None)))
+  }
+  #[inline]
+  pub fn noop() -> RequestBuilder<'a> {
+    RequestBuilder::new(OpCode::Nop)
+  }
+  #[inline]
+  pub fn version() -> RequestBuilder<'a> {
+    RequestBuilder::new(OpCode::Version)
+  }
+  #[inline]
+  pub fn quit() -> RequestBuilder<'a> {
+    RequestBuilder::new(OpCode::Quit)
+  }
+  #[inline]
+  pub fn verbosity(level: u32) -> RequestBuilder<'a> {
+    RequestBuilder::new(OpCode::Verbosity)
+      .extras(ReqExtras::Verbosity(VerbosityExtras::new(level)))
+  }
+  /// Request statistics, optionally for a group such as `b"items"`
+  #[inline]
+  pub fn stat(group: Option<&'a [u8]>) -> RequestBuilder<'a> {
+    let b = RequestBuilder::new(OpCode::Stat);
+    match group {
+      Option::Some(g) => b.key(g),
+      Option::None => b
+    }
+  }
+  /// List the server's SASL mechanisms
+  #[inline]
+  pub fn sasl_list() -> RequestBuilder<'a> {
+    RequestBuilder::new(OpCode::SASLlistmech)
+  }
+  /// Start a SASL exchange, the key is the mechanism
+  #[inline]
+  pub fn sasl_auth(mechanism: &'a [u8], data: &'a [u8]) -> RequestBuilder<'a> {
+    RequestBuilder::with_key(OpCode::SASLAuth, mechanism).value(data)
+  }
+  /// Answer a SASL challenge
+  #[inline]
+  pub fn sasl_step(mechanism: &'a [u8], data: &'a [u8]) -> RequestBuilder<'a> {
+    RequestBuilder::with_key(OpCode::SASLStep, mechanism).value(data)
+  }
+  /// Set the key
+  #[inline]
+  pub fn key(mut self, key: &'a [u8]) -> Self {
+    self.key = Some(key);
+    self
+  }
+  /// Set the value (body)
+  #[inline]
+  pub fn value(mut self, value: &'a [u8]) -> Self {
+    self.body = Some(value);
+    self
+  }
+  /// Replace the extras field
+  #[inline]
+  pub fn extras(mut self, extras: ReqExtras) -> Self {
+    self.extras = extras;
+    self
+  }
+  /// Item flags of `Set`/`Add`/`Replace`
+  #[inline]
+  pub fn flags(mut self, flags: u32) -> Self {
+    match self.extras {
+      ReqExtras::Store(ref mut e) => e.flags = flags,
+      _ => self.bad_extras = true
+    };
+    self
+  }
+  /// Expiration of a stored item, counter, or touch. On a
+  /// `Flush` this is the delay before the flush happens.
+  #[inline]
+  pub fn expire(mut self, expiration: u32) -> Self {
+    match self.extras {
+      ReqExtras::Store(ref mut e) => e.expiration = expiration,
+      ReqExtras::Arith(ref mut e) => e.expiration = expiration,
+      ReqExtras::Touch(ref mut e) => e.expiration = expiration,
+      ReqExtras::Flush(ref mut e) => e.expiration = Some(expiration),
+      _ => self.bad_extras = true
+    };
+    self
+  }
+  /// Value stored when an `incr`/`decr` counter is missing
+  #[inline]
+  pub fn initial(mut self, initial: u64) -> Self {
+    match self.extras {
+      ReqExtras::Arith(ref mut e) => e.initial = initial,
+      _ => self.bad_extras = true
+    };
+    self
+  }
+  #[inline]
+  pub fn cas(mut self, cas: u64) -> Self {
+    self.cas = cas;
+    self
+  }
+  #[inline]
+  pub fn opaque(mut self, opaque: u32) -> Self {
+    self.opaque = opaque;
+    self
+  }
+  #[inline]
+  pub fn vbucket(mut self, vbucket: u16) -> Self {
+    self.vbucket = vbucket;
+    self
+  }
+  /// Set the data type flags describing the value
+  #[inline]
+  pub fn datatype(mut self, dt: DataType) -> Self {
+    self.datatype = dt;
+    self
+  }
+  /// Use the quiet version of the opcode (if it has one)
+  #[inline]
+  pub fn quiet(mut self) -> Self {
+    if let Some(code) = self.code.quiet_form() {
+      self.code = code;
+    }
+    self
+  }
+  /// Change the largest value the builder will accept
+  #[inline]
+  pub fn max_body(mut self, max: usize) -> Self {
+    self.max_body = max;
+    self
+  }
+  #[inline(always)]
+  pub fn get_opcode(&self) -> OpCode {
+    self.code
+  }
+  #[inline(always)]
+  pub fn get_key(&self) -> Option<&'a [u8]> {
+    self.key
+  }
+  /// Check the packet against the protocol rules without
+  /// building it.
+  pub fn validate(&self) -> Result<(), BuildError> {
+    match (key_rule(self.code), self.key) {
+      (Field::Required, Option::None) => return Err(BuildError::KeyRequired(self.code)),
+      (Field::Forbidden, Option::Some(_)) => return Err(BuildError::KeyForbidden(self.code)),
+      (_, Option::Some(k)) => check_key(k)?,
+      _ => { }
+    };
+    if let Some(b) = self.body {
+      if body_rule(self.code) == Field::Forbidden {
+        return Err(BuildError::BodyForbidden(self.code));
+      }
+      if b.len() > self.max_body {
+        return Err(BuildError::BodyTooLarge(b.len()));
+      }
+    }
+    if self.bad_extras {
+      return Err(BuildError::BadExtras(self.code));
+    }
+    let raw = self.extras.to_vec();
+    let raw = if raw.is_empty() { None } else { Some(raw.as_slice()) };
+    match ReqExtras::parse(self.code, raw).ok() {
+      Option::Some(ref e) if *e == self.extras => Ok(()),
+      Option::Some(ReqExtras::None) if self.extras.is_empty() => Ok(()),
+      _ => Err(BuildError::BadExtras(self.code))
+    }
+  }
+  /// Validate and build an owned packet
+  pub fn build(&self) -> Result<OwnedRequest, BuildError> {
+    self.validate()?;
+    let mut req = OwnedRequest::new(
+      self.code,
+      self.vbucket,
+      self.opaque,
+      self.cas,
+      self.extras.to_vec(),
+      self.key.map(|k| k.to_vec()).unwrap_or_default(),
+      self.body.map(|b| b.to_vec()).unwrap_or_default());
+    req.set_datatype(self.datatype);
+    Ok(req)
+  }
+  /// Validate and build a packet which borrows the key and
+  /// value. The extras field is borrowed from the builder.
+  pub fn build_borrowed(&mut self) -> Result<Request<'_>, BuildError> {
+    self.validate()?;
+    self.extra_buf = self.extras.to_vec();
+    let extra = if self.extra_buf.is_empty() {
+      None
+    } else {
+      Some(self.extra_buf.as_slice())
+    };
+    let mut req = Request::new(self.code, self.vbucket, self.opaque, self.cas, extra, self.key, self.body);
+    req.set_datatype(self.datatype);
+    Ok(req)
+  }
+}
+
+#[test]
+fn test_builder_valid() {
+  use super::extras::Extras;
+
+  let req = RequestBuilder::set(b"Hello", b"World")
+    .flags(0xDEADBEEF)
+    .expire(3600)
+    .build()
+    .unwrap();
+  assert_eq!(req.get_opcode(), OpCode::Set);
+  assert_eq!(req.get_extra(), Some(&b"\xDE\xAD\xBE\xEF\x00\x00\x0E\x10"[..]));
+  assert_eq!(req.get_key(), Some(&b"Hello"[..]));
+  assert_eq!(req.get_body(), Some(&b"World"[..]));
+
+  let mut b = RequestBuilder::incr(b"counter", 5).initial(10).opaque(7).quiet();
+  let req = b.build_borrowed().unwrap();
+  assert_eq!(req.get_opcode(), OpCode::IncrementQ);
+  assert_eq!(req.get_opaque(), 7);
+  assert_eq!(req.get_extra(), Some(IncrExtras::new(5, 10, 0).to_vec().as_slice()));
+
+  assert!(RequestBuilder::flush().build().unwrap().get_extra().is_none());
+  assert_eq!(RequestBuilder::flush().expire(10).build().unwrap().get_extra(), Some(&b"\x00\x00\x00\x0A"[..]));
+  assert!(RequestBuilder::stat(None).build().is_ok());
+  assert!(RequestBuilder::stat(Some(b"items")).build().is_ok());
+  assert!(RequestBuilder::noop().build().is_ok());
+}
+
+#[test]
+fn test_builder_invalid() {
+  let long = [b'a'; 251];
+  assert_eq!(RequestBuilder::get(&long).build().err(), Some(BuildError::KeyTooLong(251)));
+  assert_eq!(RequestBuilder::get(b"a b").build().err(), Some(BuildError::InvalidKeyByte(b' ', 1)));
+  assert_eq!(RequestBuilder::get(b"a\nb").build().err(), Some(BuildError::InvalidKeyByte(b'\n', 1)));
+  assert_eq!(RequestBuilder::new(OpCode::Get).build().err(), Some(BuildError::KeyRequired(OpCode::Get)));
+  assert_eq!(RequestBuilder::noop().key(b"a").build().err(), Some(BuildError::KeyForbidden(OpCode::Nop)));
+  assert_eq!(RequestBuilder::get(b"a").value(b"b").build().err(), Some(BuildError::BodyForbidden(OpCode::Get)));
+  assert_eq!(RequestBuilder::set(b"a", b"toolarge").max_body(4).build().err(), Some(BuildError::BodyTooLarge(8)));
+  assert_eq!(RequestBuilder::delete(b"a").flags(1).build().err(), Some(BuildError::BadExtras(OpCode::Delete)));
+  assert_eq!(RequestBuilder::new(OpCode::Set).key(b"a").build().err(), Some(BuildError::BadExtras(OpCode::Set)));
+  assert_eq!(RequestBuilder::get(b"a").extras(ReqExtras::Touch(TouchExtras::new(1))).build().err(), Some(BuildError::BadExtras(OpCode::Get)));
+}
@@ -0,0 +1,625 @@
+//! Synchronous Memcached Client
+//!
+//! `Client` drives a single connection over any `Read + Write`
+//! stream (`TcpStream`, `UnixStream`, etc.). Every call blocks
+//! until the server's reply arrives.
+
+use super::{
+  Error as ParseError,
+  Encoding,
+  Encoder
+};
+use super::opcode::OpCode;
+use super::status::StatusField;
+use super::request::OwnedRequest;
+use super::response::OwnedResponse;
+use super::extras::ResExtras;
+use super::builder::{
+  RequestBuilder,
+  BuildError
+};
+use super::stream::StreamDecoder;
+use super::stats::Stats;
+#[cfg(feature = "compression")]
+use super::compression::{
+  Compression,
+  decompress_response_with_max
+};
+use std::io::{
+  self,
+  Read,
+  Write
+};
+use std::collections::HashMap;
+use std::fmt;
+use std::error::Error;
+
+/// Errors returned by `Client`
+#[derive(Debug)]
+pub enum ClientError {
+  /// The connection failed
+  Io(io::Error),
+  /// The server replied with an error status
+  Status(StatusField),
+  /// The server sent a packet which could not be parsed
+  Parse(ParseError),
+  /// The request broke the protocol's rules
+  Build(BuildError),
+  /// The server's reply does not fit the request
+  UnexpectedResponse(OpCode)
+}
+impl fmt::Display for ClientError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      ClientError::Io(ref e) => write!(f, "io error: {}", e),
+      ClientError::Status(s) => write!(f, "server returned {:?}", s),
+      ClientError::Parse(e) => write!(f, "malformed packet: {}", e),
+      ClientError::Build(ref e) => write!(f, "invalid request: {}", e),
+      ClientError::UnexpectedResponse(code) => write!(f, "unexpected {:?} response", code)
+    }
+  }
+}
+impl Error for ClientError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match *self {
+      ClientError::Io(ref e) => Some(e),
+      ClientError::Build(ref e) => Some(e),
+      ClientError::Parse(ref e) => Some(e),
+      _ => None
+    }
+  }
+}
+impl From<io::Error> for ClientError {
+  #[inline]
+  fn from(e: io::Error) -> ClientError {
+    ClientError::Io(e)
+  }
+}
+impl From<BuildError> for ClientError {
+  #[inline]
+  fn from(e: BuildError) -> ClientError {
+    ClientError::Build(e)
+  }
+}
+impl From<ParseError> for ClientError {
+  #[inline]
+  fn from(e: ParseError) -> ClientError {
+    ClientError::Parse(e)
+  }
+}
+impl From<StatusField> for ClientError {
+  #[inline]
+  fn from(e: StatusField) -> ClientError {
+    ClientError::Status(e)
+  }
+}
+
+/// A value read from the server
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Item {
+  pub value: Vec<u8>,
+  pub flags: u32,
+  pub cas: u64
+}
+impl Item {
+  /// Build an item from a `Get` family response
+  pub fn from_response(resp: OwnedResponse) -> Result<Item, ClientError> {
+    let flags = match resp.get_typed_extra()? {
+      ResExtras::Get(e) => e.flags,
+      _ => 0
+    };
+    Ok(Item {
+      cas: resp.get_cas(),
+      flags,
+      value: resp.body
+    })
+  }
+}
+
+/// Turn an error status into `ClientError::Status`
+#[inline]
+pub fn check(resp: OwnedResponse) -> Result<OwnedResponse, ClientError> {
+  match resp.check_status() {
+    Ok(()) => Ok(resp),
+    Err(e) => Err(ClientError::Status(e))
+  }
+}
+
+/// Counters are returned as a big endian `u64` body
+#[inline]
+pub(crate) fn counter(resp: &OwnedResponse) -> Result<u64, ClientError> {
+  match resp.get_body() {
+    Option::Some(b) if b.len() == 8 => {
+      let mut field = [0u8; 8];
+      field.copy_from_slice(b);
+      Ok(u64::from_be_bytes(field))
+    },
+    _ => Err(ClientError::UnexpectedResponse(resp.get_opcode()))
+  }
+}
+
+/// Pipelined Bulk Read
+///
+/// Encodes a batch of `GetKQ` requests followed by a `Nop` into
+/// a single buffer. Quiet gets only reply on a hit, and the
+/// server answers in order, so once the `Nop` reply arrives
+/// every hit has been seen.
+///
+/// Request `i` carries the `opaque` `first + i`, the `Nop`
+/// carries `first + keys.len()`. Hits are matched by the key
+/// the server returns, or by `opaque` if it returned none.
+pub struct MultiGet {
+  requests: Vec<OwnedRequest>,
+  first: u32,
+  hits: HashMap<Vec<u8>, Item>,
+  error: Option<StatusField>,
+  done: bool
+}
+impl MultiGet {
+  /// Build the batch. Fails if any key is invalid.
+  pub fn new(keys: &[&[u8]], first: u32) -> Result<MultiGet, ClientError> {
+    let mut requests = Vec::with_capacity(keys.len() + 1);
+    for (i, key) in keys.iter().enumerate() {
+      let opaque = first.wrapping_add(i as u32);
+      requests.push(RequestBuilder::getk(key).quiet().opaque(opaque).build()?);
+    }
+    let opaque = first.wrapping_add(keys.len() as u32);
+    requests.push(RequestBuilder::noop().opaque(opaque).build()?);
+    Ok(MultiGet {
+      requests,
+      first,
+      hits: HashMap::with_capacity(keys.len()),
+      error: None,
+      done: false
+    })
+  }
+  /// Number of `opaque` values the batch uses
+  #[inline(always)]
+  pub fn opaque_count(&self) -> usize {
+    self.requests.len()
+  }
+  /// Encode the whole batch into one buffer
+  pub fn encode(&self) -> Encoder {
+    let mut e = Encoder::for_packets(&self.requests);
+    for req in self.requests.iter() {
+      req.encode(&mut e);
+    }
+    e
+  }
+  /// Consume a reply. Returns `true` once the terminating
+  /// `Nop` has been seen. Replies for other requests are
+  /// ignored.
+  pub fn feed(&mut self, resp: OwnedResponse) -> Result<bool, ClientError> {
+    let index = resp.get_opaque().wrapping_sub(self.first) as usize;
+    if self.done || index >= self.requests.len() {
+      return Ok(self.done);
+    }
+    if index == self.requests.len() - 1 {
+      self.done = true;
+      return Ok(true);
+    }
+    match resp.check_status() {
+      Ok(()) => { },
+      Err(StatusField::KeyNotFound) => return Ok(false),
+      Err(e) => {
+        self.error = self.error.or(Some(e));
+        return Ok(false);
+      }
+    };
+    let key = if resp.has_key() {
+      resp.key.clone()
+    } else {
+      self.requests[index].key.clone()
+    };
+    let item = Item::from_response(resp)?;
+    self.hits.insert(key, item);
+    Ok(false)
+  }
+  /// Hits by key. Misses are absent. If the server replied
+  /// to any key with an error other than `KeyNotFound` that
+  /// error is returned.
+  pub fn finish(self) -> Result<HashMap<Vec<u8>, Item>, ClientError> {
+    match self.error {
+      Option::Some(e) => Err(ClientError::Status(e)),
+      Option::None => Ok(self.hits)
+    }
+  }
+}
+
+/// Pipelined Quiet Mutations
+///
+/// Queues many mutations which are sent as their quiet forms
+/// (`SetQ`, `DeleteQ`, `IncrementQ`, etc.) in one buffer followed
+/// by a `Nop`. Quiet operations only reply on failure, so each
+/// operation is given it's own `opaque` and any reply is matched
+/// back to the operation which caused it.
+///
+/// Operations are validated as they are queued.
+#[derive(Clone,Debug,Default)]
+pub struct Pipeline<'a> {
+  ops: Vec<RequestBuilder<'a>>
+}
+impl<'a> Pipeline<'a> {
+  #[inline]
+  pub fn new() -> Pipeline<'a> {
+    Pipeline {
+      ops: Vec::new()
+    }
+  }
+  /// Number of queued operations
+  #[inline(always)]
+  pub fn len(&self) -> usize {
+    self.ops.len()
+  }
+  #[inline(always)]
+  pub fn is_empty(&self) -> bool {
+    self.ops.is_empty()
+  }
+  /// Queue any request, switching it to it's quiet form.
+  /// Returns the operation's index within the pipeline.
+  ///
+  /// Opcodes without a quiet form are sent as is, their
+  /// successful reply is simply consumed.
+  pub fn push(&mut self, b: RequestBuilder<'a>) -> Result<usize, BuildError> {
+    let b = b.quiet();
+    b.validate()?;
+    self.ops.push(b);
+    Ok(self.ops.len() - 1)
+  }
+  pub fn set(&mut self, key: &'a [u8], value: &'a [u8], flags: u32, expiration: u32) -> Result<usize, BuildError> {
+    self.push(RequestBuilder::set(key, value).flags(flags).expire(expiration))
+  }
+  pub fn add(&mut self, key: &'a [u8], value: &'a [u8], flags: u32, expiration: u32) -> Result<usize, BuildError> {
+    self.push(RequestBuilder::add(key, value).flags(flags).expire(expiration))
+  }
+  pub fn replace(&mut self, key: &'a [u8], value: &'a [u8], flags: u32, expiration: u32) -> Result<usize, BuildError> {
+    self.push(RequestBuilder::replace(key, value).flags(flags).expire(expiration))
+  }
+  pub fn delete(&mut self, key: &'a [u8]) -> Result<usize, BuildError> {
+    self.push(RequestBuilder::delete(key))
+  }
+  pub fn incr(&mut self, key: &'a [u8], delta: u64, initial: u64, expiration: u32) -> Result<usize, BuildError> {
+    self.push(RequestBuilder::incr(key, delta).initial(initial).expire(expiration))
+  }
+  pub fn decr(&mut self, key: &'a [u8], delta: u64, initial: u64, expiration: u32) -> Result<usize, BuildError> {
+    self.push(RequestBuilder::decr(key, delta).initial(initial).expire(expiration))
+  }
+  pub fn append(&mut self, key: &'a [u8], value: &'a [u8]) -> Result<usize, BuildError> {
+    self.push(RequestBuilder::append(key, value))
+  }
+  pub fn prepend(&mut self, key: &'a [u8], value: &'a [u8]) -> Result<usize, BuildError> {
+    self.push(RequestBuilder::prepend(key, value))
+  }
+  /// Encode every operation followed by the terminating `Nop`.
+  ///
+  /// Operation `i` carries the `opaque` `first + i`, the `Nop`
+  /// carries `first + len()`.
+  pub fn encode(&self, first: u32) -> Result<Encoder, BuildError> {
+    let mut requests = Vec::with_capacity(self.ops.len() + 1);
+    for (i, b) in self.ops.iter().enumerate() {
+      requests.push(b.clone().opaque(first.wrapping_add(i as u32)).build()?);
+    }
+    let opaque = first.wrapping_add(self.ops.len() as u32);
+    requests.push(RequestBuilder::noop().opaque(opaque).build()?);
+    let mut e = Encoder::for_packets(&requests);
+    for req in requests.iter() {
+      req.encode(&mut e);
+    }
+    Ok(e)
+  }
+}
+
+/// Blocking Memcached Client
+///
+/// Each request is given a unique `opaque`, replies carrying
+/// another `opaque` (such as failures of quiet commands sent
+/// with `send`) are discarded while waiting.
+pub struct Client<S: Read + Write> {
+  stream: S,
+  decoder: StreamDecoder<OwnedResponse>,
+  opaque: u32,
+  #[cfg(feature = "compression")]
+  compression: Option<Compression>
+}
+impl<S: Read + Write> Client<S> {
+
+  /// Wrap a connected stream
+  #[inline]
+  pub fn new(stream: S) -> Client<S> {
+    Client {
+      stream,
+      decoder: StreamDecoder::new(),
+      opaque: 0,
+      #[cfg(feature = "compression")]
+      compression: None
+    }
+  }
+  #[inline(always)]
+  pub fn get_ref(&self) -> &S {
+    &self.stream
+  }
+  #[inline(always)]
+  pub fn get_mut(&mut self) -> &mut S {
+    &mut self.stream
+  }
+  /// Consume the client returning the stream
+  #[inline]
+  pub fn into_inner(self) -> S {
+    self.stream
+  }
+  /// Reserve an `opaque` value for a request
+  #[inline]
+  pub fn next_opaque(&mut self) -> u32 {
+    self.opaque = self.opaque.wrapping_add(1);
+    self.opaque
+  }
+  /// Change the largest value the client will read, see
+  /// `StreamDecoder::set_max_body`. Compressed values are held
+  /// to it once decompressed.
+  #[inline]
+  pub fn set_max_body(&mut self, max: usize) {
+    self.decoder.set_max_body(max);
+  }
+  /// Compress large values sent by `execute` (and so `set`,
+  /// `add`, and `replace`). `None` disables compression.
+  ///
+  /// Compressed values received are always decompressed.
+  #[cfg(feature = "compression")]
+  #[inline]
+  pub fn set_compression(&mut self, c: Option<Compression>) {
+    self.compression = c;
+  }
+  /// Reserve `count` consecutive `opaque` values, returning
+  /// the first.
+  #[inline]
+  pub fn reserve_opaques(&mut self, count: usize) -> u32 {
+    let first = self.opaque.wrapping_add(1);
+    self.opaque = self.opaque.wrapping_add(count as u32);
+    first
+  }
+  /// Write raw encoded packets to the server
+  #[inline]
+  pub fn write_encoded(&mut self, e: &Encoder) -> Result<(), ClientError> {
+    self.stream.write_all(e.as_slice())?;
+    self.stream.flush()?;
+    Ok(())
+  }
+  /// Write a request without waiting for the reply
+  #[inline]
+  pub fn send(&mut self, req: &OwnedRequest) -> Result<(), ClientError> {
+    let e = req.encode_self();
+    self.write_encoded(&e)
+  }
+  /// Read the next response from the server
+  pub fn recv(&mut self) -> Result<OwnedResponse, ClientError> {
+    loop {
+      #[cfg_attr(not(feature = "compression"), allow(unused_mut))]
+      if let Some(mut resp) = self.decoder.next_packet()? {
+        #[cfg(feature = "compression")]
+        decompress_response_with_max(&mut resp, self.decoder.get_max_body())?;
+        return Ok(resp);
+      }
+      if self.decoder.read_from(&mut self.stream)? == 0 {
+        return Err(ClientError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed")));
+      }
+    }
+  }
+  /// Read responses until one with the given `opaque` arrives
+  pub fn recv_for(&mut self, opaque: u32) -> Result<OwnedResponse, ClientError> {
+    loop {
+      let resp = self.recv()?;
+      if resp.get_opaque() == opaque {
+        return Ok(resp);
+      }
+    }
+  }
+  /// Send a request and wait for it's reply. The reply is
+  /// returned even if it carries an error status.
+  #[inline]
+  pub fn request(&mut self, req: &OwnedRequest) -> Result<OwnedResponse, ClientError> {
+    self.send(req)?;
+    self.recv_for(req.get_opaque())
+  }
+  /// Build a request (with a fresh `opaque`), send it, and
+  /// wait for it's reply. The reply is returned even if it
+  /// carries an error status.
+  #[inline]
+  pub fn execute(&mut self, b: RequestBuilder) -> Result<OwnedResponse, ClientError> {
+    let opaque = self.next_opaque();
+    #[cfg_attr(not(feature = "compression"), allow(unused_mut))]
+    let mut req = b.opaque(opaque).build()?;
+    #[cfg(feature = "compression")]
+    if let Some(ref c) = self.compression {
+      c.compress_request(&mut req);
+    }
+    self.request(&req)
+  }
+  /// Read an item. A missing key is `Ok(None)`.
+  pub fn get(&mut self, key: &[u8]) -> Result<Option<Item>, ClientError> {
+    let resp = self.execute(RequestBuilder::get(key))?;
+    match resp.check_status() {
+      Err(StatusField::KeyNotFound) => Ok(None),
+      Err(e) => Err(ClientError::Status(e)),
+      Ok(()) => Item::from_response(resp).map(Some)
+    }
+  }
+  #[inline]
+  fn store(&mut self, b: RequestBuilder, flags: u32, expiration: u32) -> Result<u64, ClientError> {
+    let resp = check(self.execute(b.flags(flags).expire(expiration))?)?;
+    Ok(resp.get_cas())
+  }
+  /// Store an item. Returns the item's new CAS value.
+  #[inline]
+  pub fn set(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> Result<u64, ClientError> {
+    self.store(RequestBuilder::set(key, value), flags, expiration)
+  }
+  /// Store an item only if it does not exist. Returns the
+  /// item's new CAS value.
+  #[inline]
+  pub fn add(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> Result<u64, ClientError> {
+    self.store(RequestBuilder::add(key, value), flags, expiration)
+  }
+  /// Store an item only if it already exists. Returns the
+  /// item's new CAS value.
+  #[inline]
+  pub fn replace(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> Result<u64, ClientError> {
+    self.store(RequestBuilder::replace(key, value), flags, expiration)
+  }
+  #[inline]
+  pub fn delete(&mut self, key: &[u8]) -> Result<(), ClientError> {
+    check(self.execute(RequestBuilder::delete(key))?).map(|_| ())
+  }
+  /// Increment a counter, returning it's new value. A missing
+  /// counter is created holding `initial`.
+  #[inline]
+  pub fn incr(&mut self, key: &[u8], delta: u64, initial: u64, expiration: u32) -> Result<u64, ClientError> {
+    let b = RequestBuilder::incr(key, delta).initial(initial).expire(expiration);
+    counter(&check(self.execute(b)?)?)
+  }
+  /// Decrement a counter, returning it's new value. A missing
+  /// counter is created holding `initial`.
+  #[inline]
+  pub fn decr(&mut self, key: &[u8], delta: u64, initial: u64, expiration: u32) -> Result<u64, ClientError> {
+    let b = RequestBuilder::decr(key, delta).initial(initial).expire(expiration);
+    counter(&check(self.execute(b)?)?)
+  }
+  #[inline]
+  pub fn append(&mut self, key: &[u8], value: &[u8]) -> Result<(), ClientError> {
+    check(self.execute(RequestBuilder::append(key, value))?).map(|_| ())
+  }
+  #[inline]
+  pub fn prepend(&mut self, key: &[u8], value: &[u8]) -> Result<(), ClientError> {
+    check(self.execute(RequestBuilder::prepend(key, value))?).map(|_| ())
+  }
+  /// Change the expiration of an item
+  #[inline]
+  pub fn touch(&mut self, key: &[u8], expiration: u32) -> Result<(), ClientError> {
+    check(self.execute(RequestBuilder::touch(key, expiration))?).map(|_| ())
+  }
+  /// Get And Touch. A missing key is `Ok(None)`.
+  pub fn gat(&mut self, key: &[u8], expiration: u32) -> Result<Option<Item>, ClientError> {
+    let resp = self.execute(RequestBuilder::gat(key, expiration))?;
+    match resp.check_status() {
+      Err(StatusField::KeyNotFound) => Ok(None),
+      Err(e) => Err(ClientError::Status(e)),
+      Ok(()) => Item::from_response(resp).map(Some)
+    }
+  }
+  /// Invalidate every item, optionally after a delay
+  pub fn flush(&mut self, delay: Option<u32>) -> Result<(), ClientError> {
+    let b = match delay {
+      Option::Some(d) => RequestBuilder::flush().expire(d),
+      Option::None => RequestBuilder::flush()
+    };
+    check(self.execute(b)?).map(|_| ())
+  }
+  /// The server's version string
+  pub fn version(&mut self) -> Result<String, ClientError> {
+    let resp = check(self.execute(RequestBuilder::version())?)?;
+    Ok(String::from_utf8_lossy(&resp.body).into_owned())
+  }
+  #[inline]
+  pub fn noop(&mut self) -> Result<(), ClientError> {
+    check(self.execute(RequestBuilder::noop())?).map(|_| ())
+  }
+  /// Read many items in one round trip. Misses are absent
+  /// from the map.
+  ///
+  /// The keys are sent as a pipeline of `GetKQ` requests
+  /// terminated by a `Nop`, see `MultiGet`.
+  pub fn multi_get(&mut self, keys: &[&[u8]]) -> Result<HashMap<Vec<u8>, Item>, ClientError> {
+    let first = self.reserve_opaques(keys.len() + 1);
+    let mut batch = MultiGet::new(keys, first)?;
+    self.write_encoded(&batch.encode())?;
+    loop {
+      let resp = self.recv()?;
+      if batch.feed(resp)? {
+        return batch.finish();
+      }
+    }
+  }
+  /// Send every operation in the pipeline in a single write.
+  ///
+  /// The outer error is a connection failure. Otherwise each
+  /// operation's outcome is returned in the order it was queued.
+  pub fn execute_pipeline(&mut self, p: &Pipeline) -> Result<Vec<Result<(), StatusField>>, ClientError> {
+    let first = self.reserve_opaques(p.len() + 1);
+    self.write_encoded(&p.encode(first)?)?;
+    let mut results = vec![Ok(()); p.len()];
+    loop {
+      let resp = self.recv()?;
+      let index = resp.get_opaque().wrapping_sub(first) as usize;
+      if index == p.len() {
+        return Ok(results);
+      }
+      if index < p.len() {
+        results[index] = resp.check_status();
+      }
+    }
+  }
+  /// Read server statistics, optionally for a group such
+  /// as `"items"` or `"slabs"`. Pairs are `(name, value)`.
+  pub fn stat(&mut self, group: Option<&str>) -> Result<Vec<(String, String)>, ClientError> {
+    let opaque = self.next_opaque();
+    let req = RequestBuilder::stat(group.map(|g| g.as_bytes()))
+      .opaque(opaque)
+      .build()?;
+    self.send(&req)?;
+    let mut stats = Vec::new();
+    loop {
+      let resp = check(self.recv_for(opaque)?)?;
+      if !resp.has_key() {
+        return Ok(stats);
+      }
+      stats.push((
+        String::from_utf8_lossy(&resp.key).into_owned(),
+        String::from_utf8_lossy(&resp.body).into_owned()));
+    }
+  }
+  /// Read server statistics into a `Stats` map, optionally for
+  /// a group such as `"items"`, `"slabs"`, or `"settings"`.
+  #[inline]
+  pub fn stats(&mut self, group: Option<&str>) -> Result<Stats, ClientError> {
+    Ok(self.stat(group)?.into_iter().collect())
+  }
+}
+
+#[test]
+fn test_counter_body() {
+  let resp = OwnedResponse::new(OpCode::Increment, StatusField::NoError, 0, 0, vec![], vec![], vec![0,0,0,0,0,0,0,9]);
+  assert_eq!(counter(&resp).unwrap(), 9);
+  let resp = OwnedResponse::new(OpCode::Increment, StatusField::NoError, 0, 0, vec![], vec![], vec![9]);
+  assert!(counter(&resp).is_err());
+}
+
+#[test]
+fn test_multi_get_batch() {
+  let keys: Vec<&[u8]> = vec![b"a", b"b", b"c"];
+  let mut batch = MultiGet::new(&keys, 10).unwrap();
+  assert_eq!(batch.opaque_count(), 4);
+
+  let e = batch.encode();
+  let mut d = StreamDecoder::<OwnedRequest>::new();
+  let reqs = d.feed(e.as_slice()).unwrap();
+  assert_eq!(reqs.len(), 4);
+  assert_eq!(reqs[1].get_opcode(), OpCode::GetKQ);
+  assert_eq!(reqs[1].get_key(), Some(&b"b"[..]));
+  assert_eq!(reqs[1].get_opaque(), 11);
+  assert_eq!(reqs[3].get_opcode(), OpCode::Nop);
+  assert_eq!(reqs[3].get_opaque(), 13);
+
+  let flags = vec![0, 0, 0, 5];
+  //hit with a key, hit without a key, and a stray reply
+  let hit = OwnedResponse::new(OpCode::GetKQ, StatusField::NoError, 10, 1, flags.clone(), b"a".to_vec(), b"1".to_vec());
+  let nokey = OwnedResponse::new(OpCode::GetKQ, StatusField::NoError, 12, 2, flags.clone(), vec![], b"3".to_vec());
+  let stray = OwnedResponse::new(OpCode::GetKQ, StatusField::NoError, 99, 2, flags, b"z".to_vec(), b"3".to_vec());
+  let nop = OwnedResponse::new(OpCode::Nop, StatusField::NoError, 13, 0, vec![], vec![], vec![]);
+  assert!(!batch.feed(hit).unwrap());
+  assert!(!batch.feed(nokey).unwrap());
+  assert!(!batch.feed(stray).unwrap());
+  assert!(batch.feed(nop).unwrap());
+  let hits = batch.finish().unwrap();
+  assert_eq!(hits.len(), 2);
+  assert_eq!(hits[&b"a".to_vec()], Item{ value: b"1".to_vec(), flags: 5, cas: 1 });
+  assert_eq!(hits[&b"c".to_vec()], Item{ value: b"3".to_vec(), flags: 5, cas: 2 });
+}
@@ -0,0 +1,396 @@
+//! Sharding Keys Across Servers
+//!
+//! `ServerRing` maps keys to one of several memcached nodes, with
+//! the same results as libmemcached so caches can be shared with
+//! other clients.
+//!
+//!* `Distribution::Ketama` is libmemcached's weighted ketama
+//!  (`MEMCACHED_BEHAVIOR_KETAMA_WEIGHTED`). Each node gets
+//!  `floor(weight share * 40 * nodes) * 4` MD5 points on a
+//!  ring, so adding or removing a node only moves the keys
+//!  nearest to it.
+//!* `Distribution::Modulo` is libmemcached's default, the
+//!  one-at-a-time hash of the key modulo the number of nodes.
+//!
+//! `ClusterClient` holds a `Client` per node and routes each
+//! command to the node owning it's key.
+
+use super::request::Request;
+use super::client::{
+  Client,
+  ClientError,
+  Item,
+  MultiGet
+};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{
+  self,
+  Read,
+  Write
+};
+use std::net::TcpStream;
+use std::str::FromStr;
+
+/// Port libmemcached leaves out of ketama point names
+pub const DEFAULT_PORT: u16 = 11211;
+
+/// Ketama points per node when all weights are equal
+const POINTS_PER_SERVER: u32 = 160;
+
+/// Each MD5 digest yields this many points
+const POINTS_PER_HASH: u32 = 4;
+
+/// How keys are assigned to nodes
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Distribution {
+  Ketama,
+  Modulo
+}
+
+/// A memcached server
+#[derive(Clone,Debug,PartialEq,Eq,Hash)]
+pub struct Node {
+  pub host: String,
+  pub port: u16,
+  /// Relative share of the keys, only used by ketama
+  pub weight: u32
+}
+impl Node {
+  #[inline]
+  pub fn new(host: &str, port: u16) -> Node {
+    Node::with_weight(host, port, 1)
+  }
+  #[inline]
+  pub fn with_weight(host: &str, port: u16, weight: u32) -> Node {
+    Node {
+      host: host.to_string(),
+      port,
+      weight
+    }
+  }
+  /// Name of the node's `index`th group of ketama points
+  fn point_name(&self, index: u32) -> String {
+    if self.port == DEFAULT_PORT {
+      format!("{}-{}", self.host, index)
+    } else {
+      format!("{}:{}-{}", self.host, self.port, index)
+    }
+  }
+}
+impl fmt::Display for Node {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}:{}", self.host, self.port)
+  }
+}
+impl FromStr for Node {
+  type Err = String;
+  /// Parse `HOST:PORT`, with a weight of 1
+  fn from_str(addr: &str) -> Result<Node, String> {
+    let port = addr.rfind(':').and_then(|i| addr[i + 1..].parse::<u16>().ok().map(|p| (i, p)));
+    match port {
+      Option::Some((i, port)) if i > 0 => Ok(Node::new(&addr[..i], port)),
+      _ => Err(format!("{} is not HOST:PORT", addr))
+    }
+  }
+}
+
+/// Point `alignment` (0 to 3) of a key's MD5 digest, read
+/// little endian
+#[inline]
+fn md5_point(key: &[u8], alignment: usize) -> u32 {
+  let d = md5::compute(key);
+  let x = alignment * 4;
+  (d[x + 3] as u32) << 24 | (d[x + 2] as u32) << 16 | (d[x + 1] as u32) << 8 | d[x] as u32
+}
+
+/// Ketama hash of a key
+#[inline]
+pub fn ketama_hash(key: &[u8]) -> u32 {
+  md5_point(key, 0)
+}
+
+/// Bob Jenkins' one-at-a-time hash, libmemcached's default
+pub fn one_at_a_time(key: &[u8]) -> u32 {
+  let mut h: u32 = 0;
+  for b in key {
+    h = h.wrapping_add(*b as u32);
+    h = h.wrapping_add(h << 10);
+    h ^= h >> 6;
+  }
+  h = h.wrapping_add(h << 3);
+  h ^= h >> 11;
+  h.wrapping_add(h << 15)
+}
+
+/// Maps keys to nodes
+#[derive(Clone,Debug)]
+pub struct ServerRing {
+  nodes: Vec<Node>,
+  distribution: Distribution,
+  /// Ketama points, sorted, and the node each belongs to
+  points: Vec<(u32, usize)>
+}
+impl ServerRing {
+  /// Consistent hashing over `nodes`
+  pub fn ketama(nodes: Vec<Node>) -> ServerRing {
+    let total: u64 = nodes.iter().map(|n| n.weight as u64).sum();
+    let count = nodes.len() as f32;
+    let mut points = Vec::new();
+    for (index, node) in nodes.iter().enumerate() {
+      //libmemcached does this math in single precision
+      let share = node.weight as f32 / total as f32;
+      let per_server = ((share * (POINTS_PER_SERVER / POINTS_PER_HASH) as f32 * count + 0.000_000_000_1).floor() as u32) * POINTS_PER_HASH;
+      for i in 0..per_server / POINTS_PER_HASH {
+        let name = node.point_name(i);
+        for alignment in 0..POINTS_PER_HASH as usize {
+          points.push((md5_point(name.as_bytes(), alignment), index));
+        }
+      }
+    }
+    points.sort();
+    ServerRing {
+      nodes,
+      distribution: Distribution::Ketama,
+      points
+    }
+  }
+  /// Hash modulo the number of `nodes`
+  pub fn modulo(nodes: Vec<Node>) -> ServerRing {
+    ServerRing {
+      nodes,
+      distribution: Distribution::Modulo,
+      points: Vec::new()
+    }
+  }
+  #[inline]
+  pub fn new(nodes: Vec<Node>, distribution: Distribution) -> ServerRing {
+    match distribution {
+      Distribution::Ketama => ServerRing::ketama(nodes),
+      Distribution::Modulo => ServerRing::modulo(nodes)
+    }
+  }
+  #[inline(always)]
+  pub fn get_distribution(&self) -> Distribution {
+    self.distribution
+  }
+  #[inline(always)]
+  pub fn nodes(&self) -> &[Node] {
+    &self.nodes
+  }
+  #[inline(always)]
+  pub fn len(&self) -> usize {
+    self.nodes.len()
+  }
+  #[inline(always)]
+  pub fn is_empty(&self) -> bool {
+    self.nodes.is_empty()
+  }
+  /// Index of the node owning `key`. `None` if the ring is
+  /// empty.
+  pub fn node_for_key(&self, key: &[u8]) -> Option<usize> {
+    if self.nodes.is_empty() {
+      return None;
+    }
+    match self.distribution {
+      Distribution::Modulo => Some(one_at_a_time(key) as usize % self.nodes.len()),
+      Distribution::Ketama => {
+        if self.points.is_empty() {
+          return None;
+        }
+        let hash = ketama_hash(key);
+        //first point at or after the hash, wrapping around
+        let i = match self.points.binary_search(&(hash, 0)) {
+          Ok(i) | Err(i) => i
+        };
+        Some(self.points.get(i).unwrap_or(&self.points[0]).1)
+      }
+    }
+  }
+  /// Index of the node a request should be sent to. `None`
+  /// for requests without a key.
+  #[inline]
+  pub fn node_for<'a>(&self, req: &'a Request<'a>) -> Option<usize> {
+    req.get_key().and_then(|k| self.node_for_key(k))
+  }
+}
+
+/// A `Client` per node of a `ServerRing`
+pub struct ClusterClient<S: Read + Write> {
+  ring: ServerRing,
+  clients: Vec<Client<S>>
+}
+impl ClusterClient<TcpStream> {
+  /// Connect to every node of `ring`
+  pub fn connect(ring: ServerRing) -> io::Result<ClusterClient<TcpStream>> {
+    let mut clients = Vec::with_capacity(ring.len());
+    for node in ring.nodes() {
+      let stream = TcpStream::connect((node.host.as_str(), node.port))?;
+      stream.set_nodelay(true)?;
+      clients.push(Client::new(stream));
+    }
+    Ok(ClusterClient::new(ring, clients))
+  }
+}
+impl<S: Read + Write> ClusterClient<S> {
+  /// `clients[i]` must be connected to `ring.nodes()[i]`.
+  ///
+  /// # Panics
+  ///
+  /// If the ring is empty, or the number of clients and nodes
+  /// differ.
+  pub fn new(ring: ServerRing, clients: Vec<Client<S>>) -> ClusterClient<S> {
+    assert!(!ring.is_empty(), "a cluster needs at least one node");
+    assert_eq!(ring.len(), clients.len(), "every node needs a client");
+    ClusterClient {
+      ring,
+      clients
+    }
+  }
+  #[inline(always)]
+  pub fn ring(&self) -> &ServerRing {
+    &self.ring
+  }
+  /// Client for the node at `index` of the ring
+  #[inline(always)]
+  pub fn node(&mut self, index: usize) -> &mut Client<S> {
+    &mut self.clients[index]
+  }
+  /// Client for the node owning `key`
+  #[inline]
+  pub fn client_for(&mut self, key: &[u8]) -> &mut Client<S> {
+    let i = self.ring.node_for_key(key).unwrap_or(0);
+    &mut self.clients[i]
+  }
+  #[inline]
+  pub fn get(&mut self, key: &[u8]) -> Result<Option<Item>, ClientError> {
+    self.client_for(key).get(key)
+  }
+  #[inline]
+  pub fn set(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> Result<u64, ClientError> {
+    self.client_for(key).set(key, value, flags, expiration)
+  }
+  #[inline]
+  pub fn add(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> Result<u64, ClientError> {
+    self.client_for(key).add(key, value, flags, expiration)
+  }
+  #[inline]
+  pub fn replace(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> Result<u64, ClientError> {
+    self.client_for(key).replace(key, value, flags, expiration)
+  }
+  #[inline]
+  pub fn delete(&mut self, key: &[u8]) -> Result<(), ClientError> {
+    self.client_for(key).delete(key)
+  }
+  #[inline]
+  pub fn incr(&mut self, key: &[u8], delta: u64, initial: u64, expiration: u32) -> Result<u64, ClientError> {
+    self.client_for(key).incr(key, delta, initial, expiration)
+  }
+  #[inline]
+  pub fn decr(&mut self, key: &[u8], delta: u64, initial: u64, expiration: u32) -> Result<u64, ClientError> {
+    self.client_for(key).decr(key, delta, initial, expiration)
+  }
+  #[inline]
+  pub fn append(&mut self, key: &[u8], value: &[u8]) -> Result<(), ClientError> {
+    self.client_for(key).append(key, value)
+  }
+  #[inline]
+  pub fn prepend(&mut self, key: &[u8], value: &[u8]) -> Result<(), ClientError> {
+    self.client_for(key).prepend(key, value)
+  }
+  #[inline]
+  pub fn touch(&mut self, key: &[u8], expiration: u32) -> Result<(), ClientError> {
+    self.client_for(key).touch(key, expiration)
+  }
+  #[inline]
+  pub fn gat(&mut self, key: &[u8], expiration: u32) -> Result<Option<Item>, ClientError> {
+    self.client_for(key).gat(key, expiration)
+  }
+  /// Flush every node
+  pub fn flush(&mut self, delay: Option<u32>) -> Result<(), ClientError> {
+    for c in self.clients.iter_mut() {
+      c.flush(delay)?;
+    }
+    Ok(())
+  }
+  /// Read several items. Missing keys are left out.
+  ///
+  /// The keys are split into a `MultiGet` batch per node. Every
+  /// batch is written before any replies are read, so the
+  /// nodes work in parallel.
+  pub fn multi_get(&mut self, keys: &[&[u8]]) -> Result<HashMap<Vec<u8>, Item>, ClientError> {
+    let mut split: Vec<Vec<&[u8]>> = vec![Vec::new(); self.clients.len()];
+    for key in keys {
+      let i = self.ring.node_for_key(key).unwrap_or(0);
+      split[i].push(*key);
+    }
+    let mut batches: Vec<(usize, MultiGet)> = Vec::new();
+    for (i, keys) in split.iter().enumerate() {
+      if keys.is_empty() {
+        continue;
+      }
+      let c = &mut self.clients[i];
+      let batch = MultiGet::new(keys, c.reserve_opaques(keys.len() + 1))?;
+      c.write_encoded(&batch.encode())?;
+      batches.push((i, batch));
+    }
+    let mut hits = HashMap::new();
+    for (i, mut batch) in batches {
+      let c = &mut self.clients[i];
+      while !batch.feed(c.recv()?)? { }
+      hits.extend(batch.finish()?);
+    }
+    Ok(hits)
+  }
+  /// Ask every node for it's version
+  pub fn versions(&mut self) -> Vec<Result<String, ClientError>> {
+    self.clients.iter_mut().map(|c| c.version()).collect()
+  }
+}
+
+#[test]
+fn test_hashes() {
+  //md5("") = d41d8cd98f00b204e9800998ecf8427e
+  assert_eq!(ketama_hash(b""), 0xd98c1dd4);
+  assert_eq!(one_at_a_time(b"a"), 0xca2e9442);
+  assert_eq!(one_at_a_time(b"The quick brown fox jumps over the lazy dog"), 0x519e91f5);
+  assert_eq!(Node::new("10.0.0.1", 11211).point_name(3), "10.0.0.1-3");
+  assert_eq!(Node::new("10.0.0.1", 11212).point_name(0), "10.0.0.1:11212-0");
+  assert_eq!("10.0.0.1:11212".parse::<Node>(), Ok(Node::new("10.0.0.1", 11212)));
+  assert!("10.0.0.1".parse::<Node>().is_err());
+  assert!(":11211".parse::<Node>().is_err());
+}
+
+#[test]
+fn test_ketama_ring() {
+  let nodes = vec![
+    Node::new("10.0.0.1", 11211),
+    Node::new("10.0.0.2", 11211),
+    Node::with_weight("10.0.0.3", 11211, 2)];
+  let ring = ServerRing::ketama(nodes.clone());
+  //weights 1:1:2 of 3 nodes give 30, 30, and 60 digests
+  assert_eq!(ring.points.len(), 4 * (30 + 30 + 60));
+  let mut counts = [0usize; 3];
+  for i in 0..10_000 {
+    counts[ring.node_for_key(format!("key:{}", i).as_bytes()).unwrap()] += 1;
+  }
+  assert!(counts[2] > counts[0] && counts[2] > counts[1]);
+
+  //with equal weights, removing a node only moves the keys it
+  //owned
+  let equal: Vec<Node> = nodes.iter().map(|n| Node::new(&n.host, n.port)).collect();
+  let ring = ServerRing::ketama(equal.clone());
+  assert_eq!(ring.points.len(), 3 * 160);
+  let smaller = ServerRing::ketama(equal[..2].to_vec());
+  for i in 0..1_000 {
+    let key = format!("key:{}", i);
+    let before = ring.node_for_key(key.as_bytes()).unwrap();
+    if before != 2 {
+      assert_eq!(smaller.node_for_key(key.as_bytes()), Some(before));
+    }
+  }
+
+  let modulo = ServerRing::modulo(nodes);
+  assert_eq!(modulo.node_for_key(b"a"), Some(0xca2e9442 % 3));
+  assert_eq!(ServerRing::ketama(Vec::new()).node_for_key(b"a"), None);
+}
@@ -0,0 +1,177 @@
+//! Snappy Value Compression
+//!
+//! Available with the `compression` cargo feature. Large values
+//! stored with `Set`, `Add`, or `Replace` are compressed and
+//! marked with `DataType::SNAPPY`. `Get` family responses carrying
+//! that flag are decompressed. `Client` and `AsyncClient` apply
+//! this transparently, see `Client::set_compression`.
+
+use super::{
+  Error,
+  LengthField,
+  ParseResult,
+  PacketVal
+};
+use super::opcode::OpCode;
+use super::datatype::DataType;
+use super::request::OwnedRequest;
+use super::response::OwnedResponse;
+use super::builder::MAX_BODY_LEN;
+use snap::raw::{
+  Encoder,
+  Decoder,
+  decompress_len
+};
+
+/// Values smaller then this are not worth compressing
+pub const DEFAULT_THRESHOLD: usize = 4096;
+
+/// Does this opcode store a value?
+#[inline(always)]
+fn is_store(code: OpCode) -> bool {
+  matches!(code,
+    OpCode::Set |
+    OpCode::SetQ |
+    OpCode::Add |
+    OpCode::AddQ |
+    OpCode::Replace |
+    OpCode::ReplaceQ)
+}
+
+/// Does this opcode return a value?
+#[inline(always)]
+fn is_fetch(code: OpCode) -> bool {
+  matches!(code,
+    OpCode::Get |
+    OpCode::GetQ |
+    OpCode::GetK |
+    OpCode::GetKQ |
+    OpCode::GAT |
+    OpCode::GATQ)
+}
+
+/// Compression Settings
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct Compression {
+  threshold: usize
+}
+impl Default for Compression {
+  #[inline]
+  fn default() -> Self {
+    Compression::new(DEFAULT_THRESHOLD)
+  }
+}
+impl Compression {
+  /// Compress values of at least `threshold` bytes
+  #[inline]
+  pub fn new(threshold: usize) -> Compression {
+    Compression {
+      threshold
+    }
+  }
+  #[inline(always)]
+  pub fn get_threshold(&self) -> usize {
+    self.threshold
+  }
+  /// Compress the value of a store request if it is large
+  /// enough. Values which do not shrink are left alone.
+  ///
+  /// Returns `true` if the request was compressed.
+  pub fn compress_request(&self, req: &mut OwnedRequest) -> bool {
+    if !is_store(req.get_opcode())
+      || req.body.len() < self.threshold
+      || req.get_datatype().contains(DataType::SNAPPY) {
+      return false;
+    }
+    let packed = match Encoder::new().compress_vec(&req.body) {
+      Ok(packed) => packed,
+      Err(_) => return false
+    };
+    if packed.len() >= req.body.len() {
+      return false;
+    }
+    req.set_body(packed);
+    let mut dt = req.get_datatype();
+    dt.insert(DataType::SNAPPY);
+    req.set_datatype(dt);
+    true
+  }
+}
+
+/// Decompress the value of a `Get` family response if it is
+/// marked with `DataType::SNAPPY`, clearing the flag.
+///
+/// Returns `true` if the response was decompressed. A value
+/// which cannot be decompressed is `Error::InvalidPacket`, one
+/// which would grow past `MAX_BODY_LEN` is `Error::BadLength`.
+#[inline]
+pub fn decompress_response(resp: &mut OwnedResponse) -> ParseResult<bool> {
+  decompress_response_with_max(resp, MAX_BODY_LEN)
+}
+
+/// `decompress_response`, refusing values which would grow past
+/// `max` bytes. The length is checked before anything is
+/// allocated, as it is sent by the server.
+pub fn decompress_response_with_max(resp: &mut OwnedResponse, max: usize) -> ParseResult<bool> {
+  if !is_fetch(resp.get_opcode()) || !resp.get_datatype().contains(DataType::SNAPPY) {
+    return Ok(false);
+  }
+  let offset = 24 + resp.get_keylen() + resp.get_extralen();
+  let len = decompress_len(&resp.body)
+    .map_err(|_| Error::InvalidPacket{ offset })?;
+  if len > max {
+    return Err(Error::BadLength{ field: LengthField::Body, value: len });
+  }
+  let value = Decoder::new().decompress_vec(&resp.body)
+    .map_err(|_| Error::InvalidPacket{ offset })?;
+  resp.set_body(value);
+  let mut dt = resp.get_datatype();
+  dt.remove(DataType::SNAPPY);
+  resp.set_datatype(dt);
+  Ok(true)
+}
+
+#[test]
+fn test_compression_round_trip() {
+  use super::status::StatusField;
+  use super::builder::RequestBuilder;
+
+  let value = vec![b'a'; 8192];
+  let c = Compression::default();
+  let mut req = RequestBuilder::set(b"big", &value).build().unwrap();
+  assert!(c.compress_request(&mut req));
+  assert!(req.body.len() < value.len());
+  assert_eq!(req.get_datatype(), DataType::SNAPPY);
+  //the header agrees with the new body
+  let parsed = OwnedRequest::parse(req.encode_self().as_slice()).unwrap();
+  assert_eq!(parsed.body, req.body);
+  assert!(!c.compress_request(&mut req));
+
+  //small values, and non store opcodes, are left alone
+  let mut small = RequestBuilder::set(b"small", b"value").build().unwrap();
+  assert!(!c.compress_request(&mut small));
+  let mut append = RequestBuilder::append(b"big", &value).build().unwrap();
+  assert!(!c.compress_request(&mut append));
+  assert!(append.get_datatype().is_raw());
+
+  let mut resp = OwnedResponse::new(OpCode::Get, StatusField::NoError, 0, 0, vec![0, 0, 0, 0], vec![], req.body.clone());
+  resp.set_datatype(DataType::SNAPPY);
+  assert!(decompress_response(&mut resp).unwrap());
+  assert_eq!(resp.body, value);
+  assert!(resp.get_datatype().is_raw());
+  assert!(!decompress_response(&mut resp).unwrap());
+
+  let mut bad = OwnedResponse::new(OpCode::Get, StatusField::NoError, 0, 0, vec![0, 0, 0, 0], vec![], vec![0xFF; 4]);
+  bad.set_datatype(DataType::SNAPPY);
+  assert_eq!(decompress_response(&mut bad).err().unwrap(), Error::InvalidPacket{ offset: 28 });
+
+  //the preamble claims u32::MAX bytes, nothing is allocated
+  let mut huge = OwnedResponse::new(OpCode::GetK, StatusField::NoError, 0, 0, vec![0, 0, 0, 0], b"k".to_vec(), vec![0xFF, 0xFF, 0xFF, 0xFF, 0x0F]);
+  huge.set_datatype(DataType::SNAPPY);
+  assert_eq!(decompress_response(&mut huge).err().unwrap(), Error::BadLength{ field: LengthField::Body, value: 0xFFFF_FFFF });
+  assert_eq!(huge.get_datatype(), DataType::SNAPPY);
+  let mut resp = OwnedResponse::new(OpCode::Get, StatusField::NoError, 0, 0, vec![0, 0, 0, 0], vec![], req.body.clone());
+  resp.set_datatype(DataType::SNAPPY);
+  assert_eq!(decompress_response_with_max(&mut resp, 4096).err().unwrap(), Error::BadLength{ field: LengthField::Body, value: 8192 });
+  assert!(decompress_response_with_max(&mut resp, 8192).unwrap());
+}
@@ -0,0 +1,139 @@
+
+use super::{
+  Encoding,
+  Encoder
+};
+use super::nom::{
+  IResult,
+  ErrorKind,
+  be_u8
+};
+use std::fmt;
+use std::ops::{
+  BitOr,
+  BitOrAssign
+};
+
+/// Data Type Field
+///
+/// Byte 5 of every header. It is a set of bit flags describing
+/// how the value is encoded. `DataType::RAW` (no flags) is the
+/// default.
+#[derive(Copy,Clone,PartialEq,Eq,Hash,Default)]
+pub struct DataType(u8);
+impl DataType {
+  /// Plain bytes
+  pub const RAW: DataType = DataType(0x00);
+  /// The value is JSON
+  pub const JSON: DataType = DataType(0x01);
+  /// The value is Snappy compressed
+  pub const SNAPPY: DataType = DataType(0x02);
+
+  /// Every flag this crate understands
+  const ALL: u8 = 0x03;
+
+  /// Build from a raw byte. Returns `None` if unknown flags
+  /// are set.
+  #[inline(always)]
+  pub fn from_bits(x: u8) -> Option<DataType> {
+    if x & !DataType::ALL == 0 {
+      Some(DataType(x))
+    } else {
+      None
+    }
+  }
+  /// Raw byte as written on the wire
+  #[inline(always)]
+  pub fn bits(&self) -> u8 {
+    self.0
+  }
+  /// True if every flag in `other` is set
+  #[inline(always)]
+  pub fn contains(&self, other: DataType) -> bool {
+    self.0 & other.0 == other.0
+  }
+  #[inline(always)]
+  pub fn is_raw(&self) -> bool {
+    self.0 == 0
+  }
+  /// Set the flags in `other`
+  #[inline(always)]
+  pub fn insert(&mut self, other: DataType) {
+    self.0 |= other.0;
+  }
+  /// Clear the flags in `other`
+  #[inline(always)]
+  pub fn remove(&mut self, other: DataType) {
+    self.0 &= !other.0;
+  }
+}
+impl BitOr for DataType {
+  type Output = DataType;
+  #[inline(always)]
+  fn bitor(self, other: DataType) -> DataType {
+    DataType(self.0 | other.0)
+  }
+}
+impl BitOrAssign for DataType {
+  #[inline(always)]
+  fn bitor_assign(&mut self, other: DataType) {
+    self.insert(other);
+  }
+}
+impl fmt::Debug for DataType {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match (self.contains(DataType::JSON), self.contains(DataType::SNAPPY)) {
+      (false, false) => write!(f, "DataType(RAW)"),
+      (true, false) => write!(f, "DataType(JSON)"),
+      (false, true) => write!(f, "DataType(SNAPPY)"),
+      (true, true) => write!(f, "DataType(JSON | SNAPPY)")
+    }
+  }
+}
+impl Encoding for DataType {
+  #[inline(always)]
+  fn encode(&self, buffer: &mut Encoder) {
+    buffer.encode_u8(self.0);
+  }
+}
+
+/// Parses the data type byte from a packet
+///
+/// Unknown flags return `IResult::Error(ErrorKind::Custom(0x85))`
+#[allow(dead_code)]
+#[inline(always)]
+pub fn datatype_parse(i: &[u8]) -> IResult<&[u8], DataType> {
+  match be_u8(i) {
+    IResult::Done(rem,val) => match DataType::from_bits(val) {
+      Option::Some(dt) => IResult::Done(rem, dt),
+      Option::None => IResult::Error(ErrorKind::Custom(0x85))
+    },
+    IResult::Error(e) => IResult::Error(e),
+    IResult::Incomplete(n) => IResult::Incomplete(n)
+  }
+}
+
+#[test]
+fn test_datatype() {
+  let both = DataType::JSON | DataType::SNAPPY;
+  assert_eq!(both.bits(), 0x03);
+  assert!(both.contains(DataType::SNAPPY));
+  assert!(!DataType::JSON.contains(DataType::SNAPPY));
+  assert!(DataType::default().is_raw());
+  let mut dt = DataType::RAW;
+  dt |= DataType::SNAPPY;
+  assert_eq!(dt, DataType::SNAPPY);
+  dt.remove(DataType::SNAPPY);
+  assert!(dt.is_raw());
+  assert_eq!(DataType::from_bits(0x04), None);
+  assert_eq!(format!("{:?}", both), "DataType(JSON | SNAPPY)");
+
+  match datatype_parse(&[0x02, 0x00]) {
+    IResult::Done(rem, DataType::SNAPPY) => assert_eq!(rem, &[0x00]),
+    x => panic!("0x02 should be Snappy not {:?}", x)
+  };
+  match datatype_parse(&[0x80]) {
+    IResult::Error(ErrorKind::Custom(0x85)) => { },
+    x => panic!("0x80 should be rejected not {:?}", x)
+  };
+}
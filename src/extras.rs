@@ -0,0 +1,507 @@
+//everything inside a nom macro is dead code
+#![allow(dead_code)]
+
+use super::{
+  ParseResult,
+  Error,
+  LengthField,
+  Encoding,
+  Encoder
+};
+use super::opcode::OpCode;
+use super::status::StatusField;
+use super::nom::{
+  IResult,
+  be_u32,
+  be_u64
+};
+
+/// Typed Extras Field
+///
+/// Every opcode defines a fixed layout for the extras field
+/// of it's packets. Types implementing this trait know that
+/// layout, and can move between it and the raw bytes stored
+/// in `Request`/`Response`.
+pub trait Extras: Encoding + Sized {
+
+  /// Number of bytes this value occupies in the extras field
+  fn extras_len(&self) -> usize;
+
+  /// Parse the value from the extras field of a packet.
+  ///
+  /// The slice must be exactly `extras_len()` long, anything
+  /// else is reported as `Error::BadLength`.
+  fn parse_extras(x: &[u8]) -> ParseResult<Self>;
+
+  /// Encode the value into a new buffer. The result can be
+  /// passed as the `extra` field to `Request::new` or
+  /// `OwnedRequest::new`.
+  #[inline]
+  fn to_vec(&self) -> Vec<u8> {
+    let mut e = unsafe{ Encoder::with_capacity(self.extras_len()) };
+    self.encode(&mut e);
+    e.get_vec()
+  }
+}
+
+/// The extras field does not match the opcode's layout
+#[inline(always)]
+pub(crate) fn bad_len(x: &[u8]) -> Error {
+  Error::BadLength{ field: LengthField::Extras, value: x.len() }
+}
+
+/// Extras fields are fixed width. Anything longer or shorter
+/// then the opcode's layout is an invalid packet.
+#[inline(always)]
+pub(crate) fn exact<T>(x: &[u8], len: usize, f: fn(&[u8]) -> IResult<&[u8],T>) -> ParseResult<T> {
+  match f(x) {
+    IResult::Done(_, val) if x.len() == len => Ok(val),
+    _ => Err(bad_len(x))
+  }
+}
+
+/// Extras for `Set`, `Add`, `Replace` (and their quiet forms)
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct SetExtras {
+  pub flags: u32,
+  pub expiration: u32
+}
+impl SetExtras {
+  #[inline(always)]
+  pub fn new(flags: u32, expiration: u32) -> SetExtras {
+    SetExtras {
+      flags,
+      expiration
+    }
+  }
+}
+impl Encoding for SetExtras {
+  #[inline(always)]
+  fn encode(&self, buffer: &mut Encoder) {
+    self.flags.encode(buffer);
+    self.expiration.encode(buffer);
+  }
+}
+impl Extras for SetExtras {
+  #[inline(always)]
+  fn extras_len(&self) -> usize {
+    8
+  }
+  #[inline]
+  fn parse_extras(x: &[u8]) -> ParseResult<Self> {
+    named!(parse_set_extras<SetExtras>, do_parse!(
+      f: be_u32   >>
+      e: be_u32   >>
+      (SetExtras{ flags: f, expiration: e })
+    ));
+    exact(x, 8, parse_set_extras)
+  }
+}
+
+/// Extras for `Increment`, `Decrement` (and their quiet forms)
+///
+/// An `expiration` of `0xFFFFFFFF` tells the server not to
+/// create the item when it is missing, otherwise `initial`
+/// is stored.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct IncrExtras {
+  pub delta: u64,
+  pub initial: u64,
+  pub expiration: u32
+}
+impl IncrExtras {
+  #[inline(always)]
+  pub fn new(delta: u64, initial: u64, expiration: u32) -> IncrExtras {
+    IncrExtras {
+      delta,
+      initial,
+      expiration
+    }
+  }
+}
+impl Encoding for IncrExtras {
+  #[inline(always)]
+  fn encode(&self, buffer: &mut Encoder) {
+    self.delta.encode(buffer);
+    self.initial.encode(buffer);
+    self.expiration.encode(buffer);
+  }
+}
+impl Extras for IncrExtras {
+  #[inline(always)]
+  fn extras_len(&self) -> usize {
+    20
+  }
+  #[inline]
+  fn parse_extras(x: &[u8]) -> ParseResult<Self> {
+    named!(parse_incr_extras<IncrExtras>, do_parse!(
+      d: be_u64   >>
+      i: be_u64   >>
+      e: be_u32   >>
+      (IncrExtras{ delta: d, initial: i, expiration: e })
+    ));
+    exact(x, 20, parse_incr_extras)
+  }
+}
+
+/// Extras for `Flush` and `FlushQ`
+///
+/// The delay is optional. When it is `None` the extras field
+/// is left empty and the flush happens immediately.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct FlushExtras {
+  pub expiration: Option<u32>
+}
+impl FlushExtras {
+  #[inline(always)]
+  pub fn new(expiration: Option<u32>) -> FlushExtras {
+    FlushExtras {
+      expiration
+    }
+  }
+}
+impl Encoding for FlushExtras {
+  #[inline(always)]
+  fn encode(&self, buffer: &mut Encoder) {
+    if let Some(exp) = self.expiration {
+      exp.encode(buffer);
+    }
+  }
+}
+impl Extras for FlushExtras {
+  #[inline(always)]
+  fn extras_len(&self) -> usize {
+    match self.expiration {
+      Option::Some(_) => 4,
+      Option::None => 0
+    }
+  }
+  #[inline]
+  fn parse_extras(x: &[u8]) -> ParseResult<Self> {
+    if x.is_empty() {
+      return ParseResult::Ok(FlushExtras{ expiration: None });
+    }
+    exact(x, 4, be_u32).map(|e| FlushExtras{ expiration: Some(e) })
+  }
+}
+
+/// Extras for `Verbosity`
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct VerbosityExtras {
+  pub verbosity: u32
+}
+impl VerbosityExtras {
+  #[inline(always)]
+  pub fn new(verbosity: u32) -> VerbosityExtras {
+    VerbosityExtras {
+      verbosity
+    }
+  }
+}
+impl Encoding for VerbosityExtras {
+  #[inline(always)]
+  fn encode(&self, buffer: &mut Encoder) {
+    self.verbosity.encode(buffer);
+  }
+}
+impl Extras for VerbosityExtras {
+  #[inline(always)]
+  fn extras_len(&self) -> usize {
+    4
+  }
+  #[inline]
+  fn parse_extras(x: &[u8]) -> ParseResult<Self> {
+    exact(x, 4, be_u32).map(|v| VerbosityExtras{ verbosity: v })
+  }
+}
+
+/// Extras for `Touch`, `GAT` and `GATQ`
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct TouchExtras {
+  pub expiration: u32
+}
+impl TouchExtras {
+  #[inline(always)]
+  pub fn new(expiration: u32) -> TouchExtras {
+    TouchExtras {
+      expiration
+    }
+  }
+}
+impl Encoding for TouchExtras {
+  #[inline(always)]
+  fn encode(&self, buffer: &mut Encoder) {
+    self.expiration.encode(buffer);
+  }
+}
+impl Extras for TouchExtras {
+  #[inline(always)]
+  fn extras_len(&self) -> usize {
+    4
+  }
+  #[inline]
+  fn parse_extras(x: &[u8]) -> ParseResult<Self> {
+    exact(x, 4, be_u32).map(|e| TouchExtras{ expiration: e })
+  }
+}
+
+/// Extras returned by `Get`, `GetQ`, `GetK`, `GetKQ`, `GAT` and `GATQ`
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct GetResponseExtras {
+  pub flags: u32
+}
+impl GetResponseExtras {
+  #[inline(always)]
+  pub fn new(flags: u32) -> GetResponseExtras {
+    GetResponseExtras {
+      flags
+    }
+  }
+}
+impl Encoding for GetResponseExtras {
+  #[inline(always)]
+  fn encode(&self, buffer: &mut Encoder) {
+    self.flags.encode(buffer);
+  }
+}
+impl Extras for GetResponseExtras {
+  #[inline(always)]
+  fn extras_len(&self) -> usize {
+    4
+  }
+  #[inline]
+  fn parse_extras(x: &[u8]) -> ParseResult<Self> {
+    exact(x, 4, be_u32).map(|f| GetResponseExtras{ flags: f })
+  }
+}
+
+/// Opcodes whose extras are not modeled here (range, vbucket,
+/// and TAP commands) are passed through untouched.
+#[inline(always)]
+fn is_raw(code: OpCode) -> bool {
+  let byte: u8 = code.into();
+  byte >= 0x30
+}
+
+/// Any opcode whose layout has no extras must not carry any.
+#[inline(always)]
+fn empty<T>(x: &[u8], val: T) -> ParseResult<T> {
+  if x.is_empty() {
+    ParseResult::Ok(val)
+  } else {
+    ParseResult::Err(bad_len(x))
+  }
+}
+
+/// Extras field of a request, decoded against its opcode
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum ReqExtras {
+  /// The opcode carries no extras
+  None,
+  /// `Set`, `Add`, `Replace` (and quiet forms)
+  Store(SetExtras),
+  /// `Increment`, `Decrement` (and quiet forms)
+  Arith(IncrExtras),
+  /// `Flush`, `FlushQ`
+  Flush(FlushExtras),
+  /// `Verbosity`
+  Verbosity(VerbosityExtras),
+  /// `Touch`, `GAT`, `GATQ`
+  Touch(TouchExtras),
+  /// Range, vbucket, and TAP commands. These are not decoded.
+  Raw(Vec<u8>)
+}
+impl ReqExtras {
+  /// Decode the extras field of a request with the given opcode.
+  ///
+  /// Fails with `Error::BadLength` if the length of the
+  /// field does not match the opcode's layout.
+  pub fn parse(code: OpCode, extra: Option<&[u8]>) -> ParseResult<ReqExtras> {
+    let x = extra.unwrap_or(&[]);
+    match code {
+      OpCode::Set |
+      OpCode::SetQ |
+      OpCode::Add |
+      OpCode::AddQ |
+      OpCode::Replace |
+      OpCode::ReplaceQ => SetExtras::parse_extras(x).map(ReqExtras::Store),
+      OpCode::Increment |
+      OpCode::IncrementQ |
+      OpCode::Decrement |
+      OpCode::DecrementQ => IncrExtras::parse_extras(x).map(ReqExtras::Arith),
+      OpCode::Flush |
+      OpCode::FlushQ => FlushExtras::parse_extras(x).map(ReqExtras::Flush),
+      OpCode::Verbosity => VerbosityExtras::parse_extras(x).map(ReqExtras::Verbosity),
+      OpCode::Touch |
+      OpCode::GAT |
+      OpCode::GATQ => TouchExtras::parse_extras(x).map(ReqExtras::Touch),
+      x_code if is_raw(x_code) => if x.is_empty() {
+        ParseResult::Ok(ReqExtras::None)
+      } else {
+        ParseResult::Ok(ReqExtras::Raw(x.to_vec()))
+      },
+      _ => empty(x, ReqExtras::None)
+    }
+  }
+  /// Number of bytes this value occupies in the extras field
+  #[inline]
+  pub fn len(&self) -> usize {
+    match *self {
+      ReqExtras::None => 0,
+      ReqExtras::Store(ref e) => e.extras_len(),
+      ReqExtras::Arith(ref e) => e.extras_len(),
+      ReqExtras::Flush(ref e) => e.extras_len(),
+      ReqExtras::Verbosity(ref e) => e.extras_len(),
+      ReqExtras::Touch(ref e) => e.extras_len(),
+      ReqExtras::Raw(ref v) => v.len()
+    }
+  }
+  /// Checks if the extras field is empty
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+  /// Encode the value into a new buffer. The result can be
+  /// passed as the `extra` field to `Request::new` or
+  /// `OwnedRequest::new`.
+  #[inline]
+  pub fn to_vec(&self) -> Vec<u8> {
+    let mut e = unsafe{ Encoder::with_capacity(self.len()) };
+    self.encode(&mut e);
+    e.get_vec()
+  }
+}
+impl Encoding for ReqExtras {
+  #[inline]
+  fn encode(&self, buffer: &mut Encoder) {
+    match *self {
+      ReqExtras::None => { },
+      ReqExtras::Store(ref e) => e.encode(buffer),
+      ReqExtras::Arith(ref e) => e.encode(buffer),
+      ReqExtras::Flush(ref e) => e.encode(buffer),
+      ReqExtras::Verbosity(ref e) => e.encode(buffer),
+      ReqExtras::Touch(ref e) => e.encode(buffer),
+      ReqExtras::Raw(ref v) => v.encode(buffer)
+    };
+  }
+}
+
+/// Extras field of a response, decoded against its opcode
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum ResExtras {
+  /// The opcode carries no extras
+  None,
+  /// `Get`, `GetQ`, `GetK`, `GetKQ`, `GAT`, `GATQ`
+  Get(GetResponseExtras),
+  /// Range, vbucket, and TAP commands. These are not decoded.
+  Raw(Vec<u8>)
+}
+impl ResExtras {
+  /// Decode the extras field of a response with the given opcode.
+  ///
+  /// Failed responses carry no extras, so when `status` is an
+  /// error an empty field is always accepted.
+  ///
+  /// Fails with `Error::BadLength` if the length of the
+  /// field does not match the opcode's layout.
+  pub fn parse(code: OpCode, status: StatusField, extra: Option<&[u8]>) -> ParseResult<ResExtras> {
+    let x = extra.unwrap_or(&[]);
+    if status.check_status().is_err() && x.is_empty() {
+      return ParseResult::Ok(ResExtras::None);
+    }
+    match code {
+      OpCode::Get |
+      OpCode::GetQ |
+      OpCode::GetK |
+      OpCode::GetKQ |
+      OpCode::GAT |
+      OpCode::GATQ => GetResponseExtras::parse_extras(x).map(ResExtras::Get),
+      x_code if is_raw(x_code) => if x.is_empty() {
+        ParseResult::Ok(ResExtras::None)
+      } else {
+        ParseResult::Ok(ResExtras::Raw(x.to_vec()))
+      },
+      _ => empty(x, ResExtras::None)
+    }
+  }
+  /// Number of bytes this value occupies in the extras field
+  #[inline]
+  pub fn len(&self) -> usize {
+    match *self {
+      ResExtras::None => 0,
+      ResExtras::Get(ref e) => e.extras_len(),
+      ResExtras::Raw(ref v) => v.len()
+    }
+  }
+  /// Checks if the extras field is empty
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+  /// Encode the value into a new buffer. The result can be
+  /// passed as the `extra` field to `Response::new` or
+  /// `OwnedResponse::new`.
+  #[inline]
+  pub fn to_vec(&self) -> Vec<u8> {
+    let mut e = unsafe{ Encoder::with_capacity(self.len()) };
+    self.encode(&mut e);
+    e.get_vec()
+  }
+}
+impl Encoding for ResExtras {
+  #[inline]
+  fn encode(&self, buffer: &mut Encoder) {
+    match *self {
+      ResExtras::None => { },
+      ResExtras::Get(ref e) => e.encode(buffer),
+      ResExtras::Raw(ref v) => v.encode(buffer)
+    };
+  }
+}
+
+#[test]
+fn test_extras_round_trip() {
+  use super::Fault;
+
+  let set = SetExtras::new(0xDEADBEEF, 3600);
+  assert_eq!(set.to_vec(), b"\xDE\xAD\xBE\xEF\x00\x00\x0E\x10".to_vec());
+  assert_eq!(SetExtras::parse_extras(&set.to_vec()).unwrap(), set);
+
+  let incr = IncrExtras::new(1, 0, 0xFFFFFFFF);
+  let v = incr.to_vec();
+  assert_eq!(v.len(), 20);
+  assert_eq!(IncrExtras::parse_extras(&v).unwrap(), incr);
+
+  assert_eq!(FlushExtras::new(None).to_vec(), Vec::<u8>::new());
+  assert_eq!(FlushExtras::parse_extras(b"").unwrap(), FlushExtras::new(None));
+  assert_eq!(FlushExtras::parse_extras(b"\x00\x00\x00\x0A").unwrap(), FlushExtras::new(Some(10)));
+  assert_eq!(TouchExtras::parse_extras(b"\x00\x00\x00\x0A").unwrap(), TouchExtras::new(10));
+  assert_eq!(VerbosityExtras::parse_extras(b"\x00\x00\x00\x02").unwrap(), VerbosityExtras::new(2));
+  assert_eq!(GetResponseExtras::parse_extras(b"\xDE\xAD\xBE\xEF").unwrap(), GetResponseExtras::new(0xDEADBEEF));
+
+  //lengths are strict
+  assert_eq!(SetExtras::parse_extras(b"\x00\x00\x00\x00").unwrap_err(), Error::BadLength{ field: LengthField::Extras, value: 4 });
+  assert_eq!(TouchExtras::parse_extras(b"").unwrap_err(), Fault::BadLength);
+  assert_eq!(FlushExtras::parse_extras(b"\x00\x00").unwrap_err(), Fault::BadLength);
+}
+
+#[test]
+fn test_extras_by_opcode() {
+  use super::Fault;
+
+  let set = SetExtras::new(7, 60).to_vec();
+  assert_eq!(ReqExtras::parse(OpCode::AddQ, Some(&set)).unwrap(), ReqExtras::Store(SetExtras::new(7, 60)));
+  assert_eq!(ReqExtras::parse(OpCode::Get, None).unwrap(), ReqExtras::None);
+  assert_eq!(ReqExtras::parse(OpCode::Get, Some(&set)).unwrap_err(), Fault::BadLength);
+  assert_eq!(ReqExtras::parse(OpCode::Set, None).unwrap_err(), Fault::BadLength);
+  assert_eq!(ReqExtras::parse(OpCode::Flush, None).unwrap(), ReqExtras::Flush(FlushExtras::new(None)));
+  assert_eq!(ReqExtras::parse(OpCode::TAPMutate, Some(&set)).unwrap(), ReqExtras::Raw(set.clone()));
+  assert_eq!(ReqExtras::Store(SetExtras::new(7, 60)).to_vec(), set);
+
+  let flags = b"\x00\x00\x00\x01";
+  assert_eq!(ResExtras::parse(OpCode::GetK, StatusField::NoError, Some(flags)).unwrap(), ResExtras::Get(GetResponseExtras::new(1)));
+  assert_eq!(ResExtras::parse(OpCode::GetK, StatusField::NoError, None).unwrap_err(), Fault::BadLength);
+  assert_eq!(ResExtras::parse(OpCode::GetK, StatusField::KeyNotFound, None).unwrap(), ResExtras::None);
+  assert_eq!(ResExtras::parse(OpCode::Set, StatusField::NoError, None).unwrap(), ResExtras::None);
+  assert_eq!(ResExtras::parse(OpCode::Set, StatusField::NoError, Some(flags)).unwrap_err(), Fault::BadLength);
+}
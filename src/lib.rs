@@ -18,6 +18,8 @@ extern crate nom;
 use nom::{IResult,ErrorKind};
 use std::ptr::copy_nonoverlapping;
 use std::mem::transmute;
+use std::error;
+use std::fmt;
 
 /// Binary Packet Protocols
 mod opcode;
@@ -25,15 +27,88 @@ pub use opcode::OpCode;
 
 /// Status Codes for Response Packets
 mod status;
-pub use status::StatusField;
+pub use status::{
+  StatusField,
+  status_message
+};
 
 /// RequestHeaders/Packets
 mod request;
 pub use request::{Request,OwnedRequest,ReqHeader};
 
+/// Data Type flags for Request/Response Packets
+mod datatype;
+pub use datatype::DataType;
+
 /// ResponseHeaders/Packets
 mod response;
-pub use response::{Response,OwnedResponse,ResHeader};
+pub use response::{Response,OwnedResponse,ResHeader,status_response};
+
+/// Opcode specific Extras fields
+mod extras;
+pub use extras::{
+  Extras,
+  ReqExtras,
+  ResExtras,
+  SetExtras,
+  IncrExtras,
+  FlushExtras,
+  VerbosityExtras,
+  TouchExtras,
+  GetResponseExtras
+};
+
+/// Validating Request Builder
+mod builder;
+pub use builder::{
+  RequestBuilder,
+  BuildError,
+  check_key,
+  MAX_KEY_LEN,
+  MAX_BODY_LEN,
+  RELATIVE_EXPIRATION_LIMIT
+};
+
+/// Incremental decoding of byte streams
+mod stream;
+pub use stream::{
+  StreamDecoder,
+  Decode
+};
+
+pub mod stats;
+
+pub mod client;
+
+#[cfg(feature = "compression")]
+pub mod compression;
+
+pub mod server;
+
+pub mod memory;
+
+pub mod testing;
+
+pub mod cluster;
+
+pub mod vbucket;
+
+pub mod auth;
+
+pub mod tap;
+
+pub mod range;
+
+pub mod text;
+
+pub mod meta;
+
+pub mod proxy;
+
+pub mod route;
+
+#[cfg(feature = "tokio")]
+pub mod async_client;
 
 macro_rules! write_data {
   ($val: expr, $len: expr, $start: expr, $buf: expr) => {
@@ -47,7 +122,10 @@ macro_rules! write_data {
 }
 
 
-/// Parsing Error structure
+/// Kind of parsing failure
+///
+/// This is the coarse classification of an `Error`, see
+/// `Error::fault`.
 #[derive(Copy,Clone,Debug,PartialEq,Eq)]
 pub enum Fault {
   /// Opcode does not conform to the standard
@@ -60,71 +138,163 @@ pub enum Fault {
   ///
   ///* Request = `0x80`
   ///* Response = `0x81`
-  ///* DataType = `0x00`
   ///
   /// Was wrong.
   BadMagic,
+  /// The data type field has flags which are not understood
+  BadDataType,
+  /// A length field does not agree with the packet
+  BadLength,
   InvalidPacket
 }
-/// Internal Result type
-pub enum ParseResult<T> {
-  Ok(T),
-  Err(Fault)
+
+/// Length fields of a packet
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum LengthField {
+  /// Extras length (header byte 4)
+  Extras,
+  /// Key length (header bytes 2-3)
+  Key,
+  /// Total body length (header bytes 8-11)
+  Body
 }
-impl<T> ParseResult<T> {
-  /// Unwraps the value. Panics on failure
-  #[inline(always)]
-  pub fn unwrap(self) -> T {
-    match self {
-      ParseResult::Ok(x) => x,
-      ParseResult::Err(e) => panic!("ParseResult::unwrap called on Err {:?}", e)
-    }
+
+/// Parsing Error
+///
+/// Carries enough of the offending packet to log a meaningful
+/// message. Offsets are in bytes from the start of the packet.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Error {
+  /// The opcode byte is not part of the standard
+  BadOpCode {
+    code: u8,
+    offset: usize
+  },
+  /// The status field is not part of the standard
+  BadStatus {
+    status: u16,
+    offset: usize
+  },
+  /// The magic byte was wrong
+  BadMagic {
+    magic: u8,
+    offset: usize
+  },
+  /// The data type byte has unknown flags set
+  BadDataType {
+    datatype: u8,
+    offset: usize
+  },
+  /// More data is needed, at least `needed` bytes
+  Incomplete {
+    needed: usize
+  },
+  /// A length field is too large, or too small, for the packet
+  BadLength {
+    field: LengthField,
+    value: usize
+  },
+  /// Any other malformed data
+  InvalidPacket {
+    offset: usize
   }
-  /// Checks if value is okay
-  #[inline(always)]
-  pub fn is_ok(&self) -> bool {
-    match self {
-       &ParseResult::Ok(_) => true,
-       _ => false
+}
+impl Error {
+  /// The kind of failure, without it's context
+  pub fn fault(&self) -> Fault {
+    match *self {
+      Error::BadOpCode{..} => Fault::BadOpCode,
+      Error::BadStatus{..} => Fault::BadStatus,
+      Error::BadMagic{..} => Fault::BadMagic,
+      Error::BadDataType{..} => Fault::BadDataType,
+      Error::Incomplete{..} => Fault::Incomplete,
+      Error::BadLength{..} => Fault::BadLength,
+      Error::InvalidPacket{..} => Fault::InvalidPacket
     }
   }
-  /// Check if the value is error
+}
+impl PartialEq<Fault> for Error {
   #[inline(always)]
-  pub fn is_err(&self) -> bool {
-    ! self.is_ok()
+  fn eq(&self, other: &Fault) -> bool {
+    self.fault() == *other
   }
-  /// Get Okay Value
+}
+impl From<Error> for Fault {
   #[inline(always)]
-  pub fn ok(self) -> Option<T> {
-    match self {
-      ParseResult::Ok(x) => Some(x),
-      ParseResult::Err(_) => None
-    }
+  fn from(e: Error) -> Fault {
+    e.fault()
   }
-  /// Get Error Value
-  #[inline(always)]
-  pub fn err(self) -> Option<Fault> {
-    match self {
-      ParseResult::Ok(_) => None,
-      ParseResult::Err(e) => Some(e)
+}
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Error::BadOpCode{code, offset} => write!(f, "unknown opcode 0x{:02X} at byte {}", code, offset),
+      Error::BadStatus{status, offset} => write!(f, "unknown status 0x{:04X} at byte {}", status, offset),
+      Error::BadMagic{magic, offset} => write!(f, "bad magic 0x{:02X} at byte {}", magic, offset),
+      Error::BadDataType{datatype, offset} => write!(f, "unknown data type 0x{:02X} at byte {}", datatype, offset),
+      Error::Incomplete{needed} => write!(f, "packet is incomplete, {} more bytes needed", needed),
+      Error::BadLength{field, value} => write!(f, "{:?} length {} does not fit the packet", field, value),
+      Error::InvalidPacket{offset} => write!(f, "malformed packet at byte {}", offset)
     }
   }
 }
-impl<'a,T> From<IResult<&'a [u8],T>> for ParseResult<T> {
+impl error::Error for Error { }
 
-  /// You can ignore this
-  ///
-  /// It is an internal method for handling nom's parser errors
-  #[inline(always)]
-  fn from(x: IResult<&'a [u8], T>) -> ParseResult<T> {
-    match x {
-      IResult::Done(_,x) => ParseResult::Ok(x),
-      IResult::Incomplete(_) => ParseResult::Err(Fault::Incomplete),
-      IResult::Error(ErrorKind::Custom(0x83u32)) => ParseResult::Err(Fault::BadStatus),
-      IResult::Error(ErrorKind::Custom(0x81u32)) => ParseResult::Err(Fault::BadOpCode),
-      IResult::Error(ErrorKind::Tag) => ParseResult::Err(Fault::BadMagic),
-      IResult::Error(_) => ParseResult::Err(Fault::InvalidPacket)
-    }
+/// Result of parsing
+pub type ParseResult<T> = Result<T, Error>;
+
+/// Read a big endian `u16` from a header, if present
+#[inline(always)]
+fn header_u16(buf: &[u8], offset: usize) -> u16 {
+  match buf.get(offset..offset + 2) {
+    Option::Some(x) => ((x[0] as u16) << 8) | x[1] as u16,
+    Option::None => 0
+  }
+}
+
+/// Read a big endian `u32` from a header, if present
+#[inline(always)]
+fn header_u32(buf: &[u8], offset: usize) -> u32 {
+  let mut field = [0u8; 4];
+  if let Some(x) = buf.get(offset..offset + 4) {
+    field.copy_from_slice(x);
+  }
+  u32::from_be_bytes(field)
+}
+
+/// Bytes still missing before the packet in `buf` is complete
+fn bytes_needed(buf: &[u8]) -> usize {
+  if buf.len() < 24 {
+    return 24 - buf.len();
+  }
+  let total = 24 + header_u32(buf, 8) as usize;
+  ::std::cmp::max(total.saturating_sub(buf.len()), 1)
+}
+
+/// Used within the header parsers. Rejects a header whose total
+/// body length is too short to hold it's key and extras.
+#[inline(always)]
+pub(crate) fn check_lengths(i: &[u8], keylen: u16, extralen: u8, bodylen: u32) -> IResult<&[u8], ()> {
+  if keylen as u32 + extralen as u32 > bodylen {
+    IResult::Error(ErrorKind::Custom(0x84u32))
+  } else {
+    IResult::Done(i, ())
+  }
+}
+
+/// Convert nom's result for a packet (or header) parsed from
+/// `buf`. The header is used to give failures their context.
+///
+pub(crate) fn from_nom<T>(buf: &[u8], x: IResult<&[u8], T>) -> ParseResult<T> {
+  match x {
+    IResult::Done(_,x) => Ok(x),
+    IResult::Incomplete(_) => Err(Error::Incomplete{ needed: bytes_needed(buf) }),
+    IResult::Error(ErrorKind::Custom(0x83u32)) => Err(Error::BadStatus{ status: header_u16(buf, 6), offset: 6 }),
+    IResult::Error(ErrorKind::Custom(0x81u32)) => Err(Error::BadOpCode{ code: buf.get(1).cloned().unwrap_or(0), offset: 1 }),
+    IResult::Error(ErrorKind::Custom(0x84u32)) => Err(Error::BadLength{ field: LengthField::Body, value: header_u32(buf, 8) as usize }),
+    IResult::Error(ErrorKind::Custom(0x85u32)) => Err(Error::BadDataType{ datatype: buf.get(5).cloned().unwrap_or(0), offset: 5 }),
+    IResult::Error(ErrorKind::Tag) => Err(Error::BadMagic{ magic: buf.first().cloned().unwrap_or(0), offset: 0 }),
+    IResult::Error(_) => Err(Error::InvalidPacket{ offset: 0 })
   }
 }
 
@@ -149,6 +319,16 @@ impl Encoder {
       pos: 0
     }
   }
+  /// Allocates a buffer large enough to hold every packet passed in.
+  /// This is used to pipeline several packets into a single write.
+  #[inline]
+  pub fn for_packets<P: PacketVal>(msgs: &[P]) -> Encoder {
+    let len = msgs.iter().map(|m| m.total_len()).sum();
+    Encoder {
+      data: vec![0u8; len],
+      pos: 0
+    }
+  }
   /// To avoid allocations this method allows for a pre-allocated vector
   /// be passed in. The Vector's size will be checked, and it **MAY** be resized
   /// if too small. If it's capacity is sufficient no allocations will be done.
@@ -159,7 +339,7 @@ impl Encoder {
     let capac = x.capacity();
     /* resize if needed */
     if capac < len {
-      let delta = capac - len;
+      let delta = len - x.len();
       x.reserve(delta);
     }
     unsafe{ x.set_len(len) };
@@ -236,7 +416,7 @@ impl Encoder {
     let len = x.len();
     let s = self.len();
     unsafe {
-      copy_nonoverlapping(x.as_ptr(), self.data.get_unchecked_mut(s), len);
+      copy_nonoverlapping(x.as_ptr(), self.data.as_mut_ptr().add(s), len);
     }
     self.pos += len as isize;
   }
@@ -384,3 +564,63 @@ fn test_encoding_vec() {
   assert_eq!(e.len(), slice.len());
   assert_eq!(e.as_slice(), b"\x01\x02\x03\x04\x05\x06\x07\x08\x09\x0A");
 }
+
+#[test]
+fn test_parse_error_context() {
+  let mut msg: Vec<u8> = vec![
+    0x81, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x04,
+    0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+    0xDE, 0xAD, 0xBE, 0xEF
+  ];
+  assert!(OwnedResponse::parse(&msg).is_ok());
+  assert_eq!(OwnedResponse::parse(&msg[0..20]).err().unwrap(), Error::Incomplete{ needed: 4 });
+  assert_eq!(OwnedResponse::parse(&msg[0..26]).err().unwrap(), Error::Incomplete{ needed: 2 });
+  assert_eq!(OwnedRequest::parse(&msg).err().unwrap(), Error::BadMagic{ magic: 0x81, offset: 0 });
+
+  msg[5] = 0x07;
+  assert_eq!(OwnedResponse::parse(&msg).err().unwrap(), Error::BadDataType{ datatype: 0x07, offset: 5 });
+  msg[5] = 0x02;
+  let snappy = OwnedResponse::parse(&msg).unwrap();
+  assert_eq!(snappy.get_datatype(), DataType::SNAPPY);
+  assert_eq!(snappy.encode_self().as_slice(), msg.as_slice());
+  let req = RequestBuilder::set(b"k", b"v").datatype(DataType::JSON).build().unwrap();
+  let e = req.encode_self();
+  assert_eq!(e.as_slice()[5], 0x01);
+  assert_eq!(OwnedRequest::parse(e.as_slice()).unwrap().get_datatype(), DataType::JSON);
+  msg[5] = 0x00;
+  msg[6] = 0x01;
+  msg[7] = 0x23;
+  let e = OwnedResponse::parse(&msg).err().unwrap();
+  assert_eq!(e, Error::BadStatus{ status: 0x0123, offset: 6 });
+  assert_eq!(e, Fault::BadStatus);
+  msg[1] = 0xF0;
+  let e = OwnedResponse::parse(&msg).err().unwrap();
+  assert_eq!(e, Error::BadOpCode{ code: 0xF0, offset: 1 });
+  assert_eq!(e.to_string(), "unknown opcode 0xF0 at byte 1");
+}
+
+#[test]
+fn test_short_body_length() {
+  //key length 5, extras length 4, but total body length 3
+  let msg: Vec<u8> = vec![
+    0x80, 0x00, 0x00, 0x05,
+    0x04, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x03,
+    0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00
+  ];
+  let e = ReqHeader::parse(&msg).err().unwrap();
+  assert_eq!(e, Error::BadLength{ field: LengthField::Body, value: 3 });
+  assert!(OwnedRequest::parse(&msg).is_err());
+  let mut res = msg.clone();
+  res[0] = 0x81;
+  assert_eq!(ResHeader::parse(&res).err().unwrap(), Fault::BadLength);
+  assert_eq!(OwnedRequest::parse(&[]).err().unwrap(), Error::Incomplete{ needed: 24 });
+  assert_eq!(OwnedRequest::parse(&[0x80]).err().unwrap(), Error::Incomplete{ needed: 23 });
+}
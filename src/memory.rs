@@ -0,0 +1,548 @@
+//! In-Memory Cache Backend
+//!
+//! `MemoryStore` is a `server::Handler` implementing memcached's
+//! storage semantics, intended for testing clients without a
+//! real memcached.
+//!
+//!* Every mutation generates a new CAS value, a non zero CAS in
+//!  a request must match (`StatusField::KeyExists`)
+//!* `Add` of an existing key, and `Replace`/`Append`/`Prepend`
+//!  of a missing key fail with `StatusField::ItemNotStored`
+//!* Counters wrap on increment and stop at `0` on decrement,
+//!  values which are not decimal numbers fail with
+//!  `StatusField::IncrDecrNonNumeric`
+//!* Expirations up to 30 days are relative, larger values are
+//!  unix timestamps
+//!* `Flush` may be delayed, it then invalidates every item
+//!  stored before it takes effect
+//!* The least recently used items are evicted to stay within a
+//!  byte budget
+
+use super::status::StatusField;
+use super::datatype::DataType;
+use super::builder::RELATIVE_EXPIRATION_LIMIT;
+use super::server::{
+  Handler,
+  HandlerResult,
+  Value,
+  StoreMode,
+  StoreRequest,
+  ArithMode,
+  ArithRequest
+};
+use std::collections::{
+  BTreeMap,
+  HashMap
+};
+use std::sync::Mutex;
+use std::time::{
+  SystemTime,
+  UNIX_EPOCH
+};
+
+/// Bytes charged per item on top of it's key and value
+const ITEM_OVERHEAD: usize = 48;
+
+/// Source of the current unix time, in seconds
+pub type Clock = Box<dyn Fn() -> u64 + Send + Sync>;
+
+/// The system clock
+fn system_time() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+struct Item {
+  value: Vec<u8>,
+  flags: u32,
+  cas: u64,
+  datatype: DataType,
+  /// Unix time the item expires, `0` is never
+  expires: u64,
+  /// Unix time the item was stored
+  stored: u64,
+  /// Position in the LRU
+  tick: u64,
+  /// Has the item been read (or touched) since it was stored
+  fetched: bool
+}
+impl Item {
+  #[inline(always)]
+  fn size(key: &[u8], value: &[u8]) -> usize {
+    key.len() + value.len() + ITEM_OVERHEAD
+  }
+}
+
+#[derive(Default)]
+struct Counters {
+  get_hits: u64,
+  get_misses: u64,
+  cmd_get: u64,
+  cmd_set: u64,
+  cmd_touch: u64,
+  cmd_flush: u64,
+  total_items: u64,
+  evictions: u64,
+  /// Items which expired without ever being fetched
+  expired_unfetched: u64
+}
+
+#[derive(Default)]
+struct Inner {
+  items: HashMap<Vec<u8>, Item>,
+  /// Keys by last use, oldest first
+  lru: BTreeMap<u64, Vec<u8>>,
+  tick: u64,
+  cas: u64,
+  bytes: usize,
+  /// A delayed flush, and when it takes effect
+  flush_at: Option<u64>,
+  stats: Counters
+}
+impl Inner {
+  /// Apply a delayed flush once it's time has come
+  fn apply_flush(&mut self, now: u64) {
+    let at = match self.flush_at {
+      Option::Some(at) if now >= at => at,
+      _ => return
+    };
+    self.flush_at = None;
+    let dead: Vec<Vec<u8>> = self.items.iter()
+      .filter(|&(_, item)| item.stored <= at)
+      .map(|(k, _)| k.clone())
+      .collect();
+    for k in dead {
+      self.remove(&k);
+    }
+  }
+  /// Is the key stored (and still valid)? Expired items are
+  /// removed.
+  fn live(&mut self, key: &[u8], now: u64) -> bool {
+    self.apply_flush(now);
+    let expired = match self.items.get(key) {
+      Option::Some(item) => item.expires != 0 && now >= item.expires,
+      Option::None => return false
+    };
+    if expired {
+      let fetched = self.remove(key).map(|item| item.fetched).unwrap_or(false);
+      self.stats.expired_unfetched += if fetched { 0 } else { 1 };
+    }
+    !expired
+  }
+  /// Mark a key as most recently used
+  fn bump(&mut self, key: &[u8]) {
+    self.tick += 1;
+    let tick = self.tick;
+    if let Some(item) = self.items.get_mut(key) {
+      self.lru.remove(&item.tick);
+      item.tick = tick;
+      self.lru.insert(tick, key.to_vec());
+    }
+  }
+  fn remove(&mut self, key: &[u8]) -> Option<Item> {
+    let item = self.items.remove(key)?;
+    self.lru.remove(&item.tick);
+    self.bytes -= Item::size(key, &item.value);
+    Some(item)
+  }
+  #[inline]
+  fn next_cas(&mut self) -> u64 {
+    self.cas += 1;
+    self.cas
+  }
+  /// Store an item, evicting the least recently used items
+  /// until it fits within `budget`. Returns it's CAS.
+  fn insert(&mut self, key: &[u8], mut item: Item, budget: usize) -> HandlerResult<u64> {
+    let size = Item::size(key, &item.value);
+    if size > budget {
+      return Err(StatusField::ValueTooLarge);
+    }
+    self.remove(key);
+    while self.bytes + size > budget {
+      let oldest = match self.lru.iter().next() {
+        Option::Some((_, k)) => k.clone(),
+        Option::None => break
+      };
+      self.remove(&oldest);
+      self.stats.evictions += 1;
+    }
+    item.cas = self.next_cas();
+    let cas = item.cas;
+    self.bytes += size;
+    self.items.insert(key.to_vec(), item);
+    self.bump(key);
+    self.stats.total_items += 1;
+    Ok(cas)
+  }
+  /// A non zero CAS in a request must match the stored item
+  fn check_cas(&self, key: &[u8], cas: u64) -> HandlerResult<()> {
+    match self.items.get(key) {
+      _ if cas == 0 => Ok(()),
+      Option::Some(item) if item.cas == cas => Ok(()),
+      Option::Some(_) => Err(StatusField::KeyExists),
+      Option::None => Err(StatusField::KeyNotFound)
+    }
+  }
+}
+
+/// In-Memory Cache
+///
+/// Thread safe, share it between connections with an `Arc`.
+pub struct MemoryStore {
+  inner: Mutex<Inner>,
+  budget: usize,
+  clock: Clock,
+  started: u64
+}
+impl MemoryStore {
+  /// Create a store holding at most `budget` bytes
+  pub fn new(budget: usize) -> MemoryStore {
+    MemoryStore::with_clock(budget, Box::new(system_time))
+  }
+  /// Create a store which reads the time from `clock`. This
+  /// allows expiration to be tested without waiting.
+  pub fn with_clock(budget: usize, clock: Clock) -> MemoryStore {
+    let started = clock();
+    MemoryStore {
+      inner: Mutex::new(Inner::default()),
+      budget,
+      clock,
+      started
+    }
+  }
+  /// Bytes charged for the items currently stored
+  pub fn bytes(&self) -> usize {
+    self.inner.lock().unwrap().bytes
+  }
+  /// Number of items stored, including expired items which have
+  /// not been cleaned up yet
+  pub fn len(&self) -> usize {
+    self.inner.lock().unwrap().items.len()
+  }
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+  #[inline(always)]
+  fn now(&self) -> u64 {
+    (self.clock)()
+  }
+  /// Convert a protocol expiration to a unix time, `0` is never
+  #[inline]
+  fn deadline(&self, expiration: u32, now: u64) -> u64 {
+    match expiration {
+      0 => 0,
+      x if x <= RELATIVE_EXPIRATION_LIMIT => now + x as u64,
+      x => x as u64
+    }
+  }
+}
+impl Handler for MemoryStore {
+  fn get(&self, key: &[u8]) -> HandlerResult<Value> {
+    let now = self.now();
+    let mut inner = self.inner.lock().unwrap();
+    inner.stats.cmd_get += 1;
+    if !inner.live(key, now) {
+      inner.stats.get_misses += 1;
+      return Err(StatusField::KeyNotFound);
+    }
+    inner.stats.get_hits += 1;
+    inner.bump(key);
+    let item = inner.items.get_mut(key).unwrap();
+    item.fetched = true;
+    Ok(Value {
+      value: item.value.clone(),
+      flags: item.flags,
+      cas: item.cas,
+      datatype: item.datatype
+    })
+  }
+  fn set(&self, req: StoreRequest) -> HandlerResult<u64> {
+    let now = self.now();
+    let mut inner = self.inner.lock().unwrap();
+    inner.stats.cmd_set += 1;
+    let exists = inner.live(req.key, now);
+    match req.mode {
+      StoreMode::Add if exists => return Err(StatusField::ItemNotStored),
+      StoreMode::Replace |
+      StoreMode::Append |
+      StoreMode::Prepend if !exists => return Err(StatusField::ItemNotStored),
+      _ => { }
+    };
+    inner.check_cas(req.key, req.cas)?;
+    let item = match req.mode {
+      StoreMode::Append |
+      StoreMode::Prepend => {
+        let old = &inner.items[req.key];
+        let mut value = Vec::with_capacity(old.value.len() + req.value.len());
+        if req.mode == StoreMode::Append {
+          value.extend_from_slice(&old.value);
+          value.extend_from_slice(req.value);
+        } else {
+          value.extend_from_slice(req.value);
+          value.extend_from_slice(&old.value);
+        }
+        Item {
+          value,
+          flags: old.flags,
+          cas: 0,
+          datatype: old.datatype,
+          expires: old.expires,
+          stored: now,
+          tick: 0,
+          fetched: false
+        }
+      },
+      _ => Item {
+        value: req.value.to_vec(),
+        flags: req.flags,
+        cas: 0,
+        datatype: req.datatype,
+        expires: self.deadline(req.expiration, now),
+        stored: now,
+        tick: 0,
+        fetched: false
+      }
+    };
+    inner.insert(req.key, item, self.budget)
+  }
+  fn delete(&self, key: &[u8], cas: u64) -> HandlerResult<()> {
+    let now = self.now();
+    let mut inner = self.inner.lock().unwrap();
+    if !inner.live(key, now) {
+      return Err(StatusField::KeyNotFound);
+    }
+    inner.check_cas(key, cas)?;
+    inner.remove(key);
+    Ok(())
+  }
+  fn arith(&self, req: ArithRequest) -> HandlerResult<(u64, u64)> {
+    let now = self.now();
+    let mut inner = self.inner.lock().unwrap();
+    if !inner.live(req.key, now) {
+      if req.expiration == 0xFFFF_FFFF {
+        return Err(StatusField::KeyNotFound);
+      }
+      let item = Item {
+        value: req.initial.to_string().into_bytes(),
+        flags: 0,
+        cas: 0,
+        datatype: DataType::RAW,
+        expires: self.deadline(req.expiration, now),
+        stored: now,
+        tick: 0,
+        fetched: false
+      };
+      let cas = inner.insert(req.key, item, self.budget)?;
+      return Ok((req.initial, cas));
+    }
+    inner.check_cas(req.key, req.cas)?;
+    let current = {
+      let item = &inner.items[req.key];
+      match ::std::str::from_utf8(&item.value).ok().and_then(|s| s.parse::<u64>().ok()) {
+        Option::Some(v) => v,
+        Option::None => return Err(StatusField::IncrDecrNonNumeric)
+      }
+    };
+    let next = match req.mode {
+      ArithMode::Increment => current.wrapping_add(req.delta),
+      ArithMode::Decrement => current.saturating_sub(req.delta)
+    };
+    //store through `insert` so a longer value still respects
+    //the budget
+    let item = {
+      let old = &inner.items[req.key];
+      Item {
+        value: next.to_string().into_bytes(),
+        flags: old.flags,
+        cas: 0,
+        datatype: old.datatype,
+        expires: old.expires,
+        stored: old.stored,
+        tick: 0,
+        fetched: old.fetched
+      }
+    };
+    let cas = inner.insert(req.key, item, self.budget)?;
+    Ok((next, cas))
+  }
+  fn touch(&self, key: &[u8], expiration: u32) -> HandlerResult<()> {
+    let now = self.now();
+    let expires = self.deadline(expiration, now);
+    let mut inner = self.inner.lock().unwrap();
+    inner.stats.cmd_touch += 1;
+    if !inner.live(key, now) {
+      return Err(StatusField::KeyNotFound);
+    }
+    let item = inner.items.get_mut(key).unwrap();
+    item.expires = expires;
+    item.fetched = true;
+    inner.bump(key);
+    Ok(())
+  }
+  fn flush(&self, delay: Option<u32>) -> HandlerResult<()> {
+    let now = self.now();
+    let mut inner = self.inner.lock().unwrap();
+    inner.stats.cmd_flush += 1;
+    match delay {
+      Option::Some(d) if d > 0 => inner.flush_at = Some(self.deadline(d, now)),
+      _ => {
+        inner.flush_at = None;
+        inner.items.clear();
+        inner.lru.clear();
+        inner.bytes = 0;
+      }
+    };
+    Ok(())
+  }
+  fn stat(&self, group: Option<&[u8]>) -> HandlerResult<Vec<(String, String)>> {
+    let now = self.now();
+    let inner = self.inner.lock().unwrap();
+    let s = &inner.stats;
+    //there are no slab allocators, every item is reported in
+    //slab class 1
+    let stats: Vec<(&str, String)> = match group {
+      Option::None => vec![
+        ("pid", ::std::process::id().to_string()),
+        ("uptime", now.saturating_sub(self.started).to_string()),
+        ("time", now.to_string()),
+        ("version", self.version()),
+        ("curr_items", inner.items.len().to_string()),
+        ("total_items", s.total_items.to_string()),
+        ("bytes", inner.bytes.to_string()),
+        ("limit_maxbytes", self.budget.to_string()),
+        ("cmd_get", s.cmd_get.to_string()),
+        ("cmd_set", s.cmd_set.to_string()),
+        ("cmd_touch", s.cmd_touch.to_string()),
+        ("cmd_flush", s.cmd_flush.to_string()),
+        ("get_hits", s.get_hits.to_string()),
+        ("get_misses", s.get_misses.to_string()),
+        ("evictions", s.evictions.to_string()),
+        ("expired_unfetched", s.expired_unfetched.to_string())
+      ],
+      Option::Some(b"settings") => vec![
+        ("maxbytes", self.budget.to_string()),
+        ("evictions", "on".to_string()),
+        ("cas_enabled", "yes".to_string()),
+        ("lru_crawler", "no".to_string())
+      ],
+      Option::Some(b"items") => vec![
+        ("items:1:number", inner.items.len().to_string()),
+        ("items:1:evicted", s.evictions.to_string()),
+        ("items:1:expired_unfetched", s.expired_unfetched.to_string())
+      ],
+      Option::Some(b"slabs") => vec![
+        ("1:used_chunks", inner.items.len().to_string()),
+        ("1:mem_requested", inner.bytes.to_string()),
+        ("active_slabs", "1".to_string()),
+        ("total_malloced", inner.bytes.to_string())
+      ],
+      Option::Some(_) => return Err(StatusField::KeyNotFound)
+    };
+    Ok(stats.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+  }
+}
+
+#[test]
+fn test_memory_store() {
+  use std::sync::Arc;
+  use std::sync::atomic::{
+    AtomicU64,
+    Ordering
+  };
+
+  let time = Arc::new(AtomicU64::new(1_000_000_000));
+  let t = time.clone();
+  let m = MemoryStore::with_clock(1024, Box::new(move || t.load(Ordering::SeqCst)));
+  let store = |mode, key, value, expiration, cas| StoreRequest {
+    mode,
+    key,
+    value,
+    flags: 1,
+    expiration,
+    cas,
+    datatype: DataType::RAW
+  };
+  let arith = |mode, key, expiration| ArithRequest {
+    mode,
+    key,
+    delta: 5,
+    initial: 10,
+    expiration,
+    cas: 0
+  };
+
+  //cas
+  let cas = m.set(store(StoreMode::Set, b"a", b"1", 0, 0)).unwrap();
+  assert_eq!(m.set(store(StoreMode::Set, b"a", b"2", 0, cas + 1)), Err(StatusField::KeyExists));
+  let cas2 = m.set(store(StoreMode::Set, b"a", b"2", 0, cas)).unwrap();
+  assert!(cas2 > cas);
+  assert_eq!(m.set(store(StoreMode::Set, b"nope", b"2", 0, 7)), Err(StatusField::KeyNotFound));
+  assert_eq!(m.delete(b"a", cas), Err(StatusField::KeyExists));
+
+  //preconditions
+  assert_eq!(m.set(store(StoreMode::Add, b"a", b"x", 0, 0)), Err(StatusField::ItemNotStored));
+  assert_eq!(m.set(store(StoreMode::Replace, b"b", b"x", 0, 0)), Err(StatusField::ItemNotStored));
+  assert_eq!(m.set(store(StoreMode::Append, b"b", b"x", 0, 0)), Err(StatusField::ItemNotStored));
+  m.set(store(StoreMode::Append, b"a", b"3", 0, 0)).unwrap();
+  m.set(store(StoreMode::Prepend, b"a", b"1", 0, 0)).unwrap();
+  assert_eq!(m.get(b"a").unwrap().value, b"123".to_vec());
+
+  //counters
+  assert_eq!(m.arith(arith(ArithMode::Increment, b"n", 0xFFFF_FFFF)), Err(StatusField::KeyNotFound));
+  assert_eq!(m.arith(arith(ArithMode::Increment, b"n", 0)).unwrap().0, 10);
+  assert_eq!(m.arith(arith(ArithMode::Increment, b"n", 0)).unwrap().0, 15);
+  assert_eq!(m.arith(arith(ArithMode::Decrement, b"n", 0)).unwrap().0, 10);
+  m.set(store(StoreMode::Set, b"n", b"3", 0, 0)).unwrap();
+  assert_eq!(m.arith(arith(ArithMode::Decrement, b"n", 0)).unwrap().0, 0);
+  let max = u64::MAX.to_string();
+  m.set(store(StoreMode::Set, b"n", max.as_bytes(), 0, 0)).unwrap();
+  assert_eq!(m.arith(arith(ArithMode::Increment, b"n", 0)).unwrap().0, 4);
+  m.set(store(StoreMode::Set, b"word", b"abc", 0, 0)).unwrap();
+  assert_eq!(m.arith(arith(ArithMode::Increment, b"word", 0)), Err(StatusField::IncrDecrNonNumeric));
+
+  //relative and absolute expiration
+  m.set(store(StoreMode::Set, b"rel", b"x", 10, 0)).unwrap();
+  m.set(store(StoreMode::Set, b"abs", b"x", 1_000_000_020, 0)).unwrap();
+  m.set(store(StoreMode::Set, b"seen", b"x", 10, 0)).unwrap();
+  assert!(m.get(b"seen").is_ok());
+  time.fetch_add(10, Ordering::SeqCst);
+  assert_eq!(m.get(b"rel").unwrap_err(), StatusField::KeyNotFound);
+  assert_eq!(m.get(b"seen").unwrap_err(), StatusField::KeyNotFound);
+  assert!(m.get(b"abs").is_ok());
+  m.touch(b"abs", 100).unwrap();
+  time.fetch_add(50, Ordering::SeqCst);
+  assert!(m.get(b"abs").is_ok());
+
+  //delayed flush
+  m.flush(Some(5)).unwrap();
+  assert!(m.get(b"a").is_ok());
+  time.fetch_add(5, Ordering::SeqCst);
+  assert_eq!(m.get(b"a").unwrap_err(), StatusField::KeyNotFound);
+  assert!(m.is_empty());
+  assert_eq!(m.bytes(), 0);
+
+  //lru eviction
+  let big = vec![0u8; 250];
+  for key in [b"k1", b"k2", b"k3"].iter() {
+    m.set(store(StoreMode::Set, &key[..], &big, 0, 0)).unwrap();
+  }
+  assert!(m.get(b"k1").is_ok());
+  m.set(store(StoreMode::Set, b"k4", &big, 0, 0)).unwrap();
+  assert!(m.get(b"k1").is_ok());
+  assert_eq!(m.get(b"k2").unwrap_err(), StatusField::KeyNotFound);
+  assert!(m.bytes() <= 1024);
+  assert_eq!(m.set(store(StoreMode::Set, b"huge", &[0u8; 2048], 0, 0)), Err(StatusField::ValueTooLarge));
+
+  //a counter growing a digit on a full store evicts
+  m.set(store(StoreMode::Set, b"c", b"5", 0, 0)).unwrap();
+  m.set(store(StoreMode::Set, b"pad", &[0u8; 23], 0, 0)).unwrap();
+  assert_eq!(m.bytes(), 1024);
+  assert_eq!(m.arith(arith(ArithMode::Increment, b"c", 0)).unwrap().0, 10);
+  assert!(m.bytes() <= 1024);
+  assert_eq!(m.get(b"k3").unwrap_err(), StatusField::KeyNotFound);
+  assert_eq!(m.get(b"c").unwrap().value, b"10".to_vec());
+
+  let stats = m.stat(None).unwrap();
+  assert!(stats.contains(&("evictions".to_string(), "2".to_string())));
+  //only `rel` expired without being read
+  assert!(stats.contains(&("expired_unfetched".to_string(), "1".to_string())));
+}
@@ -0,0 +1,943 @@
+//! Meta Protocol
+//!
+//! The meta commands are the text protocol memcached recommends
+//! today. Every command is a two letter name, a key, and a list
+//! of single letter flags, some carrying a token (`T30` sets a
+//! TTL of 30 seconds, `v` asks for the value):
+//!
+//!```text
+//! mg <key> <flags>*\r\n
+//! ms <key> <datalen> <flags>*\r\n<data>\r\n
+//! md <key> <flags>*\r\n
+//! ma <key> <flags>*\r\n
+//! mn\r\n
+//! me <key>\r\n
+//!```
+//!
+//! Replies start with a two letter code: `HD` (success), `VA`
+//! (success with a value), `EN` (get miss), `NF` (not found),
+//! `NS` (not stored), `EX` (CAS mismatch), `MN` (end of a
+//! pipeline) and `ME` (debug information).
+//!
+//! Keys which are not valid text keys travel base64 encoded
+//! with the `b` flag. `MetaCommand` always holds the decoded key,
+//! and adds `b` when it's key needs it.
+//!
+//! Where the semantics overlap, commands and replies convert
+//! to and from binary requests and responses. Flags with no
+//! binary counterpart (stale items, recache wins, vivify on a
+//! get, invalidation) make `MetaCommand::to_request` return
+//! `None`.
+
+use super::opcode::OpCode;
+use super::status::StatusField;
+use super::request::OwnedRequest;
+use super::response::{
+  OwnedResponse,
+  status_response
+};
+use super::extras::{
+  ReqExtras,
+  ResExtras
+};
+use super::builder::{
+  BuildError,
+  RequestBuilder,
+  check_key
+};
+use super::text::{
+  Parsed,
+  Reply,
+  TextError,
+  BAD_LINE,
+  read_line,
+  read_block,
+  data_len,
+  tokens,
+  number
+};
+use std::fmt::Display;
+use std::str::FromStr;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard base64, with padding
+pub fn base64_encode(x: &[u8]) -> Vec<u8> {
+  let mut out = Vec::with_capacity((x.len() + 2) / 3 * 4);
+  for chunk in x.chunks(3) {
+    let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+    let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+    for i in 0..4 {
+      if i <= chunk.len() {
+        out.push(BASE64[(n >> (18 - 6 * i) & 0x3F) as usize]);
+      } else {
+        out.push(b'=');
+      }
+    }
+  }
+  out
+}
+
+/// Decode standard base64. `None` if `x` is not valid base64.
+pub fn base64_decode(x: &[u8]) -> Option<Vec<u8>> {
+  if x.len() % 4 != 0 {
+    return None;
+  }
+  let mut out = Vec::with_capacity(x.len() / 4 * 3);
+  for (i, chunk) in x.chunks(4).enumerate() {
+    let last = i == x.len() / 4 - 1;
+    let pad = chunk.iter().rev().take_while(|b| **b == b'=').count();
+    if pad > 2 || (pad > 0 && !last) {
+      return None;
+    }
+    let mut n = 0u32;
+    for b in chunk[..4 - pad].iter() {
+      let v = BASE64.iter().position(|c| c == b)? as u32;
+      n = n << 6 | v;
+    }
+    n <<= 6 * pad as u32;
+    let bytes = [(n >> 16) as u8, (n >> 8) as u8, n as u8];
+    out.extend_from_slice(&bytes[..3 - pad]);
+  }
+  Some(out)
+}
+
+/// The flags of a meta command or reply, in the order given.
+/// Flags without a token have an empty one.
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct MetaFlags {
+  flags: Vec<(u8, Vec<u8>)>
+}
+impl MetaFlags {
+  /// The key is base64 encoded
+  pub const BASE64: u8 = b'b';
+  /// Return the item's CAS
+  pub const RETURN_CAS: u8 = b'c';
+  /// Compare CAS, the token is the expected value
+  pub const COMPARE_CAS: u8 = b'C';
+  /// Delta of `ma` (default `1`)
+  pub const DELTA: u8 = b'D';
+  /// Use the token as the item's new CAS
+  pub const NEW_CAS: u8 = b'E';
+  /// Client flags stored by `ms`
+  pub const CLIENT_FLAGS: u8 = b'F';
+  /// Return the client flags
+  pub const RETURN_FLAGS: u8 = b'f';
+  /// Return whether the item was fetched before
+  pub const HIT: u8 = b'h';
+  /// Invalidate, marking the item stale instead of removing it
+  pub const INVALIDATE: u8 = b'I';
+  /// Initial value of a counter created by `ma`
+  pub const INITIAL: u8 = b'J';
+  /// Return the key
+  pub const RETURN_KEY: u8 = b'k';
+  /// Return seconds since the item was last accessed
+  pub const LAST_ACCESS: u8 = b'l';
+  /// Mode switch of `ms` and `ma`
+  pub const MODE: u8 = b'M';
+  /// Create a missing item with the token as it's TTL
+  pub const VIVIFY: u8 = b'N';
+  /// Opaque token echoed in the reply
+  pub const OPAQUE: u8 = b'O';
+  /// Suppress replies which report success (or a get miss)
+  pub const QUIET: u8 = b'q';
+  /// Win the recache if the TTL is below the token
+  pub const RECACHE: u8 = b'R';
+  /// Return the size of the value
+  pub const RETURN_SIZE: u8 = b's';
+  /// Set the TTL
+  pub const TTL: u8 = b'T';
+  /// Return the remaining TTL
+  pub const RETURN_TTL: u8 = b't';
+  /// Do not bump the item in the LRU
+  pub const NO_BUMP: u8 = b'u';
+  /// Return the value
+  pub const RETURN_VALUE: u8 = b'v';
+  /// Reply flag, this client should recache the item
+  pub const WIN: u8 = b'W';
+  /// Delete only the value of an item (`md`)
+  pub const REMOVE_VALUE: u8 = b'x';
+  /// Reply flag, the item is stale
+  pub const STALE: u8 = b'X';
+  /// Reply flag, another client already won the recache
+  pub const WON: u8 = b'Z';
+
+  #[inline]
+  pub fn new() -> MetaFlags {
+    MetaFlags::default()
+  }
+  /// Add a flag without a token
+  #[inline]
+  pub fn with(mut self, flag: u8) -> Self {
+    self.insert(flag, &[]);
+    self
+  }
+  /// Add a flag with a token
+  #[inline]
+  pub fn with_token(mut self, flag: u8, token: &[u8]) -> Self {
+    self.insert(flag, token);
+    self
+  }
+  /// Add a flag with a number as it's token
+  #[inline]
+  pub fn with_number<T: Display>(self, flag: u8, n: T) -> Self {
+    self.with_token(flag, n.to_string().as_bytes())
+  }
+  /// Set a flag, replacing any token it had
+  pub fn insert(&mut self, flag: u8, token: &[u8]) {
+    match self.flags.iter_mut().find(|f| f.0 == flag) {
+      Option::Some(f) => f.1 = token.to_vec(),
+      Option::None => self.flags.push((flag, token.to_vec()))
+    };
+  }
+  #[inline]
+  pub fn remove(&mut self, flag: u8) {
+    self.flags.retain(|f| f.0 != flag);
+  }
+  #[inline]
+  pub fn has(&self, flag: u8) -> bool {
+    self.flags.iter().any(|f| f.0 == flag)
+  }
+  #[inline]
+  pub fn token(&self, flag: u8) -> Option<&[u8]> {
+    self.flags.iter().find(|f| f.0 == flag).map(|f| f.1.as_slice())
+  }
+  /// A flag's token as a number. `None` if it is missing or
+  /// not a number.
+  #[inline]
+  pub fn number<T: FromStr>(&self, flag: u8) -> Option<T> {
+    self.token(flag).and_then(|t| number(t).ok())
+  }
+  #[inline(always)]
+  pub fn len(&self) -> usize {
+    self.flags.len()
+  }
+  #[inline(always)]
+  pub fn is_empty(&self) -> bool {
+    self.flags.is_empty()
+  }
+  #[inline]
+  pub fn iter(&self) -> impl Iterator<Item = (u8, &[u8])> {
+    self.flags.iter().map(|f| (f.0, f.1.as_slice()))
+  }
+  #[inline(always)]
+  pub fn is_quiet(&self) -> bool {
+    self.has(MetaFlags::QUIET)
+  }
+  #[inline]
+  pub fn get_opaque(&self) -> Option<&[u8]> {
+    self.token(MetaFlags::OPAQUE)
+  }
+  #[inline]
+  pub fn get_cas(&self) -> Option<u64> {
+    self.number(MetaFlags::RETURN_CAS)
+  }
+  #[inline]
+  pub fn get_ttl(&self) -> Option<u32> {
+    self.number(MetaFlags::TTL)
+  }
+  /// This client won the right to recache the item
+  #[inline(always)]
+  pub fn is_win(&self) -> bool {
+    self.has(MetaFlags::WIN)
+  }
+  #[inline(always)]
+  pub fn is_stale(&self) -> bool {
+    self.has(MetaFlags::STALE)
+  }
+  fn parse(t: &[&[u8]]) -> Result<MetaFlags, TextError> {
+    let mut flags = MetaFlags::new();
+    for tok in t.iter() {
+      if !tok[0].is_ascii_alphabetic() {
+        return Err(TextError::BadFormat("invalid flag"));
+      }
+      flags.flags.push((tok[0], tok[1..].to_vec()));
+    }
+    Ok(flags)
+  }
+  fn encode(&self, out: &mut Vec<u8>) {
+    for f in self.flags.iter() {
+      out.push(b' ');
+      out.push(f.0);
+      out.extend_from_slice(&f.1);
+    }
+  }
+  /// Only these flags are set
+  fn only(&self, allowed: &[u8]) -> bool {
+    self.flags.iter().all(|f| allowed.contains(&f.0))
+  }
+}
+
+static NO_FLAGS: MetaFlags = MetaFlags{ flags: Vec::new() };
+
+/// A meta command
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum MetaCommand {
+  /// `mg`
+  Get {
+    key: Vec<u8>,
+    flags: MetaFlags
+  },
+  /// `ms`
+  Set {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    flags: MetaFlags
+  },
+  /// `md`
+  Delete {
+    key: Vec<u8>,
+    flags: MetaFlags
+  },
+  /// `ma`
+  Arithmetic {
+    key: Vec<u8>,
+    flags: MetaFlags
+  },
+  /// `mn`
+  Noop,
+  /// `me`
+  Debug {
+    key: Vec<u8>,
+    flags: MetaFlags
+  }
+}
+impl MetaCommand {
+  /// Parse a command from the front of `buf`
+  pub fn parse(buf: &[u8]) -> Parsed<MetaCommand> {
+    let (line, used) = match read_line(buf) {
+      Parsed::Done(line, used) => (line, used),
+      Parsed::Incomplete => return Parsed::Incomplete,
+      Parsed::Invalid(e, n) => return Parsed::Invalid(e, n)
+    };
+    let (mut cmd, data) = match MetaCommand::parse_line(&tokens(line)) {
+      Ok(x) => x,
+      Err(e) => return Parsed::Invalid(e, used)
+    };
+    let len = match data {
+      Option::Some(len) => len,
+      Option::None => return Parsed::Done(cmd, used)
+    };
+    match read_block(&buf[used..], len) {
+      Parsed::Done(block, n) => {
+        if let MetaCommand::Set{ ref mut value, .. } = cmd {
+          *value = block.to_vec();
+        }
+        Parsed::Done(cmd, used + n)
+      },
+      Parsed::Incomplete => Parsed::Incomplete,
+      Parsed::Invalid(e, n) => Parsed::Invalid(e, used + n)
+    }
+  }
+  fn parse_line(t: &[&[u8]]) -> Result<(MetaCommand, Option<usize>), TextError> {
+    let (name, args) = match t.split_first() {
+      Option::Some((name, args)) => (*name, args),
+      Option::None => return Err(TextError::UnknownCommand)
+    };
+    if name == b"mn" {
+      return Ok((MetaCommand::Noop, None));
+    }
+    let (key, args) = match args.split_first() {
+      Option::Some((key, args)) => (*key, args),
+      Option::None if name.len() == 2 && name[0] == b'm' => return Err(BAD_LINE),
+      Option::None => return Err(TextError::UnknownCommand)
+    };
+    //the data length of `ms` comes before the flags
+    let (len, args) = match (name, args.split_first()) {
+      (b"ms", Some((len, rest))) => (Some(data_len(len)?), rest),
+      (b"ms", None) => return Err(BAD_LINE),
+      _ => (None, args)
+    };
+    let flags = MetaFlags::parse(args)?;
+    let key = if flags.has(MetaFlags::BASE64) {
+      base64_decode(key).ok_or(TextError::BadFormat("bad base64 key"))?
+    } else {
+      check_key(key).map_err(|_| TextError::BadFormat("bad key"))?;
+      key.to_vec()
+    };
+    let cmd = match name {
+      b"mg" => MetaCommand::Get{ key, flags },
+      b"ms" => MetaCommand::Set{ key, value: Vec::new(), flags },
+      b"md" => MetaCommand::Delete{ key, flags },
+      b"ma" => MetaCommand::Arithmetic{ key, flags },
+      b"me" => MetaCommand::Debug{ key, flags },
+      _ => return Err(TextError::UnknownCommand)
+    };
+    Ok((cmd, len))
+  }
+  /// Two letter name on the wire
+  pub fn name(&self) -> &'static str {
+    match *self {
+      MetaCommand::Get{ .. } => "mg",
+      MetaCommand::Set{ .. } => "ms",
+      MetaCommand::Delete{ .. } => "md",
+      MetaCommand::Arithmetic{ .. } => "ma",
+      MetaCommand::Noop => "mn",
+      MetaCommand::Debug{ .. } => "me"
+    }
+  }
+  #[inline]
+  pub fn get_key(&self) -> Option<&[u8]> {
+    match *self {
+      MetaCommand::Get{ ref key, .. } |
+      MetaCommand::Set{ ref key, .. } |
+      MetaCommand::Delete{ ref key, .. } |
+      MetaCommand::Arithmetic{ ref key, .. } |
+      MetaCommand::Debug{ ref key, .. } => Some(key),
+      MetaCommand::Noop => None
+    }
+  }
+  #[inline]
+  pub fn get_flags(&self) -> &MetaFlags {
+    match *self {
+      MetaCommand::Get{ ref flags, .. } |
+      MetaCommand::Set{ ref flags, .. } |
+      MetaCommand::Delete{ ref flags, .. } |
+      MetaCommand::Arithmetic{ ref flags, .. } |
+      MetaCommand::Debug{ ref flags, .. } => flags,
+      MetaCommand::Noop => &NO_FLAGS
+    }
+  }
+  /// Encode the command as it is sent on the wire. Keys which
+  /// are not valid text keys are sent base64 encoded.
+  pub fn encode(&self) -> Vec<u8> {
+    let mut out = self.name().as_bytes().to_vec();
+    if let Some(key) = self.get_key() {
+      let mut flags = self.get_flags().clone();
+      if check_key(key).is_err() {
+        flags.insert(MetaFlags::BASE64, &[]);
+      }
+      out.push(b' ');
+      if flags.has(MetaFlags::BASE64) {
+        out.extend_from_slice(&base64_encode(key));
+      } else {
+        out.extend_from_slice(key);
+      }
+      if let MetaCommand::Set{ ref value, .. } = *self {
+        out.extend_from_slice(format!(" {}", value.len()).as_bytes());
+      }
+      flags.encode(&mut out);
+    }
+    out.extend_from_slice(b"\r\n");
+    if let MetaCommand::Set{ ref value, .. } = *self {
+      out.extend_from_slice(value);
+      out.extend_from_slice(b"\r\n");
+    }
+    out
+  }
+  /// `reply` is not sent because the command is quiet
+  pub fn suppresses(&self, reply: &MetaReply) -> bool {
+    if !self.get_flags().is_quiet() {
+      return false;
+    }
+    matches!((self, reply),
+      (_, MetaReply::Header(_)) |
+      (MetaCommand::Get{ .. }, MetaReply::Miss) |
+      (MetaCommand::Delete{ .. }, MetaReply::NotFound(_)))
+  }
+  /// The binary request with the same meaning, `None` if the
+  /// command has no binary counterpart.
+  ///
+  /// A get with a TTL is a `GAT` (or a `Touch` without `v`).
+  /// Quiet commands use quiet opcodes where there is one.
+  pub fn to_request(&self, opaque: u32) -> Result<Option<OwnedRequest>, BuildError> {
+    let f = self.get_flags();
+    let b = match *self {
+      MetaCommand::Get{ ref key, ref flags } => {
+        if !flags.only(b"bcfkOqsvTu") {
+          return Ok(None);
+        }
+        let b = match (flags.get_ttl(), flags.has(MetaFlags::RETURN_VALUE)) {
+          (Option::Some(ttl), false) => return RequestBuilder::touch(key, ttl).opaque(opaque).build().map(Some),
+          (Option::Some(ttl), true) => RequestBuilder::gat(key, ttl),
+          (Option::None, _) => RequestBuilder::getk(key)
+        };
+        if flags.is_quiet() { b.quiet() } else { b }
+      },
+      MetaCommand::Set{ ref key, ref value, ref flags } => {
+        if !flags.only(b"bcCFkOqTM") {
+          return Ok(None);
+        }
+        let b = match flags.token(MetaFlags::MODE) {
+          Option::None | Option::Some(b"S") | Option::Some(b"s") => RequestBuilder::set(key, value),
+          Option::Some(b"E") | Option::Some(b"e") => RequestBuilder::add(key, value),
+          Option::Some(b"R") | Option::Some(b"r") => RequestBuilder::replace(key, value),
+          Option::Some(b"A") | Option::Some(b"a") => RequestBuilder::append(key, value),
+          Option::Some(b"P") | Option::Some(b"p") => RequestBuilder::prepend(key, value),
+          Option::Some(_) => return Ok(None)
+        };
+        //append and prepend carry no flags or TTL
+        let b = match b.get_opcode() {
+          OpCode::Append | OpCode::Prepare => b,
+          _ => b.flags(flags.number(MetaFlags::CLIENT_FLAGS).unwrap_or(0)).expire(flags.get_ttl().unwrap_or(0))
+        };
+        b.cas(flags.number(MetaFlags::COMPARE_CAS).unwrap_or(0))
+      },
+      MetaCommand::Delete{ ref key, ref flags } => {
+        if !flags.only(b"bCkOq") {
+          return Ok(None);
+        }
+        RequestBuilder::delete(key).cas(flags.number(MetaFlags::COMPARE_CAS).unwrap_or(0))
+      },
+      MetaCommand::Arithmetic{ ref key, ref flags } => {
+        if !flags.only(b"bcCDJkMNOqv") {
+          return Ok(None);
+        }
+        let delta = flags.number(MetaFlags::DELTA).unwrap_or(1);
+        let b = match flags.token(MetaFlags::MODE) {
+          Option::None | Option::Some(b"I") | Option::Some(b"i") | Option::Some(b"+") => RequestBuilder::incr(key, delta),
+          Option::Some(b"D") | Option::Some(b"d") | Option::Some(b"-") => RequestBuilder::decr(key, delta),
+          Option::Some(_) => return Ok(None)
+        };
+        //without `N` a missing counter is not created
+        b.initial(flags.number(MetaFlags::INITIAL).unwrap_or(0))
+          .expire(flags.number(MetaFlags::VIVIFY).unwrap_or(0xFFFFFFFF))
+          .cas(flags.number(MetaFlags::COMPARE_CAS).unwrap_or(0))
+      },
+      MetaCommand::Noop => RequestBuilder::noop(),
+      MetaCommand::Debug{ .. } => return Ok(None)
+    };
+    let b = match *self {
+      MetaCommand::Set{ .. } |
+      MetaCommand::Delete{ .. } |
+      MetaCommand::Arithmetic{ .. } if f.is_quiet() => b.quiet(),
+      _ => b
+    };
+    b.opaque(opaque).build().map(Some)
+  }
+  /// The meta command with the same meaning as a binary
+  /// request, `None` if there is none. The request's `opaque`
+  /// is sent as the `O` flag, and the flags needed to rebuild a
+  /// binary response (`c`, `f`, `v`, `k`) are requested.
+  pub fn from_request(req: &OwnedRequest) -> Option<MetaCommand> {
+    let code = req.get_opcode();
+    let loud = code.loud_form();
+    let mut flags = MetaFlags::new().with_number(MetaFlags::OPAQUE, req.get_opaque());
+    if code.is_quiet() {
+      flags = flags.with(MetaFlags::QUIET);
+    }
+    if req.get_cas() != 0 {
+      flags = flags.with_number(MetaFlags::COMPARE_CAS, req.get_cas());
+    }
+    let key = req.key.clone();
+    let cmd = match (loud, req.get_typed_extra().ok()?) {
+      (OpCode::Get, _) |
+      (OpCode::GetK, _) => {
+        flags = flags.with(MetaFlags::RETURN_VALUE).with(MetaFlags::RETURN_FLAGS).with(MetaFlags::RETURN_CAS);
+        if loud == OpCode::GetK {
+          flags = flags.with(MetaFlags::RETURN_KEY);
+        }
+        MetaCommand::Get{ key, flags }
+      },
+      (OpCode::GAT, ReqExtras::Touch(e)) => {
+        flags = flags.with(MetaFlags::RETURN_VALUE).with(MetaFlags::RETURN_FLAGS).with(MetaFlags::RETURN_CAS);
+        MetaCommand::Get{ key, flags: flags.with_number(MetaFlags::TTL, e.expiration) }
+      },
+      (OpCode::Touch, ReqExtras::Touch(e)) => MetaCommand::Get{ key, flags: flags.with_number(MetaFlags::TTL, e.expiration) },
+      (OpCode::Set, extras) |
+      (OpCode::Add, extras) |
+      (OpCode::Replace, extras) |
+      (OpCode::Append, extras) |
+      (OpCode::Prepare, extras) => {
+        let mode: &[u8] = match loud {
+          OpCode::Add => b"E",
+          OpCode::Replace => b"R",
+          OpCode::Append => b"A",
+          OpCode::Prepare => b"P",
+          _ => b"S"
+        };
+        flags = flags.with(MetaFlags::RETURN_CAS).with_token(MetaFlags::MODE, mode);
+        if let ReqExtras::Store(e) = extras {
+          flags = flags.with_number(MetaFlags::CLIENT_FLAGS, e.flags).with_number(MetaFlags::TTL, e.expiration);
+        }
+        MetaCommand::Set{ key, value: req.body.clone(), flags }
+      },
+      (OpCode::Delete, _) => MetaCommand::Delete{ key, flags },
+      (OpCode::Increment, ReqExtras::Arith(e)) |
+      (OpCode::Decrement, ReqExtras::Arith(e)) => {
+        flags = flags.with(MetaFlags::RETURN_VALUE)
+          .with(MetaFlags::RETURN_CAS)
+          .with_token(MetaFlags::MODE, if loud == OpCode::Increment { b"I" } else { b"D" })
+          .with_number(MetaFlags::DELTA, e.delta);
+        if e.expiration != 0xFFFFFFFF {
+          flags = flags.with_number(MetaFlags::INITIAL, e.initial).with_number(MetaFlags::VIVIFY, e.expiration);
+        }
+        MetaCommand::Arithmetic{ key, flags }
+      },
+      (OpCode::Nop, _) => MetaCommand::Noop,
+      _ => return None
+    };
+    Some(cmd)
+  }
+}
+
+/// A meta reply
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum MetaReply {
+  /// `HD`, success without a value
+  Header(MetaFlags),
+  /// `VA`, success with a value
+  Value(Vec<u8>, MetaFlags),
+  /// `EN`, a get missed
+  Miss,
+  /// `NF`
+  NotFound(MetaFlags),
+  /// `NS`
+  NotStored(MetaFlags),
+  /// `EX`, the CAS did not match
+  Exists(MetaFlags),
+  /// `MN`
+  Noop,
+  /// `ME`, the key and `name=value` pairs
+  Debug(Vec<u8>, Vec<(String, String)>),
+  /// `ERROR`
+  Error,
+  ClientError(String),
+  ServerError(String)
+}
+impl MetaReply {
+  /// Parse a reply from the front of `buf`
+  pub fn parse(buf: &[u8]) -> Parsed<MetaReply> {
+    let (line, used) = match read_line(buf) {
+      Parsed::Done(line, used) => (line, used),
+      Parsed::Incomplete => return Parsed::Incomplete,
+      Parsed::Invalid(e, n) => return Parsed::Invalid(e, n)
+    };
+    let t = tokens(line);
+    let (code, args) = match t.split_first() {
+      Option::Some((code, args)) => (*code, args),
+      Option::None => return Parsed::Invalid(BAD_LINE, used)
+    };
+    let text = || {
+      let rest = line.splitn(2, |b| *b == b' ').nth(1).unwrap_or(b"");
+      String::from_utf8_lossy(rest).into_owned()
+    };
+    let flags = |args: &[&[u8]]| MetaFlags::parse(args);
+    let reply = match code {
+      b"HD" => flags(args).map(MetaReply::Header),
+      b"NF" => flags(args).map(MetaReply::NotFound),
+      b"NS" => flags(args).map(MetaReply::NotStored),
+      b"EX" => flags(args).map(MetaReply::Exists),
+      b"EN" => Ok(MetaReply::Miss),
+      b"MN" => Ok(MetaReply::Noop),
+      b"ERROR" => Ok(MetaReply::Error),
+      b"CLIENT_ERROR" => Ok(MetaReply::ClientError(text())),
+      b"SERVER_ERROR" => Ok(MetaReply::ServerError(text())),
+      b"ME" => match args.split_first() {
+        Option::Some((key, pairs)) => Ok(MetaReply::Debug(key.to_vec(), pairs.iter().map(|p| {
+          let mut kv = p.splitn(2, |b| *b == b'=');
+          let name = String::from_utf8_lossy(kv.next().unwrap_or(b"")).into_owned();
+          let value = String::from_utf8_lossy(kv.next().unwrap_or(b"")).into_owned();
+          (name, value)
+        }).collect())),
+        Option::None => Err(BAD_LINE)
+      },
+      b"VA" => {
+        let (len, f) = match args.split_first() {
+          Option::Some((len, rest)) => (data_len(len), flags(rest)),
+          Option::None => return Parsed::Invalid(BAD_LINE, used)
+        };
+        return match (len, f) {
+          (Ok(len), Ok(f)) => match read_block(&buf[used..], len) {
+            Parsed::Done(value, n) => Parsed::Done(MetaReply::Value(value.to_vec(), f), used + n),
+            Parsed::Incomplete => Parsed::Incomplete,
+            Parsed::Invalid(e, n) => Parsed::Invalid(e, used + n)
+          },
+          (Err(e), _) |
+          (_, Err(e)) => Parsed::Invalid(e, used)
+        };
+      },
+      _ => Err(TextError::BadFormat("unknown reply"))
+    };
+    match reply {
+      Ok(reply) => Parsed::Done(reply, used),
+      Err(e) => Parsed::Invalid(e, used)
+    }
+  }
+  /// Two letter code on the wire (or the error's name)
+  pub fn code(&self) -> &'static str {
+    match *self {
+      MetaReply::Header(_) => "HD",
+      MetaReply::Value(_, _) => "VA",
+      MetaReply::Miss => "EN",
+      MetaReply::NotFound(_) => "NF",
+      MetaReply::NotStored(_) => "NS",
+      MetaReply::Exists(_) => "EX",
+      MetaReply::Noop => "MN",
+      MetaReply::Debug(_, _) => "ME",
+      MetaReply::Error => "ERROR",
+      MetaReply::ClientError(_) => "CLIENT_ERROR",
+      MetaReply::ServerError(_) => "SERVER_ERROR"
+    }
+  }
+  /// Flags of the reply, empty for replies which carry none
+  pub fn get_flags(&self) -> &MetaFlags {
+    match *self {
+      MetaReply::Header(ref f) |
+      MetaReply::Value(_, ref f) |
+      MetaReply::NotFound(ref f) |
+      MetaReply::NotStored(ref f) |
+      MetaReply::Exists(ref f) => f,
+      _ => &NO_FLAGS
+    }
+  }
+  /// Encode the reply as it is sent on the wire
+  pub fn encode(&self) -> Vec<u8> {
+    let mut out = self.code().as_bytes().to_vec();
+    match *self {
+      MetaReply::Value(ref value, ref flags) => {
+        out.extend_from_slice(format!(" {}", value.len()).as_bytes());
+        flags.encode(&mut out);
+        out.extend_from_slice(b"\r\n");
+        out.extend_from_slice(value);
+      },
+      MetaReply::Debug(ref key, ref pairs) => {
+        out.push(b' ');
+        out.extend_from_slice(key);
+        for (name, value) in pairs.iter() {
+          out.extend_from_slice(format!(" {}={}", name, value).as_bytes());
+        }
+      },
+      MetaReply::ClientError(ref msg) |
+      MetaReply::ServerError(ref msg) => {
+        out.push(b' ');
+        out.extend_from_slice(msg.as_bytes());
+      },
+      ref x => x.get_flags().encode(&mut out)
+    };
+    out.extend_from_slice(b"\r\n");
+    out
+  }
+  /// The reply to `cmd` from the response to
+  /// `cmd.to_request(..)`. `resp` is `None` when a quiet
+  /// request got no response.
+  pub fn from_response(cmd: &MetaCommand, resp: Option<&OwnedResponse>) -> MetaReply {
+    let asked = cmd.get_flags();
+    let mut flags = MetaFlags::new();
+    if let Some(o) = asked.get_opaque() {
+      flags.insert(MetaFlags::OPAQUE, o);
+    }
+    if let (true, Some(key)) = (asked.has(MetaFlags::RETURN_KEY), cmd.get_key()) {
+      if asked.has(MetaFlags::BASE64) {
+        flags.insert(MetaFlags::RETURN_KEY, &base64_encode(key));
+        flags.insert(MetaFlags::BASE64, &[]);
+      } else {
+        flags.insert(MetaFlags::RETURN_KEY, key);
+      }
+    }
+    let resp = match resp {
+      Option::Some(resp) => resp,
+      Option::None => return match *cmd {
+        MetaCommand::Get{ .. } => MetaReply::Miss,
+        MetaCommand::Noop => MetaReply::Noop,
+        _ => MetaReply::Header(flags)
+      }
+    };
+    let status = resp.get_status();
+    if status != StatusField::NoError {
+      let with_c = asked.has(MetaFlags::COMPARE_CAS);
+      return match (cmd, status) {
+        (MetaCommand::Get{ .. }, StatusField::KeyNotFound) => MetaReply::Miss,
+        (MetaCommand::Set{ .. }, StatusField::KeyNotFound) if !with_c => MetaReply::NotStored(flags),
+        (_, StatusField::KeyNotFound) => MetaReply::NotFound(flags),
+        (MetaCommand::Set{ .. }, StatusField::KeyExists) if !with_c => MetaReply::NotStored(flags),
+        (_, StatusField::KeyExists) => MetaReply::Exists(flags),
+        (_, StatusField::ItemNotStored) => MetaReply::NotStored(flags),
+        (_, x) => match Reply::for_error(x) {
+          Reply::ClientError(msg) => MetaReply::ClientError(msg),
+          Reply::ServerError(msg) => MetaReply::ServerError(msg),
+          _ => MetaReply::Error
+        }
+      };
+    }
+    if asked.has(MetaFlags::RETURN_CAS) {
+      flags.insert(MetaFlags::RETURN_CAS, resp.get_cas().to_string().as_bytes());
+    }
+    match *cmd {
+      MetaCommand::Get{ .. } => {
+        if asked.has(MetaFlags::RETURN_FLAGS) {
+          let f = match resp.get_typed_extra() {
+            Ok(ResExtras::Get(e)) => e.flags,
+            _ => 0
+          };
+          flags.insert(MetaFlags::RETURN_FLAGS, f.to_string().as_bytes());
+        }
+        if asked.has(MetaFlags::RETURN_SIZE) {
+          flags.insert(MetaFlags::RETURN_SIZE, resp.body.len().to_string().as_bytes());
+        }
+        if asked.has(MetaFlags::RETURN_VALUE) {
+          MetaReply::Value(resp.body.clone(), flags)
+        } else {
+          MetaReply::Header(flags)
+        }
+      },
+      MetaCommand::Arithmetic{ .. } if asked.has(MetaFlags::RETURN_VALUE) && resp.body.len() == 8 => {
+        let mut n = [0u8; 8];
+        n.copy_from_slice(&resp.body);
+        MetaReply::Value(u64::from_be_bytes(n).to_string().into_bytes(), flags)
+      },
+      MetaCommand::Noop => MetaReply::Noop,
+      _ => MetaReply::Header(flags)
+    }
+  }
+  /// The binary response answering `req`, from the reply to
+  /// `MetaCommand::from_request(req)`. `None` when the binary
+  /// protocol sends nothing (a quiet success or get miss).
+  pub fn to_response(&self, req: &OwnedRequest) -> Option<OwnedResponse> {
+    let code = req.get_opcode();
+    let loud = code.loud_form();
+    let get_family = matches!(loud, OpCode::Get | OpCode::GetK | OpCode::GAT);
+    let status = match *self {
+      MetaReply::Miss |
+      MetaReply::NotFound(_) => StatusField::KeyNotFound,
+      MetaReply::Exists(_) => StatusField::KeyExists,
+      MetaReply::NotStored(_) => match loud {
+        OpCode::Add => StatusField::KeyExists,
+        OpCode::Replace => StatusField::KeyNotFound,
+        _ => StatusField::ItemNotStored
+      },
+      MetaReply::Error => StatusField::UnknownCommand,
+      MetaReply::ClientError(ref msg) => Reply::ClientError(msg.clone()).status(),
+      MetaReply::ServerError(ref msg) => Reply::ServerError(msg.clone()).status(),
+      MetaReply::Debug(_, _) => return None,
+      _ => StatusField::NoError
+    };
+    if status != StatusField::NoError {
+      if code.is_quiet() && get_family && status == StatusField::KeyNotFound {
+        return None;
+      }
+      return Some(status_response(req, status));
+    }
+    if code.is_quiet() && !get_family {
+      return None;
+    }
+    let flags = self.get_flags();
+    let value = match *self {
+      MetaReply::Value(ref v, _) => v.as_slice(),
+      _ => &[]
+    };
+    let cas = flags.get_cas().unwrap_or(0);
+    let resp = match loud {
+      OpCode::Get |
+      OpCode::GetK |
+      OpCode::GAT => {
+        let key = if loud == OpCode::GetK { req.key.clone() } else { Vec::new() };
+        let f: u32 = flags.number(MetaFlags::RETURN_FLAGS).unwrap_or(0);
+        OwnedResponse::new(code, status, req.get_opaque(), cas, f.to_be_bytes().to_vec(), key, value.to_vec())
+      },
+      OpCode::Increment |
+      OpCode::Decrement => {
+        let n: u64 = number(value).unwrap_or(0);
+        OwnedResponse::new(code, status, req.get_opaque(), cas, Vec::new(), Vec::new(), n.to_be_bytes().to_vec())
+      },
+      _ => OwnedResponse::new(code, status, req.get_opaque(), cas, Vec::new(), Vec::new(), Vec::new())
+    };
+    Some(resp)
+  }
+}
+
+#[test]
+fn test_base64() {
+  let cases: [(&[u8], &[u8]); 6] = [
+    (b"", b""),
+    (b"f", b"Zg=="),
+    (b"fo", b"Zm8="),
+    (b"foo", b"Zm9v"),
+    (b"foob", b"Zm9vYg=="),
+    (b"\x00\xFF key", b"AP8ga2V5")];
+  for &(plain, encoded) in cases.iter() {
+    assert_eq!(base64_encode(plain), encoded.to_vec());
+    assert_eq!(base64_decode(encoded), Some(plain.to_vec()));
+  }
+  assert_eq!(base64_decode(b"Zm9"), None);
+  assert_eq!(base64_decode(b"Z=9v"), None);
+  assert_eq!(base64_decode(b"Zm9v!A=="), None);
+}
+
+#[test]
+fn test_meta_commands() {
+  let buf = b"ms foo 2 T60 F5 c q\r\nhi\r\nmg Zm9v b v k Oab\r\nmn\r\n";
+  let (set, n) = match MetaCommand::parse(buf) {
+    Parsed::Done(c, n) => (c, n),
+    x => panic!("ms should parse, not {:?}", x)
+  };
+  assert_eq!(set.encode(), buf[..n].to_vec());
+  assert_eq!(set.get_flags().get_ttl(), Some(60));
+  let (get, m) = match MetaCommand::parse(&buf[n..]) {
+    Parsed::Done(c, m) => (c, m),
+    x => panic!("mg should parse, not {:?}", x)
+  };
+  assert_eq!(get.get_key(), Some(&b"foo"[..]));
+  assert_eq!(get.get_flags().get_opaque(), Some(&b"ab"[..]));
+  assert_eq!(MetaCommand::parse(&buf[n + m..]), Parsed::Done(MetaCommand::Noop, 4));
+  assert_eq!(MetaCommand::parse(&buf[..n - 1]), Parsed::Incomplete);
+  assert_eq!(MetaCommand::parse(b"mg\r\n"), Parsed::Invalid(BAD_LINE, 4));
+  assert_eq!(MetaCommand::parse(b"mg a 5\r\n"), Parsed::Invalid(TextError::BadFormat("invalid flag"), 8));
+  assert_eq!(MetaCommand::parse(b"ms k 4000000000\r\nab"), Parsed::Invalid(TextError::TooLarge, 17));
+  assert_eq!(MetaCommand::parse(b"ms k 18446744073709551615 T0\r\nab"), Parsed::Invalid(TextError::TooLarge, 30));
+
+  //binary keys travel base64 encoded
+  let odd = MetaCommand::Delete{ key: b"a b".to_vec(), flags: MetaFlags::new() };
+  assert_eq!(odd.encode(), b"md YSBi b\r\n".to_vec());
+
+  let req = set.to_request(9).unwrap().unwrap();
+  assert_eq!((req.get_opcode(), req.get_opaque()), (OpCode::SetQ, 9));
+  match req.get_typed_extra().unwrap() {
+    ReqExtras::Store(e) => assert_eq!((e.flags, e.expiration), (5, 60)),
+    x => panic!("ms should carry store extras, not {:?}", x)
+  };
+  let gat = MetaCommand::Get{ key: b"k".to_vec(), flags: MetaFlags::new().with(MetaFlags::RETURN_VALUE).with_number(MetaFlags::TTL, 30) };
+  assert_eq!(gat.to_request(0).unwrap().unwrap().get_opcode(), OpCode::GAT);
+  let stale = MetaCommand::Delete{ key: b"k".to_vec(), flags: MetaFlags::new().with(MetaFlags::INVALIDATE) };
+  assert!(stale.to_request(0).unwrap().is_none());
+
+  let add = RequestBuilder::add(b"k", b"v").flags(3).opaque(77).build().unwrap();
+  match MetaCommand::from_request(&add).unwrap() {
+    MetaCommand::Set{ ref key, ref flags, .. } => {
+      assert_eq!(key, b"k");
+      assert_eq!(flags.token(MetaFlags::MODE), Some(&b"E"[..]));
+      assert_eq!(flags.number::<u32>(MetaFlags::OPAQUE), Some(77));
+    },
+    x => panic!("add should be an ms, not {:?}", x)
+  };
+}
+
+#[test]
+fn test_meta_replies() {
+  let va = MetaReply::Value(b"hi".to_vec(), MetaFlags::new().with_number(MetaFlags::RETURN_CAS, 7).with(MetaFlags::WIN));
+  let raw = va.encode();
+  assert_eq!(raw, b"VA 2 c7 W\r\nhi\r\n".to_vec());
+  assert_eq!(MetaReply::parse(&raw), Parsed::Done(va.clone(), raw.len()));
+  assert!(va.get_flags().is_win());
+  assert_eq!(MetaReply::parse(b"EN\r\n"), Parsed::Done(MetaReply::Miss, 4));
+  assert_eq!(MetaReply::parse(b"NS Oxy\r\n"), Parsed::Done(MetaReply::NotStored(MetaFlags::new().with_token(MetaFlags::OPAQUE, b"xy")), 8));
+  let me = b"ME foo exp=-1 la=2 cas=3\r\n";
+  match MetaReply::parse(me) {
+    Parsed::Done(MetaReply::Debug(key, pairs), 26) => {
+      assert_eq!(key, b"foo".to_vec());
+      assert_eq!(pairs[2], ("cas".to_string(), "3".to_string()));
+    },
+    x => panic!("ME should parse, not {:?}", x)
+  };
+  assert_eq!(MetaReply::parse(b"VA 2\r\nhi"), Parsed::Incomplete);
+  assert_eq!(MetaReply::parse(b"VA 4000000000\r\nhi"), Parsed::Invalid(TextError::TooLarge, 15));
+  assert_eq!(MetaReply::parse(b"VA 18446744073709551615 c1\r\nhi"), Parsed::Invalid(TextError::TooLarge, 28));
+
+  //responses to binary requests, and back
+  let cmd = MetaCommand::Get{ key: b"k".to_vec(), flags: MetaFlags::new().with(MetaFlags::RETURN_VALUE).with(MetaFlags::RETURN_CAS).with(MetaFlags::RETURN_KEY) };
+  let req = cmd.to_request(1).unwrap().unwrap();
+  let hit = OwnedResponse::new(OpCode::GetK, StatusField::NoError, 1, 12, vec![0, 0, 0, 4], b"k".to_vec(), b"v".to_vec());
+  let reply = MetaReply::from_response(&cmd, Some(&hit));
+  assert_eq!(reply.encode(), b"VA 1 kk c12\r\nv\r\n".to_vec());
+  let miss = OwnedResponse::new(OpCode::GetK, StatusField::KeyNotFound, 1, 0, vec![], vec![], vec![]);
+  assert_eq!(MetaReply::from_response(&cmd, Some(&miss)), MetaReply::Miss);
+  let back = reply.to_response(&req).unwrap();
+  assert_eq!((back.get_cas(), back.get_key()), (12, Some(&b"k"[..])));
+
+  let quiet = RequestBuilder::set(b"k", b"v").quiet().build().unwrap();
+  assert!(MetaReply::Header(MetaFlags::new()).to_response(&quiet).is_none());
+  let add = RequestBuilder::add(b"k", b"v").build().unwrap();
+  assert_eq!(MetaReply::NotStored(MetaFlags::new()).to_response(&add).unwrap().get_status(), StatusField::KeyExists);
+  let set = MetaCommand::Set{ key: b"k".to_vec(), value: vec![], flags: MetaFlags::new().with(MetaFlags::QUIET) };
+  assert!(set.suppresses(&MetaReply::from_response(&set, None)));
+}
@@ -4,7 +4,8 @@ use super::{
 };
 use super::nom::{
   IResult, 
-  ErrorKind
+  ErrorKind,
+  Needed
 };
 use std::mem;
 
@@ -75,6 +76,90 @@ pub enum OpCode {
   TAPCheckpointStart = 0x46,
   TAPCheckpointEnd = 0x47
 }
+impl OpCode {
+  /// Quiet commands only generate a response on failure
+  /// (or for the `Get` family on a hit).
+  #[inline]
+  pub fn is_quiet(&self) -> bool {
+    matches!(*self,
+      OpCode::GetQ |
+      OpCode::GetKQ |
+      OpCode::SetQ |
+      OpCode::AddQ |
+      OpCode::ReplaceQ |
+      OpCode::DeleteQ |
+      OpCode::IncrementQ |
+      OpCode::DecrementQ |
+      OpCode::QuitQ |
+      OpCode::FlushQ |
+      OpCode::AppendQ |
+      OpCode::PrependQ |
+      OpCode::GATQ |
+      OpCode::RSetQ |
+      OpCode::RAppendQ |
+      OpCode::RPrependQ |
+      OpCode::RDeleteQ |
+      OpCode::RIncrQ |
+      OpCode::RDecrQ)
+  }
+  /// Get the quiet version of a command.
+  ///
+  /// Quiet commands are returned as is. `None` is returned
+  /// if the command has no quiet version.
+  #[inline]
+  pub fn quiet_form(&self) -> Option<OpCode> {
+    match *self {
+      OpCode::Get => Some(OpCode::GetQ),
+      OpCode::GetK => Some(OpCode::GetKQ),
+      OpCode::Set => Some(OpCode::SetQ),
+      OpCode::Add => Some(OpCode::AddQ),
+      OpCode::Replace => Some(OpCode::ReplaceQ),
+      OpCode::Delete => Some(OpCode::DeleteQ),
+      OpCode::Increment => Some(OpCode::IncrementQ),
+      OpCode::Decrement => Some(OpCode::DecrementQ),
+      OpCode::Quit => Some(OpCode::QuitQ),
+      OpCode::Flush => Some(OpCode::FlushQ),
+      OpCode::Append => Some(OpCode::AppendQ),
+      OpCode::Prepare => Some(OpCode::PrependQ),
+      OpCode::GAT => Some(OpCode::GATQ),
+      OpCode::RSet => Some(OpCode::RSetQ),
+      OpCode::RAppend => Some(OpCode::RAppendQ),
+      OpCode::RPrepend => Some(OpCode::RPrependQ),
+      OpCode::RDelete => Some(OpCode::RDeleteQ),
+      OpCode::RIncr => Some(OpCode::RIncrQ),
+      OpCode::RDecr => Some(OpCode::RDecrQ),
+      x if x.is_quiet() => Some(x),
+      _ => None
+    }
+  }
+  /// Get the normal (loud) version of a command. Commands
+  /// which are not quiet are returned as is.
+  #[inline]
+  pub fn loud_form(&self) -> OpCode {
+    match *self {
+      OpCode::GetQ => OpCode::Get,
+      OpCode::GetKQ => OpCode::GetK,
+      OpCode::SetQ => OpCode::Set,
+      OpCode::AddQ => OpCode::Add,
+      OpCode::ReplaceQ => OpCode::Replace,
+      OpCode::DeleteQ => OpCode::Delete,
+      OpCode::IncrementQ => OpCode::Increment,
+      OpCode::DecrementQ => OpCode::Decrement,
+      OpCode::QuitQ => OpCode::Quit,
+      OpCode::FlushQ => OpCode::Flush,
+      OpCode::AppendQ => OpCode::Append,
+      OpCode::PrependQ => OpCode::Prepare,
+      OpCode::GATQ => OpCode::GAT,
+      OpCode::RSetQ => OpCode::RSet,
+      OpCode::RAppendQ => OpCode::RAppend,
+      OpCode::RPrependQ => OpCode::RPrepend,
+      OpCode::RDeleteQ => OpCode::RDelete,
+      OpCode::RIncrQ => OpCode::RIncr,
+      OpCode::RDecrQ => OpCode::RDecr,
+      x => x
+    }
+  }
+}
 impl Into<u8> for OpCode {
   
   /// Converts an OpCode into it's byte code (at no
@@ -113,7 +198,10 @@ fn from_u8(x: u8) -> OpCode {
 pub fn opcode_parse<'a>(i: &'a [u8])
 -> IResult<&'a [u8], OpCode>
 {
-  let byte = i[0].clone();
+  let byte = match i.first() {
+    Option::Some(b) => *b,
+    Option::None => return IResult::Incomplete(Needed::Size(1))
+  };
   if byte <= 0x1E {
     return IResult::Done(&i[1..], from_u8(byte));
   }
@@ -132,7 +220,6 @@ pub fn opcode_parse<'a>(i: &'a [u8])
 #[test]
 fn test_opcode_decode() {
 
-use super::{Fault,ParseResult};
 /*
  *Abstract test boiler plate
  */
@@ -143,11 +230,9 @@ macro_rules! ot {
     assert_eq!($a, encode);
     assert_eq!(from_u8($a), dut);
     let v: Vec<u8> = vec![ $a, 0x01u8];
-    let p = ParseResult::from(opcode_parse(v.as_slice())); 
-    assert!(p.is_ok());
-    let parse_out = match p {
-      ParseResult::Ok(x) => x,
-      ParseResult::Err(e) => panic!("Value {:?} should be a valid opcode not {:?}",encode, e)
+    let parse_out = match opcode_parse(v.as_slice()) {
+      IResult::Done(_, x) => x,
+      e => panic!("Value {:?} should be a valid opcode not {:?}",encode, e)
     };
     assert_eq!(parse_out, dut);
   }
@@ -160,12 +245,9 @@ macro_rules! bad_code {
   ($a: expr) => {
     let dut: u8 = $a;
     let v: Vec<u8> = vec![ dut, 0x01 ];
-    let p = ParseResult::from(opcode_parse(v.as_slice()));
-    assert!(p.is_err());
-    match p {
-      ParseResult::Err(Fault::BadOpCode) => { },
-      ParseResult::Err(x) => panic!("Opcode {:?} should be `Fault::BadOpCode` not {:?}", dut, x),
-      _ => unreachable!()
+    match opcode_parse(v.as_slice()) {
+      IResult::Error(ErrorKind::Custom(0x81)) => { },
+      x => panic!("Opcode {:?} should be `Custom(0x81)` not {:?}", dut, x)
     };
   }
 }
@@ -269,4 +351,29 @@ macro_rules! bad_code {
 
   //rust loops are range inclusive
   bad_code!(0xFFu8);
+
+  //empty input is incomplete, not a panic
+  match opcode_parse(&[]) {
+    IResult::Incomplete(Needed::Size(1)) => { },
+    x => panic!("An empty slice should be incomplete not {:?}", x)
+  };
+}
+
+#[test]
+fn test_quiet_forms() {
+  assert!(!OpCode::Get.is_quiet());
+  assert!(OpCode::GetKQ.is_quiet());
+  assert_eq!(OpCode::Set.quiet_form(), Some(OpCode::SetQ));
+  assert_eq!(OpCode::Prepare.quiet_form(), Some(OpCode::PrependQ));
+  assert_eq!(OpCode::SetQ.quiet_form(), Some(OpCode::SetQ));
+  assert_eq!(OpCode::Nop.quiet_form(), None);
+  assert_eq!(OpCode::Version.quiet_form(), None);
+  assert_eq!(OpCode::PrependQ.loud_form(), OpCode::Prepare);
+  assert_eq!(OpCode::Nop.loud_form(), OpCode::Nop);
+  for byte in (0u8..0x1F).chain(0x30..0x48) {
+    let code = from_u8(byte);
+    if let Some(q) = code.quiet_form() {
+      assert_eq!(q.loud_form(), code.loud_form());
+    }
+  }
 }
@@ -0,0 +1,383 @@
+//! Protocol Proxies
+//!
+//! `TextProxy` accepts clients speaking the text protocol and
+//! drives an upstream server with the binary protocol. Each
+//! command is translated with `text::Command::to_requests`, so
+//!
+//!* `noreply` commands are sent with quiet opcodes and are not
+//!  waited on
+//!* `get`/`gets` are pipelined `GetKQ` requests (which carry the
+//!  CAS) closed by a `Nop`
+//!
+//! and the binary responses are translated back with
+//! `text::Reply::from_responses`.
+//!
+//! `BinaryProxy` accepts binary protocol clients and sends each
+//! request through a `route::Route`, by default to one server of
+//! a `ServerRing` picked by it's key. Many clients share one
+//! connection per server, the opaque of every request is
+//! rewritten on the way up and restored on the way back.
+//!
+//! The `mbpr-proxy` binary runs `serve_text`, `serve_binary` or
+//! `serve_route` on a listening socket.
+
+use super::opcode::OpCode;
+use super::status::StatusField;
+use super::request::OwnedRequest;
+use super::response::{
+  OwnedResponse,
+  status_response
+};
+use super::stream::StreamDecoder;
+use super::{
+  Encoding,
+  Encoder
+};
+use super::client::{
+  Client,
+  ClientError
+};
+use super::cluster::ServerRing;
+use super::route::{
+  PoolRoute,
+  Route
+};
+use super::text::{
+  Command,
+  Parsed,
+  Reply,
+  TextError
+};
+use std::collections::BTreeMap;
+use std::io;
+use std::io::{
+  Read,
+  Write
+};
+use std::net::{
+  Shutdown,
+  SocketAddr,
+  TcpListener,
+  TcpStream
+};
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::sync::mpsc::{
+  Receiver,
+  Sender
+};
+use std::thread;
+
+/// Bytes read from a text client at once
+const READ_SIZE: usize = 16 * 1024;
+
+/// Translates text commands into binary requests against one
+/// upstream connection
+pub struct TextProxy<U: Read + Write> {
+  upstream: Client<U>
+}
+impl<U: Read + Write> TextProxy<U> {
+  #[inline]
+  pub fn new(upstream: Client<U>) -> TextProxy<U> {
+    TextProxy {
+      upstream
+    }
+  }
+  #[inline(always)]
+  pub fn get_ref(&self) -> &Client<U> {
+    &self.upstream
+  }
+  #[inline]
+  pub fn into_inner(self) -> Client<U> {
+    self.upstream
+  }
+  /// Carry out one command upstream. The reply is `None` when
+  /// the client asked for none.
+  pub fn execute(&mut self, cmd: &Command) -> Result<Option<Reply>, ClientError> {
+    let count = match *cmd {
+      Command::Get{ ref keys, .. } |
+      Command::Gat{ ref keys, .. } => keys.len() + 1,
+      _ => 1
+    };
+    let first = self.upstream.reserve_opaques(count);
+    let reqs = cmd.to_requests(first)?;
+    let mut e = Encoder::for_packets(&reqs);
+    for req in reqs.iter() {
+      req.encode(&mut e);
+    }
+    self.upstream.write_encoded(&e)?;
+
+    //quiet requests only answer failures, which are skipped
+    //when they arrive
+    let last = &reqs[reqs.len() - 1];
+    if last.get_opcode().is_quiet() {
+      return Ok(None);
+    }
+    let resps = self.collect(first, last.get_opaque())?;
+    if cmd.is_noreply() {
+      return Ok(None);
+    }
+    Ok(Some(Reply::from_responses(cmd, first, &resps)))
+  }
+  /// Read the responses carrying `first..=last`, until the one
+  /// which ends `last` (the only response, or for `Stat` the
+  /// one without a key)
+  fn collect(&mut self, first: u32, last: u32) -> Result<Vec<OwnedResponse>, ClientError> {
+    let mut resps = Vec::new();
+    loop {
+      let resp = self.upstream.recv()?;
+      let opaque = resp.get_opaque();
+      if opaque.wrapping_sub(first) > last.wrapping_sub(first) {
+        continue;
+      }
+      let done = opaque == last && (resp.get_opcode() != OpCode::Stat || resp.key.is_empty());
+      resps.push(resp);
+      if done {
+        return Ok(resps);
+      }
+    }
+  }
+  /// Serve a text client until it disconnects or sends `quit`.
+  ///
+  /// Replies to every command that arrived in one read are
+  /// written together. A failed upstream, or a data block longer
+  /// then `MAX_BODY_LEN`, is reported to the client as a
+  /// `SERVER_ERROR` before the connection closes.
+  pub fn serve_connection<S: Read + Write>(&mut self, mut stream: S) -> io::Result<()> {
+    let mut buf: Vec<u8> = Vec::new();
+    let mut out = Vec::new();
+    let mut chunk = vec![0u8; READ_SIZE];
+    loop {
+      let mut pos = 0;
+      loop {
+        let cmd = match Command::parse(&buf[pos..]) {
+          Parsed::Done(cmd, used) => {
+            pos += used;
+            cmd
+          },
+          Parsed::Incomplete => break,
+          Parsed::Invalid(TextError::TooLarge, _) => {
+            //the data block is never buffered, so the stream can't be resynchronized
+            out.extend_from_slice(&TextError::TooLarge.reply().encode());
+            stream.write_all(&out)?;
+            return stream.flush();
+          },
+          Parsed::Invalid(e, used) => {
+            pos += used;
+            out.extend_from_slice(&e.reply().encode());
+            continue;
+          }
+        };
+        if cmd == Command::Quit {
+          stream.write_all(&out)?;
+          return stream.flush();
+        }
+        match self.execute(&cmd) {
+          Ok(Option::Some(reply)) => out.extend_from_slice(&reply.encode()),
+          Ok(Option::None) => { },
+          Err(ClientError::Build(_)) => out.extend_from_slice(&Reply::ClientError("bad command line format".to_string()).encode()),
+          Err(e) => {
+            out.extend_from_slice(&Reply::ServerError(format!("upstream failed: {}", e)).encode());
+            stream.write_all(&out)?;
+            stream.flush()?;
+            return Err(io::Error::new(io::ErrorKind::Other, e.to_string()));
+          }
+        };
+      }
+      buf.drain(..pos);
+      if !out.is_empty() {
+        stream.write_all(&out)?;
+        stream.flush()?;
+        out.clear();
+      }
+      let n = stream.read(&mut chunk)?;
+      if n == 0 {
+        return Ok(());
+      }
+      buf.extend_from_slice(&chunk[..n]);
+    }
+  }
+}
+
+/// Accept text clients forever, serving each on it's own thread
+/// with it's own connection to `upstream`
+pub fn serve_text(listener: TcpListener, upstream: SocketAddr) -> io::Result<()> {
+  for stream in listener.incoming() {
+    let stream = stream?;
+    stream.set_nodelay(true)?;
+    thread::spawn(move || {
+      let conn = TcpStream::connect(upstream)?;
+      conn.set_nodelay(true)?;
+      TextProxy::new(Client::new(conn)).serve_connection(stream)
+    });
+  }
+  Ok(())
+}
+
+/// Is `status` one a quiet `code` does not answer? Quiet gets
+/// only skip misses, other quiet commands only skip successes.
+#[inline]
+fn suppressed(code: OpCode, status: StatusField) -> bool {
+  match code {
+    OpCode::GetQ |
+    OpCode::GetKQ |
+    OpCode::GATQ => status == StatusField::KeyNotFound,
+    x => x.is_quiet() && status == StatusField::NoError
+  }
+}
+
+/// Responses for one client request, in the order the client
+/// sent it's requests (`seq`)
+struct Event {
+  seq: u64,
+  resps: Vec<OwnedResponse>,
+  close: bool
+}
+
+/// Serves binary clients through a `Route`
+///
+/// `BinaryProxy::new` routes each request by key to one server
+/// of a ring, with one connection per server shared by every
+/// client (see `route::PoolRoute`). Requests go upstream under
+/// fresh opaques, and come back with the client's opaque.
+///
+/// Quiet requests are routed in their loud form so the proxy
+/// always knows when they finished, the responses a quiet
+/// request would not have produced are dropped. Responses reach
+/// a client in the order it sent the requests, so a `Nop`
+/// (answered by the proxy) still marks that every earlier
+/// request has completed.
+///
+/// SASL, range, vbucket and TAP requests are answered with
+/// `NotSupported`.
+pub struct BinaryProxy {
+  route: Arc<dyn Route>
+}
+impl BinaryProxy {
+  #[inline]
+  pub fn new(ring: ServerRing) -> BinaryProxy {
+    BinaryProxy::with_route(Arc::new(PoolRoute::new(ring)))
+  }
+  #[inline]
+  pub fn with_route(route: Arc<dyn Route>) -> BinaryProxy {
+    BinaryProxy {
+      route
+    }
+  }
+  #[inline(always)]
+  pub fn get_route(&self) -> &Arc<dyn Route> {
+    &self.route
+  }
+  /// Route one client request, the responses are sent to
+  /// `client` tagged with `seq`
+  fn route(&self, mut req: OwnedRequest, seq: u64, client: &Sender<Event>) {
+    let code = req.get_opcode();
+    let local = |resps: Vec<OwnedResponse>, close: bool| {
+      let _ = client.send(Event{ seq, resps, close });
+    };
+    match code {
+      OpCode::Nop => return local(vec![status_response(&req, StatusField::NoError)], false),
+      OpCode::Quit => return local(vec![status_response(&req, StatusField::NoError)], true),
+      OpCode::QuitQ => return local(Vec::new(), true),
+      x if x as u8 >= OpCode::SASLlistmech as u8 => return local(vec![status_response(&req, StatusField::NotSupported)], false),
+      _ => { }
+    };
+    req.set_opcode(code.loud_form());
+    let client = client.clone();
+    self.route.route(req, Box::new(move |mut resps| {
+      for resp in resps.iter_mut() {
+        resp.set_opcode(code);
+      }
+      if resps.last().map(|r| suppressed(code, r.get_status())).unwrap_or(false) {
+        resps.clear();
+      }
+      let _ = client.send(Event{ seq, resps, close: false });
+    }));
+  }
+  /// Serve a binary client until it disconnects or sends
+  /// `Quit`
+  pub fn serve_connection(&self, mut stream: TcpStream) -> io::Result<()> {
+    let (client, events) = mpsc::channel();
+    let out = stream.try_clone()?;
+    let writer = thread::spawn(move || write_events(out, events));
+    let mut decoder = StreamDecoder::<OwnedRequest>::new();
+    let mut seq = 0u64;
+    let result = 'read: loop {
+      loop {
+        let req = match decoder.next_packet() {
+          Ok(Option::Some(req)) => req,
+          Ok(Option::None) => break,
+          Err(e) => break 'read Err(io::Error::new(io::ErrorKind::InvalidData, e))
+        };
+        let quit = req.get_opcode() == OpCode::Quit || req.get_opcode() == OpCode::QuitQ;
+        self.route(req, seq, &client);
+        seq += 1;
+        if quit {
+          break 'read Ok(());
+        }
+      }
+      match decoder.read_from(&mut stream) {
+        Ok(0) => break Ok(()),
+        Ok(_) => { },
+        Err(e) => break Err(e)
+      };
+    };
+    //the writer finishes once every pending request answered
+    drop(client);
+    let _ = writer.join();
+    result
+  }
+}
+
+/// Write each client's responses in request order
+fn write_events(mut stream: TcpStream, events: Receiver<Event>) {
+  let mut waiting: BTreeMap<u64, Event> = BTreeMap::new();
+  let mut next = 0u64;
+  let mut out = Vec::new();
+  while let Ok(event) = events.recv() {
+    waiting.insert(event.seq, event);
+    while let Ok(event) = events.try_recv() {
+      waiting.insert(event.seq, event);
+    }
+    let mut close = false;
+    while let Option::Some(event) = waiting.remove(&next) {
+      next += 1;
+      for resp in event.resps.iter() {
+        out.extend_from_slice(resp.encode_self().as_slice());
+      }
+      if event.close {
+        close = true;
+        break;
+      }
+    }
+    if !out.is_empty() && stream.write_all(&out).is_err() {
+      return;
+    }
+    out.clear();
+    if close {
+      let _ = stream.shutdown(Shutdown::Both);
+      return;
+    }
+  }
+  let _ = stream.shutdown(Shutdown::Both);
+}
+
+/// Accept binary clients forever, serving each on it's own
+/// thread. All clients share the connections to the `ring`.
+#[inline]
+pub fn serve_binary(listener: TcpListener, ring: ServerRing) -> io::Result<()> {
+  serve_route(listener, Arc::new(PoolRoute::new(ring)))
+}
+
+/// Accept binary clients forever, sending their requests
+/// through `route`
+pub fn serve_route(listener: TcpListener, route: Arc<dyn Route>) -> io::Result<()> {
+  let proxy = Arc::new(BinaryProxy::with_route(route));
+  for stream in listener.incoming() {
+    let stream = stream?;
+    stream.set_nodelay(true)?;
+    let proxy = proxy.clone();
+    thread::spawn(move || proxy.serve_connection(stream));
+  }
+  Ok(())
+}
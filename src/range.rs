@@ -0,0 +1,315 @@
+//! Range Operations
+//!
+//! The range commands (`RGet` through `RDecrQ`) act on every key
+//! between a start and an end key. The key field holds the start
+//! key followed by the end key, the 8 byte extras say where one
+//! ends:
+//!
+//!```text
+//! start key length: u16
+//! reserved:         u8
+//! flags:            u8
+//! max results:      u32
+//!```
+//!
+//! The server answers with one response per item in the range,
+//! each carrying the item's key, then a final response without a
+//! key. `RangeRequest` builds and decodes the requests,
+//! `RangeItem` the per item responses, and `RangeResults` reads
+//! them from a `Client`.
+
+use super::{
+  ParseResult,
+  Error,
+  LengthField,
+  Encoding,
+  Encoder
+};
+use super::opcode::OpCode;
+use super::status::StatusField;
+use super::request::OwnedRequest;
+use super::response::OwnedResponse;
+use super::extras::{
+  Extras,
+  ReqExtras,
+  exact
+};
+use super::builder::{
+  BuildError,
+  RequestBuilder
+};
+use super::client::{
+  Client,
+  ClientError
+};
+use super::nom::{
+  be_u8,
+  be_u16,
+  be_u32
+};
+use std::io::{
+  Read,
+  Write
+};
+
+/// Checks if the opcode is one of the range commands
+#[inline]
+pub fn is_range(code: OpCode) -> bool {
+  let byte: u8 = code.into();
+  (0x30..=0x3C).contains(&byte)
+}
+
+/// Extras of a range request
+#[derive(Copy,Clone,Debug,PartialEq,Eq,Default)]
+pub struct RangeExtras {
+  /// Bytes of the key field which are the start key
+  pub start_len: u16,
+  pub flags: u8,
+  /// Most items to return, `0` is no limit
+  pub max_results: u32
+}
+impl Encoding for RangeExtras {
+  #[inline(always)]
+  fn encode(&self, buffer: &mut Encoder) {
+    self.start_len.encode(buffer);
+    buffer.encode_u8(0);
+    buffer.encode_u8(self.flags);
+    self.max_results.encode(buffer);
+  }
+}
+impl Extras for RangeExtras {
+  #[inline(always)]
+  fn extras_len(&self) -> usize {
+    8
+  }
+  #[inline]
+  fn parse_extras(x: &[u8]) -> ParseResult<Self> {
+    named!(parse_range_extras<RangeExtras>, do_parse!(
+      s: be_u16   >>
+      be_u8       >>
+      f: be_u8    >>
+      m: be_u32   >>
+      (RangeExtras{ start_len: s, flags: f, max_results: m })
+    ));
+    exact(x, 8, parse_range_extras)
+  }
+}
+
+/// A range command
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct RangeRequest<'a> {
+  pub code: OpCode,
+  pub start: &'a [u8],
+  pub end: &'a [u8],
+  pub flags: u8,
+  pub max_results: u32,
+  /// Value to store, append, or prepend, or the delta of an
+  /// `RIncr`/`RDecr` as decimal text
+  pub value: &'a [u8]
+}
+impl<'a> RangeRequest<'a> {
+  /// A request over the keys from `start` to `end`.
+  ///
+  /// Panics if `code` is not a range command.
+  #[inline]
+  pub fn new(code: OpCode, start: &'a [u8], end: &'a [u8]) -> RangeRequest<'a> {
+    assert!(is_range(code), "{:?} is not a range command", code);
+    RangeRequest {
+      code,
+      start,
+      end,
+      flags: 0,
+      max_results: 0,
+      value: &[]
+    }
+  }
+  /// Return at most `max` items (`0` is no limit)
+  #[inline]
+  pub fn max_results(mut self, max: u32) -> Self {
+    self.max_results = max;
+    self
+  }
+  #[inline]
+  pub fn flags(mut self, flags: u8) -> Self {
+    self.flags = flags;
+    self
+  }
+  #[inline]
+  pub fn value(mut self, value: &'a [u8]) -> Self {
+    self.value = value;
+    self
+  }
+  #[inline]
+  pub fn get_extras(&self) -> RangeExtras {
+    RangeExtras {
+      start_len: self.start.len() as u16,
+      flags: self.flags,
+      max_results: self.max_results
+    }
+  }
+  pub fn build(&self, opaque: u32) -> Result<OwnedRequest, BuildError> {
+    let mut key = Vec::with_capacity(self.start.len() + self.end.len());
+    key.extend_from_slice(self.start);
+    key.extend_from_slice(self.end);
+    let mut b = RequestBuilder::new(self.code)
+      .opaque(opaque)
+      .extras(ReqExtras::Raw(self.get_extras().to_vec()));
+    if !key.is_empty() {
+      b = b.key(&key);
+    }
+    if !self.value.is_empty() {
+      b = b.value(self.value);
+    }
+    b.build()
+  }
+  /// Decode a range request. Other opcodes are
+  /// `Error::BadOpCode`, a start key longer then the key field
+  /// is `Error::BadLength`.
+  pub fn parse(req: &'a OwnedRequest) -> ParseResult<RangeRequest<'a>> {
+    let code = req.get_opcode();
+    if !is_range(code) {
+      return Err(Error::BadOpCode{ code: code.into(), offset: 1 });
+    }
+    let extras = RangeExtras::parse_extras(&req.extra)?;
+    let split = extras.start_len as usize;
+    if split > req.key.len() {
+      return Err(Error::BadLength{ field: LengthField::Key, value: req.key.len() });
+    }
+    Ok(RangeRequest {
+      code,
+      start: &req.key[..split],
+      end: &req.key[split..],
+      flags: extras.flags,
+      max_results: extras.max_results,
+      value: &req.body
+    })
+  }
+}
+
+/// One item of a range command's results
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct RangeItem {
+  pub key: Vec<u8>,
+  pub value: Vec<u8>,
+  pub flags: u32,
+  pub cas: u64
+}
+impl RangeItem {
+  /// Decode a per item response. The response ending the
+  /// results (which has no key) is `None`.
+  pub fn from_response(resp: &OwnedResponse) -> ParseResult<Option<RangeItem>> {
+    if resp.key.is_empty() {
+      return Ok(None);
+    }
+    let flags = if resp.extra.is_empty() {
+      0
+    } else {
+      exact(&resp.extra, 4, be_u32)?
+    };
+    Ok(Some(RangeItem {
+      key: resp.key.clone(),
+      value: resp.body.clone(),
+      flags,
+      cas: resp.get_cas()
+    }))
+  }
+  /// Encode this item as a response to a range command
+  #[inline]
+  pub fn to_response(&self, code: OpCode, opaque: u32) -> OwnedResponse {
+    OwnedResponse::new(code, StatusField::NoError, opaque, self.cas, self.flags.to_be_bytes().to_vec(), self.key.clone(), self.value.clone())
+  }
+}
+
+/// The response ending a range command's results
+#[inline]
+pub fn range_end(code: OpCode, opaque: u32) -> OwnedResponse {
+  OwnedResponse::new(code, StatusField::NoError, opaque, 0, Vec::new(), Vec::new(), Vec::new())
+}
+
+/// Iterates the results of a range command
+///
+/// Ends after the response without a key. A response with an
+/// error status is yielded as `ClientError::Status` and ends the
+/// iteration.
+pub struct RangeResults<'c, S: Read + Write> {
+  client: &'c mut Client<S>,
+  opaque: u32,
+  done: bool
+}
+impl<'c, S: Read + Write> RangeResults<'c, S> {
+  /// Send `req` and iterate it's results
+  pub fn start(client: &'c mut Client<S>, req: &RangeRequest) -> Result<RangeResults<'c, S>, ClientError> {
+    let opaque = client.next_opaque();
+    client.send(&req.build(opaque)?)?;
+    Ok(RangeResults {
+      client,
+      opaque,
+      done: false
+    })
+  }
+}
+impl<'c, S: Read + Write> Iterator for RangeResults<'c, S> {
+  type Item = Result<RangeItem, ClientError>;
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.done {
+      return None;
+    }
+    let resp = match self.client.recv_for(self.opaque) {
+      Ok(resp) => resp,
+      Err(e) => {
+        self.done = true;
+        return Some(Err(e));
+      }
+    };
+    if let Err(status) = resp.check_status() {
+      self.done = true;
+      return Some(Err(ClientError::Status(status)));
+    }
+    match RangeItem::from_response(&resp) {
+      Ok(Some(item)) => Some(Ok(item)),
+      Ok(None) => {
+        self.done = true;
+        None
+      },
+      Err(e) => {
+        self.done = true;
+        Some(Err(ClientError::from(e)))
+      }
+    }
+  }
+}
+
+/// Send a range command, collecting every item it returns
+pub fn range<S: Read + Write>(client: &mut Client<S>, req: &RangeRequest) -> Result<Vec<RangeItem>, ClientError> {
+  RangeResults::start(client, req)?.collect()
+}
+
+#[test]
+fn test_range_request() {
+  let raw = RangeExtras{ start_len: 3, flags: 1, max_results: 10 }.to_vec();
+  assert_eq!(raw, vec![0, 3, 0, 1, 0, 0, 0, 10]);
+  assert_eq!(RangeExtras::parse_extras(&raw).unwrap().max_results, 10);
+  assert_eq!(RangeExtras::parse_extras(&raw[..4]).err().unwrap(), super::Fault::BadLength);
+
+  let req = RangeRequest::new(OpCode::RSet, b"aaa", b"zz")
+    .max_results(10)
+    .flags(1)
+    .value(b"v")
+    .build(7)
+    .unwrap();
+  assert_eq!(req.key, b"aaazz".to_vec());
+  assert_eq!(req.extra, raw);
+  let parsed = RangeRequest::parse(&req).unwrap();
+  assert_eq!((parsed.start, parsed.end, parsed.value), (&b"aaa"[..], &b"zz"[..], &b"v"[..]));
+  assert_eq!((parsed.flags, parsed.max_results), (1, 10));
+
+  let long = OwnedRequest::new(OpCode::RGet, 0, 0, 0, RangeExtras{ start_len: 9, flags: 0, max_results: 0 }.to_vec(), b"abc".to_vec(), vec![]);
+  assert_eq!(RangeRequest::parse(&long).err().unwrap(), super::Fault::BadLength);
+  let get = OwnedRequest::new(OpCode::Get, 0, 0, 0, vec![], b"abc".to_vec(), vec![]);
+  assert!(RangeRequest::parse(&get).is_err());
+
+  let item = RangeItem{ key: b"b".to_vec(), value: b"2".to_vec(), flags: 4, cas: 9 };
+  assert_eq!(RangeItem::from_response(&item.to_response(OpCode::RGet, 7)).unwrap(), Some(item));
+  assert_eq!(RangeItem::from_response(&range_end(OpCode::RGet, 7)).unwrap(), None);
+  assert!(is_range(OpCode::RDecrQ) && !is_range(OpCode::TAPConnect));
+}
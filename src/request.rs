@@ -3,14 +3,21 @@
 
 use super::{
   ParseResult,
+  from_nom,
+  check_lengths,
   Encoding,
   PacketVal,
   Encoder
 };
+use super::datatype::{
+  DataType,
+  datatype_parse
+};
 use super::opcode::{
   OpCode,
   opcode_parse
 };
+use super::extras::ReqExtras;
 use super::nom::{
   be_u8,
   be_u16,
@@ -26,6 +33,7 @@ use super::nom::{
 pub struct ReqHeader {
   code: OpCode,
   extralen: u8,
+  datatype: DataType,
   vbucket_id: u16,
   keylen: u16,
   bodylen: u32,
@@ -36,7 +44,7 @@ impl ReqHeader {
   /// Parse a request header
   #[inline]
   pub fn parse(buffer: &[u8]) -> ParseResult<Self> {
-    ParseResult::from(parse_req_header(buffer))
+    from_nom(buffer, parse_req_header(buffer))
   }
   #[inline(always)]
   pub fn get_opaque(&self) -> u32 {
@@ -55,6 +63,20 @@ impl ReqHeader {
     self.vbucket_id
   }
 }
+impl ReqHeader {
+  #[inline(always)]
+  pub fn get_datatype(&self) -> DataType {
+    self.datatype
+  }
+  #[inline(always)]
+  pub fn set_datatype(&mut self, dt: DataType) {
+    self.datatype = dt;
+  }
+  #[inline(always)]
+  pub fn set_vbucket_id(&mut self, vbucket: u16) {
+    self.vbucket_id = vbucket;
+  }
+}
 impl PacketVal for ReqHeader {
   #[inline(always)]
   fn get_keylen(&self) -> usize {
@@ -74,12 +96,11 @@ impl Encoding for ReqHeader {
   #[inline(always)]
   fn encode(&self, buffer: &mut Encoder) {
     let magic = 0x80u8;
-    let datatype = 0x00u8;
     magic.encode(buffer);
     self.code.encode(buffer);
     self.keylen.encode(buffer);
     self.extralen.encode(buffer);
-    datatype.encode(buffer);
+    self.datatype.encode(buffer);
     self.vbucket_id.encode(buffer);
     ( (
         self.get_bodylen() +
@@ -97,15 +118,17 @@ named!(pub parse_req_header<ReqHeader>, do_parse!(
   o: opcode_parse   >>
   kl: be_u16        >>
   el: be_u8         >>
-  tag!(b"\x00")     >>
+  dt: datatype_parse >>
   vb_id: be_u16     >>
   bl: be_u32        >>
+  apply!(check_lengths, kl, el, bl) >>
   op: be_u32        >>
   cas: be_u64       >>
   (
     ReqHeader{
     code: o,
     extralen: el,
+    datatype: dt,
     vbucket_id: vb_id,
     keylen: kl,
     bodylen: bl - (kl as u32 + el as u32),
@@ -162,7 +185,7 @@ impl<'a> Request<'a> {
         body: to_opt(b)
       })
     ));
-    ParseResult::from(parse_request(x))
+    from_nom(x, parse_request(x))
   }
   /// This interface does ABSOLUTELY NO verfication of the packet
   /// it is expected if you are calling this method you understand
@@ -180,14 +203,14 @@ impl<'a> Request<'a> {
     let e = get_len(&extra);
     let k = get_len(&key);
     let b = get_len(&body);
-    let bl = b + k + e;
     Request {
       header:ReqHeader {
         code: opcode,
         vbucket_id: vbucket,
         extralen: e as u8,
+        datatype: DataType::RAW,
         keylen: k as u16,
-        bodylen: bl as u32,
+        bodylen: b as u32,
         opaque: opaque,
         cas: cas
       },
@@ -220,12 +243,12 @@ impl<'a> Request<'a> {
     let e = get_len(&extra);
     let k = get_len(&key);
     let b = get_len(&body);
-    let bl = b + k + e;
     self.header.code = opcode;
     self.header.vbucket_id = vbucket;
     self.header.extralen = e as u8;
+    self.header.datatype = DataType::RAW;
     self.header.keylen = k as u16;
-    self.header.bodylen = bl as u32;
+    self.header.bodylen = b as u32;
     self.header.opaque = opaque;
     self.header.cas = cas;
     let _ = replace(&mut self.extra, extra);
@@ -261,6 +284,15 @@ impl<'a> Request<'a> {
     self.header.opaque
   }
   #[inline(always)]
+  pub fn get_datatype(&self) -> DataType {
+    self.header.datatype
+  }
+  /// Change the data type flags, the value is not modified
+  #[inline(always)]
+  pub fn set_datatype(&mut self, dt: DataType) {
+    self.header.datatype = dt;
+  }
+  #[inline(always)]
   pub fn get_cas(&self) -> u64 {
     self.header.cas
   }
@@ -269,6 +301,10 @@ impl<'a> Request<'a> {
     self.header.vbucket_id
   }
   #[inline(always)]
+  pub fn set_vbucket_id(&mut self, vbucket: u16) {
+    self.header.vbucket_id = vbucket;
+  }
+  #[inline(always)]
   pub fn has_extra(&self) -> bool {
     self.extra.is_some()
   }
@@ -280,6 +316,11 @@ impl<'a> Request<'a> {
   pub fn get_extra(&'a self) -> Option<&'a [u8]> {
     self.extra
   }
+  /// Decode the extras field against this packet's opcode
+  #[inline]
+  pub fn get_typed_extra(&self) -> ParseResult<ReqExtras> {
+    ReqExtras::parse(self.get_opcode(), self.extra)
+  }
   #[inline(always)]
   pub fn get_key(&'a self) -> Option<&'a [u8]> {
     self.key
@@ -391,7 +432,7 @@ impl OwnedRequest {
         body: if b.len() == 0 { Vec::with_capacity(0) } else { b.to_vec() }
       })
     ));
-    ParseResult::from(parse_owned_request(x))
+    from_nom(x, parse_owned_request(x))
   }
   /// This interface does ABSOLUTELY NO verfication of the packet
   /// it is expected if you are calling this method you understand
@@ -409,14 +450,14 @@ impl OwnedRequest {
     let e = extra.len();
     let k = key.len();
     let b = body.len();
-    let bl = b + k + e;
     OwnedRequest {
       header:ReqHeader {
         code: opcode,
         vbucket_id: vbucket,
         extralen: e as u8,
+        datatype: DataType::RAW,
         keylen: k as u16,
-        bodylen: bl as u32,
+        bodylen: b as u32,
         opaque: opaque,
         cas: cas
       },
@@ -449,12 +490,12 @@ impl OwnedRequest {
     let e = extra.len();
     let k = key.len();
     let b = body.len();
-    let bl = b + k + e;
     self.header.code = opcode;
     self.header.vbucket_id = vbucket;
     self.header.extralen = e as u8;
+    self.header.datatype = DataType::RAW;
     self.header.keylen = k as u16;
-    self.header.bodylen = bl as u32;
+    self.header.bodylen = b as u32;
     self.header.opaque = opaque;
     self.header.cas = cas;
     let _ = replace(&mut self.extra, extra);
@@ -490,6 +531,30 @@ impl OwnedRequest {
     self.header.opaque
   }
   #[inline(always)]
+  pub fn get_datatype(&self) -> DataType {
+    self.header.datatype
+  }
+  /// Change the data type flags, the value is not modified
+  #[inline(always)]
+  pub fn set_datatype(&mut self, dt: DataType) {
+    self.header.datatype = dt;
+  }
+  /// Replace the value, keeping the header's length in step.
+  /// The `body` field must not be resized directly.
+  #[inline]
+  pub fn set_body(&mut self, body: Vec<u8>) {
+    self.header.bodylen = body.len() as u32;
+    self.body = body;
+  }
+  #[inline(always)]
+  pub fn set_opaque(&mut self, opaque: u32) {
+    self.header.opaque = opaque;
+  }
+  #[inline(always)]
+  pub fn set_opcode(&mut self, code: OpCode) {
+    self.header.code = code;
+  }
+  #[inline(always)]
   pub fn get_cas(&self) -> u64 {
     self.header.cas
   }
@@ -498,6 +563,10 @@ impl OwnedRequest {
     self.header.vbucket_id
   }
   #[inline(always)]
+  pub fn set_vbucket_id(&mut self, vbucket: u16) {
+    self.header.vbucket_id = vbucket;
+  }
+  #[inline(always)]
   pub fn has_extra(&self) -> bool {
     self.extra.len() != 0
   }
@@ -509,6 +578,11 @@ impl OwnedRequest {
       None
     }
   }
+  /// Decode the extras field against this packet's opcode
+  #[inline]
+  pub fn get_typed_extra(&self) -> ParseResult<ReqExtras> {
+    ReqExtras::parse(self.get_opcode(), self.get_extra())
+  }
   #[inline(always)]
   pub fn has_key(&self) -> bool {
     self.key.len() != 0
@@ -3,10 +3,16 @@
 #![allow(dead_code)]
 use super::{
   ParseResult,
+  from_nom,
+  check_lengths,
   Encoder,
   Encoding,
   PacketVal,
 };
+use super::datatype::{
+  DataType,
+  datatype_parse
+};
 use super::opcode::{
   OpCode,
   opcode_parse
@@ -19,8 +25,11 @@ use super::nom::{
 };
 use super::status::{
   StatusField,
-  status_parse
+  status_parse,
+  status_message
 };
+use super::request::OwnedRequest;
+use super::extras::ResExtras;
 
 /*
  *Request Header Section
@@ -33,6 +42,7 @@ use super::status::{
 pub struct ResHeader {
   code: OpCode,
   extralen: u8,
+  datatype: DataType,
   status: StatusField,
   keylen: u16,
   bodylen: u32,
@@ -63,10 +73,25 @@ impl ResHeader {
   pub fn check_status(&self) -> Result<(),StatusField> {
     self.status.check_status()
   }
+  /// Raw Status Field, `StatusField::NoError` included
+  #[inline(always)]
+  pub fn get_status(&self) -> StatusField {
+    self.status
+  }
   /// Parse a packet header
   #[inline(always)]
   pub fn parse(x: &[u8]) -> ParseResult<ResHeader> {
-    ParseResult::from(parse_res_header(x))
+    from_nom(x, parse_res_header(x))
+  }
+}
+impl ResHeader {
+  #[inline(always)]
+  pub fn get_datatype(&self) -> DataType {
+    self.datatype
+  }
+  #[inline(always)]
+  pub fn set_datatype(&mut self, dt: DataType) {
+    self.datatype = dt;
   }
 }
 impl PacketVal for ResHeader {
@@ -94,12 +119,11 @@ impl Encoding for ResHeader {
   #[inline(always)]
   fn encode(&self, buffer: &mut Encoder) {
     let magic = 0x81u8;
-    let datatype = 0x00u8;
     magic.encode(buffer);
     self.code.encode(buffer);
     self.keylen.encode(buffer);
     self.extralen.encode(buffer);
-    datatype.encode(buffer);
+    self.datatype.encode(buffer);
     self.status.encode(buffer);
     ( (
         self.get_bodylen() +
@@ -118,15 +142,17 @@ named!(pub parse_res_header<ResHeader>, do_parse!(
   o: opcode_parse   >>
   kl: be_u16        >>
   el: be_u8         >>
-  tag!(b"\x00")     >>
+  dt: datatype_parse >>
   st: status_parse  >>
   bl: be_u32        >>
+  apply!(check_lengths, kl, el, bl) >>
   op: be_u32        >>
   cas: be_u64       >>
   (
     ResHeader{
     code: o,
     extralen: el,
+    datatype: dt,
     status: st,
     keylen: kl,
     bodylen: (bl - (kl as u32 + el as u32)),
@@ -184,7 +210,7 @@ impl<'a> Response<'a> {
         body: to_opt(b)
       })
     ));
-    ParseResult::from(parse_request(x))
+    from_nom(x, parse_request(x))
   }
   /// Allocates a new buffer and encodes this packets contents into it.
   /// this method works out to a handful of `memcp` primatives and is
@@ -222,14 +248,14 @@ impl<'a> Response<'a> {
     let e = get_len(&extra);
     let k = get_len(&key);
     let b = get_len(&body);
-    let bl = b + k + e;
     Response {
       header: ResHeader {
         code: opcode,
         status: status,
         extralen: e as u8,
+        datatype: DataType::RAW,
         keylen: k as u16,
-        bodylen: bl as u32,
+        bodylen: b as u32,
         opaque: opaque,
         cas: cas
       },
@@ -262,12 +288,12 @@ impl<'a> Response<'a> {
     let e = get_len(&extra);
     let k = get_len(&key);
     let b = get_len(&body);
-    let bl = b + k + e;
     self.header.code = opcode;
     self.header.status = status;
     self.header.extralen = e as u8;
+    self.header.datatype = DataType::RAW;
     self.header.keylen = k as u16;
-    self.header.bodylen = bl as u32;
+    self.header.bodylen = b as u32;
     self.header.opaque = opaque;
     self.header.cas = cas;
     let _ = replace(&mut self.extra, extra);
@@ -283,6 +309,15 @@ impl<'a> Response<'a> {
     self.header.opaque
   } 
   #[inline(always)]
+  pub fn get_datatype(&self) -> DataType {
+    self.header.datatype
+  }
+  /// Change the data type flags, the value is not modified
+  #[inline(always)]
+  pub fn set_datatype(&mut self, dt: DataType) {
+    self.header.datatype = dt;
+  }
+  #[inline(always)]
   pub fn get_cas(&self) -> u64 {
       self.header.cas
   }
@@ -297,6 +332,11 @@ impl<'a> Response<'a> {
   pub fn check_status(&self) -> Result<(),StatusField> {
     self.header.check_status()
   }
+  /// Raw Status Field, `StatusField::NoError` included
+  #[inline(always)]
+  pub fn get_status(&self) -> StatusField {
+    self.header.status
+  }
   #[inline(always)]
   pub fn has_extra(&self) -> bool {
     self.extra.is_some()
@@ -305,6 +345,12 @@ impl<'a> Response<'a> {
   pub fn get_extra(&'a self) -> Option<&'a [u8]> {
     self.extra
   }
+  /// Decode the extras field against this packet's opcode
+  /// and status
+  #[inline]
+  pub fn get_typed_extra(&self) -> ParseResult<ResExtras> {
+    ResExtras::parse(self.get_opcode(), self.header.get_status(), self.extra)
+  }
   #[inline(always)]
   pub fn has_key(&self) -> bool {
     self.key.is_some()
@@ -461,7 +507,7 @@ impl OwnedResponse {
         body: from_opt(b)
       })
     ));
-    ParseResult::from(parse_owned_response(x))
+    from_nom(x, parse_owned_response(x))
   }
   
   /// Allocates a new buffer and encodes this packets contents into it.
@@ -489,14 +535,14 @@ impl OwnedResponse {
     let e = extra.len();
     let k = key.len();
     let b = body.len();
-    let bl = b + k + e;
     OwnedResponse {
       header: ResHeader {
         code: opcode,
         status: status,
         extralen: e as u8,
+        datatype: DataType::RAW,
         keylen: k as u16,
-        bodylen: bl as u32,
+        bodylen: b as u32,
         opaque: opaque,
         cas: cas
       },
@@ -529,12 +575,12 @@ impl OwnedResponse {
     let e = extra.len();
     let k = key.len();
     let b = body.len();
-    let bl = b + k + e;
     self.header.code = opcode;
     self.header.status = status;
     self.header.extralen = e as u8;
+    self.header.datatype = DataType::RAW;
     self.header.keylen = k as u16;
-    self.header.bodylen = bl as u32;
+    self.header.bodylen = b as u32;
     self.header.opaque = opaque;
     self.header.cas = cas;
     let _ = replace(&mut self.extra, extra);
@@ -550,6 +596,30 @@ impl OwnedResponse {
     self.header.opaque
   } 
   #[inline(always)]
+  pub fn get_datatype(&self) -> DataType {
+    self.header.datatype
+  }
+  /// Change the data type flags, the value is not modified
+  #[inline(always)]
+  pub fn set_datatype(&mut self, dt: DataType) {
+    self.header.datatype = dt;
+  }
+  /// Replace the value, keeping the header's length in step.
+  /// The `body` field must not be resized directly.
+  #[inline]
+  pub fn set_body(&mut self, body: Vec<u8>) {
+    self.header.bodylen = body.len() as u32;
+    self.body = body;
+  }
+  #[inline(always)]
+  pub fn set_opaque(&mut self, opaque: u32) {
+    self.header.opaque = opaque;
+  }
+  #[inline(always)]
+  pub fn set_opcode(&mut self, code: OpCode) {
+    self.header.code = code;
+  }
+  #[inline(always)]
   pub fn get_cas(&self) -> u64 {
       self.header.cas
   }
@@ -562,6 +632,11 @@ impl OwnedResponse {
   pub fn check_status(&self) -> Result<(),StatusField> {
     self.header.check_status()
   }
+  /// Raw Status Field, `StatusField::NoError` included
+  #[inline(always)]
+  pub fn get_status(&self) -> StatusField {
+    self.header.status
+  }
   #[inline(always)]
   pub fn has_extra(&self) -> bool {
     self.extra.len() != 0
@@ -574,6 +649,12 @@ impl OwnedResponse {
       None
     }
   }
+  /// Decode the extras field against this packet's opcode
+  /// and status
+  #[inline]
+  pub fn get_typed_extra(&self) -> ParseResult<ResExtras> {
+    ResExtras::parse(self.get_opcode(), self.header.get_status(), self.get_extra())
+  }
   #[inline(always)]
   pub fn has_key(&self) -> bool {
     self.key.len() != 0
@@ -613,4 +694,9 @@ impl OwnedResponse {
   }
 }
 
-
+/// A response to `req` carrying only a status, and the
+/// status's message for errors
+pub fn status_response(req: &OwnedRequest, status: StatusField) -> OwnedResponse {
+  let msg = status_message(status).as_bytes().to_vec();
+  OwnedResponse::new(req.get_opcode(), status, req.get_opaque(), 0, Vec::new(), Vec::new(), msg)
+}
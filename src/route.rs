@@ -0,0 +1,732 @@
+//! Route Handles
+//!
+//! A `Route` decides where a request goes and what is answered.
+//! Routes compose as a tree, in the style of mcrouter:
+//!
+//!* `PoolRoute` sends each request to one server of a
+//!  `ServerRing`, picked by key (`Flush` and `Verbosity` go to
+//!  all of them). It is the leaf of every tree.
+//!* `FailoverRoute` tries it's children in order, moving on
+//!  after `Busy` or `TemporaryFailure` (a failed connection is
+//!  answered with `TemporaryFailure`).
+//!* `AllSyncRoute` sends a request to every child and answers
+//!  the worst response.
+//!* `AllAsyncRoute` sends a request to every child and answers
+//!  at once, without waiting.
+//!* `WarmupRoute` reads from a cold pool, falls back to a warm
+//!  one on a miss and copies the item into the cold pool.
+//!
+//! Routes don't wait on the network. They are handed the
+//! request and a callback, which is called with the responses
+//! once they are known, usually on the thread reading them from
+//! the server. Each server connection is opened and written by
+//! a thread of it's own, and requests a route sends after an
+//! answer (a failover, or a warmup's backfill) are sent from
+//! the route's own thread, so a callback never waits on another
+//! server. A server that can't be reached is marked down for a
+//! while, see `PoolRoute`.
+//!
+//! With the `json` cargo feature `from_json` builds a tree from
+//! a config such as
+//!
+//!```text
+//! {
+//!   "pools": {
+//!     "a": { "servers": ["10.0.0.1:11211", "10.0.0.2:11211"] },
+//!     "b": { "servers": ["10.0.1.1:11211"], "distribution": "modulo" }
+//!   },
+//!   "route": {
+//!     "type": "failover",
+//!     "children": [ { "type": "pool", "pool": "a" }, { "type": "pool", "pool": "b" } ]
+//!   }
+//! }
+//!```
+//!
+//! Route `type`s are `pool` (naming a pool, or listing it's own
+//! `servers`), `failover`, `all-sync` and `all-async` (with
+//! `children`), and `warmup` (with `cold`, `warm` and an optional
+//! backfill `expiration`). Pools named in `pools` share their
+//! connections between every route using them.
+
+use super::opcode::OpCode;
+use super::status::{
+  StatusField,
+  status_message
+};
+use super::builder::RequestBuilder;
+use super::request::OwnedRequest;
+use super::response::{
+  OwnedResponse,
+  status_response
+};
+use super::stream::StreamDecoder;
+use super::cluster::{
+  Node,
+  ServerRing
+};
+use std::cmp;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::mem;
+use std::net::{
+  Shutdown,
+  TcpStream,
+  ToSocketAddrs
+};
+use std::sync::{
+  Arc,
+  Mutex
+};
+use std::sync::mpsc;
+use std::sync::mpsc::{
+  Receiver,
+  Sender
+};
+use std::sync::atomic::{
+  AtomicBool,
+  AtomicU32,
+  Ordering
+};
+use std::thread;
+use std::time::{
+  Duration,
+  Instant
+};
+
+/// How long to wait on a connection to a server
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a failed server is left alone before reconnecting
+pub const RETRY_MIN: Duration = Duration::from_millis(500);
+
+/// The longest a server that keeps failing is left alone
+pub const RETRY_MAX: Duration = Duration::from_secs(30);
+
+/// Receives the responses to a routed request. All but the last
+/// are `Stat` entries, the last is never missing.
+pub type Callback = Box<dyn FnOnce(Vec<OwnedResponse>) + Send>;
+
+/// Decides where requests go
+pub trait Route: Send + Sync {
+  /// Send `req` on it's way, `done` is called exactly once with
+  /// it's responses. Quiet requests are still answered.
+  fn route(&self, req: OwnedRequest, done: Callback);
+}
+
+/// How bad a status is, for `AllSyncRoute`. Misses and other
+/// per item answers are better then server errors, which are
+/// better then an unavailable server.
+#[inline]
+pub fn severity(status: StatusField) -> u8 {
+  match status {
+    StatusField::NoError => 0,
+    StatusField::Busy |
+    StatusField::TemporaryFailure => 3,
+    StatusField::UnknownCommand |
+    StatusField::OutOfMemory |
+    StatusField::NotSupported |
+    StatusField::InternalError => 2,
+    _ => 1
+  }
+}
+
+/// Status of the final response
+#[inline]
+fn last_status(resps: &[OwnedResponse]) -> StatusField {
+  resps.last().map(|r| r.get_status()).unwrap_or(StatusField::TemporaryFailure)
+}
+
+/// A request forwarded upstream, waiting for it's response
+struct Pending {
+  done: Callback,
+  opaque: u32,
+  code: OpCode,
+  stats: Vec<OwnedResponse>
+}
+impl Pending {
+  /// Answer with `TemporaryFailure`
+  fn fail(self) {
+    let msg = status_message(StatusField::TemporaryFailure).as_bytes().to_vec();
+    let resp = OwnedResponse::new(self.code, StatusField::TemporaryFailure, self.opaque, 0, Vec::new(), Vec::new(), msg);
+    (self.done)(vec![resp]);
+  }
+}
+
+/// One connection to an upstream server, shared by every
+/// request routed to it
+///
+/// Requests are queued for a writer thread, which opens the
+/// connection and then writes them in order. A reader thread
+/// answers them, so forwarding a request never waits on the
+/// network.
+struct Upstream {
+  queue: Mutex<Sender<Vec<u8>>>,
+  pending: Mutex<HashMap<u32, Pending>>,
+  dead: AtomicBool,
+  connected: AtomicBool,
+  died: Mutex<Option<Instant>>,
+  next: AtomicU32
+}
+impl Upstream {
+  /// Start connecting to `node`
+  fn start(node: &Node) -> Arc<Upstream> {
+    let (tx, rx) = mpsc::channel();
+    let upstream = Arc::new(Upstream {
+      queue: Mutex::new(tx),
+      pending: Mutex::new(HashMap::new()),
+      dead: AtomicBool::new(false),
+      connected: AtomicBool::new(false),
+      died: Mutex::new(None),
+      next: AtomicU32::new(0)
+    });
+    let shared = upstream.clone();
+    let node = node.clone();
+    thread::spawn(move || {
+      let conn = match Upstream::open(&node) {
+        Ok(conn) => conn,
+        Err(_) => return shared.fail_all()
+      };
+      let reader = match conn.try_clone() {
+        Ok(reader) => reader,
+        Err(_) => return shared.fail_all()
+      };
+      shared.connected.store(true, Ordering::SeqCst);
+      thread::spawn(move || shared.read_responses(reader));
+      write_requests(conn, rx);
+    });
+    upstream
+  }
+  /// Connect to the first address of `node` that answers within
+  /// `CONNECT_TIMEOUT`
+  fn open(node: &Node) -> io::Result<TcpStream> {
+    let mut err = io::Error::new(io::ErrorKind::NotFound, "the server has no address");
+    for addr in (node.host.as_str(), node.port).to_socket_addrs()? {
+      match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+        Ok(conn) => {
+          conn.set_nodelay(true)?;
+          return Ok(conn);
+        },
+        Err(e) => err = e
+      };
+    }
+    Err(err)
+  }
+  /// When the connection failed, if it has
+  fn down_since(&self) -> Option<Instant> {
+    if self.dead.load(Ordering::SeqCst) {
+      *self.died.lock().unwrap()
+    } else {
+      None
+    }
+  }
+  /// Forward `req` in it's loud form under a fresh opaque. If
+  /// it cannot be sent it is answered with `TemporaryFailure`.
+  fn forward(&self, mut req: OwnedRequest, done: Callback) {
+    let pending = Pending {
+      done,
+      opaque: req.get_opaque(),
+      code: req.get_opcode(),
+      stats: Vec::new()
+    };
+    let opaque = self.next.fetch_add(1, Ordering::Relaxed);
+    {
+      let mut map = self.pending.lock().unwrap();
+      if self.dead.load(Ordering::SeqCst) {
+        drop(map);
+        return pending.fail();
+      }
+      map.insert(opaque, pending);
+    }
+    req.set_opaque(opaque);
+    req.set_opcode(req.get_opcode().loud_form());
+    if self.queue.lock().unwrap().send(req.encode_self().get_vec()).is_err() {
+      //the writer has stopped, the reader fails everything else
+      let failed = self.pending.lock().unwrap().remove(&opaque);
+      if let Option::Some(p) = failed {
+        p.fail();
+      }
+    }
+  }
+  /// Answer the requests waiting on responses until the
+  /// connection fails, then fail everything still pending
+  fn read_responses(&self, mut conn: TcpStream) {
+    let mut decoder = StreamDecoder::<OwnedResponse>::new();
+    'read: loop {
+      loop {
+        let mut resp = match decoder.next_packet() {
+          Ok(Option::Some(resp)) => resp,
+          Ok(Option::None) => break,
+          Err(_) => break 'read
+        };
+        let mut map = self.pending.lock().unwrap();
+        let stats = resp.get_opcode() == OpCode::Stat && !resp.key.is_empty();
+        let opaque = resp.get_opaque();
+        let mut p = match map.remove(&opaque) {
+          Option::Some(p) => p,
+          Option::None => continue
+        };
+        resp.set_opaque(p.opaque);
+        resp.set_opcode(p.code);
+        if stats {
+          p.stats.push(resp);
+          map.insert(opaque, p);
+          continue;
+        }
+        drop(map);
+        let mut resps = mem::take(&mut p.stats);
+        resps.push(resp);
+        (p.done)(resps);
+      }
+      match decoder.read_from(&mut conn) {
+        Ok(n) if n > 0 => { },
+        _ => break
+      };
+    }
+    let _ = conn.shutdown(Shutdown::Both);
+    self.fail_all();
+  }
+  /// Mark the connection dead and fail every pending request
+  fn fail_all(&self) {
+    let failed: Vec<Pending> = {
+      let mut map = self.pending.lock().unwrap();
+      self.dead.store(true, Ordering::SeqCst);
+      *self.died.lock().unwrap() = Some(Instant::now());
+      map.drain().map(|(_, p)| p).collect()
+    };
+    for p in failed {
+      p.fail();
+    }
+  }
+}
+
+/// Write queued requests to `conn` until it fails or the
+/// `Upstream` is gone
+fn write_requests(mut conn: TcpStream, queue: Receiver<Vec<u8>>) {
+  while let Ok(mut buf) = queue.recv() {
+    while let Ok(more) = queue.try_recv() {
+      buf.extend_from_slice(&more);
+    }
+    if conn.write_all(&buf).is_err() {
+      break;
+    }
+  }
+  //wakes the reader, which fails whatever is pending
+  let _ = conn.shutdown(Shutdown::Both);
+}
+
+/// A server's connection, and how long to leave it down
+struct Slot {
+  upstream: Option<Arc<Upstream>>,
+  backoff: Duration
+}
+
+/// Routes each request by key to one server of a ring
+///
+/// Every server gets one connection, opened on first use and
+/// shared by all requests. Requests are multiplexed on it under
+/// fresh opaques, the responses carry the original opaque and
+/// opcode. `Flush` and `Verbosity` go to every server, and are
+/// answered like `AllSyncRoute` would. Other requests without a
+/// key (`Stat`, `Version`, ...) go to the node
+/// `ServerRing::node_for_key(b"")` picks.
+///
+/// A failed server answers `TemporaryFailure`. It is marked
+/// down, and reconnected by the first request routed to it
+/// after `RETRY_MIN`. Every connection attempt that fails
+/// doubles that wait, up to `RETRY_MAX`.
+pub struct PoolRoute {
+  ring: ServerRing,
+  upstreams: Vec<Mutex<Slot>>
+}
+impl PoolRoute {
+  #[inline]
+  pub fn new(ring: ServerRing) -> PoolRoute {
+    let upstreams = ring.nodes().iter().map(|_| Mutex::new(Slot {
+      upstream: None,
+      backoff: RETRY_MIN
+    })).collect();
+    PoolRoute {
+      ring,
+      upstreams
+    }
+  }
+  #[inline(always)]
+  pub fn get_ring(&self) -> &ServerRing {
+    &self.ring
+  }
+  /// Connection to node `index`, started if there is no live
+  /// one. `None` while the node is down.
+  fn upstream(&self, index: usize) -> Option<Arc<Upstream>> {
+    let mut slot = self.upstreams[index].lock().unwrap();
+    if let Option::Some(u) = slot.upstream.clone() {
+      let connected = u.connected.load(Ordering::SeqCst);
+      let wait = if connected { RETRY_MIN } else { slot.backoff };
+      match u.down_since() {
+        Option::None => return Some(u),
+        Option::Some(since) if since.elapsed() < wait => return None,
+        Option::Some(_) => { }
+      };
+      slot.backoff = if connected { RETRY_MIN } else { cmp::min(slot.backoff * 2, RETRY_MAX) };
+    }
+    let u = Upstream::start(&self.ring.nodes()[index]);
+    slot.upstream = Some(u.clone());
+    Some(u)
+  }
+  /// Forward `req` to node `index`
+  fn send(&self, index: usize, req: OwnedRequest, done: Callback) {
+    match self.upstream(index) {
+      Option::Some(u) => u.forward(req, done),
+      Option::None => done(vec![status_response(&req, StatusField::TemporaryFailure)])
+    };
+  }
+}
+impl Route for PoolRoute {
+  fn route(&self, req: OwnedRequest, done: Callback) {
+    match req.get_opcode().loud_form() {
+      OpCode::Flush |
+      OpCode::Verbosity if self.upstreams.len() > 1 => {
+        let state = AllSync::new(self.upstreams.len(), done);
+        for i in 0..self.upstreams.len() {
+          self.send(i, req.clone(), AllSync::answer(&state, i));
+        }
+        return;
+      },
+      _ => { }
+    };
+    match self.ring.node_for_key(req.get_key().unwrap_or(b"")) {
+      Option::Some(n) => self.send(n, req, done),
+      Option::None => done(vec![status_response(&req, StatusField::TemporaryFailure)])
+    };
+  }
+}
+
+/// Work handed to a `Dispatcher`
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Runs follow up requests on it's own thread, so they are
+/// never routed from a thread reading upstream responses
+struct Dispatcher {
+  queue: Mutex<Sender<Job>>
+}
+impl Dispatcher {
+  fn new() -> Arc<Dispatcher> {
+    let (tx, rx) = mpsc::channel::<Job>();
+    thread::spawn(move || {
+      for job in rx {
+        job();
+      }
+    });
+    Arc::new(Dispatcher {
+      queue: Mutex::new(tx)
+    })
+  }
+  /// Queue `job`, or run it here if the thread is gone
+  fn run(&self, job: Job) {
+    let failed = self.queue.lock().unwrap().send(job).err();
+    if let Option::Some(mpsc::SendError(job)) = failed {
+      job();
+    }
+  }
+}
+
+/// Try `children[index..]` in order until one is available
+fn failover(children: Arc<Vec<Arc<dyn Route>>>, dispatch: Arc<Dispatcher>, index: usize, req: OwnedRequest, done: Callback) {
+  if index + 1 >= children.len() {
+    return children[index].route(req, done);
+  }
+  let retry = req.clone();
+  let next = children.clone();
+  let queue = dispatch.clone();
+  children[index].route(req, Box::new(move |resps| {
+    match last_status(&resps) {
+      StatusField::Busy |
+      StatusField::TemporaryFailure => queue.run(Box::new(move || failover(next, dispatch, index + 1, retry, done))),
+      _ => done(resps)
+    };
+  }));
+}
+
+/// Sends a request to it's first child, moving on to the next
+/// when a child answers `Busy` or `TemporaryFailure`. The last
+/// child's answer is final.
+pub struct FailoverRoute {
+  children: Arc<Vec<Arc<dyn Route>>>,
+  dispatch: Arc<Dispatcher>
+}
+impl FailoverRoute {
+  /// Panics if `children` is empty
+  #[inline]
+  pub fn new(children: Vec<Arc<dyn Route>>) -> FailoverRoute {
+    assert!(!children.is_empty(), "a failover route needs children");
+    FailoverRoute {
+      children: Arc::new(children),
+      dispatch: Dispatcher::new()
+    }
+  }
+}
+impl Route for FailoverRoute {
+  fn route(&self, req: OwnedRequest, done: Callback) {
+    failover(self.children.clone(), self.dispatch.clone(), 0, req, done);
+  }
+}
+
+/// The answers `AllSyncRoute` has collected, by child
+struct AllSync {
+  answers: Vec<Option<Vec<OwnedResponse>>>,
+  remaining: usize,
+  done: Option<Callback>
+}
+impl AllSync {
+  /// Wait on `count` answers, then call `done` with the worst
+  fn new(count: usize, done: Callback) -> Arc<Mutex<AllSync>> {
+    Arc::new(Mutex::new(AllSync {
+      answers: (0..count).map(|_| None).collect(),
+      remaining: count,
+      done: Some(done)
+    }))
+  }
+  /// Records the answer of child `i`
+  fn answer(state: &Arc<Mutex<AllSync>>, i: usize) -> Callback {
+    let state = state.clone();
+    Box::new(move |resps| {
+      let mut s = state.lock().unwrap();
+      s.answers[i] = Some(resps);
+      s.remaining -= 1;
+      if s.remaining == 0 {
+        let worst = s.worst();
+        let done = s.done.take().unwrap();
+        drop(s);
+        done(worst);
+      }
+    })
+  }
+  /// The worst answer, the earliest child winning ties
+  fn worst(&mut self) -> Vec<OwnedResponse> {
+    let mut worst = 0;
+    for i in 1..self.answers.len() {
+      let rank = |a: &Option<Vec<OwnedResponse>>| a.as_ref().map(|r| severity(last_status(r))).unwrap_or(0);
+      if rank(&self.answers[i]) > rank(&self.answers[worst]) {
+        worst = i;
+      }
+    }
+    self.answers[worst].take().unwrap_or_default()
+  }
+}
+
+/// Sends a request to every child and answers with the worst
+/// response (by `severity`) once all have answered. The first
+/// child's response wins ties.
+///
+/// This is meant for mutations, so every replica holds the
+/// same items.
+pub struct AllSyncRoute {
+  children: Vec<Arc<dyn Route>>
+}
+impl AllSyncRoute {
+  /// Panics if `children` is empty
+  #[inline]
+  pub fn new(children: Vec<Arc<dyn Route>>) -> AllSyncRoute {
+    assert!(!children.is_empty(), "an all sync route needs children");
+    AllSyncRoute {
+      children
+    }
+  }
+}
+impl Route for AllSyncRoute {
+  fn route(&self, req: OwnedRequest, done: Callback) {
+    let state = AllSync::new(self.children.len(), done);
+    for (i, child) in self.children.iter().enumerate() {
+      child.route(req.clone(), AllSync::answer(&state, i));
+    }
+  }
+}
+
+/// Sends a request to every child without waiting. Mutations
+/// are answered with success, anything else with
+/// `KeyNotFound`, straight away.
+pub struct AllAsyncRoute {
+  children: Vec<Arc<dyn Route>>
+}
+impl AllAsyncRoute {
+  #[inline]
+  pub fn new(children: Vec<Arc<dyn Route>>) -> AllAsyncRoute {
+    AllAsyncRoute {
+      children
+    }
+  }
+}
+impl Route for AllAsyncRoute {
+  fn route(&self, req: OwnedRequest, done: Callback) {
+    for child in self.children.iter() {
+      child.route(req.clone(), Box::new(|_| { }));
+    }
+    let status = match req.get_opcode().loud_form() {
+      OpCode::Get |
+      OpCode::GetK |
+      OpCode::GAT |
+      OpCode::Stat |
+      OpCode::Version => StatusField::KeyNotFound,
+      _ => StatusField::NoError
+    };
+    done(vec![status_response(&req, status)]);
+  }
+}
+
+/// Warms a cold pool up from a warm one
+///
+/// Gets are sent to the cold pool, on a miss they are retried
+/// against the warm pool and a hit is added to the cold pool
+/// (with the item's flags, expiring after `expiration`). All
+/// other requests only go to the cold pool.
+pub struct WarmupRoute {
+  cold: Arc<dyn Route>,
+  warm: Arc<dyn Route>,
+  expiration: u32,
+  dispatch: Arc<Dispatcher>
+}
+impl WarmupRoute {
+  #[inline]
+  pub fn new(cold: Arc<dyn Route>, warm: Arc<dyn Route>, expiration: u32) -> WarmupRoute {
+    WarmupRoute {
+      cold,
+      warm,
+      expiration,
+      dispatch: Dispatcher::new()
+    }
+  }
+}
+impl Route for WarmupRoute {
+  fn route(&self, req: OwnedRequest, done: Callback) {
+    match req.get_opcode().loud_form() {
+      OpCode::Get |
+      OpCode::GetK |
+      OpCode::GAT => { },
+      _ => return self.cold.route(req, done)
+    };
+    let retry = req.clone();
+    let cold = self.cold.clone();
+    let warm = self.warm.clone();
+    let expiration = self.expiration;
+    let dispatch = self.dispatch.clone();
+    self.cold.route(req, Box::new(move |resps| {
+      if last_status(&resps) != StatusField::KeyNotFound {
+        return done(resps);
+      }
+      let key = retry.get_key().unwrap_or(b"").to_vec();
+      let queue = dispatch.clone();
+      queue.run(Box::new(move || warm.route(retry, Box::new(move |resps| {
+        let backfill = match resps.last() {
+          Option::Some(r) if r.get_status() == StatusField::NoError && r.extra.len() == 4 => {
+            let flags = r.extra.iter().fold(0u32, |f, b| (f << 8) | *b as u32);
+            RequestBuilder::add(&key, &r.body).flags(flags).expire(expiration).build().ok()
+          },
+          _ => None
+        };
+        if let Option::Some(add) = backfill {
+          dispatch.run(Box::new(move || cold.route(add, Box::new(|_| { }))));
+        }
+        done(resps);
+      }))));
+    }));
+  }
+}
+
+/// Invalid route configurations
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum ConfigError {
+  /// The config is not valid JSON, or lacks a field
+  Json(String),
+  /// A route `type` which does not exist
+  UnknownRoute(String),
+  /// A `pool` route names a pool which is not defined
+  UnknownPool(String),
+  /// A server is not `HOST:PORT`
+  BadServer(String),
+  /// A pool without servers, or a route without children
+  Empty
+}
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      ConfigError::Json(ref e) => write!(f, "invalid config: {}", e),
+      ConfigError::UnknownRoute(ref t) => write!(f, "unknown route type {}", t),
+      ConfigError::UnknownPool(ref p) => write!(f, "unknown pool {}", p),
+      ConfigError::BadServer(ref s) => write!(f, "{} is not HOST:PORT", s),
+      ConfigError::Empty => write!(f, "empty pool or route")
+    }
+  }
+}
+impl Error for ConfigError { }
+
+/// Build a route tree from a JSON config, see the module docs
+#[cfg(feature = "json")]
+pub fn from_json(config: &str) -> Result<Arc<dyn Route>, ConfigError> {
+  use super::cluster::Distribution;
+  use serde_json::Value;
+
+  fn field<'a>(v: &'a Value, name: &str) -> Result<&'a Value, ConfigError> {
+    v.get(name).ok_or_else(|| ConfigError::Json(format!("missing {}", name)))
+  }
+  fn pool(v: &Value) -> Result<Arc<dyn Route>, ConfigError> {
+    let servers = field(v, "servers")?.as_array()
+      .ok_or_else(|| ConfigError::Json("servers is not an array".to_string()))?
+      .iter()
+      .map(|s| {
+        let s = s.as_str().ok_or_else(|| ConfigError::Json("server is not a string".to_string()))?;
+        s.parse::<Node>().map_err(|_| ConfigError::BadServer(s.to_string()))
+      })
+      .collect::<Result<Vec<Node>, ConfigError>>()?;
+    if servers.is_empty() {
+      return Err(ConfigError::Empty);
+    }
+    let distribution = match v.get("distribution").and_then(|d| d.as_str()) {
+      Option::None | Option::Some("ketama") => Distribution::Ketama,
+      Option::Some("modulo") => Distribution::Modulo,
+      Option::Some(x) => return Err(ConfigError::Json(format!("unknown distribution {}", x)))
+    };
+    Ok(Arc::new(PoolRoute::new(ServerRing::new(servers, distribution))))
+  }
+  fn route(v: &Value, pools: &HashMap<String, Arc<dyn Route>>) -> Result<Arc<dyn Route>, ConfigError> {
+    let children = |v: &Value| -> Result<Vec<Arc<dyn Route>>, ConfigError> {
+      let list = field(v, "children")?.as_array()
+        .ok_or_else(|| ConfigError::Json("children is not an array".to_string()))?;
+      if list.is_empty() {
+        return Err(ConfigError::Empty);
+      }
+      list.iter().map(|c| route(c, pools)).collect()
+    };
+    let kind = field(v, "type")?.as_str()
+      .ok_or_else(|| ConfigError::Json("type is not a string".to_string()))?;
+    Ok(match kind {
+      "pool" => match v.get("pool").and_then(|p| p.as_str()) {
+        Option::Some(name) => pools.get(name).cloned().ok_or_else(|| ConfigError::UnknownPool(name.to_string()))?,
+        Option::None => pool(v)?
+      },
+      "failover" => Arc::new(FailoverRoute::new(children(v)?)),
+      "all-sync" => Arc::new(AllSyncRoute::new(children(v)?)),
+      "all-async" => Arc::new(AllAsyncRoute::new(children(v)?)),
+      "warmup" => {
+        let expiration = match v.get("expiration") {
+          Option::Some(e) => e.as_u64().filter(|e| *e <= u32::MAX as u64)
+            .ok_or_else(|| ConfigError::Json("expiration is not a u32".to_string()))? as u32,
+          Option::None => 0
+        };
+        Arc::new(WarmupRoute::new(route(field(v, "cold")?, pools)?, route(field(v, "warm")?, pools)?, expiration))
+      },
+      x => return Err(ConfigError::UnknownRoute(x.to_string()))
+    })
+  }
+
+  let root: Value = serde_json::from_str(config).map_err(|e| ConfigError::Json(e.to_string()))?;
+  let mut pools = HashMap::new();
+  if let Option::Some(defs) = root.get("pools") {
+    let defs = defs.as_object().ok_or_else(|| ConfigError::Json("pools is not an object".to_string()))?;
+    for (name, def) in defs.iter() {
+      pools.insert(name.clone(), pool(def)?);
+    }
+  }
+  route(field(&root, "route")?, &pools)
+}
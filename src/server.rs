@@ -0,0 +1,485 @@
+//! Memcached Server Framework
+//!
+//! Implement `Handler` for a storage backend, then hand
+//! connections to `serve_connection` (or a listener to `serve`).
+//! The driver decodes requests, dispatches them to the handler,
+//! and writes back correctly shaped responses.
+//!
+//! Handlers only deal in values and `StatusField` errors. The
+//! driver takes care of the wire details: echoing the opcode
+//! and `opaque`, the extras/key/body layout of each response,
+//! and staying silent for quiet opcodes when they succeed (or
+//! for quiet gets when they miss).
+
+use super::opcode::OpCode;
+use super::status::StatusField;
+use super::datatype::DataType;
+use super::request::OwnedRequest;
+use super::response::{
+  OwnedResponse,
+  status_response
+};
+use super::extras::{
+  Extras,
+  ReqExtras,
+  GetResponseExtras
+};
+use super::stream::StreamDecoder;
+use std::io::{
+  self,
+  Read,
+  Write
+};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread;
+
+/// Result of a handler call. Errors are sent to the client as
+/// the response's status.
+pub type HandlerResult<T> = Result<T, StatusField>;
+
+/// State kept for each connection
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct Session {
+  /// Set once a SASL exchange has completed
+  pub authenticated: bool,
+  /// The user name authenticated as, if the mechanism has one
+  pub user: Option<String>,
+  /// The mechanism of a SASL exchange in progress
+  pub mechanism: Option<String>,
+  /// Scratch space for multi step SASL mechanisms
+  pub sasl_state: Vec<u8>
+}
+
+/// A stored item, as returned to the `Get` family
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Value {
+  pub value: Vec<u8>,
+  pub flags: u32,
+  pub cas: u64,
+  pub datatype: DataType
+}
+
+/// How a store request treats an existing item
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum StoreMode {
+  /// Store unconditionally
+  Set,
+  /// Only store if the key does not exist
+  Add,
+  /// Only store if the key exists
+  Replace,
+  /// Add to the end of an existing value
+  Append,
+  /// Add to the start of an existing value
+  Prepend
+}
+
+/// A `Set`, `Add`, `Replace`, `Append`, or `Prepend` request
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct StoreRequest<'a> {
+  pub mode: StoreMode,
+  pub key: &'a [u8],
+  pub value: &'a [u8],
+  /// Always `0` for `Append`/`Prepend`
+  pub flags: u32,
+  /// Always `0` for `Append`/`Prepend`
+  pub expiration: u32,
+  /// If non zero the item's CAS must match
+  pub cas: u64,
+  pub datatype: DataType
+}
+
+/// Direction of an arithmetic request
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum ArithMode {
+  Increment,
+  Decrement
+}
+
+/// An `Increment` or `Decrement` request
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct ArithRequest<'a> {
+  pub mode: ArithMode,
+  pub key: &'a [u8],
+  pub delta: u64,
+  pub initial: u64,
+  /// `0xFFFFFFFF` means a missing counter is not created
+  pub expiration: u32,
+  /// If non zero the item's CAS must match
+  pub cas: u64
+}
+
+/// Progress of a SASL exchange
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum Sasl {
+  /// Authentication succeeded, the data is sent to the client
+  Done(Vec<u8>),
+  /// The mechanism needs another step. The challenge is sent
+  /// with `StatusField::AuthContinue`.
+  Continue(Vec<u8>)
+}
+
+/// Storage Backend
+///
+/// One method per command family. Methods take `&self` as one
+/// handler is shared by every connection, implementations
+/// provide their own locking.
+pub trait Handler: Send + Sync {
+
+  /// Read an item. A miss is `Err(StatusField::KeyNotFound)`.
+  fn get(&self, key: &[u8]) -> HandlerResult<Value>;
+
+  /// Store an item, returning it's new CAS value
+  fn set(&self, req: StoreRequest) -> HandlerResult<u64>;
+
+  /// Remove an item. `cas` is checked when non zero.
+  fn delete(&self, key: &[u8], cas: u64) -> HandlerResult<()>;
+
+  /// Apply an increment or decrement, returning the new value
+  /// and CAS.
+  fn arith(&self, req: ArithRequest) -> HandlerResult<(u64, u64)>;
+
+  /// Change the expiration of an item
+  fn touch(&self, key: &[u8], expiration: u32) -> HandlerResult<()>;
+
+  /// Get And Touch. By default `touch` then `get`.
+  fn gat(&self, key: &[u8], expiration: u32) -> HandlerResult<Value> {
+    self.touch(key, expiration)?;
+    self.get(key)
+  }
+
+  /// Invalidate every item, optionally after a delay
+  fn flush(&self, delay: Option<u32>) -> HandlerResult<()>;
+
+  /// Statistics, optionally for a named group
+  fn stat(&self, group: Option<&[u8]>) -> HandlerResult<Vec<(String, String)>> {
+    match group {
+      Option::None => Ok(Vec::new()),
+      Option::Some(_) => Err(StatusField::KeyNotFound)
+    }
+  }
+
+  /// The version string
+  fn version(&self) -> String {
+    env!("CARGO_PKG_VERSION").to_string()
+  }
+
+  /// Change the logging level
+  fn verbosity(&self, _level: u32) -> HandlerResult<()> {
+    Ok(())
+  }
+
+  /// Space separated SASL mechanisms
+  fn sasl_mechs(&self) -> HandlerResult<Vec<u8>> {
+    Err(StatusField::UnknownCommand)
+  }
+
+  /// Start a SASL exchange with `mechanism`
+  fn sasl_auth(&self, _session: &mut Session, _mechanism: &[u8], _data: &[u8]) -> HandlerResult<Sasl> {
+    Err(StatusField::UnknownCommand)
+  }
+
+  /// Continue a SASL exchange
+  fn sasl_step(&self, _session: &mut Session, _mechanism: &[u8], _data: &[u8]) -> HandlerResult<Sasl> {
+    Err(StatusField::UnknownCommand)
+  }
+
+  /// Checked before every request. Refused requests are
+  /// answered with `StatusField::AuthError`. `code` is always
+  /// the loud form of the opcode.
+  fn allow(&self, _session: &Session, _code: OpCode) -> bool {
+    true
+  }
+}
+
+/// The responses to a single request
+#[derive(Default)]
+pub struct Reply {
+  /// Possibly empty for quiet requests
+  pub responses: Vec<OwnedResponse>,
+  /// The client asked for the connection to be closed
+  pub close: bool
+}
+
+/// Response echoing the request's opcode and `opaque`
+#[inline]
+fn respond(req: &OwnedRequest, status: StatusField, cas: u64, extra: Vec<u8>, key: Vec<u8>, body: Vec<u8>) -> OwnedResponse {
+  OwnedResponse::new(req.get_opcode(), status, req.get_opaque(), cas, extra, key, body)
+}
+
+/// Commands which operate on a key require one
+#[inline]
+fn keyed(req: &OwnedRequest) -> HandlerResult<&[u8]> {
+  match req.get_key() {
+    Option::Some(k) => Ok(k),
+    Option::None => Err(StatusField::InvalidArguments)
+  }
+}
+
+/// Response to a `Get` family hit
+fn hit(req: &OwnedRequest, loud: OpCode, v: Value) -> OwnedResponse {
+  let key = if loud == OpCode::GetK { req.key.clone() } else { Vec::new() };
+  let mut resp = respond(req, StatusField::NoError, v.cas, GetResponseExtras::new(v.flags).to_vec(), key, v.value);
+  resp.set_datatype(v.datatype);
+  resp
+}
+
+/// Store requests with their extras decoded
+fn store<'a>(req: &'a OwnedRequest, mode: StoreMode, extras: &ReqExtras) -> HandlerResult<StoreRequest<'a>> {
+  let (flags, expiration) = match *extras {
+    ReqExtras::Store(ref e) => (e.flags, e.expiration),
+    _ => (0, 0)
+  };
+  Ok(StoreRequest {
+    mode,
+    key: keyed(req)?,
+    value: &req.body,
+    flags,
+    expiration,
+    cas: req.get_cas(),
+    datatype: req.get_datatype()
+  })
+}
+
+/// Run one request against the handler. Every response the
+/// request should produce is returned, in order.
+pub fn dispatch<H: Handler + ?Sized>(h: &H, session: &mut Session, req: &OwnedRequest) -> Reply {
+  let code = req.get_opcode();
+  let loud = code.loud_form();
+  let mut reply = Reply::default();
+  if !h.allow(session, loud) {
+    reply.responses.push(status_response(req, StatusField::AuthError));
+    return reply;
+  }
+  let extras = match req.get_typed_extra() {
+    Ok(e) => e,
+    Err(_) => {
+      reply.responses.push(status_response(req, StatusField::InvalidArguments));
+      return reply;
+    }
+  };
+  let get_family = matches!(loud, OpCode::Get | OpCode::GetK | OpCode::GAT);
+  let result: HandlerResult<Vec<OwnedResponse>> = match (loud, &extras) {
+    (OpCode::Get, _) |
+    (OpCode::GetK, _) => keyed(req)
+      .and_then(|k| h.get(k))
+      .map(|v| vec![hit(req, loud, v)]),
+    (OpCode::GAT, ReqExtras::Touch(e)) => keyed(req)
+      .and_then(|k| h.gat(k, e.expiration))
+      .map(|v| vec![hit(req, loud, v)]),
+    (OpCode::Set, _) |
+    (OpCode::Add, _) |
+    (OpCode::Replace, _) |
+    (OpCode::Append, _) |
+    (OpCode::Prepare, _) => {
+      let mode = match loud {
+        OpCode::Set => StoreMode::Set,
+        OpCode::Add => StoreMode::Add,
+        OpCode::Replace => StoreMode::Replace,
+        OpCode::Append => StoreMode::Append,
+        _ => StoreMode::Prepend
+      };
+      store(req, mode, &extras)
+        .and_then(|s| h.set(s))
+        .map(|cas| vec![respond(req, StatusField::NoError, cas, Vec::new(), Vec::new(), Vec::new())])
+    },
+    (OpCode::Delete, _) => keyed(req)
+      .and_then(|k| h.delete(k, req.get_cas()))
+      .map(|_| vec![respond(req, StatusField::NoError, 0, Vec::new(), Vec::new(), Vec::new())]),
+    (OpCode::Increment, ReqExtras::Arith(e)) |
+    (OpCode::Decrement, ReqExtras::Arith(e)) => keyed(req)
+      .and_then(|k| h.arith(ArithRequest {
+        mode: if loud == OpCode::Increment { ArithMode::Increment } else { ArithMode::Decrement },
+        key: k,
+        delta: e.delta,
+        initial: e.initial,
+        expiration: e.expiration,
+        cas: req.get_cas()
+      }))
+      .map(|(value, cas)| vec![respond(req, StatusField::NoError, cas, Vec::new(), Vec::new(), value.to_be_bytes().to_vec())]),
+    (OpCode::Touch, ReqExtras::Touch(e)) => keyed(req)
+      .and_then(|k| h.touch(k, e.expiration))
+      .map(|_| vec![respond(req, StatusField::NoError, 0, Vec::new(), Vec::new(), Vec::new())]),
+    (OpCode::Flush, ReqExtras::Flush(e)) => h.flush(e.expiration)
+      .map(|_| vec![respond(req, StatusField::NoError, 0, Vec::new(), Vec::new(), Vec::new())]),
+    (OpCode::Verbosity, ReqExtras::Verbosity(e)) => h.verbosity(e.verbosity)
+      .map(|_| vec![respond(req, StatusField::NoError, 0, Vec::new(), Vec::new(), Vec::new())]),
+    (OpCode::Nop, _) => Ok(vec![respond(req, StatusField::NoError, 0, Vec::new(), Vec::new(), Vec::new())]),
+    (OpCode::Quit, _) => {
+      reply.close = true;
+      Ok(vec![respond(req, StatusField::NoError, 0, Vec::new(), Vec::new(), Vec::new())])
+    },
+    (OpCode::Version, _) => Ok(vec![respond(req, StatusField::NoError, 0, Vec::new(), Vec::new(), h.version().into_bytes())]),
+    (OpCode::Stat, _) => h.stat(req.get_key()).map(|stats| {
+      let mut v: Vec<OwnedResponse> = stats.into_iter()
+        .map(|(k, val)| respond(req, StatusField::NoError, 0, Vec::new(), k.into_bytes(), val.into_bytes()))
+        .collect();
+      v.push(respond(req, StatusField::NoError, 0, Vec::new(), Vec::new(), Vec::new()));
+      v
+    }),
+    (OpCode::SASLlistmech, _) => h.sasl_mechs()
+      .map(|m| vec![respond(req, StatusField::NoError, 0, Vec::new(), Vec::new(), m)]),
+    (OpCode::SASLAuth, _) |
+    (OpCode::SASLStep, _) => {
+      let mech = req.get_key().unwrap_or(&[]);
+      let step = if loud == OpCode::SASLAuth {
+        h.sasl_auth(session, mech, &req.body)
+      } else {
+        h.sasl_step(session, mech, &req.body)
+      };
+      step.map(|s| match s {
+        Sasl::Done(data) => vec![respond(req, StatusField::NoError, 0, Vec::new(), Vec::new(), data)],
+        Sasl::Continue(data) => vec![respond(req, StatusField::AuthContinue, 0, Vec::new(), Vec::new(), data)]
+      })
+    },
+    //known commands whose extras don't fit
+    (OpCode::GAT, _) |
+    (OpCode::Touch, _) |
+    (OpCode::Increment, _) |
+    (OpCode::Decrement, _) |
+    (OpCode::Flush, _) |
+    (OpCode::Verbosity, _) => Err(StatusField::InvalidArguments),
+    _ => Err(StatusField::UnknownCommand)
+  };
+  match result {
+    //quiet commands are silent on success, except for get hits
+    Ok(_) if code.is_quiet() && !get_family => { },
+    Ok(responses) => reply.responses = responses,
+    //quiet gets are silent on a miss
+    Err(StatusField::KeyNotFound) if code.is_quiet() && get_family => { },
+    Err(status) => reply.responses.push(status_response(req, status))
+  };
+  if loud == OpCode::Quit {
+    reply.close = true;
+  }
+  reply
+}
+
+/// Serve a single connection until the client disconnects or
+/// sends `Quit`.
+///
+/// Responses to every request that arrived in one read are
+/// written together. A malformed packet closes the connection
+/// with an `InvalidData` error.
+pub fn serve_connection<S: Read + Write, H: Handler + ?Sized>(mut stream: S, handler: &H) -> io::Result<()> {
+  let mut decoder = StreamDecoder::<OwnedRequest>::new();
+  let mut session = Session::default();
+  let mut out = Vec::new();
+  loop {
+    loop {
+      let req = match decoder.next_packet() {
+        Ok(Option::Some(req)) => req,
+        Ok(Option::None) => break,
+        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e))
+      };
+      let reply = dispatch(handler, &mut session, &req);
+      for resp in reply.responses.iter() {
+        out.extend_from_slice(resp.encode_self().as_slice());
+      }
+      if reply.close {
+        stream.write_all(&out)?;
+        return stream.flush();
+      }
+    }
+    if !out.is_empty() {
+      stream.write_all(&out)?;
+      stream.flush()?;
+      out.clear();
+    }
+    if decoder.read_from(&mut stream)? == 0 {
+      return Ok(());
+    }
+  }
+}
+
+/// Accept connections forever, serving each on it's own thread
+pub fn serve<H: Handler + 'static>(listener: TcpListener, handler: Arc<H>) -> io::Result<()> {
+  for stream in listener.incoming() {
+    let stream = stream?;
+    stream.set_nodelay(true)?;
+    let handler = handler.clone();
+    thread::spawn(move || {
+      let _ = serve_connection(stream, &*handler);
+    });
+  }
+  Ok(())
+}
+
+#[test]
+fn test_dispatch_shapes() {
+  use super::builder::RequestBuilder;
+  use std::collections::HashMap;
+  use std::sync::Mutex;
+
+  //just enough of a handler to check the wire shapes
+  struct Map(Mutex<HashMap<Vec<u8>, Vec<u8>>>);
+  impl Handler for Map {
+    fn get(&self, key: &[u8]) -> HandlerResult<Value> {
+      match self.0.lock().unwrap().get(key) {
+        Option::Some(v) => Ok(Value{ value: v.clone(), flags: 3, cas: 9, datatype: DataType::RAW }),
+        Option::None => Err(StatusField::KeyNotFound)
+      }
+    }
+    fn set(&self, req: StoreRequest) -> HandlerResult<u64> {
+      self.0.lock().unwrap().insert(req.key.to_vec(), req.value.to_vec());
+      Ok(9)
+    }
+    fn delete(&self, key: &[u8], _cas: u64) -> HandlerResult<()> {
+      self.0.lock().unwrap().remove(key).map(|_| ()).ok_or(StatusField::KeyNotFound)
+    }
+    fn arith(&self, req: ArithRequest) -> HandlerResult<(u64, u64)> {
+      Ok((req.initial, 1))
+    }
+    fn touch(&self, _key: &[u8], _expiration: u32) -> HandlerResult<()> {
+      Ok(())
+    }
+    fn flush(&self, _delay: Option<u32>) -> HandlerResult<()> {
+      Ok(())
+    }
+  }
+  let h = Map(Mutex::new(HashMap::new()));
+  let mut s = Session::default();
+  let run = |s: &mut Session, b: RequestBuilder| dispatch(&h, s, &b.opaque(77).build().unwrap());
+
+  //quiet success is silent, loud success is not
+  assert!(run(&mut s, RequestBuilder::set(b"k", b"v").quiet()).responses.is_empty());
+  let r = run(&mut s, RequestBuilder::set(b"k", b"v"));
+  assert_eq!(r.responses.len(), 1);
+  assert_eq!(r.responses[0].get_opcode(), OpCode::Set);
+  assert_eq!(r.responses[0].get_opaque(), 77);
+  assert_eq!(r.responses[0].get_cas(), 9);
+
+  //quiet failures reply, with the quiet opcode
+  let r = run(&mut s, RequestBuilder::delete(b"missing").quiet());
+  assert_eq!(r.responses[0].get_opcode(), OpCode::DeleteQ);
+  assert_eq!(r.responses[0].get_status(), StatusField::KeyNotFound);
+  assert_eq!(r.responses[0].get_body(), Some(&b"Not found"[..]));
+
+  //quiet get hits reply, misses do not
+  let r = run(&mut s, RequestBuilder::getk(b"k").quiet());
+  assert_eq!(r.responses[0].get_key(), Some(&b"k"[..]));
+  assert_eq!(r.responses[0].get_extra(), Some(&b"\x00\x00\x00\x03"[..]));
+  assert!(run(&mut s, RequestBuilder::getk(b"missing").quiet()).responses.is_empty());
+  let r = run(&mut s, RequestBuilder::get(b"k"));
+  assert!(!r.responses[0].has_key());
+
+  let r = run(&mut s, RequestBuilder::incr(b"n", 1).initial(40));
+  assert_eq!(r.responses[0].get_body(), Some(&[0, 0, 0, 0, 0, 0, 0, 40][..]));
+
+  let r = run(&mut s, RequestBuilder::stat(None));
+  assert_eq!(r.responses.len(), 1);
+  assert!(!r.responses[0].has_key());
+  let r = run(&mut s, RequestBuilder::new(OpCode::SASLlistmech));
+  assert_eq!(r.responses[0].get_status(), StatusField::UnknownCommand);
+
+  let r = run(&mut s, RequestBuilder::quit().quiet());
+  assert!(r.close);
+  assert!(r.responses.is_empty());
+
+  //missing extras are invalid arguments, not unknown commands
+  for &code in [OpCode::GAT, OpCode::Touch, OpCode::Increment, OpCode::DecrementQ, OpCode::Verbosity].iter() {
+    let r = dispatch(&h, &mut s, &OwnedRequest::new(code, 0, 5, 0, Vec::new(), b"k".to_vec(), Vec::new()));
+    assert_eq!((r.responses[0].get_opcode(), r.responses[0].get_status()), (code, StatusField::InvalidArguments));
+  }
+}
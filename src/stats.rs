@@ -0,0 +1,164 @@
+//! Server Statistics
+//!
+//! `Stats` collects the `(name, value)` pairs a `Stat` request
+//! returns (see `Client::stats`). Values are kept as strings,
+//! the typed accessors parse them on demand. Groups which
+//! report per slab class, such as `items` (`items:1:number`)
+//! and `slabs` (`1:used_chunks`), can be split with
+//! `Stats::by_slab`.
+
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+use std::iter::FromIterator;
+
+/// Statistics by name
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct Stats {
+  map: BTreeMap<String, String>
+}
+impl Stats {
+  #[inline]
+  pub fn new() -> Stats {
+    Stats::default()
+  }
+  #[inline(always)]
+  pub fn len(&self) -> usize {
+    self.map.len()
+  }
+  #[inline(always)]
+  pub fn is_empty(&self) -> bool {
+    self.map.is_empty()
+  }
+  #[inline]
+  pub fn insert(&mut self, name: String, value: String) {
+    self.map.insert(name, value);
+  }
+  /// Raw value of a statistic
+  #[inline]
+  pub fn get(&self, name: &str) -> Option<&str> {
+    self.map.get(name).map(|s| s.as_str())
+  }
+  /// A statistic as an integer. `None` if it is missing or
+  /// not a number.
+  #[inline]
+  pub fn get_u64(&self, name: &str) -> Option<u64> {
+    self.get(name).and_then(|v| v.trim().parse().ok())
+  }
+  #[inline]
+  pub fn get_f64(&self, name: &str) -> Option<f64> {
+    self.get(name).and_then(|v| v.trim().parse().ok())
+  }
+  /// A setting such as `cas_enabled`, memcached writes these as
+  /// `yes`/`no` or `on`/`off`
+  pub fn get_bool(&self, name: &str) -> Option<bool> {
+    match self.get(name).map(|v| v.trim()) {
+      Option::Some("yes") |
+      Option::Some("on") |
+      Option::Some("true") |
+      Option::Some("1") => Some(true),
+      Option::Some("no") |
+      Option::Some("off") |
+      Option::Some("false") |
+      Option::Some("0") => Some(false),
+      _ => None
+    }
+  }
+  /// Seconds since the server started
+  #[inline]
+  pub fn uptime(&self) -> Option<u64> {
+    self.get_u64("uptime")
+  }
+  #[inline]
+  pub fn curr_items(&self) -> Option<u64> {
+    self.get_u64("curr_items")
+  }
+  #[inline]
+  pub fn get_hits(&self) -> Option<u64> {
+    self.get_u64("get_hits")
+  }
+  #[inline]
+  pub fn get_misses(&self) -> Option<u64> {
+    self.get_u64("get_misses")
+  }
+  /// Bytes used to store items
+  #[inline]
+  pub fn bytes(&self) -> Option<u64> {
+    self.get_u64("bytes")
+  }
+  #[inline]
+  pub fn evictions(&self) -> Option<u64> {
+    self.get_u64("evictions")
+  }
+  /// Share of gets which hit, `None` before any gets
+  pub fn hit_ratio(&self) -> Option<f64> {
+    let hits = self.get_hits()?;
+    let total = hits + self.get_misses()?;
+    if total == 0 {
+      None
+    } else {
+      Some(hits as f64 / total as f64)
+    }
+  }
+  /// Split per slab class statistics, named `items:1:number`
+  /// or `1:used_chunks`, by class. Each class's `Stats` holds
+  /// just the field names. Other statistics are left out.
+  pub fn by_slab(&self) -> BTreeMap<u32, Stats> {
+    let mut slabs: BTreeMap<u32, Stats> = BTreeMap::new();
+    for (name, value) in self.map.iter() {
+      let name = name.trim_start_matches("items:");
+      let mut parts = name.splitn(2, ':');
+      let class = parts.next().and_then(|c| c.parse::<u32>().ok());
+      if let (Some(class), Some(field)) = (class, parts.next()) {
+        slabs.entry(class).or_default().insert(field.to_string(), value.clone());
+      }
+    }
+    slabs
+  }
+  #[inline]
+  pub fn iter(&self) -> btree_map::Iter<'_, String, String> {
+    self.map.iter()
+  }
+}
+impl FromIterator<(String, String)> for Stats {
+  fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> Stats {
+    Stats {
+      map: iter.into_iter().collect()
+    }
+  }
+}
+impl IntoIterator for Stats {
+  type Item = (String, String);
+  type IntoIter = btree_map::IntoIter<String, String>;
+  #[inline]
+  fn into_iter(self) -> Self::IntoIter {
+    self.map.into_iter()
+  }
+}
+
+#[test]
+fn test_stats() {
+  let pairs = vec![
+    ("uptime", "42"),
+    ("get_hits", "3"),
+    ("get_misses", "1"),
+    ("rusage_user", "0.125"),
+    ("cas_enabled", "yes"),
+    ("items:1:number", "5"),
+    ("items:1:evicted", "0"),
+    ("items:12:number", "2"),
+    ("1:chunk_size", "96"),
+    ("active_slabs", "2")];
+  let stats: Stats = pairs.into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+  assert_eq!(stats.uptime(), Some(42));
+  assert_eq!(stats.hit_ratio(), Some(0.75));
+  assert_eq!(stats.get_f64("rusage_user"), Some(0.125));
+  assert_eq!(stats.get_bool("cas_enabled"), Some(true));
+  assert_eq!(stats.bytes(), None);
+  assert_eq!(stats.get_u64("cas_enabled"), None);
+
+  let slabs = stats.by_slab();
+  assert_eq!(slabs.keys().cloned().collect::<Vec<u32>>(), vec![1, 12]);
+  assert_eq!(slabs[&1].get_u64("number"), Some(5));
+  assert_eq!(slabs[&1].get_u64("chunk_size"), Some(96));
+  assert_eq!(slabs[&12].len(), 1);
+}
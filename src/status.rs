@@ -106,13 +106,34 @@ pub fn status_parse<'a>(i: &'a [u8])
   }
 }
 
+/// Message sent in the body of error responses
+pub fn status_message(status: StatusField) -> &'static str {
+  match status {
+    StatusField::NoError => "",
+    StatusField::KeyNotFound => "Not found",
+    StatusField::KeyExists => "Data exists for key",
+    StatusField::ValueTooLarge => "Too large",
+    StatusField::InvalidArguments => "Invalid arguments",
+    StatusField::ItemNotStored => "Not stored",
+    StatusField::IncrDecrNonNumeric => "Non-numeric server-side value for incr or decr",
+    StatusField::VBucketNotHere => "Not my vbucket",
+    StatusField::AuthError => "Auth failure",
+    StatusField::AuthContinue => "Auth continue",
+    StatusField::UnknownCommand => "Unknown command",
+    StatusField::OutOfMemory => "Out of memory",
+    StatusField::NotSupported => "Not supported",
+    StatusField::InternalError => "Internal error",
+    StatusField::Busy => "Busy",
+    StatusField::TemporaryFailure => "Temporary failure"
+  }
+}
+
 /*
  *Tests below here
  */
 #[test]
 fn test_status_field() {
 
-use super::{Fault,ParseResult};
   
 macro_rules! ot {
   ($a: expr, $b: ident) => {
@@ -124,9 +145,9 @@ macro_rules! ot {
     sf.encode(&mut v);
     v.encode_u8(0);
     assert_eq!(v.len(), 3);
-    let dut = match ParseResult::from(status_parse(v.as_slice())) {
-      ParseResult::Ok(x) => x,
-      ParseResult::Err(e) => panic!("Status {:?} should not return error {:?}",value,e)
+    let dut = match status_parse(v.as_slice()) {
+      IResult::Done(_, x) => x,
+      e => panic!("Status {:?} should not return error {:?}",value,e)
     };
     assert_eq!(dut, sf);
     if sf == StatusField::NoError {
@@ -147,10 +168,9 @@ macro_rules! bt {
     let mut v = unsafe{Encoder::with_capacity(100)};
     val.encode(&mut v);
     (0u16).encode(&mut v);
-    match ParseResult::from(status_parse(v.as_slice())) {
-      ParseResult::Ok(x) => panic!("u16 {:?} should not be opcode {:?}", val, x),
-      ParseResult::Err(Fault::BadStatus) => { },
-      ParseResult::Err(e) => panic!("u16 {:?} should retun `Fault::BadStatus` not {:?}", val, e)
+    match status_parse(v.as_slice()) {
+      IResult::Error(ErrorKind::Custom(0x83)) => { },
+      e => panic!("u16 {:?} should retun `Custom(0x83)` not {:?}", val, e)
     };
   }
 }
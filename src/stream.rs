@@ -0,0 +1,289 @@
+use super::{
+  ParseResult,
+  Error,
+  LengthField
+};
+use super::builder::MAX_BODY_LEN;
+use super::request::OwnedRequest;
+use super::response::OwnedResponse;
+use std::io::{
+  self,
+  Read
+};
+use std::marker::PhantomData;
+
+/// Size of a packet header
+const HEADER_LEN: usize = 24;
+
+/// Offset of the key length field within a header
+const KEYLEN_OFFSET: usize = 2;
+
+/// Offset of the extras length field within a header
+const EXTRALEN_OFFSET: usize = 4;
+
+/// Offset of the total body length field within a header
+const BODYLEN_OFFSET: usize = 8;
+
+/// Smallest read `StreamDecoder::read_from` will issue
+const READ_CHUNK: usize = 4096;
+
+/// Largest read `StreamDecoder::read_from` will issue
+const MAX_READ: usize = 64 * 1024;
+
+/// Packets a `StreamDecoder` can produce
+///
+/// This is implemented for `OwnedRequest` and `OwnedResponse`.
+pub trait Decode: Sized {
+  /// Parse a complete packet
+  fn decode(x: &[u8]) -> ParseResult<Self>;
+}
+impl Decode for OwnedRequest {
+  #[inline(always)]
+  fn decode(x: &[u8]) -> ParseResult<Self> {
+    OwnedRequest::parse(x)
+  }
+}
+impl Decode for OwnedResponse {
+  #[inline(always)]
+  fn decode(x: &[u8]) -> ParseResult<Self> {
+    OwnedResponse::parse(x)
+  }
+}
+
+/// Incremental Packet Decoder
+///
+/// Accepts a byte stream in arbitrary chunks (as they arrive
+/// from a socket), buffers them, and produces packets once
+/// they are complete. The 24 byte header's total body length
+/// is used to know exactly how many bytes are still missing.
+///
+/// Headers are checked as soon as they arrive. A value larger
+/// then `max_body` (by default `MAX_BODY_LEN`) is rejected before
+/// it is buffered, so a hostile peer cannot make the decoder
+/// hold an arbitrary amount of memory.
+///
+/// A parsing error means the stream is corrupt, the connection
+/// should be closed.
+pub struct StreamDecoder<T: Decode> {
+  buffer: Vec<u8>,
+  start: usize,
+  max_body: usize,
+  _packet: PhantomData<T>
+}
+impl<T: Decode> Default for StreamDecoder<T> {
+  #[inline]
+  fn default() -> Self {
+    StreamDecoder::new()
+  }
+}
+impl<T: Decode> StreamDecoder<T> {
+  #[inline]
+  pub fn new() -> Self {
+    StreamDecoder::with_max_body(MAX_BODY_LEN)
+  }
+  /// Create a decoder accepting values of up to `max` bytes
+  #[inline]
+  pub fn with_max_body(max: usize) -> Self {
+    StreamDecoder {
+      buffer: Vec::new(),
+      start: 0,
+      max_body: max,
+      _packet: PhantomData
+    }
+  }
+  /// Change the largest value the decoder will accept
+  #[inline(always)]
+  pub fn set_max_body(&mut self, max: usize) {
+    self.max_body = max;
+  }
+  #[inline(always)]
+  pub fn get_max_body(&self) -> usize {
+    self.max_body
+  }
+  /// Number of bytes buffered but not yet decoded
+  #[inline(always)]
+  pub fn buffered(&self) -> usize {
+    self.buffer.len() - self.start
+  }
+  /// Length of the next packet, known once it's header
+  /// has been buffered.
+  #[inline]
+  pub fn next_len(&self) -> Option<usize> {
+    if self.buffered() < HEADER_LEN {
+      return None;
+    }
+    let s = self.start + BODYLEN_OFFSET;
+    let mut field = [0u8; 4];
+    field.copy_from_slice(&self.buffer[s..s + 4]);
+    Some(HEADER_LEN + u32::from_be_bytes(field) as usize)
+  }
+  /// Number of bytes that must still arrive before the next
+  /// packet can be decoded.
+  ///
+  /// While the header is incomplete this only counts up to the
+  /// end of the header, as the packet's length isn't known yet.
+  /// `0` means a full packet is buffered.
+  #[inline]
+  pub fn needed(&self) -> usize {
+    match self.next_len() {
+      Option::Some(len) => len.saturating_sub(self.buffered()),
+      Option::None => HEADER_LEN - self.buffered()
+    }
+  }
+  /// Buffer a chunk without decoding it
+  #[inline]
+  pub fn push(&mut self, chunk: &[u8]) {
+    self.compact();
+    self.buffer.extend_from_slice(chunk);
+  }
+  /// Check the length fields of a buffered header
+  fn check_header(&self) -> ParseResult<()> {
+    let h = &self.buffer[self.start..self.start + HEADER_LEN];
+    let keylen = ((h[KEYLEN_OFFSET] as usize) << 8) | h[KEYLEN_OFFSET + 1] as usize;
+    let extralen = h[EXTRALEN_OFFSET] as usize;
+    let mut field = [0u8; 4];
+    field.copy_from_slice(&h[BODYLEN_OFFSET..BODYLEN_OFFSET + 4]);
+    let total = u32::from_be_bytes(field) as usize;
+    match total.checked_sub(keylen + extralen) {
+      Option::Some(value) if value > self.max_body => Err(Error::BadLength{ field: LengthField::Body, value: total }),
+      Option::Some(_) => Ok(()),
+      Option::None => Err(Error::BadLength{ field: LengthField::Body, value: total })
+    }
+  }
+  /// Decode the next packet if it is completely buffered
+  ///
+  /// A header with invalid lengths is an error as soon as it
+  /// is buffered, without waiting for the rest of the packet.
+  pub fn next_packet(&mut self) -> ParseResult<Option<T>> {
+    let len = match self.next_len() {
+      Option::Some(len) => len,
+      Option::None => return ParseResult::Ok(None)
+    };
+    self.check_header()?;
+    if len > self.buffered() {
+      return ParseResult::Ok(None);
+    }
+    let end = self.start + len;
+    let packet = T::decode(&self.buffer[self.start..end])?;
+    self.start = end;
+    ParseResult::Ok(Some(packet))
+  }
+  /// Buffer a chunk, and decode every packet it completed
+  pub fn feed(&mut self, chunk: &[u8]) -> ParseResult<Vec<T>> {
+    self.push(chunk);
+    let mut packets = Vec::new();
+    while let Some(p) = self.next_packet()? {
+      packets.push(p);
+    }
+    ParseResult::Ok(packets)
+  }
+  /// Issue a single `read` into the internal buffer. Large
+  /// packets are read in 64KB pieces.
+  ///
+  /// Returns the number of bytes read, `0` is end of stream.
+  pub fn read_from<R: Read>(&mut self, r: &mut R) -> io::Result<usize> {
+    self.compact();
+    let want = self.needed().clamp(READ_CHUNK, MAX_READ);
+    let old = self.buffer.len();
+    self.buffer.resize(old + want, 0);
+    match r.read(&mut self.buffer[old..]) {
+      Ok(n) => {
+        self.buffer.truncate(old + n);
+        Ok(n)
+      },
+      Err(e) => {
+        self.buffer.truncate(old);
+        Err(e)
+      }
+    }
+  }
+  /// Drop bytes which have already been decoded
+  #[inline]
+  fn compact(&mut self) {
+    if self.start == 0 {
+      return;
+    }
+    if self.start == self.buffer.len() {
+      self.buffer.clear();
+    } else {
+      self.buffer.drain(..self.start);
+    }
+    self.start = 0;
+  }
+}
+
+#[test]
+fn test_stream_decoder() {
+  use super::opcode::OpCode;
+
+  let msg: Vec<u8> = vec![
+    0x81, 0x00, 0x00, 0x05,
+    0x04, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x0E,
+    0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x01,
+    0xDE, 0xAD, 0xBE, 0xEF,
+    0x48, 0x65, 0x6C, 0x6C,
+    0x6F, 0x57, 0x6F, 0x72,
+    0x6C, 0x64
+  ];
+  let mut d = StreamDecoder::<OwnedResponse>::new();
+  assert_eq!(d.needed(), 24);
+
+  //byte at a time
+  for (i, b) in msg.iter().enumerate() {
+    let out = d.feed(&[*b]).unwrap();
+    if i + 1 < msg.len() {
+      assert!(out.is_empty());
+      if i + 1 < 24 {
+        assert_eq!(d.needed(), 24 - (i + 1));
+      } else {
+        assert_eq!(d.needed(), msg.len() - (i + 1));
+      }
+    } else {
+      assert_eq!(out.len(), 1);
+      assert_eq!(out[0].get_key(), Some(&b"Hello"[..]));
+      assert_eq!(out[0].get_body(), Some(&b"World"[..]));
+    }
+  }
+  assert_eq!(d.buffered(), 0);
+  assert_eq!(d.needed(), 24);
+
+  //two and a half packets in one chunk
+  let mut chunk = msg.clone();
+  chunk.extend_from_slice(&msg);
+  chunk.extend_from_slice(&msg[0..10]);
+  let out = d.feed(&chunk).unwrap();
+  assert_eq!(out.len(), 2);
+  assert_eq!(out[1].get_opcode(), OpCode::Get);
+  assert_eq!(d.buffered(), 10);
+  assert_eq!(d.needed(), 14);
+  let out = d.feed(&msg[10..]).unwrap();
+  assert_eq!(out.len(), 1);
+
+  //read from a reader
+  let mut r = io::Cursor::new(msg.clone());
+  let mut d = StreamDecoder::<OwnedResponse>::new();
+  assert_eq!(d.read_from(&mut r).unwrap(), msg.len());
+  assert!(d.next_packet().unwrap().is_some());
+  assert!(d.next_packet().unwrap().is_none());
+  assert_eq!(d.read_from(&mut r).unwrap(), 0);
+
+  //corrupt streams are errors
+  let mut d = StreamDecoder::<OwnedRequest>::new();
+  assert!(d.feed(&msg).is_err());
+
+  //oversized values are rejected once the header arrives
+  let mut d = StreamDecoder::<OwnedResponse>::with_max_body(4);
+  assert_eq!(d.get_max_body(), 4);
+  assert_eq!(d.feed(&msg[0..24]).err().unwrap(), Error::BadLength{ field: LengthField::Body, value: 14 });
+  let mut d = StreamDecoder::<OwnedResponse>::with_max_body(5);
+  assert_eq!(d.feed(&msg).unwrap().len(), 1);
+
+  //as are headers too short to hold their key and extras
+  let mut short = msg.clone();
+  short[11] = 0x08;
+  let mut d = StreamDecoder::<OwnedResponse>::new();
+  assert_eq!(d.feed(&short[0..24]).err().unwrap(), Error::BadLength{ field: LengthField::Body, value: 8 });
+}
@@ -0,0 +1,522 @@
+//! TAP Replication Streams
+//!
+//! A `TAPConnect` request turns a connection into a stream of
+//! TAP messages from the server. The messages are *requests*
+//! (`TAPMutate`, `TAPDelete`, ...) whose extras start with an
+//! 8 byte `TapHeader`:
+//!
+//!```text
+//! engine specific length: u16
+//! tap flags:              u16
+//! ttl:                    u8
+//! reserved:               [u8;3]
+//!```
+//!
+//! `TAPMutate` adds the item's flags and expiration. The body
+//! holds the engine specific data followed by the value.
+//!
+//! `TapConnect` builds the connect request, `TapMessage` decodes
+//! the messages, and `TapStream` reads them off a connection,
+//! acknowledging those flagged `TapFlags::ACK`.
+
+use super::{
+  ParseResult,
+  Error,
+  LengthField,
+  Encoding,
+  Encoder
+};
+use super::opcode::OpCode;
+use super::status::StatusField;
+use super::request::{
+  Request,
+  OwnedRequest
+};
+use super::response::OwnedResponse;
+use super::extras::{
+  Extras,
+  ReqExtras,
+  exact
+};
+use super::builder::{
+  BuildError,
+  RequestBuilder
+};
+use super::stream::StreamDecoder;
+use super::client::ClientError;
+use super::nom::{
+  be_u8,
+  be_u16,
+  be_u32
+};
+use std::io::{
+  Read,
+  Write
+};
+use std::ops::BitOr;
+
+/// Flags of a `TAPConnect` request's extras
+#[derive(Copy,Clone,Debug,PartialEq,Eq,Hash,Default)]
+pub struct TapConnectFlags(u32);
+impl TapConnectFlags {
+  /// Send items changed since a date, the body starts with
+  /// the date as a `u64`
+  pub const BACKFILL: TapConnectFlags = TapConnectFlags(0x01);
+  /// Send the current contents then close the stream
+  pub const DUMP: TapConnectFlags = TapConnectFlags(0x02);
+  /// Only stream the listed vbuckets, the body holds a `u16`
+  /// count then the `u16` ids
+  pub const LIST_VBUCKETS: TapConnectFlags = TapConnectFlags(0x04);
+  /// Take over the listed vbuckets
+  pub const TAKEOVER_VBUCKETS: TapConnectFlags = TapConnectFlags(0x08);
+  /// The client acknowledges messages flagged `TapFlags::ACK`
+  pub const SUPPORT_ACK: TapConnectFlags = TapConnectFlags(0x10);
+  /// Only send keys, not values
+  pub const KEYS_ONLY: TapConnectFlags = TapConnectFlags(0x20);
+  pub const CHECKPOINT: TapConnectFlags = TapConnectFlags(0x40);
+  pub const REGISTERED_CLIENT: TapConnectFlags = TapConnectFlags(0x80);
+
+  #[inline(always)]
+  pub fn from_bits(x: u32) -> TapConnectFlags {
+    TapConnectFlags(x)
+  }
+  #[inline(always)]
+  pub fn bits(&self) -> u32 {
+    self.0
+  }
+  /// True if every flag in `other` is set
+  #[inline(always)]
+  pub fn contains(&self, other: TapConnectFlags) -> bool {
+    self.0 & other.0 == other.0
+  }
+  #[inline(always)]
+  pub fn insert(&mut self, other: TapConnectFlags) {
+    self.0 |= other.0;
+  }
+}
+impl BitOr for TapConnectFlags {
+  type Output = TapConnectFlags;
+  #[inline(always)]
+  fn bitor(self, other: TapConnectFlags) -> TapConnectFlags {
+    TapConnectFlags(self.0 | other.0)
+  }
+}
+
+/// Flags of a TAP message's header
+#[derive(Copy,Clone,Debug,PartialEq,Eq,Hash,Default)]
+pub struct TapFlags(u16);
+impl TapFlags {
+  /// The server waits for an acknowledgement
+  pub const ACK: TapFlags = TapFlags(0x01);
+  /// The message carries no value
+  pub const NO_VALUE: TapFlags = TapFlags(0x02);
+  /// The item flags are in network byte order
+  pub const NETWORK_BYTE_ORDER: TapFlags = TapFlags(0x04);
+
+  #[inline(always)]
+  pub fn from_bits(x: u16) -> TapFlags {
+    TapFlags(x)
+  }
+  #[inline(always)]
+  pub fn bits(&self) -> u16 {
+    self.0
+  }
+  /// True if every flag in `other` is set
+  #[inline(always)]
+  pub fn contains(&self, other: TapFlags) -> bool {
+    self.0 & other.0 == other.0
+  }
+  #[inline(always)]
+  pub fn insert(&mut self, other: TapFlags) {
+    self.0 |= other.0;
+  }
+}
+impl BitOr for TapFlags {
+  type Output = TapFlags;
+  #[inline(always)]
+  fn bitor(self, other: TapFlags) -> TapFlags {
+    TapFlags(self.0 | other.0)
+  }
+}
+
+/// Extras of a `TAPConnect` request
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct TapConnectExtras {
+  pub flags: TapConnectFlags
+}
+impl Encoding for TapConnectExtras {
+  #[inline(always)]
+  fn encode(&self, buffer: &mut Encoder) {
+    self.flags.0.encode(buffer);
+  }
+}
+impl Extras for TapConnectExtras {
+  #[inline(always)]
+  fn extras_len(&self) -> usize {
+    4
+  }
+  #[inline]
+  fn parse_extras(x: &[u8]) -> ParseResult<Self> {
+    named!(parse_connect_extras<TapConnectExtras>, do_parse!(
+      f: be_u32   >>
+      (TapConnectExtras{ flags: TapConnectFlags(f) })
+    ));
+    exact(x, 4, parse_connect_extras)
+  }
+}
+
+/// Header starting the extras of every TAP message
+#[derive(Copy,Clone,Debug,PartialEq,Eq,Default)]
+pub struct TapHeader {
+  /// Bytes of engine specific data at the start of the body
+  pub engine_len: u16,
+  pub flags: TapFlags,
+  /// Hops left before the message is dropped
+  pub ttl: u8
+}
+impl Encoding for TapHeader {
+  #[inline(always)]
+  fn encode(&self, buffer: &mut Encoder) {
+    self.engine_len.encode(buffer);
+    self.flags.0.encode(buffer);
+    buffer.encode_u8(self.ttl);
+    buffer.encode_u8(0);
+    buffer.encode_u16(0);
+  }
+}
+impl Extras for TapHeader {
+  #[inline(always)]
+  fn extras_len(&self) -> usize {
+    8
+  }
+  #[inline]
+  fn parse_extras(x: &[u8]) -> ParseResult<Self> {
+    exact(x, 8, parse_tap_header)
+  }
+}
+named!(parse_tap_header<TapHeader>, do_parse!(
+  e: be_u16   >>
+  f: be_u16   >>
+  t: be_u8    >>
+  take!(3)    >>
+  (TapHeader{ engine_len: e, flags: TapFlags(f), ttl: t })
+));
+
+/// Extras of a `TAPMutate` message
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct TapMutationExtras {
+  pub header: TapHeader,
+  /// The item's flags
+  pub item_flags: u32,
+  pub expiration: u32
+}
+impl Encoding for TapMutationExtras {
+  #[inline(always)]
+  fn encode(&self, buffer: &mut Encoder) {
+    self.header.encode(buffer);
+    self.item_flags.encode(buffer);
+    self.expiration.encode(buffer);
+  }
+}
+impl Extras for TapMutationExtras {
+  #[inline(always)]
+  fn extras_len(&self) -> usize {
+    16
+  }
+  #[inline]
+  fn parse_extras(x: &[u8]) -> ParseResult<Self> {
+    named!(parse_mutation_extras<TapMutationExtras>, do_parse!(
+      h: parse_tap_header >>
+      f: be_u32           >>
+      e: be_u32           >>
+      (TapMutationExtras{ header: h, item_flags: f, expiration: e })
+    ));
+    exact(x, 16, parse_mutation_extras)
+  }
+}
+
+/// Builds `TAPConnect` requests
+#[derive(Clone,Debug,Default)]
+pub struct TapConnect<'a> {
+  name: Option<&'a [u8]>,
+  flags: TapConnectFlags,
+  backfill: u64,
+  vbuckets: Vec<u16>
+}
+impl<'a> TapConnect<'a> {
+  #[inline]
+  pub fn new() -> TapConnect<'a> {
+    TapConnect::default()
+  }
+  /// Name the stream, so the server can resume it
+  #[inline]
+  pub fn name(mut self, name: &'a [u8]) -> Self {
+    self.name = Some(name);
+    self
+  }
+  /// Include items changed since `date` (`0` is everything)
+  #[inline]
+  pub fn backfill(mut self, date: u64) -> Self {
+    self.flags.insert(TapConnectFlags::BACKFILL);
+    self.backfill = date;
+    self
+  }
+  /// Send the current contents then close
+  #[inline]
+  pub fn dump(mut self) -> Self {
+    self.flags.insert(TapConnectFlags::DUMP);
+    self
+  }
+  /// Only stream these vbuckets
+  #[inline]
+  pub fn vbuckets(mut self, vbuckets: &[u16]) -> Self {
+    self.flags.insert(TapConnectFlags::LIST_VBUCKETS);
+    self.vbuckets = vbuckets.to_vec();
+    self
+  }
+  /// Take over the listed vbuckets
+  #[inline]
+  pub fn takeover(mut self) -> Self {
+    self.flags.insert(TapConnectFlags::TAKEOVER_VBUCKETS);
+    self
+  }
+  /// Acknowledge messages which ask for it. `TapStream` does
+  /// this automatically.
+  #[inline]
+  pub fn support_ack(mut self) -> Self {
+    self.flags.insert(TapConnectFlags::SUPPORT_ACK);
+    self
+  }
+  #[inline]
+  pub fn keys_only(mut self) -> Self {
+    self.flags.insert(TapConnectFlags::KEYS_ONLY);
+    self
+  }
+  /// Set any other flags
+  #[inline]
+  pub fn flags(mut self, flags: TapConnectFlags) -> Self {
+    self.flags.insert(flags);
+    self
+  }
+  #[inline(always)]
+  pub fn get_flags(&self) -> TapConnectFlags {
+    self.flags
+  }
+  /// The body, in the order the flags define
+  fn body(&self) -> Vec<u8> {
+    let mut body = Vec::new();
+    if self.flags.contains(TapConnectFlags::BACKFILL) {
+      body.extend_from_slice(&self.backfill.to_be_bytes());
+    }
+    if self.flags.contains(TapConnectFlags::LIST_VBUCKETS) {
+      body.extend_from_slice(&(self.vbuckets.len() as u16).to_be_bytes());
+      for v in self.vbuckets.iter() {
+        body.extend_from_slice(&v.to_be_bytes());
+      }
+    }
+    body
+  }
+  pub fn build(&self, opaque: u32) -> Result<OwnedRequest, BuildError> {
+    let body = self.body();
+    let extras = TapConnectExtras{ flags: self.flags }.to_vec();
+    let mut b = RequestBuilder::new(OpCode::TAPConnect)
+      .opaque(opaque)
+      .extras(ReqExtras::Raw(extras));
+    if let Some(name) = self.name {
+      b = b.key(name);
+    }
+    if !body.is_empty() {
+      b = b.value(&body);
+    }
+    b.build()
+  }
+}
+
+/// A decoded TAP message
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct TapMessage {
+  pub opcode: OpCode,
+  pub header: TapHeader,
+  pub vbucket: u16,
+  pub opaque: u32,
+  pub cas: u64,
+  pub key: Vec<u8>,
+  /// Engine specific data from the start of the body
+  pub engine: Vec<u8>,
+  /// The rest of the body, the value of a `TAPMutate`
+  pub value: Vec<u8>,
+  /// The item's flags and expiration, only for `TAPMutate`
+  pub item: Option<(u32, u32)>
+}
+impl TapMessage {
+  fn parse(code: OpCode, vbucket: u16, opaque: u32, cas: u64, extra: &[u8], key: &[u8], body: &[u8]) -> ParseResult<TapMessage> {
+    let (header, item) = match code {
+      OpCode::TAPMutate => {
+        let e = TapMutationExtras::parse_extras(extra)?;
+        (e.header, Some((e.item_flags, e.expiration)))
+      },
+      OpCode::TAPDelete |
+      OpCode::TAPFlush |
+      OpCode::TAPOpaque |
+      OpCode::TAPVBucketSet |
+      OpCode::TAPCheckpointStart |
+      OpCode::TAPCheckpointEnd => {
+        //newer servers may append fields, only the header is read
+        if extra.len() < 8 {
+          return Err(Error::BadLength{ field: LengthField::Extras, value: extra.len() });
+        }
+        (TapHeader::parse_extras(&extra[..8])?, None)
+      },
+      x => return Err(Error::BadOpCode{ code: x.into(), offset: 1 })
+    };
+    let engine_len = header.engine_len as usize;
+    if engine_len > body.len() {
+      return Err(Error::BadLength{ field: LengthField::Body, value: body.len() });
+    }
+    Ok(TapMessage {
+      opcode: code,
+      header,
+      vbucket,
+      opaque,
+      cas,
+      key: key.to_vec(),
+      engine: body[..engine_len].to_vec(),
+      value: body[engine_len..].to_vec(),
+      item
+    })
+  }
+  /// Decode a TAP message. Other opcodes are
+  /// `Error::BadOpCode`.
+  pub fn from_request<'a>(req: &'a Request<'a>) -> ParseResult<TapMessage> {
+    TapMessage::parse(
+      req.get_opcode(),
+      req.get_vbucket_id(),
+      req.get_opaque(),
+      req.get_cas(),
+      req.get_extra().unwrap_or(&[]),
+      req.get_key().unwrap_or(&[]),
+      req.get_body().unwrap_or(&[]))
+  }
+  #[inline]
+  pub fn from_owned(req: &OwnedRequest) -> ParseResult<TapMessage> {
+    TapMessage::parse(
+      req.get_opcode(),
+      req.get_vbucket_id(),
+      req.get_opaque(),
+      req.get_cas(),
+      &req.extra,
+      &req.key,
+      &req.body)
+  }
+  /// The server is waiting for `ack()`
+  #[inline(always)]
+  pub fn needs_ack(&self) -> bool {
+    self.header.flags.contains(TapFlags::ACK)
+  }
+  /// The acknowledgement of this message
+  #[inline]
+  pub fn ack(&self) -> OwnedResponse {
+    OwnedResponse::new(self.opcode, StatusField::NoError, self.opaque, 0, Vec::new(), Vec::new(), Vec::new())
+  }
+  /// New state of the vbucket for `TAPVBucketSet` (`1` active,
+  /// `2` replica, `3` pending, `4` dead)
+  pub fn vbucket_state(&self) -> Option<u32> {
+    if self.opcode != OpCode::TAPVBucketSet || self.value.len() != 4 {
+      return None;
+    }
+    Some((self.value[0] as u32) << 24 | (self.value[1] as u32) << 16 | (self.value[2] as u32) << 8 | self.value[3] as u32)
+  }
+}
+
+/// Reads TAP messages from a connection
+pub struct TapStream<S: Read + Write> {
+  stream: S,
+  decoder: StreamDecoder<OwnedRequest>
+}
+impl<S: Read + Write> TapStream<S> {
+  /// Send `c` and start reading messages.
+  ///
+  /// The server does not answer a successful connect. An error
+  /// response (which is not a request) is reported as
+  /// `ClientError::Parse` by `next_message`.
+  pub fn connect(mut stream: S, c: &TapConnect) -> Result<TapStream<S>, ClientError> {
+    let req = c.build(0)?;
+    stream.write_all(req.encode_self().as_slice())?;
+    stream.flush()?;
+    Ok(TapStream {
+      stream,
+      decoder: StreamDecoder::new()
+    })
+  }
+  /// The next message, `None` once the server closes the
+  /// stream. Messages flagged `TapFlags::ACK` are acknowledged
+  /// before they are returned.
+  pub fn next_message(&mut self) -> Result<Option<TapMessage>, ClientError> {
+    loop {
+      if let Some(req) = self.decoder.next_packet()? {
+        let msg = TapMessage::from_owned(&req)?;
+        if msg.needs_ack() {
+          self.stream.write_all(msg.ack().encode_self().as_slice())?;
+          self.stream.flush()?;
+        }
+        return Ok(Some(msg));
+      }
+      if self.decoder.read_from(&mut self.stream)? == 0 {
+        return Ok(None);
+      }
+    }
+  }
+  #[inline]
+  pub fn into_inner(self) -> S {
+    self.stream
+  }
+}
+
+#[test]
+fn test_tap_extras() {
+  let header = TapHeader{ engine_len: 2, flags: TapFlags::ACK | TapFlags::NO_VALUE, ttl: 5 };
+  let raw = header.to_vec();
+  assert_eq!(raw, vec![0, 2, 0, 3, 5, 0, 0, 0]);
+  assert_eq!(TapHeader::parse_extras(&raw).unwrap(), header);
+  let m = TapMutationExtras{ header, item_flags: 7, expiration: 9 };
+  assert_eq!(TapMutationExtras::parse_extras(&m.to_vec()).unwrap(), m);
+  assert_eq!(TapMutationExtras::parse_extras(&raw).err().unwrap(), super::Fault::BadLength);
+
+  let req = OwnedRequest::new(OpCode::TAPMutate, 3, 11, 99, m.to_vec(), b"key".to_vec(), b"\xAA\xBBvalue".to_vec());
+  let msg = TapMessage::from_owned(&req).unwrap();
+  assert_eq!(msg.engine, vec![0xAA, 0xBB]);
+  assert_eq!(msg.value, b"value".to_vec());
+  assert_eq!(msg.item, Some((7, 9)));
+  assert_eq!((msg.vbucket, msg.cas), (3, 99));
+  assert!(msg.needs_ack());
+  let encoded = req.encode_self();
+  let borrowed = Request::parse(encoded.as_slice()).unwrap();
+  assert_eq!(TapMessage::from_request(&borrowed).unwrap(), msg);
+
+  let state = OwnedRequest::new(OpCode::TAPVBucketSet, 4, 0, 0, TapHeader::default().to_vec(), vec![], vec![0, 0, 0, 2]);
+  assert_eq!(TapMessage::from_owned(&state).unwrap().vbucket_state(), Some(2));
+  let short = OwnedRequest::new(OpCode::TAPDelete, 0, 0, 0, header.to_vec(), b"key".to_vec(), vec![]);
+  assert_eq!(TapMessage::from_owned(&short).err().unwrap(), super::Fault::BadLength);
+  let get = OwnedRequest::new(OpCode::Get, 0, 0, 0, vec![], b"key".to_vec(), vec![]);
+  assert!(TapMessage::from_owned(&get).is_err());
+}
+
+#[test]
+fn test_tap_connect() {
+  let req = TapConnect::new()
+    .name(b"warmer")
+    .backfill(0x0102)
+    .vbuckets(&[1, 7])
+    .takeover()
+    .build(5)
+    .unwrap();
+  let flags = TapConnectExtras::parse_extras(&req.extra).unwrap().flags;
+  assert_eq!(flags, TapConnectFlags::BACKFILL | TapConnectFlags::LIST_VBUCKETS | TapConnectFlags::TAKEOVER_VBUCKETS);
+  assert_eq!(req.get_key(), Some(&b"warmer"[..]));
+  assert_eq!(req.body, vec![0, 0, 0, 0, 0, 0, 1, 2, 0, 2, 0, 1, 0, 7]);
+  assert_eq!(req.get_opaque(), 5);
+
+  let dump = TapConnect::new().dump().build(0).unwrap();
+  assert_eq!(dump.extra, vec![0, 0, 0, 2]);
+  assert!(dump.body.is_empty());
+}
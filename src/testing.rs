@@ -0,0 +1,213 @@
+//! Scriptable Fake Server
+//!
+//! `FakeServer` listens on an ephemeral port of `127.0.0.1` and
+//! answers the binary protocol from a `MemoryStore`. Tests queue
+//! `Action`s to inject faults, one per request received, and can
+//! inspect every request the server saw.
+//!
+//!```
+//! # use mbpr::{OpCode, StatusField};
+//! # use mbpr::client::Client;
+//! # use mbpr::testing::{Action, FakeServer};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let server = FakeServer::start()?;
+//! server.push(Action::Status(StatusField::Busy));
+//! let mut c = Client::new(server.connect()?);
+//! assert!(c.get(b"key").is_err());
+//! assert_eq!(server.received()[0].get_opcode(), OpCode::Get);
+//! # Ok(())
+//! # }
+//!```
+
+use super::status::StatusField;
+use super::request::OwnedRequest;
+use super::stream::StreamDecoder;
+use super::response::status_response;
+use super::memory::MemoryStore;
+use super::server::{
+  Session,
+  dispatch
+};
+use std::collections::VecDeque;
+use std::io::{
+  self,
+  Write
+};
+use std::net::{
+  Shutdown,
+  SocketAddr,
+  TcpListener,
+  TcpStream
+};
+use std::sync::{
+  Arc,
+  Mutex
+};
+use std::sync::atomic::{
+  AtomicBool,
+  Ordering
+};
+use std::thread;
+use std::time::Duration;
+
+/// Byte budget of the backing `MemoryStore`
+const STORE_BUDGET: usize = 64 << 20;
+
+/// What to do with a request
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum Action {
+  /// Answer normally
+  Pass,
+  /// Answer with this status instead (even for quiet opcodes)
+  Status(StatusField),
+  /// Wait, then answer normally
+  Delay(Duration),
+  /// Close the connection without answering
+  Drop,
+  /// Send only the first `n` bytes of the normal answer, then
+  /// close the connection
+  Truncate(usize),
+  /// Send these bytes instead of an answer
+  Raw(Vec<u8>)
+}
+
+struct Shared {
+  store: MemoryStore,
+  script: Mutex<VecDeque<Action>>,
+  received: Mutex<Vec<OwnedRequest>>,
+  stopped: AtomicBool
+}
+impl Shared {
+  #[inline]
+  fn next_action(&self) -> Action {
+    self.script.lock().unwrap().pop_front().unwrap_or(Action::Pass)
+  }
+}
+
+/// In process memcached for tests
+///
+/// The listener shuts down when the `FakeServer` is dropped.
+pub struct FakeServer {
+  addr: SocketAddr,
+  shared: Arc<Shared>
+}
+impl FakeServer {
+  /// Bind to an ephemeral port and start accepting connections
+  pub fn start() -> io::Result<FakeServer> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let shared = Arc::new(Shared {
+      store: MemoryStore::new(STORE_BUDGET),
+      script: Mutex::new(VecDeque::new()),
+      received: Mutex::new(Vec::new()),
+      stopped: AtomicBool::new(false)
+    });
+    let s = shared.clone();
+    thread::spawn(move || accept(listener, s));
+    Ok(FakeServer {
+      addr,
+      shared
+    })
+  }
+  #[inline(always)]
+  pub fn addr(&self) -> SocketAddr {
+    self.addr
+  }
+  /// Open a new connection to the server
+  #[inline]
+  pub fn connect(&self) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect(self.addr)?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+  }
+  /// Queue an action for the next request without one. Once the
+  /// queue is empty requests are answered normally.
+  pub fn push(&self, action: Action) {
+    self.shared.script.lock().unwrap().push_back(action);
+  }
+  /// Every request received so far, across all connections, in
+  /// order of arrival
+  pub fn received(&self) -> Vec<OwnedRequest> {
+    self.shared.received.lock().unwrap().clone()
+  }
+  /// Forget the requests received so far
+  pub fn clear_received(&self) {
+    self.shared.received.lock().unwrap().clear();
+  }
+  /// The backing store, to seed or inspect items directly
+  #[inline(always)]
+  pub fn store(&self) -> &MemoryStore {
+    &self.shared.store
+  }
+}
+impl Drop for FakeServer {
+  fn drop(&mut self) {
+    self.shared.stopped.store(true, Ordering::SeqCst);
+    //wake the accept loop so it sees the flag
+    let _ = TcpStream::connect(self.addr);
+  }
+}
+
+fn accept(listener: TcpListener, shared: Arc<Shared>) {
+  for stream in listener.incoming() {
+    if shared.stopped.load(Ordering::SeqCst) {
+      return;
+    }
+    let stream = match stream {
+      Ok(stream) => stream,
+      Err(_) => continue
+    };
+    let shared = shared.clone();
+    thread::spawn(move || {
+      let _ = connection(stream, &shared);
+    });
+  }
+}
+
+/// The normal answer to a request, and whether the connection
+/// should be closed afterwards
+fn answer(shared: &Shared, session: &mut Session, req: &OwnedRequest) -> (Vec<u8>, bool) {
+  let reply = dispatch(&shared.store, session, req);
+  let mut out = Vec::new();
+  for resp in reply.responses.iter() {
+    out.extend_from_slice(resp.encode_self().as_slice());
+  }
+  (out, reply.close)
+}
+
+fn connection(mut stream: TcpStream, shared: &Shared) -> io::Result<()> {
+  stream.set_nodelay(true)?;
+  let mut decoder = StreamDecoder::<OwnedRequest>::new();
+  let mut session = Session::default();
+  loop {
+    //answer one request at a time so actions apply to exactly
+    //the request they were taken for
+    while let Option::Some(req) = decoder.next_packet()
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))? {
+      shared.received.lock().unwrap().push(req.clone());
+      let (out, close) = match shared.next_action() {
+        Action::Pass => answer(shared, &mut session, &req),
+        Action::Status(status) => (status_response(&req, status).encode_self().as_slice().to_vec(), false),
+        Action::Delay(d) => {
+          thread::sleep(d);
+          answer(shared, &mut session, &req)
+        },
+        Action::Drop => return stream.shutdown(Shutdown::Both),
+        Action::Truncate(n) => {
+          let (mut out, _) = answer(shared, &mut session, &req);
+          out.truncate(n);
+          (out, true)
+        },
+        Action::Raw(bytes) => (bytes, false)
+      };
+      stream.write_all(&out)?;
+      stream.flush()?;
+      if close {
+        return stream.shutdown(Shutdown::Both);
+      }
+    }
+    if decoder.read_from(&mut stream)? == 0 {
+      return Ok(());
+    }
+  }
+}
@@ -0,0 +1,1011 @@
+//! Text Protocol
+//!
+//! Parses and emits the classic memcached text protocol, and
+//! maps it onto the binary vocabulary so one code path can
+//! serve both.
+//!
+//!* `Command` is a text command (`get`, `set ... noreply`,
+//!  `incr`, `stats`, ...). `Command::to_requests` turns it into
+//!  the binary requests which carry it out, `Command::from_request`
+//!  goes the other way.
+//!* `Reply` is a text reply (`STORED`, `VALUE ... END`, ...).
+//!  `Reply::from_responses` builds the reply to a command from
+//!  the binary responses, `Reply::to_responses` answers a binary
+//!  request from a text reply.
+//!
+//! Parsing works from the front of a buffer and reports how many
+//! bytes were used (see `Parsed`), so it can be driven from a
+//! socket the way `StreamDecoder` is.
+//!
+//! Text counters are never created by `incr`/`decr`, so they
+//! are sent as binary requests with an expiration of
+//! `0xFFFFFFFF`. A negative text expiration (expire at once) is
+//! sent as an absolute time in the past.
+
+use super::opcode::OpCode;
+use super::status::{
+  StatusField,
+  status_message
+};
+use super::request::OwnedRequest;
+use super::response::{
+  OwnedResponse,
+  status_response
+};
+use super::extras::{
+  ReqExtras,
+  ResExtras
+};
+use super::builder::{
+  BuildError,
+  RequestBuilder,
+  MAX_BODY_LEN,
+  RELATIVE_EXPIRATION_LIMIT,
+  check_key
+};
+use std::str;
+use std::str::FromStr;
+
+/// Longest line accepted, not counting data blocks
+pub const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// Problems with text input
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum TextError {
+  /// The command is not known, answered with `ERROR`
+  UnknownCommand,
+  /// The input breaks the protocol, answered with
+  /// `CLIENT_ERROR <reason>`
+  BadFormat(&'static str),
+  /// A data block is longer then `MAX_BODY_LEN`, answered with
+  /// `SERVER_ERROR object too large for cache`
+  TooLarge
+}
+impl TextError {
+  /// The reply a server sends for this error
+  #[inline]
+  pub fn reply(&self) -> Reply {
+    match *self {
+      TextError::UnknownCommand => Reply::Error,
+      TextError::BadFormat(reason) => Reply::ClientError(reason.to_string()),
+      TextError::TooLarge => Reply::ServerError("object too large for cache".to_string())
+    }
+  }
+}
+
+/// Result of parsing from the front of a buffer
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum Parsed<T> {
+  /// A value, and the number of bytes it used
+  Done(T, usize),
+  /// More input is needed
+  Incomplete,
+  /// The input is malformed, skip the given number of bytes
+  /// before parsing again
+  Invalid(TextError, usize)
+}
+
+pub(crate) const BAD_LINE: TextError = TextError::BadFormat("bad command line format");
+const BAD_CHUNK: TextError = TextError::BadFormat("bad data chunk");
+
+/// The first line of `buf`, without it's line ending, and the
+/// bytes it used
+fn next_line(buf: &[u8]) -> Option<(&[u8], usize)> {
+  let end = buf.iter().position(|b| *b == b'\n')?;
+  let line = match buf[..end].last() {
+    Option::Some(b'\r') => &buf[..end - 1],
+    _ => &buf[..end]
+  };
+  Some((line, end + 1))
+}
+
+/// Like `next_line`, reporting overlong lines
+pub(crate) fn read_line(buf: &[u8]) -> Parsed<&[u8]> {
+  match next_line(buf) {
+    Option::Some((line, used)) => Parsed::Done(line, used),
+    Option::None if buf.len() > MAX_LINE_LEN => Parsed::Invalid(TextError::BadFormat("line too long"), buf.len()),
+    Option::None => Parsed::Incomplete
+  }
+}
+
+/// A data block of `len` bytes ending in `\r\n`. The length
+/// should have been checked with `data_len`.
+pub(crate) fn read_block(buf: &[u8], len: usize) -> Parsed<&[u8]> {
+  let end = match len.checked_add(2) {
+    Option::Some(end) => end,
+    Option::None => return Parsed::Invalid(TextError::TooLarge, 0)
+  };
+  if buf.len() < end {
+    Parsed::Incomplete
+  } else if &buf[len..end] != b"\r\n" {
+    Parsed::Invalid(BAD_CHUNK, end)
+  } else {
+    Parsed::Done(&buf[..len], end)
+  }
+}
+
+/// The length of a data block, which may be no longer then
+/// `MAX_BODY_LEN`
+pub(crate) fn data_len(arg: &[u8]) -> Result<usize, TextError> {
+  let len: usize = number(arg)?;
+  match len.checked_add(2) {
+    Option::Some(_) if len <= MAX_BODY_LEN => Ok(len),
+    _ => Err(TextError::TooLarge)
+  }
+}
+
+#[inline]
+pub(crate) fn tokens(line: &[u8]) -> Vec<&[u8]> {
+  line.split(|b| *b == b' ').filter(|t| !t.is_empty()).collect()
+}
+
+pub(crate) fn number<T: FromStr>(tok: &[u8]) -> Result<T, TextError> {
+  str::from_utf8(tok).ok()
+    .and_then(|s| s.parse().ok())
+    .ok_or(BAD_LINE)
+}
+
+fn key_arg(tok: &[u8]) -> Result<Vec<u8>, TextError> {
+  match check_key(tok) {
+    Ok(()) => Ok(tok.to_vec()),
+    Err(_) => Err(TextError::BadFormat("bad key"))
+  }
+}
+
+fn expiration(tok: &[u8]) -> Result<u32, TextError> {
+  let x: i64 = number(tok)?;
+  if x < 0 {
+    //an absolute time in 1970
+    Ok(RELATIVE_EXPIRATION_LIMIT + 1)
+  } else if x > u32::MAX as i64 {
+    Err(BAD_LINE)
+  } else {
+    Ok(x as u32)
+  }
+}
+
+fn noreply(rest: &[&[u8]]) -> Result<bool, TextError> {
+  match rest {
+    [] => Ok(false),
+    [b"noreply"] => Ok(true),
+    _ => Err(BAD_LINE)
+  }
+}
+
+#[inline]
+fn quiet_if(b: RequestBuilder, noreply: bool) -> RequestBuilder {
+  if noreply {
+    b.quiet()
+  } else {
+    b
+  }
+}
+
+/// Item flags from the extras of a `Get` family response
+#[inline]
+fn item_flags(resp: &OwnedResponse) -> u32 {
+  match resp.get_typed_extra() {
+    Ok(ResExtras::Get(e)) => e.flags,
+    _ => 0
+  }
+}
+
+/// A text protocol command
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum Command {
+  /// `get` or, with `cas`, `gets`
+  Get {
+    keys: Vec<Vec<u8>>,
+    cas: bool
+  },
+  /// `gat` or, with `cas`, `gats`
+  Gat {
+    expiration: u32,
+    keys: Vec<Vec<u8>>,
+    cas: bool
+  },
+  /// `set`, `add`, `replace`, `append` and `prepend`, with `code`
+  /// the matching binary opcode. A `cas` command is a `Set` with
+  /// a non zero `cas`.
+  Store {
+    code: OpCode,
+    key: Vec<u8>,
+    flags: u32,
+    expiration: u32,
+    value: Vec<u8>,
+    cas: u64,
+    noreply: bool
+  },
+  Delete {
+    key: Vec<u8>,
+    noreply: bool
+  },
+  /// `incr` (`OpCode::Increment`) and `decr`
+  /// (`OpCode::Decrement`)
+  Arith {
+    code: OpCode,
+    key: Vec<u8>,
+    delta: u64,
+    noreply: bool
+  },
+  Touch {
+    key: Vec<u8>,
+    expiration: u32,
+    noreply: bool
+  },
+  /// `flush_all`
+  Flush {
+    delay: Option<u32>,
+    noreply: bool
+  },
+  /// `stats`, with any arguments as the group
+  Stats(Option<Vec<u8>>),
+  Version,
+  Verbosity {
+    level: u32,
+    noreply: bool
+  },
+  Quit
+}
+impl Command {
+  /// Parse a command from the front of `buf`
+  pub fn parse(buf: &[u8]) -> Parsed<Command> {
+    let (line, used) = match read_line(buf) {
+      Parsed::Done(line, used) => (line, used),
+      Parsed::Incomplete => return Parsed::Incomplete,
+      Parsed::Invalid(e, n) => return Parsed::Invalid(e, n)
+    };
+    let (mut cmd, data) = match Command::parse_line(&tokens(line)) {
+      Ok(x) => x,
+      Err(e) => return Parsed::Invalid(e, used)
+    };
+    let len = match data {
+      Option::Some(len) => len,
+      Option::None => return Parsed::Done(cmd, used)
+    };
+    match read_block(&buf[used..], len) {
+      Parsed::Done(block, n) => {
+        if let Command::Store{ ref mut value, .. } = cmd {
+          *value = block.to_vec();
+        }
+        Parsed::Done(cmd, used + n)
+      },
+      Parsed::Incomplete => Parsed::Incomplete,
+      Parsed::Invalid(e, n) => Parsed::Invalid(e, used + n)
+    }
+  }
+  /// A command line, and the length of the data block which
+  /// follows it (for storage commands)
+  fn parse_line(t: &[&[u8]]) -> Result<(Command, Option<usize>), TextError> {
+    let (name, args) = match t.split_first() {
+      Option::Some((name, args)) => (*name, args),
+      Option::None => return Err(TextError::UnknownCommand)
+    };
+    let cmd = match name {
+      b"get" |
+      b"gets" => {
+        if args.is_empty() {
+          return Err(BAD_LINE);
+        }
+        Command::Get {
+          keys: args.iter().map(|k| key_arg(k)).collect::<Result<_, _>>()?,
+          cas: name == b"gets"
+        }
+      },
+      b"gat" |
+      b"gats" => {
+        if args.len() < 2 {
+          return Err(BAD_LINE);
+        }
+        Command::Gat {
+          expiration: expiration(args[0])?,
+          keys: args[1..].iter().map(|k| key_arg(k)).collect::<Result<_, _>>()?,
+          cas: name == b"gats"
+        }
+      },
+      b"set" |
+      b"add" |
+      b"replace" |
+      b"append" |
+      b"prepend" |
+      b"cas" => {
+        let cas_cmd = name == b"cas";
+        let n = if cas_cmd { 5 } else { 4 };
+        if args.len() < n {
+          return Err(BAD_LINE);
+        }
+        let code = match name {
+          b"add" => OpCode::Add,
+          b"replace" => OpCode::Replace,
+          b"append" => OpCode::Append,
+          b"prepend" => OpCode::Prepare,
+          _ => OpCode::Set
+        };
+        let len = data_len(args[3])?;
+        let cmd = Command::Store {
+          code,
+          key: key_arg(args[0])?,
+          flags: number(args[1])?,
+          expiration: expiration(args[2])?,
+          value: Vec::new(),
+          cas: if cas_cmd { number(args[4])? } else { 0 },
+          noreply: noreply(&args[n..])?
+        };
+        return Ok((cmd, Some(len)));
+      },
+      b"delete" => {
+        let (key, rest) = args.split_first().ok_or(BAD_LINE)?;
+        //old clients send a hold time of `0`
+        let rest = match rest.first() {
+          Option::Some(&b"0") => &rest[1..],
+          _ => rest
+        };
+        Command::Delete {
+          key: key_arg(key)?,
+          noreply: noreply(rest)?
+        }
+      },
+      b"incr" |
+      b"decr" => {
+        if args.len() < 2 {
+          return Err(BAD_LINE);
+        }
+        Command::Arith {
+          code: if name == b"incr" { OpCode::Increment } else { OpCode::Decrement },
+          key: key_arg(args[0])?,
+          delta: number(args[1]).map_err(|_| TextError::BadFormat("invalid numeric delta argument"))?,
+          noreply: noreply(&args[2..])?
+        }
+      },
+      b"touch" => {
+        if args.len() < 2 {
+          return Err(BAD_LINE);
+        }
+        Command::Touch {
+          key: key_arg(args[0])?,
+          expiration: expiration(args[1])?,
+          noreply: noreply(&args[2..])?
+        }
+      },
+      b"flush_all" => match args.first() {
+        Option::Some(&b"noreply") |
+        Option::None => Command::Flush{ delay: None, noreply: noreply(args)? },
+        Option::Some(delay) => Command::Flush{ delay: Some(expiration(delay)?), noreply: noreply(&args[1..])? }
+      },
+      b"stats" => if args.is_empty() {
+        Command::Stats(None)
+      } else {
+        Command::Stats(Some(args.join(&b' ')))
+      },
+      b"version" => Command::Version,
+      b"verbosity" => {
+        let (level, rest) = args.split_first().ok_or(BAD_LINE)?;
+        Command::Verbosity {
+          level: number(level)?,
+          noreply: noreply(rest)?
+        }
+      },
+      b"quit" => Command::Quit,
+      _ => return Err(TextError::UnknownCommand)
+    };
+    Ok((cmd, None))
+  }
+  /// The binary opcode with the same meaning. Gets are `GetK`
+  /// so the replies carry the key.
+  pub fn get_opcode(&self) -> OpCode {
+    match *self {
+      Command::Get{ .. } => OpCode::GetK,
+      Command::Gat{ .. } => OpCode::GAT,
+      Command::Store{ code, .. } => code,
+      Command::Delete{ .. } => OpCode::Delete,
+      Command::Arith{ code, .. } => code,
+      Command::Touch{ .. } => OpCode::Touch,
+      Command::Flush{ .. } => OpCode::Flush,
+      Command::Stats(_) => OpCode::Stat,
+      Command::Version => OpCode::Version,
+      Command::Verbosity{ .. } => OpCode::Verbosity,
+      Command::Quit => OpCode::Quit
+    }
+  }
+  /// The client does not want a reply
+  pub fn is_noreply(&self) -> bool {
+    match *self {
+      Command::Store{ noreply, .. } |
+      Command::Delete{ noreply, .. } |
+      Command::Arith{ noreply, .. } |
+      Command::Touch{ noreply, .. } |
+      Command::Flush{ noreply, .. } |
+      Command::Verbosity{ noreply, .. } => noreply,
+      _ => false
+    }
+  }
+  /// Encode the command as it is sent on the wire
+  pub fn encode(&self) -> Vec<u8> {
+    fn suffix(out: &mut Vec<u8>, noreply: bool) {
+      if noreply {
+        out.extend_from_slice(b" noreply");
+      }
+      out.extend_from_slice(b"\r\n");
+    }
+    let mut out = Vec::new();
+    match *self {
+      Command::Get{ ref keys, cas } |
+      Command::Gat{ ref keys, cas, .. } => {
+        let name: &[u8] = match (self, cas) {
+          (Command::Get{ .. }, false) => b"get",
+          (Command::Get{ .. }, true) => b"gets",
+          (_, false) => b"gat",
+          (_, true) => b"gats"
+        };
+        out.extend_from_slice(name);
+        if let Command::Gat{ expiration, .. } = *self {
+          out.extend_from_slice(format!(" {}", expiration).as_bytes());
+        }
+        for key in keys.iter() {
+          out.push(b' ');
+          out.extend_from_slice(key);
+        }
+        out.extend_from_slice(b"\r\n");
+      },
+      Command::Store{ code, ref key, flags, expiration, ref value, cas, noreply } => {
+        let name = match code {
+          _ if cas != 0 => "cas",
+          OpCode::Add => "add",
+          OpCode::Replace => "replace",
+          OpCode::Append => "append",
+          OpCode::Prepare => "prepend",
+          _ => "set"
+        };
+        out.extend_from_slice(name.as_bytes());
+        out.push(b' ');
+        out.extend_from_slice(key);
+        out.extend_from_slice(format!(" {} {} {}", flags, expiration, value.len()).as_bytes());
+        if cas != 0 {
+          out.extend_from_slice(format!(" {}", cas).as_bytes());
+        }
+        suffix(&mut out, noreply);
+        out.extend_from_slice(value);
+        out.extend_from_slice(b"\r\n");
+      },
+      Command::Delete{ ref key, noreply } => {
+        out.extend_from_slice(b"delete ");
+        out.extend_from_slice(key);
+        suffix(&mut out, noreply);
+      },
+      Command::Arith{ code, ref key, delta, noreply } => {
+        out.extend_from_slice(if code == OpCode::Decrement { b"decr " } else { b"incr " });
+        out.extend_from_slice(key);
+        out.extend_from_slice(format!(" {}", delta).as_bytes());
+        suffix(&mut out, noreply);
+      },
+      Command::Touch{ ref key, expiration, noreply } => {
+        out.extend_from_slice(b"touch ");
+        out.extend_from_slice(key);
+        out.extend_from_slice(format!(" {}", expiration).as_bytes());
+        suffix(&mut out, noreply);
+      },
+      Command::Flush{ delay, noreply } => {
+        out.extend_from_slice(b"flush_all");
+        if let Some(delay) = delay {
+          out.extend_from_slice(format!(" {}", delay).as_bytes());
+        }
+        suffix(&mut out, noreply);
+      },
+      Command::Stats(ref group) => {
+        out.extend_from_slice(b"stats");
+        if let Some(ref group) = *group {
+          out.push(b' ');
+          out.extend_from_slice(group);
+        }
+        out.extend_from_slice(b"\r\n");
+      },
+      Command::Version => out.extend_from_slice(b"version\r\n"),
+      Command::Verbosity{ level, noreply } => {
+        out.extend_from_slice(format!("verbosity {}", level).as_bytes());
+        suffix(&mut out, noreply);
+      },
+      Command::Quit => out.extend_from_slice(b"quit\r\n")
+    };
+    out
+  }
+  /// The binary requests which carry out this command.
+  ///
+  /// Request `i` carries the `opaque` `first + i`. Gets are sent
+  /// as quiet `GetKQ`/`GATQ` requests, one per key, followed by a
+  /// `Nop`. `noreply` commands use the quiet opcode where there
+  /// is one.
+  pub fn to_requests(&self, first: u32) -> Result<Vec<OwnedRequest>, BuildError> {
+    let b = match *self {
+      Command::Get{ ref keys, .. } |
+      Command::Gat{ ref keys, .. } => {
+        let mut reqs = Vec::with_capacity(keys.len() + 1);
+        for (i, key) in keys.iter().enumerate() {
+          let b = match *self {
+            Command::Gat{ expiration, .. } => RequestBuilder::gat(key, expiration),
+            _ => RequestBuilder::getk(key)
+          };
+          reqs.push(b.quiet().opaque(first.wrapping_add(i as u32)).build()?);
+        }
+        reqs.push(RequestBuilder::noop().opaque(first.wrapping_add(keys.len() as u32)).build()?);
+        return Ok(reqs);
+      },
+      Command::Store{ code, ref key, flags, expiration, ref value, cas, noreply } => {
+        let b = match code {
+          OpCode::Add => RequestBuilder::add(key, value).flags(flags).expire(expiration),
+          OpCode::Replace => RequestBuilder::replace(key, value).flags(flags).expire(expiration),
+          OpCode::Append => RequestBuilder::append(key, value),
+          OpCode::Prepare => RequestBuilder::prepend(key, value),
+          _ => RequestBuilder::set(key, value).flags(flags).expire(expiration)
+        };
+        quiet_if(b.cas(cas), noreply)
+      },
+      Command::Delete{ ref key, noreply } => quiet_if(RequestBuilder::delete(key), noreply),
+      Command::Arith{ code, ref key, delta, noreply } => {
+        let b = match code {
+          OpCode::Decrement => RequestBuilder::decr(key, delta),
+          _ => RequestBuilder::incr(key, delta)
+        };
+        quiet_if(b.expire(0xFFFFFFFF), noreply)
+      },
+      Command::Touch{ ref key, expiration, .. } => RequestBuilder::touch(key, expiration),
+      Command::Flush{ delay, noreply } => match delay {
+        Option::Some(delay) => quiet_if(RequestBuilder::flush().expire(delay), noreply),
+        Option::None => quiet_if(RequestBuilder::flush(), noreply)
+      },
+      Command::Stats(ref group) => RequestBuilder::stat(group.as_ref().map(|g| g.as_slice())),
+      Command::Version => RequestBuilder::version(),
+      Command::Verbosity{ level, .. } => RequestBuilder::verbosity(level),
+      Command::Quit => RequestBuilder::quit()
+    };
+    Ok(vec![b.opaque(first).build()?])
+  }
+  /// The text command with the same meaning as a binary
+  /// request. `None` if the text protocol cannot express it
+  /// (such as a `Nop`, or a `Delete` with a CAS).
+  pub fn from_request(req: &OwnedRequest) -> Option<Command> {
+    let code = req.get_opcode();
+    let noreply = code.is_quiet();
+    let key = req.key.clone();
+    let extras = req.get_typed_extra().ok()?;
+    let cmd = match (code.loud_form(), extras) {
+      (OpCode::Get, _) |
+      (OpCode::GetK, _) => Command::Get{ keys: vec![key], cas: true },
+      (OpCode::GAT, ReqExtras::Touch(e)) => Command::Gat{ expiration: e.expiration, keys: vec![key], cas: true },
+      (OpCode::Set, ReqExtras::Store(e)) |
+      (OpCode::Add, ReqExtras::Store(e)) |
+      (OpCode::Replace, ReqExtras::Store(e)) => Command::Store {
+        code: code.loud_form(),
+        key,
+        flags: e.flags,
+        expiration: e.expiration,
+        value: req.body.clone(),
+        cas: req.get_cas(),
+        noreply
+      },
+      (OpCode::Append, _) |
+      (OpCode::Prepare, _) => Command::Store {
+        code: code.loud_form(),
+        key,
+        flags: 0,
+        expiration: 0,
+        value: req.body.clone(),
+        cas: req.get_cas(),
+        noreply
+      },
+      (OpCode::Delete, _) if req.get_cas() == 0 => Command::Delete{ key, noreply },
+      (OpCode::Increment, ReqExtras::Arith(e)) |
+      (OpCode::Decrement, ReqExtras::Arith(e)) => Command::Arith {
+        code: code.loud_form(),
+        key,
+        delta: e.delta,
+        noreply
+      },
+      (OpCode::Touch, ReqExtras::Touch(e)) => Command::Touch{ key, expiration: e.expiration, noreply },
+      (OpCode::Flush, ReqExtras::Flush(e)) => Command::Flush{ delay: e.expiration, noreply },
+      (OpCode::Stat, _) => Command::Stats(req.get_key().map(|k| k.to_vec())),
+      (OpCode::Version, _) => Command::Version,
+      (OpCode::Verbosity, ReqExtras::Verbosity(e)) => Command::Verbosity{ level: e.verbosity, noreply },
+      (OpCode::Quit, _) => Command::Quit,
+      _ => return None
+    };
+    Some(cmd)
+  }
+}
+
+/// An item in a `VALUE` reply
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct TextValue {
+  pub key: Vec<u8>,
+  pub flags: u32,
+  pub value: Vec<u8>,
+  /// Only sent for `gets` and `gats`
+  pub cas: Option<u64>
+}
+
+/// A text protocol reply
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum Reply {
+  Stored,
+  NotStored,
+  Exists,
+  NotFound,
+  Deleted,
+  Touched,
+  Ok,
+  /// `VALUE` lines ending with `END`. A bare `END` parses as an
+  /// empty list.
+  Values(Vec<TextValue>),
+  /// The new value of a counter
+  Number(u64),
+  /// `STAT` lines ending with `END`
+  Stats(Vec<(String, String)>),
+  Version(String),
+  /// `ERROR`, the command is unknown
+  Error,
+  ClientError(String),
+  ServerError(String)
+}
+impl Reply {
+  /// Parse a reply from the front of `buf`
+  pub fn parse(buf: &[u8]) -> Parsed<Reply> {
+    let (line, used) = match read_line(buf) {
+      Parsed::Done(line, used) => (line, used),
+      Parsed::Incomplete => return Parsed::Incomplete,
+      Parsed::Invalid(e, n) => return Parsed::Invalid(e, n)
+    };
+    let mut parts = line.splitn(2, |b| *b == b' ');
+    let word = parts.next().unwrap_or(b"");
+    let rest = String::from_utf8_lossy(parts.next().unwrap_or(b"")).into_owned();
+    let reply = match word {
+      b"STORED" => Reply::Stored,
+      b"NOT_STORED" => Reply::NotStored,
+      b"EXISTS" => Reply::Exists,
+      b"NOT_FOUND" => Reply::NotFound,
+      b"DELETED" => Reply::Deleted,
+      b"TOUCHED" => Reply::Touched,
+      b"OK" => Reply::Ok,
+      b"ERROR" => Reply::Error,
+      b"CLIENT_ERROR" => Reply::ClientError(rest),
+      b"SERVER_ERROR" => Reply::ServerError(rest),
+      b"VERSION" => Reply::Version(rest),
+      b"END" |
+      b"VALUE" => return Reply::parse_values(buf),
+      b"STAT" => return Reply::parse_stats(buf),
+      x => match number(x) {
+        Ok(n) => Reply::Number(n),
+        Err(_) => return Parsed::Invalid(TextError::BadFormat("unknown reply"), used)
+      }
+    };
+    Parsed::Done(reply, used)
+  }
+  fn parse_values(buf: &[u8]) -> Parsed<Reply> {
+    let mut values = Vec::new();
+    let mut pos = 0;
+    loop {
+      let (line, used) = match read_line(&buf[pos..]) {
+        Parsed::Done(line, used) => (line, used),
+        Parsed::Incomplete => return Parsed::Incomplete,
+        Parsed::Invalid(e, n) => return Parsed::Invalid(e, pos + n)
+      };
+      pos += used;
+      let t = tokens(line);
+      let value = match t.as_slice() {
+        [b"END"] => return Parsed::Done(Reply::Values(values), pos),
+        [b"VALUE", key, flags, len] |
+        [b"VALUE", key, flags, len, _] => {
+          let header = number(flags).and_then(|f| data_len(len).map(|l| (f, l)));
+          let cas = match t.get(4) {
+            Option::Some(cas) => number(cas).map(Some),
+            Option::None => Ok(None)
+          };
+          match (header, cas) {
+            (Err(TextError::TooLarge), _) => return Parsed::Invalid(TextError::TooLarge, pos),
+            (Ok((flags, len)), Ok(cas)) => match read_block(&buf[pos..], len) {
+              Parsed::Done(block, n) => {
+                pos += n;
+                TextValue{ key: key.to_vec(), flags, value: block.to_vec(), cas }
+              },
+              Parsed::Incomplete => return Parsed::Incomplete,
+              Parsed::Invalid(e, n) => return Parsed::Invalid(e, pos + n)
+            },
+            _ => return Parsed::Invalid(BAD_LINE, pos)
+          }
+        },
+        _ => return Parsed::Invalid(BAD_LINE, pos)
+      };
+      values.push(value);
+    }
+  }
+  fn parse_stats(buf: &[u8]) -> Parsed<Reply> {
+    let mut stats = Vec::new();
+    let mut pos = 0;
+    loop {
+      let (line, used) = match read_line(&buf[pos..]) {
+        Parsed::Done(line, used) => (line, used),
+        Parsed::Incomplete => return Parsed::Incomplete,
+        Parsed::Invalid(e, n) => return Parsed::Invalid(e, pos + n)
+      };
+      pos += used;
+      if line == b"END" {
+        return Parsed::Done(Reply::Stats(stats), pos);
+      }
+      let mut parts = line.splitn(3, |b| *b == b' ');
+      match (parts.next(), parts.next()) {
+        (Some(b"STAT"), Some(name)) => {
+          let value = parts.next().unwrap_or(b"");
+          stats.push((String::from_utf8_lossy(name).into_owned(), String::from_utf8_lossy(value).into_owned()));
+        },
+        _ => return Parsed::Invalid(BAD_LINE, pos)
+      };
+    }
+  }
+  /// Encode the reply as it is sent on the wire
+  pub fn encode(&self) -> Vec<u8> {
+    let mut out = Vec::new();
+    match *self {
+      Reply::Stored => out.extend_from_slice(b"STORED\r\n"),
+      Reply::NotStored => out.extend_from_slice(b"NOT_STORED\r\n"),
+      Reply::Exists => out.extend_from_slice(b"EXISTS\r\n"),
+      Reply::NotFound => out.extend_from_slice(b"NOT_FOUND\r\n"),
+      Reply::Deleted => out.extend_from_slice(b"DELETED\r\n"),
+      Reply::Touched => out.extend_from_slice(b"TOUCHED\r\n"),
+      Reply::Ok => out.extend_from_slice(b"OK\r\n"),
+      Reply::Values(ref values) => {
+        for v in values.iter() {
+          out.extend_from_slice(b"VALUE ");
+          out.extend_from_slice(&v.key);
+          out.extend_from_slice(format!(" {} {}", v.flags, v.value.len()).as_bytes());
+          if let Some(cas) = v.cas {
+            out.extend_from_slice(format!(" {}", cas).as_bytes());
+          }
+          out.extend_from_slice(b"\r\n");
+          out.extend_from_slice(&v.value);
+          out.extend_from_slice(b"\r\n");
+        }
+        out.extend_from_slice(b"END\r\n");
+      },
+      Reply::Number(n) => out.extend_from_slice(format!("{}\r\n", n).as_bytes()),
+      Reply::Stats(ref stats) => {
+        for (name, value) in stats.iter() {
+          out.extend_from_slice(format!("STAT {} {}\r\n", name, value).as_bytes());
+        }
+        out.extend_from_slice(b"END\r\n");
+      },
+      Reply::Version(ref v) => out.extend_from_slice(format!("VERSION {}\r\n", v).as_bytes()),
+      Reply::Error => out.extend_from_slice(b"ERROR\r\n"),
+      Reply::ClientError(ref msg) => out.extend_from_slice(format!("CLIENT_ERROR {}\r\n", msg).as_bytes()),
+      Reply::ServerError(ref msg) => out.extend_from_slice(format!("SERVER_ERROR {}\r\n", msg).as_bytes())
+    };
+    out
+  }
+  /// The status a binary response would carry
+  pub fn status(&self) -> StatusField {
+    match *self {
+      Reply::NotStored => StatusField::ItemNotStored,
+      Reply::Exists => StatusField::KeyExists,
+      Reply::NotFound => StatusField::KeyNotFound,
+      Reply::Error => StatusField::UnknownCommand,
+      Reply::ClientError(ref msg) => if msg.contains("non-numeric") {
+        StatusField::IncrDecrNonNumeric
+      } else {
+        StatusField::InvalidArguments
+      },
+      Reply::ServerError(ref msg) => if msg.contains("too large") {
+        StatusField::ValueTooLarge
+      } else if msg.contains("out of memory") {
+        StatusField::OutOfMemory
+      } else {
+        StatusField::InternalError
+      },
+      _ => StatusField::NoError
+    }
+  }
+  /// The reply to `cmd` when it's binary form ended with
+  /// `status`. Successful commands which return data (gets,
+  /// counters, `stats`, `version`) should use `from_responses`.
+  pub fn for_status(cmd: &Command, status: StatusField) -> Reply {
+    match status {
+      StatusField::NoError => match *cmd {
+        Command::Get{ .. } |
+        Command::Gat{ .. } => Reply::Values(Vec::new()),
+        Command::Store{ .. } => Reply::Stored,
+        Command::Delete{ .. } => Reply::Deleted,
+        Command::Touch{ .. } => Reply::Touched,
+        Command::Arith{ .. } => Reply::Number(0),
+        Command::Stats(_) => Reply::Stats(Vec::new()),
+        Command::Version => Reply::Version(String::new()),
+        Command::Flush{ .. } |
+        Command::Verbosity{ .. } |
+        Command::Quit => Reply::Ok
+      },
+      StatusField::KeyNotFound => match *cmd {
+        //a cas on a missing key is `NOT_FOUND`
+        Command::Store{ cas: 0, .. } => Reply::NotStored,
+        _ => Reply::NotFound
+      },
+      StatusField::KeyExists => match *cmd {
+        Command::Store{ code: OpCode::Add, .. } => Reply::NotStored,
+        _ => Reply::Exists
+      },
+      StatusField::ItemNotStored => Reply::NotStored,
+      x => Reply::for_error(x)
+    }
+  }
+  /// The `ERROR`, `CLIENT_ERROR` or `SERVER_ERROR` reply for a
+  /// failure which has no reply of it's own
+  pub(crate) fn for_error(status: StatusField) -> Reply {
+    match status {
+      StatusField::IncrDecrNonNumeric => Reply::ClientError("cannot increment or decrement non-numeric value".to_string()),
+      StatusField::ValueTooLarge => Reply::ServerError("object too large for cache".to_string()),
+      StatusField::OutOfMemory => Reply::ServerError("out of memory storing object".to_string()),
+      StatusField::InvalidArguments => Reply::ClientError("bad command line format".to_string()),
+      StatusField::UnknownCommand |
+      StatusField::NotSupported => Reply::Error,
+      x => Reply::ServerError(status_message(x).to_lowercase())
+    }
+  }
+  /// The reply to `cmd` from the responses to the requests of
+  /// `cmd.to_requests(first)`. Quiet requests which succeeded
+  /// may have no response.
+  pub fn from_responses(cmd: &Command, first: u32, resps: &[OwnedResponse]) -> Reply {
+    let failed = resps.iter()
+      .map(|r| r.get_status())
+      .find(|s| *s != StatusField::NoError && *s != StatusField::KeyNotFound);
+    match *cmd {
+      Command::Get{ ref keys, cas } |
+      Command::Gat{ ref keys, cas, .. } => {
+        if let Some(status) = failed {
+          return Reply::for_status(cmd, status);
+        }
+        let values = resps.iter()
+          .filter(|r| r.get_status() == StatusField::NoError)
+          .filter_map(|r| {
+            let key = keys.get(r.get_opaque().wrapping_sub(first) as usize)?;
+            Some(TextValue {
+              key: key.clone(),
+              flags: item_flags(r),
+              value: r.body.clone(),
+              cas: if cas { Some(r.get_cas()) } else { None }
+            })
+          })
+          .collect();
+        Reply::Values(values)
+      },
+      Command::Stats(_) => match resps.iter().map(|r| r.get_status()).find(|s| *s != StatusField::NoError) {
+        Option::Some(status) => Reply::for_status(cmd, status),
+        Option::None => Reply::Stats(resps.iter()
+          .filter(|r| !r.key.is_empty())
+          .map(|r| (String::from_utf8_lossy(&r.key).into_owned(), String::from_utf8_lossy(&r.body).into_owned()))
+          .collect())
+      },
+      _ => match resps.first() {
+        Option::None => Reply::for_status(cmd, StatusField::NoError),
+        Option::Some(r) => match (cmd, r.get_status()) {
+          (&Command::Arith{ .. }, StatusField::NoError) if r.body.len() == 8 => {
+            let mut n = [0u8; 8];
+            n.copy_from_slice(&r.body);
+            Reply::Number(u64::from_be_bytes(n))
+          },
+          (&Command::Version, StatusField::NoError) => Reply::Version(String::from_utf8_lossy(&r.body).into_owned()),
+          (_, status) => Reply::for_status(cmd, status)
+        }
+      }
+    }
+  }
+  /// The binary responses answering `req`, from the text reply
+  /// to `Command::from_request(req)`. Quiet requests which
+  /// succeeded (or missed, for gets) get no response.
+  pub fn to_responses(&self, req: &OwnedRequest) -> Vec<OwnedResponse> {
+    let code = req.get_opcode();
+    let quiet = code.is_quiet();
+    let respond = |cas: u64, extra: Vec<u8>, key: Vec<u8>, body: Vec<u8>| {
+      OwnedResponse::new(code, StatusField::NoError, req.get_opaque(), cas, extra, key, body)
+    };
+    match *self {
+      Reply::Values(ref values) => match values.first() {
+        Option::Some(v) => {
+          let key = if code.loud_form() == OpCode::GetK { v.key.clone() } else { Vec::new() };
+          vec![respond(v.cas.unwrap_or(0), v.flags.to_be_bytes().to_vec(), key, v.value.clone())]
+        },
+        Option::None if quiet => Vec::new(),
+        Option::None => vec![status_response(req, StatusField::KeyNotFound)]
+      },
+      Reply::Stats(ref stats) => {
+        let mut resps: Vec<OwnedResponse> = stats.iter()
+          .map(|(name, value)| respond(0, Vec::new(), name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+          .collect();
+        resps.push(respond(0, Vec::new(), Vec::new(), Vec::new()));
+        resps
+      },
+      Reply::Number(_) |
+      Reply::Stored |
+      Reply::Deleted |
+      Reply::Touched |
+      Reply::Ok if quiet => Vec::new(),
+      Reply::Number(n) => vec![respond(0, Vec::new(), Vec::new(), n.to_be_bytes().to_vec())],
+      Reply::Version(ref v) => vec![respond(0, Vec::new(), Vec::new(), v.as_bytes().to_vec())],
+      ref x => match x.status() {
+        StatusField::NoError => vec![respond(0, Vec::new(), Vec::new(), Vec::new())],
+        status => vec![status_response(req, status)]
+      }
+    }
+  }
+}
+
+#[test]
+fn test_text_commands() {
+  let buf = b"set a 5 0 3 noreply\r\nabc\r\nget a b\r\n";
+  let (set, n) = match Command::parse(buf) {
+    Parsed::Done(c, n) => (c, n),
+    x => panic!("set should parse, not {:?}", x)
+  };
+  assert_eq!(set, Command::Store{ code: OpCode::Set, key: b"a".to_vec(), flags: 5, expiration: 0, value: b"abc".to_vec(), cas: 0, noreply: true });
+  assert_eq!(set.encode(), buf[..n].to_vec());
+  assert_eq!(Command::parse(&buf[n..]), Parsed::Done(Command::Get{ keys: vec![b"a".to_vec(), b"b".to_vec()], cas: false }, 9));
+  assert_eq!(Command::parse(&buf[..n - 1]), Parsed::Incomplete);
+  assert_eq!(Command::parse(b"get a"), Parsed::Incomplete);
+
+  let cas = Command::parse(b"cas k 0 -1 1 77\r\nx\r\n");
+  assert_eq!(cas, Parsed::Done(Command::Store{ code: OpCode::Set, key: b"k".to_vec(), flags: 0, expiration: RELATIVE_EXPIRATION_LIMIT + 1, value: b"x".to_vec(), cas: 77, noreply: false }, 20));
+  assert_eq!(Command::parse(b"delete k 0 noreply\r\n"), Parsed::Done(Command::Delete{ key: b"k".to_vec(), noreply: true }, 20));
+  assert_eq!(Command::parse(b"flush_all 10\r\n"), Parsed::Done(Command::Flush{ delay: Some(10), noreply: false }, 14));
+  assert_eq!(Command::parse(b"stats slabs\r\n"), Parsed::Done(Command::Stats(Some(b"slabs".to_vec())), 13));
+
+  assert_eq!(Command::parse(b"bogus\r\n"), Parsed::Invalid(TextError::UnknownCommand, 7));
+  assert_eq!(Command::parse(b"incr k x\r\n"), Parsed::Invalid(TextError::BadFormat("invalid numeric delta argument"), 10));
+  assert_eq!(Command::parse(b"set k 0 0 1\r\nxy\r\n"), Parsed::Invalid(BAD_CHUNK, 16));
+  assert_eq!(Command::parse(b"touch k 1 yes\r\n"), Parsed::Invalid(BAD_LINE, 15));
+
+  //data lengths are capped before anything is buffered
+  assert_eq!(Command::parse(b"set k 0 0 4000000000\r\nab"), Parsed::Invalid(TextError::TooLarge, 22));
+  assert_eq!(Command::parse(b"set k 0 0 18446744073709551615\r\nab"), Parsed::Invalid(TextError::TooLarge, 32));
+  assert_eq!(Command::parse(format!("set k 0 0 {}\r\n", MAX_BODY_LEN).as_bytes()), Parsed::Incomplete);
+  assert_eq!(TextError::TooLarge.reply().encode(), b"SERVER_ERROR object too large for cache\r\n".to_vec());
+  assert_eq!(read_block(b"ab", usize::MAX), Parsed::Invalid(TextError::TooLarge, 0));
+
+  //noreply picks the quiet opcode, counters are never created
+  let reqs = set.to_requests(4).unwrap();
+  assert_eq!((reqs[0].get_opcode(), reqs[0].get_opaque()), (OpCode::SetQ, 4));
+  assert_eq!(Command::from_request(&reqs[0]), Some(set));
+  let incr = Command::Arith{ code: OpCode::Increment, key: b"n".to_vec(), delta: 2, noreply: false };
+  match incr.to_requests(0).unwrap()[0].get_typed_extra().unwrap() {
+    ReqExtras::Arith(e) => assert_eq!((e.delta, e.expiration), (2, 0xFFFFFFFF)),
+    x => panic!("incr should carry arith extras, not {:?}", x)
+  };
+  let gets = Command::Get{ keys: vec![b"a".to_vec(), b"b".to_vec()], cas: true }.to_requests(10).unwrap();
+  let codes: Vec<(OpCode, u32)> = gets.iter().map(|r| (r.get_opcode(), r.get_opaque())).collect();
+  assert_eq!(codes, vec![(OpCode::GetKQ, 10), (OpCode::GetKQ, 11), (OpCode::Nop, 12)]);
+}
+
+#[test]
+fn test_text_replies() {
+  let values = Reply::Values(vec![
+    TextValue{ key: b"a".to_vec(), flags: 1, value: b"xyz".to_vec(), cas: Some(9) },
+    TextValue{ key: b"b".to_vec(), flags: 0, value: Vec::new(), cas: Some(10) }]);
+  let raw = values.encode();
+  assert_eq!(raw, b"VALUE a 1 3 9\r\nxyz\r\nVALUE b 0 0 10\r\n\r\nEND\r\n".to_vec());
+  assert_eq!(Reply::parse(&raw), Parsed::Done(values.clone(), raw.len()));
+  assert_eq!(Reply::parse(&raw[..raw.len() - 2]), Parsed::Incomplete);
+  let stats = Reply::Stats(vec![("pid".to_string(), "1".to_string()), ("version".to_string(), "1.6 beta".to_string())]);
+  assert_eq!(Reply::parse(&stats.encode()), Parsed::Done(stats.clone(), stats.encode().len()));
+  assert_eq!(Reply::parse(b"END\r\n"), Parsed::Done(Reply::Values(Vec::new()), 5));
+  assert_eq!(Reply::parse(b"42\r\n"), Parsed::Done(Reply::Number(42), 4));
+  assert_eq!(Reply::parse(b"SERVER_ERROR out of memory storing object\r\n"), Parsed::Done(Reply::ServerError("out of memory storing object".to_string()), 43));
+  assert_eq!(Reply::parse(b"WHAT\r\n"), Parsed::Invalid(TextError::BadFormat("unknown reply"), 6));
+  assert_eq!(Reply::parse(b"VALUE k 0 4000000000\r\nab"), Parsed::Invalid(TextError::TooLarge, 22));
+  assert_eq!(Reply::parse(b"VALUE k 0 18446744073709551615 1\r\nab"), Parsed::Invalid(TextError::TooLarge, 34));
+
+  let add = Command::Store{ code: OpCode::Add, key: b"k".to_vec(), flags: 0, expiration: 0, value: vec![], cas: 0, noreply: false };
+  assert_eq!(Reply::for_status(&add, StatusField::KeyExists), Reply::NotStored);
+  assert_eq!(Reply::for_status(&add, StatusField::KeyExists).status(), StatusField::ItemNotStored);
+  assert_eq!(Reply::for_status(&add, StatusField::OutOfMemory).status(), StatusField::OutOfMemory);
+  assert_eq!(Reply::Error.status(), StatusField::UnknownCommand);
+
+  let req = RequestBuilder::getk(b"a").opaque(3).build().unwrap();
+  let resps = values.to_responses(&req);
+  assert_eq!((resps[0].get_key(), resps[0].get_cas(), item_flags(&resps[0])), (Some(&b"a"[..]), 9, 1));
+  let miss = Reply::Values(Vec::new()).to_responses(&req);
+  assert_eq!(miss[0].get_status(), StatusField::KeyNotFound);
+  let quiet = RequestBuilder::getk(b"a").quiet().build().unwrap();
+  assert!(Reply::Values(Vec::new()).to_responses(&quiet).is_empty());
+}
@@ -0,0 +1,424 @@
+//! VBucket Routing
+//!
+//! Couchbase style clusters split the key space into a fixed
+//! number (a power of two) of vbuckets. A key's vbucket is
+//! `(crc32(key) >> 16) & 0x7FFF` masked to the vbucket count,
+//! and every request must carry it in `vbucket_id`. The cluster
+//! publishes which server is master for each vbucket, and during
+//! a rebalance a fast-forward map of where they are moving.
+//!
+//! A server answering `StatusField::VBucketNotHere` no longer
+//! owns the vbucket. `VBucketMap::not_my_vbucket` then names the
+//! fast-forward server to retry against, or asks for the map to
+//! be refreshed.
+//!
+//! `VBucketMap::from_json` (with the `json` cargo feature) reads
+//! the `vBucketServerMap` section of a cluster config.
+
+use super::status::StatusField;
+use super::builder::RequestBuilder;
+use super::request::OwnedRequest;
+use super::response::OwnedResponse;
+use super::client::{
+  Client,
+  ClientError,
+  Item
+};
+use std::error::Error;
+use std::fmt;
+use std::io::{
+  self,
+  Read,
+  Write
+};
+use std::net::TcpStream;
+
+/// Couchbase's standard vbucket count
+pub const DEFAULT_VBUCKETS: usize = 1024;
+
+/// CRC-32 (IEEE), as used for vbucket hashing
+pub fn crc32(data: &[u8]) -> u32 {
+  let mut crc: u32 = 0xFFFF_FFFF;
+  for b in data {
+    crc ^= *b as u32;
+    for _ in 0..8 {
+      crc = (crc >> 1) ^ (0xEDB8_8320 & 0u32.wrapping_sub(crc & 1));
+    }
+  }
+  !crc
+}
+
+/// The 15 bit hash a vbucket is picked from
+#[inline]
+pub fn vbucket_hash(key: &[u8]) -> u16 {
+  ((crc32(key) >> 16) & 0x7FFF) as u16
+}
+
+/// Invalid vbucket configurations
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum ConfigError {
+  /// The config is not valid JSON, or lacks a field
+  Json(String),
+  /// The server list is empty
+  NoServers,
+  /// The vbucket count is not a non zero power of two, or is
+  /// more then `0x8000`
+  BadVBucketCount(usize),
+  /// A vbucket names a server which is not in the list
+  BadServerIndex{ vbucket: usize, index: i64 },
+  /// The fast-forward map has a different vbucket count
+  ForwardLength(usize)
+}
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      ConfigError::Json(ref e) => write!(f, "invalid config: {}", e),
+      ConfigError::NoServers => write!(f, "no servers"),
+      ConfigError::BadVBucketCount(n) => write!(f, "{} vbuckets is not a power of two", n),
+      ConfigError::BadServerIndex{ vbucket, index } => write!(f, "vbucket {} has invalid server {}", vbucket, index),
+      ConfigError::ForwardLength(n) => write!(f, "fast-forward map has {} vbuckets", n)
+    }
+  }
+}
+impl Error for ConfigError { }
+
+/// What to do after a `VBucketNotHere`
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Redirect {
+  /// Retry against this server, from the fast-forward map
+  Retry(usize),
+  /// The map is out of date, fetch a new one
+  Refresh
+}
+
+/// One row of a vbucket map, the master then the replicas.
+/// `None` for a missing (`-1`) server.
+pub type Chain = Vec<Option<usize>>;
+
+/// Maps vbuckets to servers
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct VBucketMap {
+  servers: Vec<String>,
+  map: Vec<Chain>,
+  forward: Option<Vec<Chain>>,
+  mask: u16
+}
+impl VBucketMap {
+  /// `servers` are `host:port` strings, `map[vbucket]` is the
+  /// vbucket's chain of indexes into `servers`.
+  pub fn new(servers: Vec<String>, map: Vec<Chain>) -> Result<VBucketMap, ConfigError> {
+    if servers.is_empty() {
+      return Err(ConfigError::NoServers);
+    }
+    let n = map.len();
+    if n == 0 || !n.is_power_of_two() || n > 0x8000 {
+      return Err(ConfigError::BadVBucketCount(n));
+    }
+    check_chains(&map, servers.len())?;
+    Ok(VBucketMap {
+      servers,
+      map,
+      forward: None,
+      mask: (n - 1) as u16
+    })
+  }
+  /// Add a fast-forward map, the layout a rebalance is moving
+  /// towards
+  pub fn with_forward(mut self, forward: Vec<Chain>) -> Result<VBucketMap, ConfigError> {
+    if forward.len() != self.map.len() {
+      return Err(ConfigError::ForwardLength(forward.len()));
+    }
+    check_chains(&forward, self.servers.len())?;
+    self.forward = Some(forward);
+    Ok(self)
+  }
+  /// Read a cluster config. Either the whole bucket config, or
+  /// just it's `vBucketServerMap`.
+  #[cfg(feature = "json")]
+  pub fn from_json(config: &str) -> Result<VBucketMap, ConfigError> {
+    use serde_json::Value;
+
+    fn field<'a>(v: &'a Value, name: &str) -> Result<&'a Value, ConfigError> {
+      v.get(name).ok_or_else(|| ConfigError::Json(format!("missing {}", name)))
+    }
+    fn chains(v: &Value) -> Result<Vec<Chain>, ConfigError> {
+      let rows = v.as_array().ok_or_else(|| ConfigError::Json("vbucket map is not an array".to_string()))?;
+      rows.iter().enumerate().map(|(vbucket, row)| {
+        let row = row.as_array().ok_or_else(|| ConfigError::Json(format!("vbucket {} is not an array", vbucket)))?;
+        row.iter().map(|x| match x.as_i64() {
+          Option::Some(-1) => Ok(None),
+          Option::Some(i) if i >= 0 => Ok(Some(i as usize)),
+          Option::Some(index) => Err(ConfigError::BadServerIndex{ vbucket, index }),
+          Option::None => Err(ConfigError::Json(format!("vbucket {} has a non integer server", vbucket)))
+        }).collect()
+      }).collect()
+    }
+
+    let root: Value = serde_json::from_str(config).map_err(|e| ConfigError::Json(e.to_string()))?;
+    let v = root.get("vBucketServerMap").unwrap_or(&root);
+    if let Some(algorithm) = v.get("hashAlgorithm").and_then(|a| a.as_str()) {
+      if algorithm != "CRC" {
+        return Err(ConfigError::Json(format!("unsupported hash {}", algorithm)));
+      }
+    }
+    let servers = field(v, "serverList")?.as_array()
+      .ok_or_else(|| ConfigError::Json("serverList is not an array".to_string()))?
+      .iter()
+      .map(|s| s.as_str().map(|s| s.to_string()).ok_or_else(|| ConfigError::Json("server is not a string".to_string())))
+      .collect::<Result<Vec<String>, ConfigError>>()?;
+    let map = VBucketMap::new(servers, chains(field(v, "vBucketMap")?)?)?;
+    match v.get("vBucketMapForward") {
+      Option::Some(f) => map.with_forward(chains(f)?),
+      Option::None => Ok(map)
+    }
+  }
+  #[inline(always)]
+  pub fn servers(&self) -> &[String] {
+    &self.servers
+  }
+  /// Number of vbuckets
+  #[inline(always)]
+  pub fn len(&self) -> usize {
+    self.map.len()
+  }
+  #[inline(always)]
+  pub fn is_empty(&self) -> bool {
+    self.map.is_empty()
+  }
+  #[inline(always)]
+  pub fn has_forward(&self) -> bool {
+    self.forward.is_some()
+  }
+  #[inline]
+  pub fn vbucket_for_key(&self, key: &[u8]) -> u16 {
+    vbucket_hash(key) & self.mask
+  }
+  /// The vbucket's master server
+  #[inline]
+  pub fn master(&self, vbucket: u16) -> Option<usize> {
+    self.map.get(vbucket as usize).and_then(|c| c.first().cloned()).and_then(|s| s)
+  }
+  /// The vbucket's replica servers, skipping missing ones
+  pub fn replicas(&self, vbucket: u16) -> Vec<usize> {
+    match self.map.get(vbucket as usize) {
+      Option::Some(c) => c.iter().skip(1).filter_map(|s| *s).collect(),
+      Option::None => Vec::new()
+    }
+  }
+  /// The key's vbucket and it's master server
+  #[inline]
+  pub fn server_for_key(&self, key: &[u8]) -> (u16, Option<usize>) {
+    let vbucket = self.vbucket_for_key(key);
+    (vbucket, self.master(vbucket))
+  }
+  /// Set `vbucket_id` from the request's key. Returns the
+  /// master server, `None` for keyless requests or a vbucket
+  /// without a master.
+  pub fn stamp(&self, req: &mut OwnedRequest) -> Option<usize> {
+    let (vbucket, server) = match req.get_key() {
+      Option::Some(key) => self.server_for_key(key),
+      Option::None => return None
+    };
+    req.set_vbucket_id(vbucket);
+    server
+  }
+  /// `server` answered `VBucketNotHere` for `vbucket`. If the
+  /// fast-forward map moves the vbucket to another server, the
+  /// move is adopted and that server should be retried.
+  pub fn not_my_vbucket(&mut self, vbucket: u16, server: usize) -> Redirect {
+    let next = self.forward.as_ref()
+      .and_then(|f| f.get(vbucket as usize))
+      .cloned();
+    match next {
+      Option::Some(chain) => match chain.first() {
+        Option::Some(&Some(s)) if s != server => {
+          self.map[vbucket as usize] = chain;
+          Redirect::Retry(s)
+        },
+        _ => Redirect::Refresh
+      },
+      Option::None => Redirect::Refresh
+    }
+  }
+}
+
+fn check_chains(map: &[Chain], servers: usize) -> Result<(), ConfigError> {
+  for (vbucket, chain) in map.iter().enumerate() {
+    for index in chain.iter().filter_map(|s| *s) {
+      if index >= servers {
+        return Err(ConfigError::BadServerIndex{ vbucket, index: index as i64 });
+      }
+    }
+  }
+  Ok(())
+}
+
+/// A `Client` per server of a `VBucketMap`
+///
+/// Requests are stamped with their vbucket and sent to it's
+/// master. A `VBucketNotHere` is retried once against the
+/// fast-forward server. If there is none, or it rejects the
+/// vbucket as well, the response is returned and `needs_refresh`
+/// is set until a new map is installed.
+pub struct VBucketClient<S: Read + Write> {
+  map: VBucketMap,
+  clients: Vec<Client<S>>,
+  stale: bool
+}
+impl VBucketClient<TcpStream> {
+  /// Connect to every server of `map`
+  pub fn connect(map: VBucketMap) -> io::Result<VBucketClient<TcpStream>> {
+    let mut clients = Vec::with_capacity(map.servers().len());
+    for server in map.servers() {
+      let stream = TcpStream::connect(server.as_str())?;
+      stream.set_nodelay(true)?;
+      clients.push(Client::new(stream));
+    }
+    Ok(VBucketClient::new(map, clients))
+  }
+}
+impl<S: Read + Write> VBucketClient<S> {
+  /// `clients[i]` must be connected to `map.servers()[i]`.
+  ///
+  /// # Panics
+  ///
+  /// If the number of clients and servers differ.
+  pub fn new(map: VBucketMap, clients: Vec<Client<S>>) -> VBucketClient<S> {
+    assert_eq!(map.servers().len(), clients.len(), "every server needs a client");
+    VBucketClient {
+      map,
+      clients,
+      stale: false
+    }
+  }
+  #[inline(always)]
+  pub fn map(&self) -> &VBucketMap {
+    &self.map
+  }
+  /// A server rejected a vbucket the map could not redirect
+  #[inline(always)]
+  pub fn needs_refresh(&self) -> bool {
+    self.stale
+  }
+  /// Install a refreshed map with the same server list.
+  ///
+  /// # Panics
+  ///
+  /// If the server list changed, build a new client instead.
+  pub fn set_map(&mut self, map: VBucketMap) {
+    assert_eq!(map.servers(), self.map.servers(), "the server list changed");
+    self.map = map;
+    self.stale = false;
+  }
+  /// Client for the server at `index` of the map
+  #[inline(always)]
+  pub fn server(&mut self, index: usize) -> &mut Client<S> {
+    &mut self.clients[index]
+  }
+  /// Send a request to the master of it's key's vbucket. Keyless
+  /// requests go to the first server.
+  pub fn execute(&mut self, b: RequestBuilder) -> Result<OwnedResponse, ClientError> {
+    let (vbucket, server) = match b.get_key() {
+      Option::Some(key) => self.map.server_for_key(key),
+      Option::None => (0, Some(0))
+    };
+    let server = match server {
+      Option::Some(s) => s,
+      Option::None => {
+        self.stale = true;
+        return Err(ClientError::Status(StatusField::VBucketNotHere));
+      }
+    };
+    let resp = self.clients[server].execute(b.clone().vbucket(vbucket))?;
+    if resp.get_status() != StatusField::VBucketNotHere {
+      return Ok(resp);
+    }
+    match self.map.not_my_vbucket(vbucket, server) {
+      Redirect::Retry(next) => {
+        let resp = self.clients[next].execute(b.vbucket(vbucket))?;
+        //the fast-forward server rejected it too
+        if resp.get_status() == StatusField::VBucketNotHere {
+          self.stale = true;
+        }
+        Ok(resp)
+      },
+      Redirect::Refresh => {
+        self.stale = true;
+        Ok(resp)
+      }
+    }
+  }
+  /// Read an item. A missing key is `Ok(None)`.
+  pub fn get(&mut self, key: &[u8]) -> Result<Option<Item>, ClientError> {
+    let resp = self.execute(RequestBuilder::get(key))?;
+    match resp.check_status() {
+      Err(StatusField::KeyNotFound) => Ok(None),
+      Err(e) => Err(ClientError::Status(e)),
+      Ok(()) => Item::from_response(resp).map(Some)
+    }
+  }
+  /// Store an item. Returns the item's new CAS value.
+  pub fn set(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> Result<u64, ClientError> {
+    let resp = self.execute(RequestBuilder::set(key, value).flags(flags).expire(expiration))?;
+    resp.check_status()?;
+    Ok(resp.get_cas())
+  }
+  pub fn delete(&mut self, key: &[u8]) -> Result<(), ClientError> {
+    self.execute(RequestBuilder::delete(key))?.check_status()?;
+    Ok(())
+  }
+}
+
+#[test]
+fn test_vbucket_map() {
+  assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+  assert_eq!(vbucket_hash(b"123456789"), 0x4BF4);
+
+  let chains = |n: usize, f: &dyn Fn(usize) -> usize| (0..n).map(|i| vec![Some(f(i)), None]).collect::<Vec<Chain>>();
+  let servers = vec!["a:11210".to_string(), "b:11210".to_string()];
+  assert_eq!(VBucketMap::new(servers.clone(), chains(1000, &|_| 0)), Err(ConfigError::BadVBucketCount(1000)));
+  assert_eq!(VBucketMap::new(Vec::new(), chains(4, &|_| 0)), Err(ConfigError::NoServers));
+  assert_eq!(VBucketMap::new(servers.clone(), chains(4, &|_| 2)), Err(ConfigError::BadServerIndex{ vbucket: 0, index: 2 }));
+
+  let mut map = VBucketMap::new(servers, chains(DEFAULT_VBUCKETS, &|i| i % 2)).unwrap()
+    .with_forward(chains(DEFAULT_VBUCKETS, &|_| 1)).unwrap();
+  let vbucket = map.vbucket_for_key(b"123456789");
+  assert_eq!(vbucket, 0x4BF4 & 0x3FF);
+  assert_eq!(map.master(vbucket), Some(0));
+  assert!(map.replicas(vbucket).is_empty());
+
+  let mut req = RequestBuilder::get(b"123456789").build().unwrap();
+  assert_eq!(map.stamp(&mut req), Some(0));
+  assert_eq!(req.get_vbucket_id(), vbucket);
+
+  //the fast-forward map is adopted once
+  assert_eq!(map.not_my_vbucket(vbucket, 0), Redirect::Retry(1));
+  assert_eq!(map.master(vbucket), Some(1));
+  assert_eq!(map.not_my_vbucket(vbucket, 1), Redirect::Refresh);
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_vbucket_json() {
+  let config = r#"{
+    "name": "default",
+    "vBucketServerMap": {
+      "hashAlgorithm": "CRC",
+      "numReplicas": 1,
+      "serverList": ["10.0.0.1:11210", "10.0.0.2:11210"],
+      "vBucketMap": [[0, 1], [1, 0], [0, -1], [1, -1]],
+      "vBucketMapForward": [[1, 0], [1, 0], [1, -1], [1, -1]]
+    }
+  }"#;
+  let map = VBucketMap::from_json(config).unwrap();
+  assert_eq!(map.len(), 4);
+  assert_eq!(map.servers()[1], "10.0.0.2:11210");
+  assert_eq!(map.master(1), Some(1));
+  assert_eq!(map.replicas(0), vec![1]);
+  assert!(map.replicas(2).is_empty());
+  assert!(map.has_forward());
+
+  assert_eq!(VBucketMap::from_json(r#"{"serverList": ["a:1"], "vBucketMap": [[3]]}"#),
+    Err(ConfigError::BadServerIndex{ vbucket: 0, index: 3 }));
+  assert!(VBucketMap::from_json("{").is_err());
+}
@@ -0,0 +1,142 @@
+#![cfg(feature = "tokio")]
+
+extern crate mbpr;
+use mbpr::*;
+use mbpr::async_client::AsyncClient;
+use std::io::{
+  Read,
+  Write
+};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread;
+
+
+
+
+/*
+ *
+ *
+ * Loopback fake server
+ *
+ * Values are their own key. Replies to keys starting with
+ * `slow` are held back until the next request has been
+ * answered, so replies arrive out of order. Quiet requests
+ * are not answered.
+ *
+ *
+ */
+fn fake_server() -> String {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = listener.local_addr().unwrap().to_string();
+  thread::spawn(move || {
+    let (mut stream, _) = listener.accept().unwrap();
+    let mut decoder = StreamDecoder::<OwnedRequest>::new();
+    let mut held: Option<Vec<u8>> = None;
+    let mut buf = [0u8; 4096];
+    loop {
+      let n = match stream.read(&mut buf) {
+        Ok(0) | Err(_) => return,
+        Ok(n) => n
+      };
+      for req in decoder.feed(&buf[..n]).unwrap() {
+        let reply = |status, key: &[u8], body: &[u8]| OwnedResponse::new(req.get_opcode(), status, req.get_opaque(), 0, vec![], key.to_vec(), body.to_vec());
+        let resps = match req.get_opcode() {
+          OpCode::Get => vec![OwnedResponse::new(OpCode::Get, StatusField::NoError, req.get_opaque(), 1, GetResponseExtras::new(3).to_vec(), vec![], req.key.clone())],
+          OpCode::Version => vec![reply(StatusField::NoError, b"", b"1.6.0")],
+          OpCode::Set => vec![reply(StatusField::NoError, b"", b"")],
+          OpCode::Stat => vec![reply(StatusField::NoError, b"pid", b"1"), reply(StatusField::NoError, b"curr_items", b"0"), reply(StatusField::NoError, b"", b"")],
+          code if code.is_quiet() => continue,
+          _ => vec![reply(StatusField::KeyNotFound, b"", b"")]
+        };
+        let bytes: Vec<u8> = resps.iter().flat_map(|r| r.encode_self().get_vec()).collect();
+        if req.key.starts_with(b"slow") {
+          held = Some(bytes);
+          continue;
+        }
+        stream.write_all(&bytes).unwrap();
+        if let Some(b) = held.take() {
+          stream.write_all(&b).unwrap();
+        }
+      }
+    }
+  });
+  addr
+}
+
+#[test]
+fn async_client_multiplexes() {
+  let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+  rt.block_on(async {
+    let c = Arc::new(AsyncClient::connect(fake_server()).await.unwrap());
+    assert_eq!(c.version().await.unwrap(), "1.6.0");
+
+    //the slow reply arrives second, but both land correctly
+    let (a, b) = tokio::join!(c.get(b"slow_key"), c.get(b"fast_key"));
+    assert_eq!(a.unwrap().unwrap().value, b"slow_key".to_vec());
+    let b = b.unwrap().unwrap();
+    assert_eq!(b.value, b"fast_key".to_vec());
+    assert_eq!(b.flags, 3);
+
+    //many concurrent tasks
+    let mut tasks = Vec::new();
+    for i in 0..20 {
+      let c = c.clone();
+      tasks.push(tokio::spawn(async move {
+        let key = format!("key{}", i).into_bytes();
+        assert_eq!(c.get(&key).await.unwrap().unwrap().value, key);
+      }));
+    }
+    for t in tasks {
+      t.await.unwrap();
+    }
+
+    match c.delete(b"missing").await {
+      Err(mbpr::client::ClientError::Status(StatusField::KeyNotFound)) => { },
+      x => panic!("delete should fail with KeyNotFound, not {:?}", x)
+    };
+  });
+}
+
+#[test]
+fn async_client_survives_dropped_requests() {
+  let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+  rt.block_on(async {
+    let c = AsyncClient::connect(fake_server()).await.unwrap();
+
+    //poll once, so the request is sent, then give up on it
+    {
+      let slow = c.get(b"slow_dropped");
+      tokio::pin!(slow);
+      tokio::select! {
+        biased;
+        _ = &mut slow => panic!("the slow reply is held back"),
+        _ = async { } => { }
+      };
+    }
+
+    //the abandoned reply follows the next one and is discarded
+    assert_eq!(c.get(b"fast_key").await.unwrap().unwrap().value, b"fast_key".to_vec());
+    assert_eq!(c.get(b"next_key").await.unwrap().unwrap().value, b"next_key".to_vec());
+  });
+}
+
+#[test]
+fn async_client_waits_on_quiet_and_stat_requests() {
+  let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+  rt.block_on(async {
+    let c = AsyncClient::connect(fake_server()).await.unwrap();
+
+    //a quiet set goes out loud, so there is a reply to wait on
+    let resp = c.execute(RequestBuilder::set(b"k", b"v").quiet()).await.unwrap();
+    assert_eq!((resp.get_opcode(), resp.get_status()), (OpCode::Set, StatusField::NoError));
+
+    //stat entries arrive together, without upsetting later replies
+    let stats = c.stat(None).await.unwrap();
+    assert_eq!(stats, vec![("pid".to_string(), "1".to_string()), ("curr_items".to_string(), "0".to_string())]);
+    assert_eq!(c.stats(None).await.unwrap().curr_items(), Some(0));
+    let resp = c.execute(RequestBuilder::stat(None)).await.unwrap();
+    assert!(!resp.has_key());
+    assert_eq!(c.version().await.unwrap(), "1.6.0");
+  });
+}
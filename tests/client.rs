@@ -0,0 +1,289 @@
+
+extern crate mbpr;
+use mbpr::*;
+use mbpr::client::{
+  Client,
+  ClientError,
+  Item,
+  Pipeline
+};
+use std::collections::HashMap;
+use std::io::{
+  self,
+  Read,
+  Write
+};
+
+
+
+
+/*
+ *
+ *
+ * In process fake server
+ *
+ * Requests written to it are answered immediately, the
+ * replies are buffered until the client reads them.
+ *
+ *
+ */
+struct FakeServer {
+  decoder: StreamDecoder<OwnedRequest>,
+  out: Vec<u8>,
+  items: HashMap<Vec<u8>, (Vec<u8>, u32, u64)>,
+  datatypes: HashMap<Vec<u8>, DataType>,
+  cas: u64
+}
+impl FakeServer {
+  fn new() -> FakeServer {
+    FakeServer {
+      decoder: StreamDecoder::new(),
+      out: Vec::new(),
+      items: HashMap::new(),
+      datatypes: HashMap::new(),
+      cas: 0
+    }
+  }
+  fn reply(&mut self, req: &OwnedRequest, status: StatusField, cas: u64, extra: Vec<u8>, key: Vec<u8>, body: Vec<u8>) {
+    //quiet mutations only reply on failure
+    let code = req.get_opcode();
+    if code.is_quiet() && code != OpCode::GetKQ && status == StatusField::NoError {
+      return;
+    }
+    let resp = OwnedResponse::new(req.get_opcode(), status, req.get_opaque(), cas, extra, key, body);
+    self.out.extend_from_slice(resp.encode_self().as_slice());
+  }
+  fn hit(&mut self, req: &OwnedRequest, key: Vec<u8>) {
+    let (v, f, c) = self.items[&req.key].clone();
+    let mut resp = OwnedResponse::new(req.get_opcode(), StatusField::NoError, req.get_opaque(), c, GetResponseExtras::new(f).to_vec(), key, v);
+    resp.set_datatype(self.datatypes.get(&req.key).cloned().unwrap_or_default());
+    self.out.extend_from_slice(resp.encode_self().as_slice());
+  }
+  fn status(&mut self, req: &OwnedRequest, status: StatusField) {
+    self.reply(req, status, 0, vec![], vec![], vec![]);
+  }
+  fn handle(&mut self, req: OwnedRequest) {
+    let key = req.key.clone();
+    match (req.get_opcode(), req.get_typed_extra().unwrap()) {
+      (OpCode::Get, _) |
+      (OpCode::GAT, _) => if self.items.contains_key(&key) {
+        self.hit(&req, vec![]);
+      } else {
+        self.status(&req, StatusField::KeyNotFound);
+      },
+      (code, ReqExtras::Store(e)) => {
+        let exists = self.items.contains_key(&key);
+        let add = code == OpCode::Add || code == OpCode::AddQ;
+        let replace = code == OpCode::Replace || code == OpCode::ReplaceQ;
+        if (add && exists) || (replace && !exists) {
+          return self.status(&req, StatusField::ItemNotStored);
+        }
+        self.cas += 1;
+        let cas = self.cas;
+        self.datatypes.insert(key.clone(), req.get_datatype());
+        self.items.insert(key, (req.body.clone(), e.flags, cas));
+        self.reply(&req, StatusField::NoError, cas, vec![], vec![], vec![]);
+      },
+      (OpCode::GetKQ, _) => if self.items.contains_key(&key) {
+        self.hit(&req, key);
+      },
+      (OpCode::Append, _) |
+      (OpCode::AppendQ, _) |
+      (OpCode::Prepare, _) |
+      (OpCode::PrependQ, _) => match self.items.get_mut(&key) {
+        Some(item) => {
+          if req.get_opcode() == OpCode::Append || req.get_opcode() == OpCode::AppendQ {
+            item.0.extend_from_slice(&req.body);
+          } else {
+            let mut v = req.body.clone();
+            v.extend_from_slice(&item.0);
+            item.0 = v;
+          }
+          self.reply(&req, StatusField::NoError, 0, vec![], vec![], vec![]);
+        },
+        None => self.status(&req, StatusField::ItemNotStored)
+      },
+      (OpCode::Delete, _) |
+      (OpCode::DeleteQ, _) |
+      (OpCode::Touch, _) => {
+        let found = if req.get_opcode() != OpCode::Touch {
+          self.items.remove(&key).is_some()
+        } else {
+          self.items.contains_key(&key)
+        };
+        self.status(&req, if found { StatusField::NoError } else { StatusField::KeyNotFound });
+      },
+      (code, ReqExtras::Arith(e)) => {
+        let current = match self.items.get(&key) {
+          Some(&(ref v, _, _)) => String::from_utf8_lossy(v).parse::<u64>().unwrap(),
+          None => e.initial
+        };
+        let next = if !self.items.contains_key(&key) {
+          current
+        } else if code == OpCode::Increment || code == OpCode::IncrementQ {
+          current.wrapping_add(e.delta)
+        } else {
+          current.saturating_sub(e.delta)
+        };
+        self.items.insert(key, (next.to_string().into_bytes(), 0, 0));
+        let mut body = Vec::new();
+        body.extend_from_slice(&[
+          (next >> 56) as u8, (next >> 48) as u8, (next >> 40) as u8, (next >> 32) as u8,
+          (next >> 24) as u8, (next >> 16) as u8, (next >> 8) as u8, next as u8]);
+        self.reply(&req, StatusField::NoError, 0, vec![], vec![], body);
+      },
+      (OpCode::Flush, _) => {
+        self.items.clear();
+        self.status(&req, StatusField::NoError);
+      },
+      (OpCode::Version, _) => self.reply(&req, StatusField::NoError, 0, vec![], vec![], b"1.6.0".to_vec()),
+      (OpCode::Nop, _) => self.status(&req, StatusField::NoError),
+      (OpCode::Stat, _) => {
+        let count = self.items.len().to_string().into_bytes();
+        self.reply(&req, StatusField::NoError, 0, vec![], b"curr_items".to_vec(), count);
+        self.reply(&req, StatusField::NoError, 0, vec![], b"pid".to_vec(), b"42".to_vec());
+        self.status(&req, StatusField::NoError);
+      },
+      _ => self.status(&req, StatusField::UnknownCommand)
+    }
+  }
+}
+impl Write for FakeServer {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    for req in self.decoder.feed(buf).unwrap() {
+      self.handle(req);
+    }
+    Ok(buf.len())
+  }
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+impl Read for FakeServer {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let n = ::std::cmp::min(buf.len(), self.out.len());
+    buf[..n].copy_from_slice(&self.out[..n]);
+    self.out.drain(..n);
+    Ok(n)
+  }
+}
+
+
+
+
+#[test]
+fn client_store_and_get() {
+
+  let mut c = Client::new(FakeServer::new());
+  assert_eq!(c.get(b"Hello").unwrap(), None);
+  let cas = c.set(b"Hello", b"World", 0xDEADBEEF, 0).unwrap();
+  assert_eq!(c.get(b"Hello").unwrap(), Some(Item{ value: b"World".to_vec(), flags: 0xDEADBEEF, cas: cas }));
+
+  match c.add(b"Hello", b"Again", 0, 0) {
+    Err(ClientError::Status(StatusField::ItemNotStored)) => { },
+    x => panic!("add of an existing key should fail, not {:?}", x)
+  };
+  match c.replace(b"Missing", b"Value", 0, 0) {
+    Err(ClientError::Status(StatusField::ItemNotStored)) => { },
+    x => panic!("replace of a missing key should fail, not {:?}", x)
+  };
+  c.replace(b"Hello", b"There", 1, 0).unwrap();
+  c.append(b"Hello", b"!").unwrap();
+  c.prepend(b"Hello", b"Why ").unwrap();
+  assert_eq!(c.gat(b"Hello", 10).unwrap().unwrap().value, b"Why There!".to_vec());
+  c.touch(b"Hello", 100).unwrap();
+  c.delete(b"Hello").unwrap();
+  match c.delete(b"Hello") {
+    Err(ClientError::Status(StatusField::KeyNotFound)) => { },
+    x => panic!("delete of a missing key should fail, not {:?}", x)
+  };
+}
+
+#[test]
+fn client_counters_and_admin() {
+
+  let mut c = Client::new(FakeServer::new());
+  assert_eq!(c.incr(b"count", 5, 10, 0).unwrap(), 10);
+  assert_eq!(c.incr(b"count", 5, 10, 0).unwrap(), 15);
+  assert_eq!(c.decr(b"count", 20, 10, 0).unwrap(), 0);
+  c.noop().unwrap();
+  assert_eq!(c.version().unwrap(), "1.6.0");
+  let stats = c.stat(None).unwrap();
+  assert_eq!(stats, vec![
+    ("curr_items".to_string(), "1".to_string()),
+    ("pid".to_string(), "42".to_string())]);
+  c.flush(None).unwrap();
+  assert_eq!(c.get(b"count").unwrap(), None);
+
+  //invalid requests never reach the server
+  match c.get(b"bad key") {
+    Err(ClientError::Build(BuildError::InvalidKeyByte(b' ', 3))) => { },
+    x => panic!("invalid keys should be rejected, not {:?}", x)
+  };
+}
+
+#[test]
+fn client_multi_get() {
+
+  let mut c = Client::new(FakeServer::new());
+  c.set(b"a", b"1", 1, 0).unwrap();
+  c.set(b"c", b"3", 3, 0).unwrap();
+  let hits = c.multi_get(&[b"a", b"b", b"c"]).unwrap();
+  assert_eq!(hits.len(), 2);
+  assert_eq!(hits[&b"a".to_vec()].value, b"1".to_vec());
+  assert_eq!(hits[&b"c".to_vec()].flags, 3);
+  assert!(c.multi_get(&[]).unwrap().is_empty());
+
+  //the connection is still usable afterwards
+  assert_eq!(c.get(b"c").unwrap().unwrap().value, b"3".to_vec());
+}
+
+#[test]
+fn client_pipeline() {
+
+  let mut c = Client::new(FakeServer::new());
+  c.set(b"old", b"x", 0, 0).unwrap();
+
+  let mut p = Pipeline::new();
+  assert_eq!(p.set(b"a", b"1", 0, 0).unwrap(), 0);
+  p.add(b"old", b"y", 0, 0).unwrap();
+  p.delete(b"missing").unwrap();
+  p.append(b"a", b"2").unwrap();
+  p.incr(b"count", 1, 7, 0).unwrap();
+  p.replace(b"nope", b"z", 0, 0).unwrap();
+  assert!(p.delete(b"bad key").is_err());
+  assert_eq!(p.len(), 6);
+
+  let results = c.execute_pipeline(&p).unwrap();
+  assert_eq!(results, vec![
+    Ok(()),
+    Err(StatusField::ItemNotStored),
+    Err(StatusField::KeyNotFound),
+    Ok(()),
+    Ok(()),
+    Err(StatusField::ItemNotStored)]);
+  assert_eq!(c.get(b"a").unwrap().unwrap().value, b"12".to_vec());
+  assert_eq!(c.get(b"count").unwrap().unwrap().value, b"7".to_vec());
+  assert_eq!(c.execute_pipeline(&Pipeline::new()).unwrap(), vec![]);
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn client_compression() {
+  use mbpr::compression::Compression;
+
+  let mut c = Client::new(FakeServer::new());
+  c.set_compression(Some(Compression::new(64)));
+  let value = vec![b'z'; 1024];
+  c.set(b"big", &value, 0, 0).unwrap();
+  c.set(b"small", b"tiny", 0, 0).unwrap();
+
+  //the server holds the compressed form
+  let stored = c.get_ref().items[&b"big".to_vec()].0.len();
+  assert!(stored < value.len());
+  assert_eq!(c.get_ref().items[&b"small".to_vec()].0, b"tiny".to_vec());
+
+  assert_eq!(c.get(b"big").unwrap().unwrap().value, value);
+  let hits = c.multi_get(&[b"big", b"small"]).unwrap();
+  assert_eq!(hits[&b"big".to_vec()].value, value);
+}
@@ -0,0 +1,55 @@
+
+extern crate mbpr;
+use mbpr::*;
+use mbpr::cluster::{
+  ClusterClient,
+  Node,
+  ServerRing
+};
+use mbpr::testing::FakeServer;
+
+
+
+
+#[test]
+fn cluster_routes_by_key() {
+
+  let servers: Vec<FakeServer> = (0..3).map(|_| FakeServer::start().unwrap()).collect();
+  let nodes: Vec<Node> = servers.iter()
+    .map(|s| Node::new("127.0.0.1", s.addr().port()))
+    .collect();
+  let ring = ServerRing::ketama(nodes);
+  let mut c = ClusterClient::connect(ring.clone()).unwrap();
+
+  let keys: Vec<String> = (0..30).map(|i| format!("key:{}", i)).collect();
+  for key in keys.iter() {
+    c.set(key.as_bytes(), key.as_bytes(), 0, 0).unwrap();
+  }
+  //every key landed on the node the ring picked
+  for (i, s) in servers.iter().enumerate() {
+    let owned = keys.iter().filter(|k| ring.node_for_key(k.as_bytes()) == Some(i)).count();
+    assert_eq!(s.store().len(), owned);
+    assert!(s.received().iter().all(|r| ring.node_for_key(r.get_key().unwrap()) == Some(i)));
+  }
+  assert_eq!(c.get(b"key:7").unwrap().unwrap().value, b"key:7".to_vec());
+
+  //a multi get is split into a batch per node
+  for s in servers.iter() {
+    s.clear_received();
+  }
+  let wanted: Vec<&[u8]> = keys.iter().map(|k| k.as_bytes()).chain(Some(&b"missing"[..])).collect();
+  let hits = c.multi_get(&wanted).unwrap();
+  assert_eq!(hits.len(), keys.len());
+  assert_eq!(hits[&b"key:3".to_vec()].value, b"key:3".to_vec());
+  for s in servers.iter() {
+    let received = s.received();
+    if received.is_empty() {
+      continue;
+    }
+    assert_eq!(received.last().unwrap().get_opcode(), OpCode::Nop);
+    assert!(received[..received.len() - 1].iter().all(|r| r.get_opcode() == OpCode::GetKQ));
+  }
+
+  c.flush(None).unwrap();
+  assert!(servers.iter().all(|s| s.store().is_empty()));
+}
@@ -396,3 +396,37 @@ fn add_response() {
   test_request_fail(&msg, Fault::BadMagic);
 }
 
+
+#[test]
+fn constructed_packets_count_key_and_extras_in_body_length() {
+  //8 bytes of extras, a 3 byte key, a 5 byte value
+  let req = OwnedRequest::new(OpCode::Set, 0, 7, 0, vec![0u8; 8], b"key".to_vec(), b"value".to_vec());
+  let e = req.encode_self();
+  assert_eq!(e.len(), 24 + 16);
+  assert_eq!(&e.as_slice()[8..12], &[0, 0, 0, 16]);
+  let parsed = OwnedRequest::parse(e.as_slice()).unwrap();
+  assert_eq!(parsed.get_extra(), Some(&[0u8; 8][..]));
+  assert_eq!(parsed.get_key(), Some(&b"key"[..]));
+  assert_eq!(parsed.get_body(), Some(&b"value"[..]));
+
+  let extra = [0u8; 8];
+  let req = Request::new(OpCode::Set, 0, 7, 0, Some(&extra), Some(b"key"), Some(b"value"));
+  let e = req.encode_self();
+  assert_eq!(&e.as_slice()[8..12], &[0, 0, 0, 16]);
+  assert_eq!(Request::parse(e.as_slice()).unwrap().get_body(), Some(&b"value"[..]));
+
+  let resp = OwnedResponse::new(OpCode::Get, StatusField::NoError, 7, 1, vec![0u8; 4], b"key".to_vec(), b"value".to_vec());
+  let e = resp.encode_self();
+  assert_eq!(e.len(), 24 + 12);
+  assert_eq!(&e.as_slice()[8..12], &[0, 0, 0, 12]);
+  let parsed = OwnedResponse::parse(e.as_slice()).unwrap();
+  assert_eq!(parsed.get_extra(), Some(&[0u8; 4][..]));
+  assert_eq!(parsed.get_key(), Some(&b"key"[..]));
+  assert_eq!(parsed.get_body(), Some(&b"value"[..]));
+
+  let extra = [0u8; 4];
+  let resp = Response::new(OpCode::Get, StatusField::NoError, 7, 1, Some(&extra), Some(b"key"), Some(b"value"));
+  let e = resp.encode_self();
+  assert_eq!(&e.as_slice()[8..12], &[0, 0, 0, 12]);
+  assert_eq!(Response::parse(e.as_slice()).unwrap().get_body(), Some(&b"value"[..]));
+}
@@ -0,0 +1,55 @@
+
+extern crate mbpr;
+use mbpr::memory::MemoryStore;
+use mbpr::meta::{
+  MetaCommand,
+  MetaReply
+};
+use mbpr::server::{
+  dispatch,
+  Session
+};
+use mbpr::text::Parsed;
+
+
+
+
+/*
+ *
+ *
+ * Answer a stream of meta commands by translating each into a
+ * binary request and running it against a MemoryStore.
+ *
+ *
+ */
+fn run(store: &MemoryStore, mut input: &[u8]) -> Vec<u8> {
+  let mut session = Session::default();
+  let mut out = Vec::new();
+  while let Parsed::Done(cmd, used) = MetaCommand::parse(input) {
+    input = &input[used..];
+    let req = cmd.to_request(1).unwrap().expect("the command should have a binary form");
+    let resps = dispatch(store, &mut session, &req).responses;
+    let reply = MetaReply::from_response(&cmd, resps.first());
+    if !cmd.suppresses(&reply) {
+      out.extend_from_slice(&reply.encode());
+    }
+  }
+  out
+}
+
+#[test]
+fn meta_commands_over_binary_handler() {
+  let store = MemoryStore::new(1 << 20);
+  let out = run(&store, b"ms foo 3 F9 T0 q\r\nbar\r\nms foo 1 ME\r\nx\r\nmg foo v f k Oa1\r\n\
+mg missing v q\r\nmg missing v\r\nma n N0 J10 v\r\nma n MD D3 v\r\nmd foo q\r\nmd foo\r\nmn\r\n");
+  let expected: &[u8] = b"NS\r\nVA 3 Oa1 kfoo f9\r\nbar\r\nEN\r\nVA 2\r\n10\r\nVA 1\r\n7\r\nNF\r\nMN\r\n";
+  assert_eq!(String::from_utf8_lossy(&out), String::from_utf8_lossy(expected));
+
+  //CAS tokens guard updates
+  let cas = match MetaReply::parse(&run(&store, b"ms c 1 c\r\n1\r\n")) {
+    Parsed::Done(reply, _) => reply.get_flags().get_cas().unwrap(),
+    x => panic!("ms should return a CAS, not {:?}", x)
+  };
+  let update = format!("ms c 1 C{}\r\n2\r\nms c 1 C{}\r\n3\r\nmg c v\r\n", cas, cas);
+  assert_eq!(run(&store, update.as_bytes()), b"HD\r\nEX\r\nVA 1\r\n2\r\n".to_vec());
+}
@@ -0,0 +1,168 @@
+
+extern crate mbpr;
+use mbpr::*;
+use mbpr::client::Client;
+use mbpr::cluster::{
+  Node,
+  ServerRing
+};
+use mbpr::proxy::{
+  serve_binary,
+  serve_text
+};
+use mbpr::testing::FakeServer;
+use mbpr::text::{
+  Parsed,
+  Reply
+};
+use std::io::{
+  Read,
+  Write
+};
+use std::net::{
+  TcpListener,
+  TcpStream
+};
+use std::collections::HashSet;
+use std::thread;
+use std::time::Duration;
+
+
+
+
+/*
+ *
+ *
+ * Send text and read back `replies` replies
+ *
+ *
+ */
+fn roundtrip(conn: &mut TcpStream, input: &[u8], replies: usize) -> Vec<Reply> {
+  conn.write_all(input).unwrap();
+  let mut buf = Vec::new();
+  let mut out = Vec::new();
+  let mut chunk = [0u8; 1024];
+  while out.len() < replies {
+    match Reply::parse(&buf) {
+      Parsed::Done(reply, used) => {
+        out.push(reply);
+        buf.drain(..used);
+      },
+      Parsed::Incomplete => {
+        let n = conn.read(&mut chunk).unwrap();
+        assert!(n > 0, "the proxy closed the connection");
+        buf.extend_from_slice(&chunk[..n]);
+      },
+      Parsed::Invalid(e, _) => panic!("the proxy sent a malformed reply {:?}", e)
+    };
+  }
+  out
+}
+
+#[test]
+fn text_proxy_over_loopback() {
+  let upstream = FakeServer::start().unwrap();
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = listener.local_addr().unwrap();
+  let target = upstream.addr();
+  thread::spawn(move || serve_text(listener, target));
+
+  let mut conn = TcpStream::connect(addr).unwrap();
+  let replies = roundtrip(&mut conn, b"set a 1 0 2 noreply\r\nhi\r\nadd a 0 0 1\r\nx\r\ngets a b\r\n", 2);
+  assert_eq!(replies[0], Reply::NotStored);
+  let cas = match replies[1] {
+    Reply::Values(ref v) => {
+      assert_eq!(v.len(), 1);
+      assert_eq!((v[0].key.as_slice(), v[0].flags, v[0].value.as_slice()), (&b"a"[..], 1, &b"hi"[..]));
+      v[0].cas.unwrap()
+    },
+    ref x => panic!("gets should return values, not {:?}", x)
+  };
+
+  //noreply went out quiet, gets as quiet GetK with a closing Nop
+  let codes: Vec<OpCode> = upstream.received().iter().map(|r| r.get_opcode()).collect();
+  assert_eq!(codes, vec![OpCode::SetQ, OpCode::Add, OpCode::GetKQ, OpCode::GetKQ, OpCode::Nop]);
+
+  let update = format!("cas a 0 0 1 {}\r\n!\r\nincr a 1\r\nbogus\r\nversion\r\n", cas);
+  let replies = roundtrip(&mut conn, update.as_bytes(), 4);
+  assert_eq!(replies[0], Reply::Stored);
+  assert_eq!(replies[1], Reply::ClientError("cannot increment or decrement non-numeric value".to_string()));
+  assert_eq!(replies[2], Reply::Error);
+  match replies[3] {
+    Reply::Version(ref v) => assert!(!v.is_empty()),
+    ref x => panic!("version should return a version, not {:?}", x)
+  };
+
+  match roundtrip(&mut conn, b"stats\r\n", 1).pop().unwrap() {
+    Reply::Stats(stats) => assert!(stats.iter().any(|s| s.0 == "curr_items" && s.1 == "1")),
+    x => panic!("stats should return statistics, not {:?}", x)
+  };
+  conn.write_all(b"quit\r\n").unwrap();
+  assert_eq!(conn.read(&mut [0u8; 16]).unwrap(), 0);
+}
+
+#[test]
+fn text_proxy_refuses_oversized_values() {
+  let upstream = FakeServer::start().unwrap();
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = listener.local_addr().unwrap();
+  let target = upstream.addr();
+  thread::spawn(move || serve_text(listener, target));
+
+  //the value is never sent, the proxy must not wait on it
+  let mut conn = TcpStream::connect(addr).unwrap();
+  conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+  let replies = roundtrip(&mut conn, b"version\r\nset k 0 0 4000000000\r\nab", 2);
+  assert_eq!(replies[1], Reply::ServerError("object too large for cache".to_string()));
+  assert_eq!(conn.read(&mut [0u8; 16]).unwrap(), 0);
+  assert!(upstream.received().iter().all(|r| r.get_opcode() != OpCode::Set));
+}
+
+#[test]
+fn binary_proxy_routes_by_key() {
+  let upstreams = [FakeServer::start().unwrap(), FakeServer::start().unwrap()];
+  let ring = ServerRing::ketama(upstreams.iter().map(|u| Node::new("127.0.0.1", u.addr().port())).collect());
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = listener.local_addr().unwrap();
+  let proxy_ring = ring.clone();
+  thread::spawn(move || serve_binary(listener, proxy_ring));
+
+  //one key living on each node
+  let keys: Vec<Vec<u8>> = (0..2).map(|node| {
+    (0..100).map(|i| format!("key{}", i).into_bytes()).find(|k| ring.node_for_key(k) == Some(node)).unwrap()
+  }).collect();
+
+  //quiet successes and quiet misses are not answered, the Nop
+  //still arrives after them
+  let mut a = Client::new(TcpStream::connect(addr).unwrap());
+  a.send(&RequestBuilder::set(&keys[0], b"x").opaque(7).quiet().build().unwrap()).unwrap();
+  a.send(&RequestBuilder::set(&keys[1], b"y").opaque(8).quiet().build().unwrap()).unwrap();
+  a.send(&RequestBuilder::getk(b"missing").opaque(9).quiet().build().unwrap()).unwrap();
+  a.send(&RequestBuilder::noop().opaque(10).build().unwrap()).unwrap();
+  let resp = a.recv().unwrap();
+  assert_eq!((resp.get_opcode(), resp.get_opaque()), (OpCode::Nop, 10));
+
+  //a second client reuses the same opaques
+  let mut b = Client::new(TcpStream::connect(addr).unwrap());
+  for (key, value) in keys.iter().zip([&b"x"[..], &b"y"[..]].iter()) {
+    let resp = b.request(&RequestBuilder::getk(key).opaque(7).build().unwrap()).unwrap();
+    assert_eq!((resp.get_opcode(), resp.get_opaque()), (OpCode::GetK, 7));
+    assert_eq!((resp.key.as_slice(), resp.body.as_slice()), (key.as_slice(), *value));
+  }
+  let resp = b.request(&RequestBuilder::add(&keys[1], b"z").opaque(11).quiet().build().unwrap()).unwrap();
+  assert_eq!((resp.get_opcode(), resp.get_opaque(), resp.get_status()), (OpCode::AddQ, 11, StatusField::ItemNotStored));
+
+  //each upstream only saw it's own keys, loud and under distinct opaques
+  for (node, upstream) in upstreams.iter().enumerate() {
+    let received = upstream.received();
+    assert!(received.iter().all(|r| !r.get_opcode().is_quiet()));
+    assert!(received.iter().all(|r| ring.node_for_key(&r.key) == Some(node)));
+    let opaques: HashSet<u32> = received.iter().map(|r| r.get_opaque()).collect();
+    assert_eq!(opaques.len(), received.len());
+  }
+
+  a.send(&RequestBuilder::quit().opaque(12).build().unwrap()).unwrap();
+  let resp = a.recv().unwrap();
+  assert_eq!((resp.get_opcode(), resp.get_opaque()), (OpCode::Quit, 12));
+  assert_eq!(a.get_mut().read(&mut [0u8; 16]).unwrap(), 0);
+}
@@ -0,0 +1,90 @@
+
+extern crate mbpr;
+use mbpr::*;
+use mbpr::client::{
+  Client,
+  ClientError
+};
+use mbpr::range::{
+  range,
+  range_end,
+  RangeItem,
+  RangeRequest,
+  RangeResults
+};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::net::{
+  TcpListener,
+  TcpStream
+};
+use std::thread;
+
+
+
+
+#[test]
+fn range_get_results() {
+
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = listener.local_addr().unwrap();
+
+  //answers range gets from a sorted map, rejects anything else
+  let server = thread::spawn(move || {
+    let mut items = BTreeMap::new();
+    for (i, k) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+      items.insert(k.as_bytes().to_vec(), i.to_string().into_bytes());
+    }
+    let (mut conn, _) = listener.accept().unwrap();
+    let mut d = StreamDecoder::<OwnedRequest>::new();
+    loop {
+      let req = match d.next_packet().unwrap() {
+        Some(req) => req,
+        None => if d.read_from(&mut conn).unwrap() == 0 {
+          return;
+        } else {
+          continue;
+        }
+      };
+      let code = req.get_opcode();
+      let opaque = req.get_opaque();
+      let r = match RangeRequest::parse(&req) {
+        Ok(r) if code == OpCode::RGet => r,
+        _ => {
+          let err = OwnedResponse::new(code, StatusField::UnknownCommand, opaque, 0, vec![], vec![], vec![]);
+          conn.write_all(err.encode_self().as_slice()).unwrap();
+          continue;
+        }
+      };
+      let limit = match r.max_results {
+        0 => usize::MAX,
+        n => n as usize
+      };
+      for (k, v) in items.range(r.start.to_vec()..=r.end.to_vec()).take(limit) {
+        let item = RangeItem{ key: k.clone(), value: v.clone(), flags: 0, cas: 1 };
+        conn.write_all(item.to_response(code, opaque).encode_self().as_slice()).unwrap();
+      }
+      conn.write_all(range_end(code, opaque).encode_self().as_slice()).unwrap();
+    }
+  });
+
+  let mut c = Client::new(TcpStream::connect(addr).unwrap());
+  let all = range(&mut c, &RangeRequest::new(OpCode::RGet, b"b", b"d")).unwrap();
+  let keys: Vec<Vec<u8>> = all.iter().map(|i| i.key.clone()).collect();
+  assert_eq!(keys, vec![b"b".to_vec(), b"c".to_vec(), b"d".to_vec()]);
+  assert_eq!(all[0].value, b"1".to_vec());
+
+  let first = RangeResults::start(&mut c, &RangeRequest::new(OpCode::RGet, b"a", b"z").max_results(2))
+    .unwrap()
+    .map(|i| i.unwrap().key)
+    .collect::<Vec<_>>();
+  assert_eq!(first, vec![b"a".to_vec(), b"b".to_vec()]);
+
+  assert!(range(&mut c, &RangeRequest::new(OpCode::RGet, b"x", b"z")).unwrap().is_empty());
+  match range(&mut c, &RangeRequest::new(OpCode::RDelete, b"a", b"z")) {
+    Err(ClientError::Status(StatusField::UnknownCommand)) => { },
+    x => panic!("the rejection should be returned, not {:?}", x.map(|v| v.len()))
+  };
+  drop(c);
+  server.join().unwrap();
+}
@@ -0,0 +1,238 @@
+
+extern crate mbpr;
+use mbpr::*;
+use mbpr::cluster::{
+  Node,
+  ServerRing
+};
+use mbpr::route::{
+  AllAsyncRoute,
+  AllSyncRoute,
+  FailoverRoute,
+  PoolRoute,
+  Route,
+  WarmupRoute,
+  CONNECT_TIMEOUT,
+  RETRY_MIN
+};
+use mbpr::testing::{
+  Action,
+  FakeServer
+};
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{
+  Duration,
+  Instant
+};
+
+
+
+
+/*
+ *
+ *
+ * Route a request and wait for it's answer
+ *
+ *
+ */
+fn call(route: &dyn Route, req: OwnedRequest) -> OwnedResponse {
+  let (tx, rx) = mpsc::channel();
+  route.route(req, Box::new(move |resps| {
+    let _ = tx.send(resps);
+  }));
+  rx.recv_timeout(Duration::from_secs(5)).unwrap().pop().unwrap()
+}
+
+/*
+ *
+ *
+ * A single server pool
+ *
+ *
+ */
+fn pool(server: &FakeServer) -> Arc<dyn Route> {
+  Arc::new(PoolRoute::new(ServerRing::ketama(vec![Node::new("127.0.0.1", server.addr().port())])))
+}
+
+/*
+ *
+ *
+ * Wait for requests sent without waiting on an answer
+ *
+ *
+ */
+fn wait_received(server: &FakeServer, count: usize) -> Vec<OwnedRequest> {
+  for _ in 0..500 {
+    let received = server.received();
+    if received.len() >= count {
+      return received;
+    }
+    thread::sleep(Duration::from_millis(10));
+  }
+  panic!("the server only received {} requests", server.received().len());
+}
+
+#[test]
+fn pool_route_broadcasts_flush() {
+  let servers = [FakeServer::start().unwrap(), FakeServer::start().unwrap()];
+  let nodes = servers.iter().map(|s| Node::new("127.0.0.1", s.addr().port())).collect();
+  let route = PoolRoute::new(ServerRing::modulo(nodes));
+
+  let resp = call(&route, RequestBuilder::flush().opaque(9).build().unwrap());
+  assert_eq!((resp.get_opcode(), resp.get_status(), resp.get_opaque()), (OpCode::Flush, StatusField::NoError, 9));
+  servers[1].push(Action::Status(StatusField::OutOfMemory));
+  let resp = call(&route, RequestBuilder::verbosity(1).build().unwrap());
+  assert_eq!(resp.get_status(), StatusField::OutOfMemory);
+  for server in servers.iter() {
+    let codes: Vec<OpCode> = server.received().iter().map(|r| r.get_opcode()).collect();
+    assert_eq!(codes, vec![OpCode::Flush, OpCode::Verbosity]);
+  }
+
+  //other keyless requests still go to a single server
+  call(&route, RequestBuilder::version().build().unwrap());
+  assert_eq!(servers[0].received().len() + servers[1].received().len(), 5);
+}
+
+#[test]
+fn failover_route_skips_unavailable_pools() {
+  let servers = [FakeServer::start().unwrap(), FakeServer::start().unwrap()];
+  let route = FailoverRoute::new(vec![pool(&servers[0]), pool(&servers[1])]);
+
+  servers[0].push(Action::Status(StatusField::Busy));
+  let resp = call(&route, RequestBuilder::set(b"a", b"1").opaque(3).build().unwrap());
+  assert_eq!((resp.get_status(), resp.get_opaque()), (StatusField::NoError, 3));
+  assert_eq!(servers[1].received().len(), 1);
+
+  //a dropped connection fails over too
+  servers[0].push(Action::Drop);
+  let resp = call(&route, RequestBuilder::get(b"a").opaque(4).build().unwrap());
+  assert_eq!((resp.get_status(), resp.body.as_slice()), (StatusField::NoError, &b"1"[..]));
+
+  //the failed server is left alone for a while
+  let resp = call(&route, RequestBuilder::get(b"b").build().unwrap());
+  assert_eq!(resp.get_status(), StatusField::KeyNotFound);
+  assert_eq!((servers[0].received().len(), servers[1].received().len()), (2, 3));
+
+  //then reconnected, and a miss is an answer
+  thread::sleep(RETRY_MIN);
+  let resp = call(&route, RequestBuilder::get(b"b").build().unwrap());
+  assert_eq!(resp.get_status(), StatusField::KeyNotFound);
+  assert_eq!((servers[0].received().len(), servers[1].received().len()), (3, 3));
+}
+
+#[test]
+fn failover_route_skips_unreachable_servers() {
+  let server = FakeServer::start().unwrap();
+  //nothing answers in TEST-NET-1
+  let unreachable = Arc::new(PoolRoute::new(ServerRing::ketama(vec![Node::new("192.0.2.1", 11211)])));
+  let route = FailoverRoute::new(vec![unreachable, pool(&server)]);
+
+  let started = Instant::now();
+  let resp = call(&route, RequestBuilder::set(b"a", b"1").build().unwrap());
+  assert_eq!(resp.get_status(), StatusField::NoError);
+  assert!(started.elapsed() < CONNECT_TIMEOUT * 2);
+
+  //the server is down now, and skipped without waiting
+  let started = Instant::now();
+  for _ in 0..10 {
+    let resp = call(&route, RequestBuilder::get(b"a").build().unwrap());
+    assert_eq!(resp.body.as_slice(), &b"1"[..]);
+  }
+  assert!(started.elapsed() < RETRY_MIN);
+  assert_eq!(server.received().len(), 11);
+}
+
+#[test]
+fn all_sync_route_reports_the_worst_status() {
+  let servers = [FakeServer::start().unwrap(), FakeServer::start().unwrap()];
+  let route = AllSyncRoute::new(vec![pool(&servers[0]), pool(&servers[1])]);
+
+  let resp = call(&route, RequestBuilder::set(b"a", b"1").opaque(5).build().unwrap());
+  assert_eq!((resp.get_opcode(), resp.get_status(), resp.get_opaque()), (OpCode::Set, StatusField::NoError, 5));
+
+  servers[1].push(Action::Status(StatusField::ItemNotStored));
+  let resp = call(&route, RequestBuilder::replace(b"a", b"2").build().unwrap());
+  assert_eq!(resp.get_status(), StatusField::ItemNotStored);
+
+  servers[0].push(Action::Status(StatusField::KeyExists));
+  servers[1].push(Action::Status(StatusField::OutOfMemory));
+  let resp = call(&route, RequestBuilder::set(b"a", b"3").build().unwrap());
+  assert_eq!(resp.get_status(), StatusField::OutOfMemory);
+  for server in servers.iter() {
+    let codes: Vec<OpCode> = server.received().iter().map(|r| r.get_opcode()).collect();
+    assert_eq!(codes, vec![OpCode::Set, OpCode::Replace, OpCode::Set]);
+  }
+}
+
+#[test]
+fn all_async_route_answers_at_once() {
+  let servers = [FakeServer::start().unwrap(), FakeServer::start().unwrap()];
+  let route = AllAsyncRoute::new(vec![pool(&servers[0]), pool(&servers[1])]);
+  servers[0].push(Action::Delay(Duration::from_millis(200)));
+
+  let resp = call(&route, RequestBuilder::set(b"a", b"1").opaque(6).build().unwrap());
+  assert_eq!((resp.get_status(), resp.get_opaque()), (StatusField::NoError, 6));
+  for server in servers.iter() {
+    assert_eq!(wait_received(server, 1)[0].get_key(), Some(&b"a"[..]));
+  }
+  let resp = call(&route, RequestBuilder::get(b"a").build().unwrap());
+  assert_eq!(resp.get_status(), StatusField::KeyNotFound);
+}
+
+#[test]
+fn warmup_route_backfills_the_cold_pool() {
+  let cold = FakeServer::start().unwrap();
+  let warm = FakeServer::start().unwrap();
+  let resp = call(&*pool(&warm), RequestBuilder::set(b"a", b"warm").flags(7).build().unwrap());
+  assert_eq!(resp.get_status(), StatusField::NoError);
+  warm.clear_received();
+  let route = WarmupRoute::new(pool(&cold), pool(&warm), 60);
+
+  let resp = call(&route, RequestBuilder::getk(b"a").opaque(8).build().unwrap());
+  assert_eq!((resp.get_status(), resp.get_opaque(), resp.body.as_slice()), (StatusField::NoError, 8, &b"warm"[..]));
+  let received = wait_received(&cold, 2);
+  assert_eq!((received[0].get_opcode(), received[1].get_opcode()), (OpCode::GetK, OpCode::Add));
+  assert_eq!((received[1].get_key(), received[1].get_body()), (Some(&b"a"[..]), Some(&b"warm"[..])));
+
+  //now the cold pool answers by itself, mutations only go there
+  let resp = call(&route, RequestBuilder::get(b"a").build().unwrap());
+  assert_eq!((resp.get_status(), resp.extra.as_slice()), (StatusField::NoError, &[0, 0, 0, 7][..]));
+  let resp = call(&route, RequestBuilder::delete(b"a").build().unwrap());
+  assert_eq!(resp.get_status(), StatusField::NoError);
+  assert_eq!(warm.received().len(), 1);
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn route_tree_from_json() {
+  use mbpr::route::{
+    from_json,
+    ConfigError
+  };
+
+  let servers = [FakeServer::start().unwrap(), FakeServer::start().unwrap()];
+  servers[0].push(Action::Status(StatusField::TemporaryFailure));
+  let config = format!(r#"{{
+    "pools": {{ "main": {{ "servers": ["127.0.0.1:{}"] }} }},
+    "route": {{
+      "type": "failover",
+      "children": [
+        {{ "type": "pool", "pool": "main" }},
+        {{ "type": "all-sync", "children": [ {{ "type": "pool", "servers": ["127.0.0.1:{}"], "distribution": "modulo" }} ] }}
+      ]
+    }}
+  }}"#, servers[0].addr().port(), servers[1].addr().port());
+  let route = from_json(&config).unwrap();
+  let resp = call(&*route, RequestBuilder::set(b"a", b"1").build().unwrap());
+  assert_eq!(resp.get_status(), StatusField::NoError);
+  assert_eq!((servers[0].received().len(), servers[1].received().len()), (1, 1));
+
+  let err = |config: &str| from_json(config).err().unwrap();
+  assert_eq!(err(r#"{"route": {"type": "shadow"}}"#), ConfigError::UnknownRoute("shadow".to_string()));
+  assert_eq!(err(r#"{"route": {"type": "pool", "pool": "x"}}"#), ConfigError::UnknownPool("x".to_string()));
+  assert_eq!(err(r#"{"route": {"type": "pool", "servers": ["localhost"]}}"#), ConfigError::BadServer("localhost".to_string()));
+  assert_eq!(err(r#"{"route": {"type": "failover", "children": []}}"#), ConfigError::Empty);
+  assert!(matches!(err(r#"{"pools": {}}"#), ConfigError::Json(_)));
+}
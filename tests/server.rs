@@ -0,0 +1,162 @@
+
+extern crate mbpr;
+use mbpr::*;
+use mbpr::client::{
+  Client,
+  ClientError
+};
+use mbpr::memory::MemoryStore;
+use mbpr::server::serve;
+use std::net::{
+  TcpListener,
+  TcpStream
+};
+use std::sync::Arc;
+use std::thread;
+
+
+
+
+/*
+ *
+ *
+ * Run a MemoryStore on an ephemeral port and connect a
+ * client to it.
+ *
+ *
+ */
+fn connect(store: MemoryStore) -> Client<TcpStream> {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = listener.local_addr().unwrap();
+  let store = Arc::new(store);
+  thread::spawn(move || serve(listener, store));
+  Client::new(TcpStream::connect(addr).unwrap())
+}
+
+fn status<T: ::std::fmt::Debug>(x: Result<T, ClientError>) -> StatusField {
+  match x {
+    Err(ClientError::Status(s)) => s,
+    x => panic!("expected an error status, not {:?}", x)
+  }
+}
+
+
+
+
+#[test]
+fn memory_store_over_tcp() {
+
+  let mut c = connect(MemoryStore::new(1 << 20));
+  assert_eq!(c.get(b"Hello").unwrap(), None);
+  let cas = c.set(b"Hello", b"World", 7, 0).unwrap();
+  let item = c.get(b"Hello").unwrap().unwrap();
+  assert_eq!((item.value, item.flags, item.cas), (b"World".to_vec(), 7, cas));
+
+  //cas mismatches, and preconditions
+  let resp = c.execute(RequestBuilder::set(b"Hello", b"Again").cas(cas + 1)).unwrap();
+  assert_eq!(resp.check_status(), Err(StatusField::KeyExists));
+  let resp = c.execute(RequestBuilder::set(b"Hello", b"Again").cas(cas)).unwrap();
+  assert_eq!(resp.check_status(), Ok(()));
+  assert_eq!(status(c.add(b"Hello", b"x", 0, 0)), StatusField::ItemNotStored);
+  assert_eq!(status(c.replace(b"Missing", b"x", 0, 0)), StatusField::ItemNotStored);
+  c.append(b"Hello", b"!").unwrap();
+  assert_eq!(status(c.prepend(b"Missing", b"x")), StatusField::ItemNotStored);
+  c.prepend(b"Hello", b"Why ").unwrap();
+  assert_eq!(c.gat(b"Hello", 100).unwrap().unwrap().value, b"Why Again!".to_vec());
+
+  //counters
+  assert_eq!(c.incr(b"count", 1, 10, 0).unwrap(), 10);
+  assert_eq!(c.incr(b"count", 5, 10, 0).unwrap(), 15);
+  assert_eq!(c.decr(b"count", 20, 10, 0).unwrap(), 0);
+  assert_eq!(status(c.incr(b"none", 1, 0, 0xFFFF_FFFF)), StatusField::KeyNotFound);
+  assert_eq!(status(c.incr(b"Hello", 1, 0, 0)), StatusField::IncrDecrNonNumeric);
+
+  //quiet mutations through a pipeline
+  let mut p = client::Pipeline::new();
+  p.set(b"a", b"1", 0, 0).unwrap();
+  p.add(b"a", b"2", 0, 0).unwrap();
+  p.delete(b"missing").unwrap();
+  assert_eq!(c.execute_pipeline(&p).unwrap(), vec![
+    Ok(()),
+    Err(StatusField::ItemNotStored),
+    Err(StatusField::KeyNotFound)]);
+  let hits = c.multi_get(&[b"a", b"b", b"count"]).unwrap();
+  assert_eq!(hits.len(), 2);
+
+  let stats = c.stat(None).unwrap();
+  assert!(stats.iter().any(|&(ref k, ref v)| k == "curr_items" && v == "3"));
+  c.flush(None).unwrap();
+  assert_eq!(c.get(b"a").unwrap(), None);
+  c.noop().unwrap();
+  assert_eq!(c.version().unwrap(), env!("CARGO_PKG_VERSION"));
+}
+
+#[test]
+fn sasl_authentication() {
+  use mbpr::auth::{
+    Authenticator,
+    Credentials,
+    Mechanism,
+    list_mechanisms
+  };
+
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = listener.local_addr().unwrap();
+  let store = Authenticator::new(MemoryStore::new(1 << 20)).with_user("tim", "tanstaaftanstaaf");
+  thread::spawn(move || serve(listener, Arc::new(store)));
+  let connect = || Client::new(TcpStream::connect(addr).unwrap());
+
+  //nothing but SASL before authenticating
+  let mut c = connect();
+  assert_eq!(status(c.get(b"key")), StatusField::AuthError);
+  assert_eq!(list_mechanisms(&mut c).unwrap(), vec!["CRAM-MD5", "PLAIN"]);
+  let wrong = Credentials::new("tim", "guess");
+  assert_eq!(status(wrong.authenticate(&mut c, Mechanism::CramMd5)), StatusField::AuthError);
+  assert_eq!(status(wrong.authenticate(&mut c, Mechanism::Plain)), StatusField::AuthError);
+  //near misses, one byte off or a prefix/extension of the password
+  for guess in ["tanstaaftanstaaF", "tanstaaftanstaa", "tanstaaftanstaafx"].iter() {
+    let near = Credentials::new("tim", guess);
+    assert_eq!(status(near.authenticate(&mut c, Mechanism::Plain)), StatusField::AuthError);
+    assert_eq!(status(near.authenticate(&mut c, Mechanism::CramMd5)), StatusField::AuthError);
+  }
+  assert_eq!(status(c.set(b"key", b"value", 0, 0)), StatusField::AuthError);
+
+  let good = Credentials::new("tim", "tanstaaftanstaaf");
+  assert_eq!(good.authenticate_best(&mut c).unwrap(), Mechanism::CramMd5);
+  c.set(b"key", b"value", 0, 0).unwrap();
+
+  let mut c = connect();
+  good.authenticate(&mut c, Mechanism::Plain).unwrap();
+  assert_eq!(c.get(b"key").unwrap().unwrap().value, b"value".to_vec());
+}
+
+#[test]
+fn stats_groups() {
+
+  let mut c = connect(MemoryStore::new(1 << 20));
+  c.set(b"a", b"1", 0, 0).unwrap();
+  c.set(b"b", b"2", 0, 0).unwrap();
+  c.get(b"a").unwrap();
+  c.get(b"missing").unwrap();
+
+  let stats = c.stats(None).unwrap();
+  assert_eq!(stats.curr_items(), Some(2));
+  assert_eq!(stats.get_hits(), Some(1));
+  assert_eq!(stats.get_misses(), Some(1));
+  assert_eq!(stats.evictions(), Some(0));
+  assert!(stats.bytes().unwrap() > 0);
+  assert!(stats.uptime().is_some());
+
+  let settings = c.stats(Some("settings")).unwrap();
+  assert_eq!(settings.get_u64("maxbytes"), Some(1 << 20));
+  assert_eq!(settings.get_bool("cas_enabled"), Some(true));
+  let items = c.stats(Some("items")).unwrap().by_slab();
+  assert_eq!(items[&1].get_u64("number"), Some(2));
+  let slabs = c.stats(Some("slabs")).unwrap();
+  assert_eq!(slabs.get_u64("active_slabs"), Some(1));
+  assert_eq!(slabs.by_slab()[&1].get_u64("used_chunks"), Some(2));
+  assert_eq!(status(c.stats(Some("nope"))), StatusField::KeyNotFound);
+
+  //the connection is still in step afterwards
+  assert_eq!(c.get(b"b").unwrap().unwrap().value, b"2".to_vec());
+}
@@ -0,0 +1,71 @@
+
+extern crate mbpr;
+use mbpr::*;
+use mbpr::tap::{
+  TapConnect,
+  TapConnectExtras,
+  TapConnectFlags,
+  TapFlags,
+  TapHeader,
+  TapMutationExtras,
+  TapStream
+};
+use std::io::Write;
+use std::net::{
+  TcpListener,
+  TcpStream
+};
+use std::thread;
+
+
+
+
+#[test]
+fn tap_stream_dump() {
+
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = listener.local_addr().unwrap();
+
+  //a producer which dumps two items and waits for an ack
+  let producer = thread::spawn(move || {
+    let (mut conn, _) = listener.accept().unwrap();
+    let mut d = StreamDecoder::<OwnedRequest>::new();
+    let connect = loop {
+      match d.next_packet().unwrap() {
+        Some(req) => break req,
+        None => { d.read_from(&mut conn).unwrap(); }
+      };
+    };
+    assert_eq!(connect.get_opcode(), OpCode::TAPConnect);
+    let flags = TapConnectExtras::parse_extras(&connect.extra).unwrap().flags;
+    assert!(flags.contains(TapConnectFlags::DUMP | TapConnectFlags::SUPPORT_ACK));
+
+    let header = TapHeader{ engine_len: 0, flags: TapFlags::ACK, ttl: 1 };
+    let mutate = TapMutationExtras{ header, item_flags: 3, expiration: 0 }.to_vec();
+    let first = OwnedRequest::new(OpCode::TAPMutate, 0, 1, 10, mutate, b"a".to_vec(), b"1".to_vec());
+    let second = OwnedRequest::new(OpCode::TAPDelete, 0, 2, 11, TapHeader::default().to_vec(), b"b".to_vec(), vec![]);
+    conn.write_all(first.encode_self().as_slice()).unwrap();
+    conn.write_all(second.encode_self().as_slice()).unwrap();
+
+    //only the first asked to be acknowledged
+    let mut d = StreamDecoder::<OwnedResponse>::new();
+    let ack = loop {
+      match d.next_packet().unwrap() {
+        Some(resp) => break resp,
+        None => { d.read_from(&mut conn).unwrap(); }
+      };
+    };
+    assert_eq!((ack.get_opcode(), ack.get_opaque()), (OpCode::TAPMutate, 1));
+  });
+
+  let conn = TcpStream::connect(addr).unwrap();
+  let mut tap = TapStream::connect(conn, &TapConnect::new().dump().support_ack()).unwrap();
+  let first = tap.next_message().unwrap().unwrap();
+  assert_eq!(first.key, b"a".to_vec());
+  assert_eq!(first.value, b"1".to_vec());
+  assert_eq!(first.item, Some((3, 0)));
+  let second = tap.next_message().unwrap().unwrap();
+  assert_eq!((second.opcode, second.key), (OpCode::TAPDelete, b"b".to_vec()));
+  producer.join().unwrap();
+  assert!(tap.next_message().unwrap().is_none());
+}
@@ -0,0 +1,76 @@
+
+extern crate mbpr;
+use mbpr::*;
+use mbpr::client::{
+  Client,
+  ClientError
+};
+use mbpr::testing::{
+  Action,
+  FakeServer
+};
+use std::io;
+use std::time::{
+  Duration,
+  Instant
+};
+
+
+
+
+fn eof(x: Result<Option<client::Item>, ClientError>) -> bool {
+  match x {
+    Err(ClientError::Io(ref e)) => e.kind() == io::ErrorKind::UnexpectedEof,
+    _ => false
+  }
+}
+
+#[test]
+fn fake_server_scripted_faults() {
+
+  let server = FakeServer::start().unwrap();
+  let mut c = Client::new(server.connect().unwrap());
+  c.set(b"key", b"value", 0, 0).unwrap();
+
+  //an injected status, then back to normal
+  server.push(Action::Status(StatusField::Busy));
+  server.push(Action::Pass);
+  match c.get(b"key") {
+    Err(ClientError::Status(StatusField::Busy)) => { },
+    x => panic!("the scripted status should be returned, not {:?}", x)
+  };
+  assert_eq!(c.get(b"key").unwrap().unwrap().value, b"value".to_vec());
+  assert_eq!(c.get(b"key").unwrap().unwrap().value, b"value".to_vec());
+
+  server.push(Action::Delay(Duration::from_millis(50)));
+  let start = Instant::now();
+  c.noop().unwrap();
+  assert!(start.elapsed() >= Duration::from_millis(50));
+
+  let received = server.received();
+  let codes: Vec<OpCode> = received.iter().map(|r| r.get_opcode()).collect();
+  assert_eq!(codes, vec![OpCode::Set, OpCode::Get, OpCode::Get, OpCode::Get, OpCode::Nop]);
+  assert_eq!(received[0].body, b"value".to_vec());
+  server.clear_received();
+  assert!(server.received().is_empty());
+
+  //a dropped connection
+  server.push(Action::Drop);
+  assert!(eof(c.get(b"key")));
+
+  //half a packet, then the connection closes
+  let mut c = Client::new(server.connect().unwrap());
+  server.push(Action::Truncate(30));
+  assert!(eof(c.get(b"key")));
+
+  //garbage instead of a reply
+  let mut c = Client::new(server.connect().unwrap());
+  let mut garbage = vec![0u8; 24];
+  garbage[0] = 0x42;
+  server.push(Action::Raw(garbage));
+  match c.get(b"key") {
+    Err(ClientError::Parse(e)) => assert_eq!(e, Fault::BadMagic),
+    x => panic!("garbage should not parse, not {:?}", x)
+  };
+  assert_eq!(server.received().len(), 3);
+}
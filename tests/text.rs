@@ -0,0 +1,73 @@
+
+extern crate mbpr;
+use mbpr::memory::MemoryStore;
+use mbpr::server::{
+  dispatch,
+  Session
+};
+use mbpr::text::{
+  Command,
+  Parsed,
+  Reply
+};
+
+
+
+
+/*
+ *
+ *
+ * Answer a stream of text commands by translating each into
+ * binary requests and running them against a MemoryStore.
+ *
+ *
+ */
+fn run(store: &MemoryStore, mut input: &[u8]) -> Vec<u8> {
+  let mut session = Session::default();
+  let mut out = Vec::new();
+  loop {
+    let (cmd, used) = match Command::parse(input) {
+      Parsed::Done(cmd, used) => (cmd, used),
+      Parsed::Incomplete => return out,
+      Parsed::Invalid(e, used) => {
+        out.extend_from_slice(&e.reply().encode());
+        input = &input[used..];
+        continue;
+      }
+    };
+    input = &input[used..];
+    let mut resps = Vec::new();
+    for req in cmd.to_requests(100).unwrap() {
+      resps.extend(dispatch(store, &mut session, &req).responses);
+    }
+    if !cmd.is_noreply() {
+      out.extend_from_slice(&Reply::from_responses(&cmd, 100, &resps).encode());
+    }
+  }
+}
+
+#[test]
+fn text_commands_over_binary_handler() {
+  let store = MemoryStore::new(1 << 20);
+  let out = run(&store, b"set a 3 0 5\r\nhello\r\nadd a 0 0 1\r\nx\r\nset n 0 0 2 noreply\r\n10\r\n\
+get a missing n\r\nincr n 5\r\ndecr missing 1\r\nincr a 1\r\ndelete a\r\ndelete a\r\nbogus\r\n");
+  let expected: &[u8] = b"STORED\r\nNOT_STORED\r\n\
+VALUE a 3 5\r\nhello\r\nVALUE n 0 2\r\n10\r\nEND\r\n\
+15\r\nNOT_FOUND\r\nCLIENT_ERROR cannot increment or decrement non-numeric value\r\n\
+DELETED\r\nNOT_FOUND\r\nERROR\r\n";
+  assert_eq!(String::from_utf8_lossy(&out), String::from_utf8_lossy(expected));
+
+  //cas tokens come back from gets and guard updates
+  let out = run(&store, b"gets n\r\n");
+  let cas = match Reply::parse(&out) {
+    Parsed::Done(Reply::Values(ref v), _) => v[0].cas.unwrap(),
+    x => panic!("gets should return a value, not {:?}", x)
+  };
+  let update = format!("cas n 0 0 1 {}\r\n1\r\ncas n 0 0 1 {}\r\n2\r\ntouch n 60\r\n", cas, cas);
+  assert_eq!(run(&store, update.as_bytes()), b"STORED\r\nEXISTS\r\nTOUCHED\r\n".to_vec());
+
+  match Reply::parse(&run(&store, b"stats\r\n")) {
+    Parsed::Done(Reply::Stats(stats), _) => assert!(stats.iter().any(|s| s.0 == "curr_items" && s.1 == "1")),
+    x => panic!("stats should return statistics, not {:?}", x)
+  };
+}
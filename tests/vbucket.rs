@@ -0,0 +1,75 @@
+
+extern crate mbpr;
+use mbpr::*;
+use mbpr::testing::{
+  Action,
+  FakeServer
+};
+use mbpr::vbucket::{
+  Chain,
+  VBucketClient,
+  VBucketMap
+};
+
+
+
+
+fn chains(n: usize, server: usize) -> Vec<Chain> {
+  (0..n).map(|_| vec![Some(server)]).collect()
+}
+
+#[test]
+fn vbucket_not_my_vbucket() {
+
+  let servers = vec![FakeServer::start().unwrap(), FakeServer::start().unwrap()];
+  let names: Vec<String> = servers.iter().map(|s| s.addr().to_string()).collect();
+  let map = VBucketMap::new(names.clone(), chains(64, 0)).unwrap()
+    .with_forward(chains(64, 1)).unwrap();
+  let mut c = VBucketClient::connect(map).unwrap();
+
+  c.set(b"Hello", b"World", 0, 0).unwrap();
+  let vbucket = c.map().vbucket_for_key(b"Hello");
+  let received = servers[0].received();
+  assert_eq!(received[0].get_vbucket_id(), vbucket);
+
+  //the rebalance moved the vbucket, the client follows the
+  //fast-forward map
+  servers[0].push(Action::Status(StatusField::VBucketNotHere));
+  c.set(b"Hello", b"Moved", 0, 0).unwrap();
+  assert_eq!(servers[1].received()[0].get_vbucket_id(), vbucket);
+  assert_eq!(c.map().master(vbucket), Some(1));
+  assert_eq!(c.get(b"Hello").unwrap().unwrap().value, b"Moved".to_vec());
+  assert!(!c.needs_refresh());
+
+  //without anywhere to go the map must be refreshed
+  servers[1].push(Action::Status(StatusField::VBucketNotHere));
+  match c.get(b"Hello") {
+    Err(client::ClientError::Status(StatusField::VBucketNotHere)) => { },
+    x => panic!("the rejection should be returned, not {:?}", x)
+  };
+  assert!(c.needs_refresh());
+  c.set_map(VBucketMap::new(names, chains(64, 1)).unwrap());
+  assert!(!c.needs_refresh());
+  assert_eq!(c.get(b"Hello").unwrap().unwrap().value, b"Moved".to_vec());
+}
+
+#[test]
+fn vbucket_forward_rejects_too() {
+
+  let servers = vec![FakeServer::start().unwrap(), FakeServer::start().unwrap()];
+  let names: Vec<String> = servers.iter().map(|s| s.addr().to_string()).collect();
+  let map = VBucketMap::new(names, chains(64, 0)).unwrap()
+    .with_forward(chains(64, 1)).unwrap();
+  let mut c = VBucketClient::connect(map).unwrap();
+
+  //both the master and the fast-forward server reject the
+  //vbucket, the map is out of date
+  servers[0].push(Action::Status(StatusField::VBucketNotHere));
+  servers[1].push(Action::Status(StatusField::VBucketNotHere));
+  match c.set(b"Hello", b"World", 0, 0) {
+    Err(client::ClientError::Status(StatusField::VBucketNotHere)) => { },
+    x => panic!("the rejection should be returned, not {:?}", x)
+  };
+  assert_eq!(servers[1].received().len(), 1);
+  assert!(c.needs_refresh());
+}